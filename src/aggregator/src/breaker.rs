@@ -0,0 +1,229 @@
+use candid::CandidType;
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+// Per-adapter circuit breaker. A closed breaker lets every call through and
+// opens after `BREAKER_FAILURE_THRESHOLD` consecutive failures. An open
+// breaker rejects calls until its backoff has passed, then turns half-open
+// and lets one probe through: success closes it, failure opens it again with
+// twice the backoff, up to `BREAKER_MAX_BACKOFF_SECS`.
+
+static FAILURE_THRESHOLD: Lazy<u32> = Lazy::new(|| {
+    option_env!("BREAKER_FAILURE_THRESHOLD")
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(3)
+        .max(1)
+});
+
+static BASE_BACKOFF_NS: Lazy<u64> = Lazy::new(|| {
+    option_env!("BREAKER_BACKOFF_SECS")
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(30)
+        .max(1)
+        * 1_000_000_000
+});
+
+static MAX_BACKOFF_NS: Lazy<u64> = Lazy::new(|| {
+    option_env!("BREAKER_MAX_BACKOFF_SECS")
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(3600)
+        * 1_000_000_000
+});
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    /// A probe is in flight
+    HalfOpen,
+}
+
+#[derive(Clone, Debug)]
+struct Breaker {
+    state: BreakerState,
+    failures: u32,
+    backoff_ns: u64,
+    /// When the breaker opened or the current probe started
+    since: u64,
+    last_error: Option<String>,
+    last_success_at: Option<u64>,
+    last_failure_at: Option<u64>,
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Breaker {
+            state: BreakerState::Closed,
+            failures: 0,
+            backoff_ns: *BASE_BACKOFF_NS,
+            since: 0,
+            last_error: None,
+            last_success_at: None,
+            last_failure_at: None,
+        }
+    }
+}
+
+impl Breaker {
+    fn retry_at(&self) -> Option<u64> {
+        (self.state != BreakerState::Closed).then(|| self.since.saturating_add(self.backoff_ns))
+    }
+
+    fn open(&mut self, now: u64) {
+        self.state = BreakerState::Open;
+        self.since = now;
+    }
+}
+
+/// Breaker of one adapter as reported by `get_adapter_health`
+#[derive(Clone, Debug, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct AdapterHealth {
+    pub id: String,
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub last_success_at: Option<u64>,
+    pub last_failure_at: Option<u64>,
+    /// When an open breaker lets the next probe through
+    pub retry_at: Option<u64>,
+}
+
+static BREAKERS: Lazy<DashMap<String, Breaker>> = Lazy::new(DashMap::new);
+
+/// Whether a call to `id` may go ahead. An open breaker whose backoff has
+/// passed turns half-open and lets this call through as the probe.
+pub fn allow(id: &str, now: u64) -> bool {
+    let Some(mut b) = BREAKERS.get_mut(id) else {
+        return true;
+    };
+    match b.state {
+        BreakerState::Closed => true,
+        // a probe that never reported back doesn't block the adapter forever
+        BreakerState::Open | BreakerState::HalfOpen if b.retry_at().is_some_and(|t| now >= t) => {
+            b.state = BreakerState::HalfOpen;
+            b.since = now;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Whether an open breaker is due for a probe
+pub fn due(id: &str, now: u64) -> bool {
+    BREAKERS
+        .get(id)
+        .is_some_and(|b| b.state == BreakerState::Open && b.retry_at().is_some_and(|t| now >= t))
+}
+
+/// Record the outcome of a call to `id`
+pub fn record(id: &str, error: Option<&str>, now: u64) {
+    let mut b = BREAKERS.entry(id.to_string()).or_default();
+    match error {
+        None => {
+            if b.state != BreakerState::Closed {
+                tracing::info!("adapter {id} recovered; closing its circuit");
+            }
+            b.state = BreakerState::Closed;
+            b.failures = 0;
+            b.backoff_ns = *BASE_BACKOFF_NS;
+            b.last_success_at = Some(now);
+        }
+        Some(e) => {
+            b.failures = b.failures.saturating_add(1);
+            b.last_error = Some(e.to_string());
+            b.last_failure_at = Some(now);
+            match b.state {
+                BreakerState::HalfOpen => {
+                    b.backoff_ns = b.backoff_ns.saturating_mul(2).min(*MAX_BACKOFF_NS);
+                    b.open(now);
+                }
+                BreakerState::Closed if b.failures >= *FAILURE_THRESHOLD => {
+                    tracing::warn!(
+                        "adapter {id} failed {} times; opening its circuit",
+                        b.failures
+                    );
+                    b.open(now);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Open the breaker of `id` straight away, e.g. when a startup check fails
+pub fn trip(id: &str, error: &str, now: u64) {
+    let mut b = BREAKERS.entry(id.to_string()).or_default();
+    b.failures = b.failures.saturating_add(1);
+    b.last_error = Some(error.to_string());
+    b.last_failure_at = Some(now);
+    b.open(now);
+}
+
+pub fn health(id: &str) -> AdapterHealth {
+    let b = BREAKERS.get(id).map(|b| b.clone()).unwrap_or_default();
+    AdapterHealth {
+        id: id.to_string(),
+        state: b.state,
+        consecutive_failures: b.failures,
+        retry_at: b.retry_at(),
+        last_error: b.last_error,
+        last_success_at: b.last_success_at,
+        last_failure_at: b.last_failure_at,
+    }
+}
+
+/// Breakers of the registered adapters
+pub fn all() -> Vec<AdapterHealth> {
+    crate::dex::registry::get()
+        .iter()
+        .map(|e| health(e.id()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const S: u64 = 1_000_000_000;
+
+    #[test]
+    fn opens_after_threshold_and_recovers() {
+        let id = "BREAKER_TEST_A";
+        for i in 0..*FAILURE_THRESHOLD {
+            assert!(allow(id, i as u64));
+            record(id, Some("down"), i as u64);
+        }
+        assert_eq!(health(id).state, BreakerState::Open);
+        assert!(!allow(id, 10));
+        let retry = health(id).retry_at.unwrap();
+        assert!(due(id, retry));
+
+        // the first call after the backoff is the probe; others wait for it
+        assert!(allow(id, retry));
+        assert_eq!(health(id).state, BreakerState::HalfOpen);
+        assert!(!allow(id, retry + 1));
+        record(id, None, retry + 2);
+        let h = health(id);
+        assert_eq!((h.state, h.consecutive_failures), (BreakerState::Closed, 0));
+        assert_eq!(h.last_error.as_deref(), Some("down"));
+        assert!(allow(id, retry + 3));
+    }
+
+    #[test]
+    fn failed_probes_back_off_exponentially() {
+        let id = "BREAKER_TEST_B";
+        trip(id, "metadata failed", 0);
+        let first = health(id).retry_at.unwrap();
+        assert_eq!(first, *BASE_BACKOFF_NS);
+        assert!(allow(id, first));
+        record(id, Some("timeout"), first);
+        let second = health(id).retry_at.unwrap();
+        assert_eq!(second - first, 2 * *BASE_BACKOFF_NS);
+
+        // a probe that never reports is retried after its backoff
+        assert!(allow(id, second));
+        assert!(allow(id, second + 4 * *BASE_BACKOFF_NS + S));
+        assert!(health("BREAKER_TEST_UNSEEN").retry_at.is_none());
+    }
+}
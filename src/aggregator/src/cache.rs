@@ -1,13 +1,384 @@
-use crate::HoldingSummary;
-use bx_core::Holding;
+use crate::memory::{self, Cached, Candid, Memory};
+use crate::{cert, config, sources, HoldingSummary};
+use bx_core::{Holding, TypedHolding};
 use candid::Principal;
-use dashmap::DashMap;
+use ic_stable_structures::StableBTreeMap;
 use once_cell::sync::Lazy;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 
-pub type Cache = DashMap<Principal, (Vec<Holding>, Vec<HoldingSummary>, u64)>;
+// Holdings are cached per principal in a bounded LRU map. An entry younger
+// than the fresh window is served as is; one inside the stale window is
+// still served, flagged stale, while the caller refreshes it in the
+// background; anything older is a miss.
+//
+// The global cache is certified: every write and eviction is mirrored into
+// the certified tree in `cert`, so `get_holdings_cert` can prove whatever a
+// query serves from it. Evicting a principal also drops the source statuses
+// of the fetch its entry came from. Its entries are kept in stable memory
+// too, so `restore` can reload them and rebuild the certified tree after an
+// upgrade instead of leaving the old certified data in place.
 
-static CACHE: Lazy<Cache> = Lazy::new(DashMap::new);
+// The windows and the size of the global cache are read from `config` on
+// every use, so `set_config` changes take effect on the next lookup.
+
+/// The fresh window in nanoseconds
+fn fresh_ns() -> u64 {
+    config::get().cache_fresh_secs.saturating_mul(1_000_000_000)
+}
+
+/// The fresh and stale windows together in nanoseconds
+fn servable_ns() -> u64 {
+    let c = config::get();
+    (c.cache_fresh_secs + c.cache_stale_secs).saturating_mul(1_000_000_000)
+}
+
+/// Holdings, their summary and when they were fetched
+pub type Entry = (Vec<TypedHolding>, Vec<HoldingSummary>, u64);
+
+/// Whether an entry fetched at `fetched_at` is inside the fresh window
+pub fn is_fresh(fetched_at: u64, now: u64) -> bool {
+    now.saturating_sub(fetched_at) < fresh_ns()
+}
+
+/// An entry of the global cache as kept in stable memory
+#[derive(candid::CandidType, serde::Deserialize)]
+struct Stored {
+    holdings: Vec<TypedHolding>,
+    summary: Vec<HoldingSummary>,
+    fetched_at: u64,
+}
+
+thread_local! {
+    static STORED: RefCell<StableBTreeMap<Candid<Principal>, Cached<Stored>, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::HOLDINGS_CACHE)));
+}
+
+pub enum Lookup {
+    Fresh(Entry),
+    /// Past the fresh window but still servable; refresh it
+    Stale(Entry),
+    Miss,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<Principal, (Entry, u64)>,
+    /// Last use of each entry, oldest first
+    order: BTreeMap<u64, Principal>,
+    tick: u64,
+}
+
+impl Inner {
+    fn touch(&mut self, principal: Principal) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some((_, used)) = self.entries.get_mut(&principal) {
+            self.order.remove(used);
+            *used = tick;
+            self.order.insert(tick, principal);
+        }
+    }
+}
+
+pub struct Cache {
+    inner: Mutex<Inner>,
+    /// Most entries kept; `None` follows `holdings_cache_size`
+    capacity: Option<usize>,
+    /// Mirror entries into the certified tree
+    certified: bool,
+    hits: AtomicU64,
+    stale_hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub len: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub stale_hits: u64,
+    pub misses: u64,
+}
+
+impl Cache {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Cache {
+            capacity: Some(capacity),
+            ..Cache::configured()
+        }
+    }
+
+    /// A cache sized by `holdings_cache_size`, following changes to it
+    pub fn configured() -> Self {
+        Cache {
+            inner: Mutex::new(Inner::default()),
+            capacity: None,
+            certified: false,
+            hits: AtomicU64::new(0),
+            stale_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+            .unwrap_or_else(|| config::get().holdings_cache_size as usize)
+    }
+
+    /// Certify every entry written to this cache and keep it in stable memory
+    pub fn certified(mut self) -> Self {
+        self.certified = true;
+        self
+    }
+
+    /// Cached entry regardless of age
+    pub fn get(&self, principal: &Principal) -> Option<Entry> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.touch(*principal);
+        inner.entries.get(principal).map(|(e, _)| e.clone())
+    }
+
+    /// Classify the entry of `principal` at `now` and count the outcome
+    pub fn lookup(&self, principal: &Principal, now: u64) -> Lookup {
+        let res = match self.get(principal) {
+            Some(e) if is_fresh(e.2, now) => Lookup::Fresh(e),
+            Some(e) if now.saturating_sub(e.2) < servable_ns() => Lookup::Stale(e),
+            _ => Lookup::Miss,
+        };
+        let counter = match res {
+            Lookup::Fresh(_) => &self.hits,
+            Lookup::Stale(_) => &self.stale_hits,
+            Lookup::Miss => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        res
+    }
+
+    /// Store `entry`, evicting the least recently used one when full
+    pub fn insert(&self, principal: Principal, entry: Entry) {
+        if self.certified {
+            let stored = Stored {
+                holdings: entry.0.clone(),
+                summary: entry.1.clone(),
+                fetched_at: entry.2,
+            };
+            STORED.with(|s| {
+                s.borrow_mut()
+                    .insert(Candid(principal), Cached(Some(stored)))
+            });
+        }
+        self.insert_heap(principal, entry);
+    }
+
+    fn insert_heap(&self, principal: Principal, entry: Entry) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((_, used)) = inner.entries.remove(&principal) {
+            inner.order.remove(&used);
+        }
+        // a lowered size is caught up with on the next insert
+        let capacity = self.capacity();
+        while inner.entries.len() >= capacity {
+            let Some((_, oldest)) = inner.order.pop_first() else {
+                break;
+            };
+            inner.entries.remove(&oldest);
+            self.dropped(oldest);
+        }
+        if self.certified {
+            let legacy: Vec<Holding> = entry.0.iter().map(Holding::from).collect();
+            cert::update(principal, &legacy, &entry.1, entry.2);
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.entries.insert(principal, (entry, tick));
+        inner.order.insert(tick, principal);
+    }
+
+    pub fn remove(&self, principal: &Principal) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((_, used)) = inner.entries.remove(principal) {
+            inner.order.remove(&used);
+            self.dropped(*principal);
+        }
+    }
+
+    pub fn clear(&self) {
+        *self.inner.lock().unwrap() = Inner::default();
+        if self.certified {
+            cert::clear();
+            sources::clear();
+            STORED.with(|s| {
+                let mut stored = s.borrow_mut();
+                let keys: Vec<_> = stored.iter().map(|(k, _)| k).collect();
+                for k in keys {
+                    stored.remove(&k);
+                }
+            });
+        }
+    }
+
+    /// Forget what the global cache kept next to an entry it no longer has
+    fn dropped(&self, principal: Principal) {
+        if self.certified {
+            cert::remove(principal);
+            sources::forget(&principal);
+            STORED.with(|s| s.borrow_mut().remove(&Candid(principal)));
+        }
+    }
+
+    /// Reload the entries kept in stable memory, oldest first so the LRU
+    /// order follows their age, and certify them. Entries past the stale
+    /// window or that no longer decode are dropped.
+    fn restore(&self, now: u64) {
+        let mut live = Vec::new();
+        let mut expired = Vec::new();
+        STORED.with(|s| {
+            for (k, v) in s.borrow().iter() {
+                match v.0 {
+                    Some(e) if now.saturating_sub(e.fetched_at) < servable_ns() => {
+                        live.push((k.0, (e.holdings, e.summary, e.fetched_at)))
+                    }
+                    _ => expired.push(k),
+                }
+            }
+        });
+        STORED.with(|s| {
+            let mut stored = s.borrow_mut();
+            for k in expired {
+                stored.remove(&k);
+            }
+        });
+        live.sort_by_key(|(_, e)| e.2);
+        for (principal, entry) in live {
+            self.insert_heap(principal, entry);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            len: self.len(),
+            capacity: self.capacity(),
+            hits: self.hits.load(Ordering::Relaxed),
+            stale_hits: self.stale_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static CACHE: Lazy<Cache> = Lazy::new(|| Cache::configured().certified());
 
 pub fn get() -> &'static Cache {
     &CACHE
 }
+
+/// Reload the global cache after an upgrade and certify what it holds. Run
+/// from `post_upgrade` even when nothing was kept, so the certified data
+/// left by the previous release is replaced.
+pub fn restore(now: u64) {
+    CACHE.restore(now);
+    cert::publish_root();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn p(i: u8) -> Principal {
+        Principal::from_slice(&[0xCA, i])
+    }
+
+    fn status() -> sources::SourceStatus {
+        sources::SourceStatus {
+            kind: "ledger".into(),
+            id: "ledger".into(),
+            principal: None,
+            holdings: 0,
+            error: None,
+            latency_ms: 0,
+            fetched_at: 1,
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = Cache::with_capacity(2);
+        cache.insert(p(1), (vec![], vec![], 0));
+        cache.insert(p(2), (vec![], vec![], 0));
+        assert!(cache.get(&p(1)).is_some());
+        cache.insert(p(3), (vec![], vec![], 0));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&p(2)).is_none());
+        assert!(cache.get(&p(1)).is_some());
+        cache.insert(p(1), (vec![], vec![], 5));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&p(1)).unwrap().2, 5);
+    }
+
+    #[test]
+    fn lookup_classifies_by_age() {
+        let cache = Cache::with_capacity(4);
+        cache.insert(p(1), (vec![], vec![], 1));
+        assert!(matches!(cache.lookup(&p(1), 1), Lookup::Fresh(_)));
+        assert!(matches!(
+            cache.lookup(&p(1), 1 + fresh_ns()),
+            Lookup::Stale(_)
+        ));
+        assert!(matches!(
+            cache.lookup(&p(1), 1 + servable_ns()),
+            Lookup::Miss
+        ));
+        assert!(matches!(cache.lookup(&p(2), 1), Lookup::Miss));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.stale_hits, stats.misses), (1, 1, 2));
+    }
+
+    #[test]
+    fn certified_cache_prunes_evicted_entries() {
+        let cache = Cache::with_capacity(1).certified();
+        let empty = cert::root_hash();
+        cache.insert(p(1), (vec![], vec![], 3));
+        sources::record(p(1), vec![status()]);
+        let root = cert::root_hash();
+        let expected = cert::entry_hash(&[], &[], 3);
+        assert!(cert::verify_witness(&cert::witness(p(1)), &root, p(1), expected).is_ok());
+        cache.insert(p(2), (vec![], vec![], 4));
+        let root = cert::root_hash();
+        assert!(cert::verify_witness(&cert::witness(p(1)), &root, p(1), expected).is_err());
+        assert!(sources::last(&p(1)).is_empty());
+        cache.remove(&p(2));
+        assert_eq!(cert::root_hash(), empty);
+    }
+
+    #[test]
+    fn certified_entries_are_restored_after_an_upgrade() {
+        let now = 10 * servable_ns();
+        let cache = Cache::with_capacity(4).certified();
+        cache.insert(p(1), (vec![], vec![], now - 1));
+        cache.insert(p(2), (vec![], vec![], 1));
+        let root = cert::root_hash();
+
+        // an upgrade keeps stable memory and loses the heap
+        cert::clear();
+        let upgraded = Cache::with_capacity(4).certified();
+        upgraded.restore(now);
+        assert_eq!(upgraded.get(&p(1)).unwrap().2, now - 1);
+        assert!(upgraded.get(&p(2)).is_none());
+        assert!(STORED.with(|s| s.borrow().get(&Candid(p(2))).is_none()));
+        assert_ne!(cert::root_hash(), root);
+        let expected = cert::entry_hash(&[], &[], now - 1);
+        let witness = cert::witness(p(1));
+        assert!(cert::verify_witness(&witness, &cert::root_hash(), p(1), expected).is_ok());
+        upgraded.clear();
+    }
+}
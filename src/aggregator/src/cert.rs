@@ -1,44 +1,249 @@
-#[cfg(target_arch = "wasm32")]
-use {
-    ic_certified_map::{leaf_hash, AsHashTree, Hash, RbTree},
-    serde::Serialize,
-    serde_cbor::Serializer,
-    std::cell::RefCell,
+use crate::{http_cert, HoldingSummary};
+use bx_core::Holding;
+use candid::Principal;
+use ic_certified_map::{
+    fork, fork_hash, labeled, labeled_hash, leaf_hash, AsHashTree, Hash, HashTree, RbTree,
 };
+use serde::Serialize;
+use serde_cbor::Serializer;
+use std::cell::RefCell;
+
+// Every cached holdings entry is certified. The certified data is the root of
+//
+//   holdings  -> principal text -> leaf hash of its `CertifiedEntry`
+//   http_expr -> route -> principal text -> `http_cert::Expr`
+//
+// where the second half is the HTTP certification v2 tree for the JSON
+// routes served by `http_request`. Entries leave both when they leave the
+// cache.
+
+const HOLDINGS: &[u8] = b"holdings";
+const HTTP_EXPR: &[u8] = b"http_expr";
+
+struct Trees {
+    holdings: RbTree<Vec<u8>, Hash>,
+    http: RbTree<Vec<u8>, RbTree<Vec<u8>, http_cert::Expr>>,
+}
+
+impl Trees {
+    fn holdings_hash(&self) -> Hash {
+        labeled_hash(HOLDINGS, &self.holdings.root_hash())
+    }
+
+    fn http_hash(&self) -> Hash {
+        labeled_hash(HTTP_EXPR, &self.http.root_hash())
+    }
+
+    fn root_hash(&self) -> Hash {
+        fork_hash(&self.holdings_hash(), &self.http_hash())
+    }
+}
 
-#[cfg(target_arch = "wasm32")]
 thread_local! {
-    static TREE: RefCell<RbTree<Vec<u8>, Hash>> = RefCell::new(RbTree::new());
+    static TREE: RefCell<Trees> = const {
+        RefCell::new(Trees {
+            holdings: RbTree::new(),
+            http: RbTree::new(),
+        })
+    };
+}
+
+/// What is certified for a principal, JSON encoded before hashing
+#[derive(Serialize)]
+pub struct CertifiedEntry<'a> {
+    pub holdings: &'a [Holding],
+    pub summary: &'a [HoldingSummary],
+    pub fetched_at: u64,
+}
+
+/// Leaf hash certified for an entry
+pub fn entry_hash(holdings: &[Holding], summary: &[HoldingSummary], fetched_at: u64) -> Hash {
+    let entry = CertifiedEntry {
+        holdings,
+        summary,
+        fetched_at,
+    };
+    leaf_hash(&serde_json::to_vec(&entry).expect("serialize certified entry"))
 }
 
 #[cfg(target_arch = "wasm32")]
-pub fn update(principal: candid::Principal, holdings: &[bx_core::Holding]) {
+fn publish(root: Hash) {
+    // certified data can't be set from queries; their state changes are
+    // discarded anyway
+    if ic_cdk::api::data_certificate().is_none() {
+        ic_cdk::api::set_certified_data(&root);
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn publish(_root: Hash) {}
+
+/// Certificate of the certified data; only available in queries
+#[cfg(target_arch = "wasm32")]
+pub fn data_certificate() -> Vec<u8> {
+    ic_cdk::api::data_certificate().unwrap_or_default()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn data_certificate() -> Vec<u8> {
+    Vec::new()
+}
+
+pub fn update(
+    principal: Principal,
+    holdings: &[Holding],
+    summary: &[HoldingSummary],
+    fetched_at: u64,
+) {
+    let hash = entry_hash(holdings, summary, fetched_at);
+    let key = principal.to_text().into_bytes();
     TREE.with(|t| {
         let mut tree = t.borrow_mut();
-        let bytes = serde_json::to_vec(holdings).expect("serialize holdings");
-        tree.insert(principal.to_text().into_bytes(), leaf_hash(&bytes));
-        ic_cdk::api::set_certified_data(&tree.root_hash());
+        tree.holdings.insert(key.clone(), hash);
+        for route in http_cert::ROUTES {
+            let expr = http_cert::Expr::new(&http_cert::body(route, holdings, summary));
+            if tree.http.get(route.as_bytes()).is_none() {
+                tree.http.insert(route.as_bytes().to_vec(), RbTree::new());
+            }
+            tree.http
+                .modify(route.as_bytes(), |paths| paths.insert(key.clone(), expr));
+        }
+        publish(tree.root_hash());
     });
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-pub fn update(_principal: candid::Principal, _holdings: &[bx_core::Holding]) {}
+pub fn remove(principal: Principal) {
+    let key = principal.to_text();
+    TREE.with(|t| {
+        let mut tree = t.borrow_mut();
+        tree.holdings.delete(key.as_bytes());
+        for route in http_cert::ROUTES {
+            tree.http
+                .modify(route.as_bytes(), |paths| paths.delete(key.as_bytes()));
+            let emptied = tree
+                .http
+                .get(route.as_bytes())
+                .is_some_and(|paths| paths.iter().next().is_none());
+            if emptied {
+                tree.http.delete(route.as_bytes());
+            }
+        }
+        publish(tree.root_hash());
+    });
+}
 
-#[cfg(target_arch = "wasm32")]
-pub fn witness(principal: candid::Principal) -> Vec<u8> {
+pub fn clear() {
+    TREE.with(|t| {
+        let mut tree = t.borrow_mut();
+        tree.holdings = RbTree::new();
+        tree.http = RbTree::new();
+        publish(tree.root_hash());
+    });
+}
+
+/// Certify the current root, e.g. after the trees were rebuilt
+pub fn publish_root() {
+    publish(root_hash());
+}
+
+/// Root hash of both trees, i.e. the certified data
+pub fn root_hash() -> Hash {
+    TREE.with(|t| t.borrow().root_hash())
+}
+
+fn encode(tree: HashTree<'_>) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut ser = Serializer::new(&mut out);
+    let _ = ser.self_describe();
+    tree.serialize(&mut ser).expect("serialize witness");
+    out
+}
+
+/// CBOR encoded witness for `principal`'s entry, or its absence
+pub fn witness(principal: Principal) -> Vec<u8> {
+    TREE.with(|t| {
+        let tree = t.borrow();
+        encode(fork(
+            labeled(
+                HOLDINGS,
+                tree.holdings.witness(principal.to_text().as_bytes()),
+            ),
+            HashTree::Pruned(tree.http_hash()),
+        ))
+    })
+}
+
+/// CBOR encoded witness for the certified response of `/<route>/<principal>`
+pub fn http_witness(route: &str, principal: Principal) -> Vec<u8> {
     TREE.with(|t| {
         let tree = t.borrow();
-        let mut out = Vec::new();
-        let mut ser = Serializer::new(&mut out);
-        let _ = ser.self_describe();
-        tree.witness(principal.to_text().as_bytes())
-            .serialize(&mut ser)
-            .expect("serialize witness");
-        out
+        let key = principal.to_text();
+        encode(fork(
+            HashTree::Pruned(tree.holdings_hash()),
+            labeled(
+                HTTP_EXPR,
+                tree.http
+                    .nested_witness(route.as_bytes(), |paths| paths.witness(key.as_bytes())),
+            ),
+        ))
     })
 }
 
+/// Check that `witness` hashes to `certified_data` and proves `expected` as
+/// the entry of `principal`. The certificate holding `certified_data` must
+/// be verified separately, see `ic_agent::Agent::verify`.
 #[cfg(not(target_arch = "wasm32"))]
-pub fn witness(_principal: candid::Principal) -> Vec<u8> {
-    Vec::new()
+pub fn verify_witness(
+    witness: &[u8],
+    certified_data: &[u8],
+    principal: Principal,
+    expected: Hash,
+) -> Result<(), String> {
+    use ic_agent::hash_tree::{HashTree, LookupResult};
+    let tree: HashTree<Vec<u8>> =
+        serde_cbor::from_slice(witness).map_err(|e| format!("invalid witness: {e}"))?;
+    if tree.digest()[..] != *certified_data {
+        return Err("witness does not match the certified data".into());
+    }
+    match tree.lookup_path([HOLDINGS, principal.to_text().as_bytes()]) {
+        LookupResult::Found(leaf) if leaf == expected => Ok(()),
+        LookupResult::Found(_) => Err("certified entry differs from the response".into()),
+        _ => Err(format!("no certified entry for {principal}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holding(amount: &str) -> Holding {
+        Holding {
+            source: "ledger".into(),
+            token: "ICP".into(),
+            amount: amount.into(),
+            status: "liquid".into(),
+            subaccount: None,
+            wallet: None,
+        }
+    }
+
+    #[test]
+    fn witness_proves_entry() {
+        let a = Principal::from_slice(&[0xCE, 1]);
+        let b = Principal::from_slice(&[0xCE, 2]);
+        let holdings = vec![holding("1.5")];
+        update(a, &holdings, &[], 7);
+        update(b, &[holding("2")], &[], 8);
+        let root = root_hash();
+        let expected = entry_hash(&holdings, &[], 7);
+        assert_eq!(verify_witness(&witness(a), &root, a, expected), Ok(()));
+        // a different timestamp is not what was certified
+        let other = entry_hash(&holdings, &[], 9);
+        assert!(verify_witness(&witness(a), &root, a, other).is_err());
+
+        remove(a);
+        assert!(verify_witness(&witness(a), &root_hash(), a, expected).is_err());
+        assert_ne!(root_hash(), root);
+        clear();
+    }
 }
@@ -0,0 +1,305 @@
+use crate::memory::{self, Candid, Memory};
+use crate::utils::DAY_SECS;
+use candid::{CandidType, Principal};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+// Limits controllers can change at runtime. The build-time values read with
+// `option_env!` stay the defaults; `set_config` stores overrides on top of
+// them in stable memory and records every changed field with its caller in
+// an audit trail.
+
+/// Effective runtime configuration
+#[derive(Clone, Copy, Debug, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct Config {
+    /// Most holdings returned per principal
+    pub max_holdings: u32,
+    /// Cycles required for most Candid calls
+    pub call_price: u128,
+    /// Cycles required for `claim_all_rewards`
+    pub claim_price: u128,
+    /// Claims allowed per principal per window
+    pub claim_daily_limit: u32,
+    pub claim_cooldown_secs: u64,
+    /// How long ledger metadata stays cached
+    pub meta_ttl_secs: u64,
+    /// LP positions kept in the stable LP cache
+    pub lp_cache_size: u64,
+    /// Principals kept in the holdings cache
+    pub holdings_cache_size: u64,
+    /// How long cached holdings are served without a refresh
+    pub cache_fresh_secs: u64,
+    /// How much longer they are served, flagged stale, while refreshed
+    pub cache_stale_secs: u64,
+}
+
+/// Fields to change; `None` leaves a field as it is. Stored as the set of
+/// overrides, so new fields must be `Option` too.
+#[derive(Clone, Debug, Default, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct ConfigUpdate {
+    pub max_holdings: Option<u32>,
+    pub call_price: Option<u128>,
+    pub claim_price: Option<u128>,
+    pub claim_daily_limit: Option<u32>,
+    pub claim_cooldown_secs: Option<u64>,
+    pub meta_ttl_secs: Option<u64>,
+    pub lp_cache_size: Option<u64>,
+    pub holdings_cache_size: Option<u64>,
+    pub cache_fresh_secs: Option<u64>,
+    pub cache_stale_secs: Option<u64>,
+}
+
+/// One field changed by `set_config`
+#[derive(Clone, Debug, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct ConfigChange {
+    /// Nanoseconds since the epoch
+    pub at: u64,
+    pub caller: Principal,
+    pub field: String,
+    pub old: String,
+    pub new: String,
+}
+
+fn env_or<T: std::str::FromStr>(value: Option<&str>, default: T) -> T {
+    value.and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// Values the canister was built with
+pub fn defaults() -> Config {
+    Config {
+        max_holdings: env_or(option_env!("MAX_HOLDINGS"), 500),
+        call_price: env_or(std::env::var("CALL_PRICE_CYCLES").ok().as_deref(), 0),
+        claim_price: env_or(std::env::var("CLAIM_PRICE_CYCLES").ok().as_deref(), 0),
+        claim_daily_limit: env_or(option_env!("CLAIM_DAILY_LIMIT"), 5),
+        claim_cooldown_secs: env_or(option_env!("CLAIM_COOLDOWN_SECS"), 60),
+        meta_ttl_secs: env_or(option_env!("META_TTL_SECS"), DAY_SECS),
+        lp_cache_size: env_or(option_env!("LP_CACHE_SIZE"), 1024),
+        holdings_cache_size: env_or(option_env!("HOLDINGS_CACHE_SIZE"), 10_000),
+        cache_fresh_secs: env_or(option_env!("CACHE_FRESH_SECS"), 60),
+        cache_stale_secs: env_or(option_env!("CACHE_STALE_SECS"), 300),
+    }
+}
+
+/// Most cycles a call may be priced at
+const MAX_PRICE: u128 = 1_000_000_000_000;
+
+fn check<T: PartialOrd + std::fmt::Display>(
+    field: &str,
+    value: T,
+    min: T,
+    max: T,
+) -> Result<(), String> {
+    if value < min || value > max {
+        return Err(format!("{field} must be between {min} and {max}"));
+    }
+    Ok(())
+}
+
+pub fn validate(c: &Config) -> Result<(), String> {
+    check("max_holdings", c.max_holdings, 1, 10_000)?;
+    check("call_price", c.call_price, 0, MAX_PRICE)?;
+    check("claim_price", c.claim_price, 0, MAX_PRICE)?;
+    check("claim_daily_limit", c.claim_daily_limit, 1, 1_000)?;
+    check("claim_cooldown_secs", c.claim_cooldown_secs, 0, DAY_SECS)?;
+    check("meta_ttl_secs", c.meta_ttl_secs, 60, 30 * DAY_SECS)?;
+    check("lp_cache_size", c.lp_cache_size, 1, 1_000_000)?;
+    check("holdings_cache_size", c.holdings_cache_size, 1, 1_000_000)?;
+    check("cache_fresh_secs", c.cache_fresh_secs, 1, DAY_SECS)?;
+    check("cache_stale_secs", c.cache_stale_secs, 0, DAY_SECS)
+}
+
+impl ConfigUpdate {
+    /// `base` with these fields changed
+    fn apply_to(&self, base: Config) -> Config {
+        Config {
+            max_holdings: self.max_holdings.unwrap_or(base.max_holdings),
+            call_price: self.call_price.unwrap_or(base.call_price),
+            claim_price: self.claim_price.unwrap_or(base.claim_price),
+            claim_daily_limit: self.claim_daily_limit.unwrap_or(base.claim_daily_limit),
+            claim_cooldown_secs: self.claim_cooldown_secs.unwrap_or(base.claim_cooldown_secs),
+            meta_ttl_secs: self.meta_ttl_secs.unwrap_or(base.meta_ttl_secs),
+            lp_cache_size: self.lp_cache_size.unwrap_or(base.lp_cache_size),
+            holdings_cache_size: self.holdings_cache_size.unwrap_or(base.holdings_cache_size),
+            cache_fresh_secs: self.cache_fresh_secs.unwrap_or(base.cache_fresh_secs),
+            cache_stale_secs: self.cache_stale_secs.unwrap_or(base.cache_stale_secs),
+        }
+    }
+
+    /// These overrides followed by `newer`
+    fn merge(&self, newer: &ConfigUpdate) -> ConfigUpdate {
+        ConfigUpdate {
+            max_holdings: newer.max_holdings.or(self.max_holdings),
+            call_price: newer.call_price.or(self.call_price),
+            claim_price: newer.claim_price.or(self.claim_price),
+            claim_daily_limit: newer.claim_daily_limit.or(self.claim_daily_limit),
+            claim_cooldown_secs: newer.claim_cooldown_secs.or(self.claim_cooldown_secs),
+            meta_ttl_secs: newer.meta_ttl_secs.or(self.meta_ttl_secs),
+            lp_cache_size: newer.lp_cache_size.or(self.lp_cache_size),
+            holdings_cache_size: newer.holdings_cache_size.or(self.holdings_cache_size),
+            cache_fresh_secs: newer.cache_fresh_secs.or(self.cache_fresh_secs),
+            cache_stale_secs: newer.cache_stale_secs.or(self.cache_stale_secs),
+        }
+    }
+}
+
+/// Fields that differ between `old` and `new` as `(field, old, new)`
+fn diff(old: &Config, new: &Config) -> Vec<(&'static str, String, String)> {
+    let mut out = Vec::new();
+    macro_rules! field {
+        ($($name:ident),*) => {$(
+            if old.$name != new.$name {
+                out.push((stringify!($name), old.$name.to_string(), new.$name.to_string()));
+            }
+        )*};
+    }
+    field!(
+        max_holdings,
+        call_price,
+        claim_price,
+        claim_daily_limit,
+        claim_cooldown_secs,
+        meta_ttl_secs,
+        lp_cache_size,
+        holdings_cache_size,
+        cache_fresh_secs,
+        cache_stale_secs
+    );
+    out
+}
+
+thread_local! {
+    static OVERRIDES: RefCell<StableCell<Candid<ConfigUpdate>, Memory>> = RefCell::new(
+        StableCell::init(memory::get(memory::CONFIG), Candid(ConfigUpdate::default()))
+            .expect("init config"),
+    );
+    static AUDIT: RefCell<StableBTreeMap<u64, Candid<ConfigChange>, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::CONFIG_AUDIT)));
+    /// Effective config, derived from the overrides on first use
+    static CURRENT: RefCell<Option<Config>> = const { RefCell::new(None) };
+}
+
+fn overrides() -> ConfigUpdate {
+    OVERRIDES.with(|o| o.borrow().get().0.clone())
+}
+
+pub fn get() -> Config {
+    CURRENT.with(|c| {
+        *c.borrow_mut()
+            .get_or_insert_with(|| overrides().apply_to(defaults()))
+    })
+}
+
+/// Validate and store `update` on behalf of `caller`, recording each changed
+/// field. Nothing is changed when validation fails.
+pub fn set(caller: Principal, update: ConfigUpdate, now: u64) -> Result<Config, String> {
+    let old = get();
+    let new = update.apply_to(old);
+    validate(&new)?;
+    let merged = overrides().merge(&update);
+    OVERRIDES.with(|o| {
+        o.borrow_mut().set(Candid(merged)).expect("write config");
+    });
+    AUDIT.with(|a| {
+        let mut audit = a.borrow_mut();
+        let next = audit.last_key_value().map(|(k, _)| k + 1).unwrap_or(0);
+        for (seq, (field, old, new)) in (next..).zip(diff(&old, &new)) {
+            tracing::info!("config {field} changed from {old} to {new} by {caller}");
+            let change = ConfigChange {
+                at: now,
+                caller,
+                field: field.to_string(),
+                old,
+                new,
+            };
+            audit.insert(seq, Candid(change));
+        }
+    });
+    CURRENT.with(|c| *c.borrow_mut() = Some(new));
+    Ok(new)
+}
+
+/// Audit trail, newest first
+pub fn history(offset: usize, limit: usize) -> Vec<ConfigChange> {
+    AUDIT.with(|a| {
+        a.borrow()
+            .iter()
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .map(|(_, c)| c.0)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_overrides_defaults_and_audits() {
+        let admin = Principal::from_slice(&[0xAD]);
+        assert_eq!(get(), defaults());
+        let update = ConfigUpdate {
+            max_holdings: Some(42),
+            meta_ttl_secs: Some(600),
+            ..Default::default()
+        };
+        let cfg = set(admin, update, 7).unwrap();
+        assert_eq!(cfg.max_holdings, 42);
+        assert_eq!(cfg.lp_cache_size, defaults().lp_cache_size);
+
+        // earlier overrides survive later partial updates
+        set(
+            admin,
+            ConfigUpdate {
+                max_holdings: Some(43),
+                ..Default::default()
+            },
+            8,
+        )
+        .unwrap();
+        CURRENT.with(|c| *c.borrow_mut() = None);
+        assert_eq!(get().meta_ttl_secs, 600);
+        assert_eq!(get().max_holdings, 43);
+
+        let log = history(0, 10);
+        assert_eq!(log.len(), 3);
+        assert_eq!(
+            (
+                log[0].field.as_str(),
+                log[0].old.as_str(),
+                log[0].new.as_str()
+            ),
+            ("max_holdings", "42", "43")
+        );
+        assert_eq!((log[0].caller, log[0].at), (admin, 8));
+        assert_eq!(history(2, 10)[0].field, "max_holdings");
+    }
+
+    #[test]
+    fn invalid_values_change_nothing() {
+        let admin = Principal::from_slice(&[0xAD]);
+        let err = set(
+            admin,
+            ConfigUpdate {
+                max_holdings: Some(100),
+                lp_cache_size: Some(0),
+                ..Default::default()
+            },
+            1,
+        )
+        .unwrap_err();
+        assert!(err.contains("lp_cache_size"));
+        let update = ConfigUpdate {
+            cache_fresh_secs: Some(0),
+            ..Default::default()
+        };
+        assert!(set(admin, update, 1)
+            .unwrap_err()
+            .contains("cache_fresh_secs"));
+        assert_eq!(get(), defaults());
+        assert!(history(0, 10).is_empty());
+        assert!(validate(&defaults()).is_ok());
+    }
+}
@@ -1,8 +1,12 @@
 #[cfg(target_arch = "wasm32")]
+use crate::memory::Memory;
+#[cfg(target_arch = "wasm32")]
 use candid::Principal;
 #[cfg(target_arch = "wasm32")]
 use ic_cdk::api::{call::call, canister_balance128, time};
 #[cfg(target_arch = "wasm32")]
+use ic_stable_structures::StableBTreeMap;
+#[cfg(target_arch = "wasm32")]
 use once_cell::sync::Lazy;
 #[cfg(target_arch = "wasm32")]
 use std::cell::RefCell;
@@ -12,7 +16,10 @@ thread_local! {
     static LAST_CHECK: RefCell<u64> = RefCell::new(0);
     static BACKOFF_UNTIL: RefCell<u64> = RefCell::new(0);
     static FAILURES: RefCell<u8> = RefCell::new(0);
-    static LOG: RefCell<Vec<String>> = RefCell::new(Vec::new());
+    /// Refill log keyed by sequence number, oldest first
+    static LOG: RefCell<StableBTreeMap<u64, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(crate::memory::get(crate::memory::CYCLES_LOG)),
+    );
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -36,10 +43,14 @@ const LOG_LIMIT: usize = 100;
 fn push_log(entry: String) {
     LOG.with(|l| {
         let mut log = l.borrow_mut();
-        if log.len() >= LOG_LIMIT {
-            log.remove(0);
+        let next = log.last_key_value().map_or(0, |(k, _)| k + 1);
+        log.insert(next, entry);
+        while log.len() as usize > LOG_LIMIT {
+            match log.first_key_value() {
+                Some((k, _)) => log.remove(&k),
+                None => break,
+            };
         }
-        log.push(entry);
     });
 }
 
@@ -127,24 +138,15 @@ pub async fn tick() {
 
 #[cfg(target_arch = "wasm32")]
 pub fn log() -> Vec<String> {
-    LOG.with(|l| l.borrow().clone())
-}
-
-#[cfg(target_arch = "wasm32")]
-pub fn take_log() -> Vec<String> {
-    LOG.with(|l| std::mem::take(&mut *l.borrow_mut()))
+    LOG.with(|l| l.borrow().iter().map(|(_, v)| v).collect())
 }
 
+/// Import a log saved by a release that serialised state on upgrade
 #[cfg(target_arch = "wasm32")]
 pub fn set_log(log: Vec<String>) {
-    LOG.with(|l| {
-        let mut target = l.borrow_mut();
-        *target = if log.len() > LOG_LIMIT {
-            log[log.len() - LOG_LIMIT..].to_vec()
-        } else {
-            log
-        };
-    });
+    for entry in log {
+        push_log(entry);
+    }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -154,10 +156,6 @@ pub fn log() -> Vec<String> {
     Vec::new()
 }
 #[cfg(not(target_arch = "wasm32"))]
-pub fn take_log() -> Vec<String> {
-    Vec::new()
-}
-#[cfg(not(target_arch = "wasm32"))]
 pub fn set_log(_: Vec<String>) {}
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
@@ -1,18 +1,13 @@
-use super::DexAdapter;
+use super::{Capabilities, DexAdapter, PoolReserves};
 use crate::error::FetchError;
-#[cfg(not(target_arch = "wasm32"))]
 use crate::{
-    lp_cache,
-    utils::{format_amount, get_agent, now},
+    lp_cache, scheduler,
+    utils::{call_query, now},
 };
 use async_trait::async_trait;
-use bx_core::Holding;
-use candid::{CandidType, Nat, Principal};
-#[cfg(not(target_arch = "wasm32"))]
-use candid::{Decode, Encode};
-#[cfg(not(target_arch = "wasm32"))]
+use bx_core::{HoldingKind, TypedHolding};
+use candid::{CandidType, Decode, Encode, Nat, Principal};
 use dashmap::DashMap;
-#[cfg(not(target_arch = "wasm32"))]
 use once_cell::sync::Lazy;
 use serde::Deserialize;
 
@@ -42,6 +37,12 @@ struct UserPositionInfoWithTokenAmount {
     token0_amount: Nat,
     #[serde(rename = "token1Amount")]
     token1_amount: Nat,
+    /// Fees earned by the position and not yet collected; what `claim`
+    /// pays out. Pools that don't report them decode as `None`.
+    #[serde(rename = "tokensOwed0")]
+    tokens_owed0: Option<Nat>,
+    #[serde(rename = "tokensOwed1")]
+    tokens_owed1: Option<Nat>,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -50,149 +51,217 @@ struct PoolMetadata {
     token1_decimals: u8,
 }
 
-#[cfg(not(target_arch = "wasm32"))]
 static META_CACHE: Lazy<DashMap<Principal, (PoolMetadata, u64)>> = Lazy::new(DashMap::new);
-#[cfg(not(target_arch = "wasm32"))]
 const META_TTL_NS: u64 = crate::utils::DAY_NS; // 24h
 
 #[async_trait]
 impl DexAdapter for IcpswapAdapter {
-    async fn fetch_positions(&self, principal: Principal) -> Result<Vec<Holding>, FetchError> {
-        fetch_positions_impl(principal).await
+    fn id(&self) -> &str {
+        "ICPSWAP_FACTORY"
+    }
+
+    fn name(&self) -> &str {
+        "ICPSwap"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            positions: true,
+            claimable: true,
+            claim: true,
+            prices: true,
+            pools: true,
+        }
+    }
+
+    fn config(&self) -> Vec<(String, String)> {
+        vec![("factory".to_string(), self.factory.to_text())]
+    }
+
+    async fn health(&self) -> Result<(), FetchError> {
+        fetch_pools(self.factory).await.map(|_| ())
+    }
+
+    async fn fetch_positions(&self, principal: Principal) -> Result<Vec<TypedHolding>, FetchError> {
+        fetch_positions_impl(self.factory, principal).await
+    }
+
+    async fn pool_reserves(&self) -> Result<Vec<PoolReserves>, FetchError> {
+        pool_reserves_impl(self.factory).await
     }
 
     #[cfg(feature = "claim")]
     async fn claim_rewards(&self, principal: Principal) -> Result<u64, String> {
-        claim_rewards_impl(principal).await
+        claim_rewards_impl(self.factory, principal).await
     }
 }
 
-pub struct IcpswapAdapter;
+pub struct IcpswapAdapter {
+    factory: Principal,
+}
+
+impl IcpswapAdapter {
+    pub fn new(factory: Principal) -> Self {
+        Self { factory }
+    }
+}
 
-#[cfg(not(target_arch = "wasm32"))]
 pub fn clear_cache() {
     META_CACHE.clear();
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-async fn fetch_positions_impl(principal: Principal) -> Result<Vec<Holding>, FetchError> {
-    let factory_id = match crate::utils::env_principal("ICPSWAP_FACTORY") {
-        Some(p) => p,
-        None => return Err(FetchError::InvalidConfig("factory".into())),
-    };
-    let agent = get_agent().await;
+async fn fetch_pools(factory_id: Principal) -> Result<Vec<PoolData>, FetchError> {
     let arg = Encode!().map_err(|_| FetchError::InvalidResponse)?;
-    let bytes = match agent
-        .query(&factory_id, "getPools")
-        .with_arg(arg)
-        .call()
-        .await
-    {
-        Ok(b) => b,
-        Err(e) => return Err(FetchError::from(e)),
+    let bytes = call_query(factory_id, "getPools", arg).await?;
+    Decode!(&bytes, Vec<PoolData>).map_err(|_| FetchError::InvalidResponse)
+}
+
+fn token_holding(address: &str, amount: Nat, decimals: u8, kind: HoldingKind) -> TypedHolding {
+    TypedHolding {
+        ledger: Principal::from_text(address).ok(),
+        ..TypedHolding::new("ICPSwap", address, amount, decimals, kind)
+    }
+}
+
+async fn fetch_positions_impl(
+    factory_id: Principal,
+    principal: Principal,
+) -> Result<Vec<TypedHolding>, FetchError> {
+    let pools = fetch_pools(factory_id).await?;
+    // pools are queried in parallel; the scheduler bounds the calls in flight
+    let per_pool = scheduler::join_all(pools.iter().map(|pool| pool_positions(pool, principal)));
+    // a pool that can't be read fails the adapter rather than reporting an
+    // empty position
+    let per_pool = per_pool.await.into_iter().collect::<Result<Vec<_>, _>>()?;
+    Ok(per_pool.into_iter().flatten().collect())
+}
+
+async fn pool_positions(
+    pool: &PoolData,
+    principal: Principal,
+) -> Result<Vec<TypedHolding>, FetchError> {
+    // pools without a block height are cached at height 0, but a call the
+    // scheduler turned away says nothing about the pool
+    let height = match crate::utils::dex_block_height(pool.canister_id).await {
+        Ok(height) => height,
+        Err(e @ FetchError::Busy(_)) => return Err(e),
+        Err(_) => 0,
     };
-    let pools: Vec<PoolData> =
-        Decode!(&bytes, Vec<PoolData>).map_err(|_| FetchError::InvalidResponse)?;
-    let mut out = Vec::with_capacity(pools.len() * 3);
-    for pool in pools.iter() {
-        let height = crate::utils::dex_block_height(&agent, pool.canister_id)
-            .await
-            .unwrap_or(0);
-        let pool_key = pool.key.clone();
-        let holdings = lp_cache::get_or_fetch(principal, &pool_key, height, || async {
-            let positions: Vec<UserPositionInfoWithTokenAmount> =
-                query_positions(&agent, pool.canister_id, principal)
-                    .await
-                    .unwrap_or_default();
-            let meta = match fetch_meta(&agent, pool.canister_id).await {
-                Some(m) => m,
-                None => return Vec::new(),
-            };
-            let mut temp = Vec::with_capacity(positions.len() * 3);
-            for pos in positions {
-                let a0 = format_amount(pos.token0_amount, meta.token0_decimals);
-                temp.push(Holding {
-                    source: "ICPSwap".into(),
-                    token: pool.token0.address.clone(),
-                    amount: a0,
-                    status: "lp_escrow".into(),
-                });
-                let a1 = format_amount(pos.token1_amount, meta.token1_decimals);
-                temp.push(Holding {
-                    source: "ICPSwap".into(),
-                    token: pool.token1.address.clone(),
-                    amount: a1,
-                    status: "lp_escrow".into(),
-                });
-            }
-            temp
-        })
-        .await;
-        out.extend(holdings);
+    let pool_key = pool.key.clone();
+    lp_cache::get_or_fetch(principal, &pool_key, height, || async {
+        // one call at a time per pool, so a fan-out over pools makes no more
+        // calls at once than it runs pools
+        let meta = fetch_meta(pool.canister_id).await?;
+        let positions = query_positions(pool.canister_id, principal).await?;
+        let mut temp = Vec::with_capacity(positions.len() * 4);
+        for pos in positions {
+            temp.extend(position_holdings(pool, &meta, pos));
+        }
+        Ok(temp)
+    })
+    .await
+}
+
+/// The LP holdings of `pos` and, when it has fees to collect, the claimable
+/// ones `claim` would pay out
+fn position_holdings(
+    pool: &PoolData,
+    meta: &PoolMetadata,
+    pos: UserPositionInfoWithTokenAmount,
+) -> Vec<TypedHolding> {
+    let lp = || HoldingKind::LpPosition {
+        pool: pool.key.clone(),
+    };
+    let owed = || HoldingKind::Claimable {
+        claim_from: Some(pool.canister_id),
+        pool: Some(pool.key.clone()),
+    };
+    let mut out = vec![
+        token_holding(
+            &pool.token0.address,
+            pos.token0_amount,
+            meta.token0_decimals,
+            lp(),
+        ),
+        token_holding(
+            &pool.token1.address,
+            pos.token1_amount,
+            meta.token1_decimals,
+            lp(),
+        ),
+    ];
+    let zero = Nat::from(0u64);
+    let tokens = [
+        (&pool.token0.address, pos.tokens_owed0, meta.token0_decimals),
+        (&pool.token1.address, pos.tokens_owed1, meta.token1_decimals),
+    ];
+    for (address, amount, decimals) in tokens {
+        if let Some(amount) = amount.filter(|a| *a > zero) {
+            out.push(token_holding(address, amount, decimals, owed()));
+        }
     }
-    Ok(out)
+    out
 }
 
-#[cfg(target_arch = "wasm32")]
-async fn fetch_positions_impl(_principal: Principal) -> Result<Vec<Holding>, FetchError> {
-    Ok(Vec::new())
+async fn pool_reserves_impl(factory_id: Principal) -> Result<Vec<PoolReserves>, FetchError> {
+    let pools = fetch_pools(factory_id).await?;
+    let reserves = scheduler::join_all(pools.into_iter().map(pool_reserve)).await;
+    reserves.into_iter().filter_map(Result::transpose).collect()
+}
+
+async fn pool_reserve(pool: PoolData) -> Result<Option<PoolReserves>, FetchError> {
+    let arg = Encode!().map_err(|_| FetchError::InvalidResponse)?;
+    let Ok(meta) = fetch_meta(pool.canister_id).await else {
+        return Ok(None);
+    };
+    let reserves = match call_query(pool.canister_id, "get_reserves", arg).await {
+        Ok(bytes) => Decode!(&bytes, Nat, Nat).map_err(|_| FetchError::InvalidResponse)?,
+        Err(e) => {
+            tracing::warn!("reserves for {} unavailable: {e}", pool.key);
+            return Ok(None);
+        }
+    };
+    Ok(Some(PoolReserves {
+        token_a: pool.token0.address,
+        decimals_a: meta.token0_decimals,
+        reserve_a: reserves.0,
+        token_b: pool.token1.address,
+        decimals_b: meta.token1_decimals,
+        reserve_b: reserves.1,
+    }))
 }
 
-#[cfg(not(target_arch = "wasm32"))]
 async fn query_positions(
-    agent: &ic_agent::Agent,
     cid: Principal,
     owner: Principal,
-) -> Option<Vec<UserPositionInfoWithTokenAmount>> {
-    let arg = Encode!(&owner).ok()?;
-    let bytes = agent
-        .query(&cid, "get_user_positions_by_principal")
-        .with_arg(arg)
-        .call()
-        .await
-        .ok()?;
-    Decode!(&bytes, Vec<UserPositionInfoWithTokenAmount>).ok()
+) -> Result<Vec<UserPositionInfoWithTokenAmount>, FetchError> {
+    let arg = Encode!(&owner).map_err(|_| FetchError::InvalidResponse)?;
+    let bytes = call_query(cid, "get_user_positions_by_principal", arg).await?;
+    Decode!(&bytes, Vec<UserPositionInfoWithTokenAmount>).map_err(|_| FetchError::InvalidResponse)
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-async fn fetch_meta(agent: &ic_agent::Agent, cid: Principal) -> Option<PoolMetadata> {
+async fn fetch_meta(cid: Principal) -> Result<PoolMetadata, FetchError> {
     if let Some(entry) = META_CACHE.get(&cid) {
         if entry.value().1 > now() {
-            return Some(entry.value().0.clone());
+            return Ok(entry.value().0.clone());
         }
     }
-    let arg = Encode!().ok()?;
-    let bytes = agent
-        .query(&cid, "metadata")
-        .with_arg(arg)
-        .call()
-        .await
-        .ok()?;
-    let meta: PoolMetadata = Decode!(&bytes, PoolMetadata).ok()?;
+    let arg = Encode!().map_err(|_| FetchError::InvalidResponse)?;
+    let bytes = call_query(cid, "metadata", arg).await?;
+    let meta = Decode!(&bytes, PoolMetadata).map_err(|_| FetchError::InvalidResponse)?;
     META_CACHE.insert(cid, (meta.clone(), now() + META_TTL_NS));
-    Some(meta)
+    Ok(meta)
 }
 
 #[cfg(all(feature = "claim", not(target_arch = "wasm32")))]
-async fn claim_rewards_impl(principal: Principal) -> Result<u64, String> {
+async fn claim_rewards_impl(factory_id: Principal, principal: Principal) -> Result<u64, String> {
     use crate::cache;
-    let factory_id = match crate::utils::env_principal("ICPSWAP_FACTORY") {
-        Some(p) => p,
-        None => return Err("factory".into()),
-    };
     let ledger = crate::ledger_fetcher::LEDGERS
         .first()
         .cloned()
         .ok_or("ledger")?;
-    let agent = get_agent().await;
-    let arg = Encode!().map_err(|_| "encode")?;
-    let bytes = agent
-        .query(&factory_id, "getPools")
-        .with_arg(arg)
-        .call()
-        .await
-        .map_err(|e| e.to_string())?;
-    let pools: Vec<PoolData> = Decode!(&bytes, Vec<PoolData>).map_err(|_| "invalid response")?;
+    let agent = crate::utils::get_agent().await;
+    let pools = fetch_pools(factory_id).await.map_err(|e| e.to_string())?;
     let mut total: u64 = 0;
     for pool in pools {
         let arg = Encode!(&principal, &ledger).map_err(|e| e.to_string())?;
@@ -206,7 +275,7 @@ async fn claim_rewards_impl(principal: Principal) -> Result<u64, String> {
         total = total.checked_add(spent).ok_or("overflow")?;
     }
     // refresh cache
-    let holdings = fetch_positions_impl(principal)
+    let holdings = fetch_positions_impl(factory_id, principal)
         .await
         .map_err(|e| format!("{:?}", e))?;
     let summary = crate::summarise(&holdings).map_err(|e| e.to_string())?;
@@ -215,18 +284,14 @@ async fn claim_rewards_impl(principal: Principal) -> Result<u64, String> {
 }
 
 #[cfg(all(feature = "claim", target_arch = "wasm32"))]
-async fn claim_rewards_impl(principal: Principal) -> Result<u64, String> {
+async fn claim_rewards_impl(factory_id: Principal, principal: Principal) -> Result<u64, String> {
     use crate::cache;
     use ic_cdk::api::call::call;
-    let factory_id = match crate::utils::env_principal("ICPSWAP_FACTORY") {
-        Some(p) => p,
-        None => return Err("factory".into()),
-    };
     let ledger = crate::ledger_fetcher::LEDGERS
         .first()
         .cloned()
         .ok_or("ledger")?;
-    let (pools,): (Vec<PoolData>,) = call(factory_id, "getPools", ()).await.map_err(|(_, e)| e)?;
+    let pools = fetch_pools(factory_id).await.map_err(|e| e.to_string())?;
     let mut total: u64 = 0;
     for pool in pools {
         let (spent,): (u64,) = call(pool.canister_id, "claim", (principal, ledger))
@@ -234,7 +299,7 @@ async fn claim_rewards_impl(principal: Principal) -> Result<u64, String> {
             .map_err(|(_, e)| e)?;
         total = total.checked_add(spent).ok_or("overflow")?;
     }
-    let holdings = fetch_positions_impl(principal)
+    let holdings = fetch_positions_impl(factory_id, principal)
         .await
         .map_err(|e| format!("{:?}", e))?;
     let summary = crate::summarise(&holdings).map_err(|e| e.to_string())?;
@@ -247,19 +312,70 @@ mod tests {
     use super::*;
     use quickcheck_macros::quickcheck;
 
-    #[tokio::test(flavor = "current_thread")]
-    async fn fetch_positions_empty_without_env() {
-        let adapter = IcpswapAdapter;
-        let res = adapter.fetch_positions(Principal::anonymous()).await;
-        assert!(matches!(res, Err(FetchError::InvalidConfig(_))));
+    #[test]
+    fn reports_the_configured_factory() {
+        let factory = Principal::from_slice(&[0x1F]);
+        let adapter = IcpswapAdapter::new(factory);
+        assert_eq!(
+            adapter.config(),
+            vec![("factory".to_string(), factory.to_text())]
+        );
     }
 
-    #[cfg(feature = "claim")]
-    #[tokio::test(flavor = "current_thread")]
-    async fn claim_fails_without_env() {
-        std::env::remove_var("ICPSWAP_FACTORY");
-        let res = claim_rewards_impl(Principal::anonymous()).await;
-        assert!(res.is_err());
+    #[test]
+    fn uncollected_fees_are_claimable() {
+        let token = |address: &str| Token {
+            address: address.into(),
+            standard: "ICRC1".into(),
+        };
+        let pool = PoolData {
+            key: "a_b".into(),
+            token0: token("aaaaa-aa"),
+            token1: token("2vxsx-fae"),
+            fee: Nat::from(3000u64),
+            tick_spacing: 60,
+            canister_id: Principal::from_slice(&[0x1F, 1]),
+        };
+        let meta = PoolMetadata {
+            token0_decimals: 8,
+            token1_decimals: 6,
+        };
+        let pos = UserPositionInfoWithTokenAmount {
+            id: Nat::from(1u64),
+            token0_amount: Nat::from(10u64),
+            token1_amount: Nat::from(20u64),
+            tokens_owed0: Some(Nat::from(0u64)),
+            tokens_owed1: Some(Nat::from(5u64)),
+        };
+        let holdings = position_holdings(&pool, &meta, pos);
+        assert_eq!(holdings.len(), 3);
+        assert_eq!(
+            holdings[2].kind,
+            HoldingKind::Claimable {
+                claim_from: Some(pool.canister_id),
+                pool: Some("a_b".into()),
+            }
+        );
+        assert_eq!(holdings[2].amount, Nat::from(5u64));
+        assert_eq!(holdings[2].decimals, 6);
+
+        // pools that don't report owed fees still decode
+        #[derive(CandidType, Deserialize)]
+        struct Bare {
+            id: Nat,
+            #[serde(rename = "token0Amount")]
+            token0_amount: Nat,
+            #[serde(rename = "token1Amount")]
+            token1_amount: Nat,
+        }
+        let bytes = Encode!(&vec![Bare {
+            id: Nat::from(1u64),
+            token0_amount: Nat::from(1u64),
+            token1_amount: Nat::from(2u64),
+        }])
+        .unwrap();
+        let decoded = Decode!(&bytes, Vec<UserPositionInfoWithTokenAmount>).unwrap();
+        assert!(decoded[0].tokens_owed0.is_none());
     }
 
     #[quickcheck]
@@ -1,26 +1,26 @@
-use super::DexAdapter;
+use super::{Capabilities, DexAdapter};
 use crate::error::FetchError;
-#[cfg(not(target_arch = "wasm32"))]
 use crate::{
     lp_cache,
-    utils::{format_amount, get_agent, now},
+    utils::{call_query, now},
 };
 use async_trait::async_trait;
-use bx_core::Holding;
-#[cfg(not(target_arch = "wasm32"))]
-use candid::Nat;
-use candid::{CandidType, Principal};
-#[cfg(not(target_arch = "wasm32"))]
-use candid::{Decode, Encode};
-#[cfg(not(target_arch = "wasm32"))]
+use bx_core::{HoldingKind, TypedHolding};
+use candid::{CandidType, Decode, Encode, Nat, Principal};
 use dashmap::DashMap;
-#[cfg(not(target_arch = "wasm32"))]
 use once_cell::sync::Lazy;
 use serde::Deserialize;
 
-pub struct InfinityAdapter;
+pub struct InfinityAdapter {
+    vault: Principal,
+}
+
+impl InfinityAdapter {
+    pub fn new(vault: Principal) -> Self {
+        Self { vault }
+    }
+}
 
-#[cfg(not(target_arch = "wasm32"))]
 pub fn clear_cache() {
     META_CACHE.clear();
 }
@@ -31,78 +31,84 @@ struct VaultPosition {
     subaccount: Vec<u8>,
 }
 
-#[cfg(not(target_arch = "wasm32"))]
 static META_CACHE: Lazy<DashMap<Principal, (String, u8, u64)>> = Lazy::new(DashMap::new);
-#[cfg(not(target_arch = "wasm32"))]
 const META_TTL_NS: u64 = crate::utils::DAY_NS; // 24h
 
 #[async_trait]
 impl DexAdapter for InfinityAdapter {
-    async fn fetch_positions(&self, principal: Principal) -> Result<Vec<Holding>, FetchError> {
-        fetch_positions_impl(principal).await
+    fn id(&self) -> &str {
+        "INFINITY_VAULT"
+    }
+
+    fn name(&self) -> &str {
+        "InfinitySwap"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            positions: true,
+            ..Default::default()
+        }
+    }
+
+    fn config(&self) -> Vec<(String, String)> {
+        vec![("vault".to_string(), self.vault.to_text())]
+    }
+
+    async fn health(&self) -> Result<(), FetchError> {
+        query_positions(self.vault, Principal::anonymous())
+            .await
+            .map(|_| ())
+    }
+
+    async fn fetch_positions(&self, principal: Principal) -> Result<Vec<TypedHolding>, FetchError> {
+        fetch_positions_impl(self.vault, principal).await
     }
 
     // uses default implementations for claimable_rewards and claim_rewards
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-async fn fetch_positions_impl(principal: Principal) -> Result<Vec<Holding>, FetchError> {
-    let vault_id = match crate::utils::env_principal("INFINITY_VAULT") {
-        Some(p) => p,
-        None => return Err(FetchError::InvalidConfig("vault".into())),
-    };
-    let agent = get_agent().await;
+async fn query_positions(
+    vault_id: Principal,
+    principal: Principal,
+) -> Result<Vec<VaultPosition>, FetchError> {
     let arg = Encode!(&principal).map_err(|_| FetchError::InvalidResponse)?;
-    let bytes = match agent
-        .query(&vault_id, "get_user_positions")
-        .with_arg(arg)
-        .call()
-        .await
-    {
-        Ok(b) => b,
-        Err(e) => return Err(FetchError::from(e)),
-    };
-    let positions: Vec<VaultPosition> =
-        Decode!(&bytes, Vec<VaultPosition>).map_err(|_| FetchError::InvalidResponse)?;
-    let height = crate::utils::dex_block_height(&agent, vault_id)
-        .await
-        .unwrap_or(0);
+    let bytes = call_query(vault_id, "get_user_positions", arg).await?;
+    Decode!(&bytes, Vec<VaultPosition>).map_err(|_| FetchError::InvalidResponse)
+}
+
+async fn fetch_positions_impl(
+    vault_id: Principal,
+    principal: Principal,
+) -> Result<Vec<TypedHolding>, FetchError> {
+    let positions = query_positions(vault_id, principal).await?;
+    let height = crate::utils::dex_block_height(vault_id).await.unwrap_or(0);
     let holdings = lp_cache::get_or_fetch(principal, "infinity", height, || async {
         let mut temp = Vec::with_capacity(positions.len() * 3);
         for pos in positions {
-            let (symbol, decimals) = match fetch_meta(&agent, pos.ledger).await {
+            let (symbol, decimals) = match fetch_meta(pos.ledger).await {
                 Some(v) => v,
                 None => continue,
             };
-            let bal = match balance_of(&agent, pos.ledger, vault_id, pos.subaccount.clone()).await {
+            let bal = match balance_of(pos.ledger, vault_id, pos.subaccount.clone()).await {
                 Some(n) => n,
                 None => continue,
             };
-            temp.push(Holding {
-                source: "InfinitySwap".into(),
-                token: symbol,
-                amount: format_amount(bal, decimals),
-                status: "lp_escrow".into(),
+            let kind = HoldingKind::LpPosition {
+                pool: "infinity".into(),
+            };
+            temp.push(TypedHolding {
+                ledger: Some(pos.ledger),
+                ..TypedHolding::new("InfinitySwap", symbol, bal, decimals, kind)
             });
         }
-        temp
+        Ok(temp)
     })
-    .await;
+    .await?;
     Ok(holdings)
 }
 
-#[cfg(target_arch = "wasm32")]
-async fn fetch_positions_impl(_principal: Principal) -> Result<Vec<Holding>, FetchError> {
-    Ok(Vec::new())
-}
-
-#[cfg(not(target_arch = "wasm32"))]
-async fn balance_of(
-    agent: &ic_agent::Agent,
-    ledger: Principal,
-    owner: Principal,
-    sub: Vec<u8>,
-) -> Option<Nat> {
+async fn balance_of(ledger: Principal, owner: Principal, sub: Vec<u8>) -> Option<Nat> {
     #[derive(CandidType)]
     struct Account {
         owner: Principal,
@@ -113,29 +119,18 @@ async fn balance_of(
         subaccount: Some(sub),
     })
     .ok()?;
-    let bytes = agent
-        .query(&ledger, "icrc1_balance_of")
-        .with_arg(arg)
-        .call()
-        .await
-        .ok()?;
+    let bytes = call_query(ledger, "icrc1_balance_of", arg).await.ok()?;
     Decode!(&bytes, Nat).ok()
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-async fn fetch_meta(agent: &ic_agent::Agent, ledger: Principal) -> Option<(String, u8)> {
+async fn fetch_meta(ledger: Principal) -> Option<(String, u8)> {
     if let Some(e) = META_CACHE.get(&ledger) {
         if e.value().2 > now() {
             return Some((e.value().0.clone(), e.value().1));
         }
     }
     let arg = Encode!().ok()?;
-    let bytes = agent
-        .query(&ledger, "icrc1_metadata")
-        .with_arg(arg)
-        .call()
-        .await
-        .ok()?;
+    let bytes = call_query(ledger, "icrc1_metadata", arg).await.ok()?;
     let items: Vec<(String, candid::types::value::IDLValue)> =
         Decode!(&bytes, Vec<(String, candid::types::value::IDLValue)>).ok()?;
     let mut symbol = String::new();
@@ -166,12 +161,14 @@ mod tests {
     use candid::Principal;
     use quickcheck_macros::quickcheck;
 
-    #[tokio::test]
-    async fn empty_without_env() {
-        std::env::remove_var("INFINITY_VAULT");
-        let adapter = InfinityAdapter;
-        let res = adapter.fetch_positions(Principal::anonymous()).await;
-        assert!(matches!(res, Err(FetchError::InvalidConfig(_))));
+    #[test]
+    fn reports_the_configured_vault() {
+        let vault = Principal::from_slice(&[0x1A]);
+        let adapter = InfinityAdapter::new(vault);
+        assert_eq!(
+            adapter.config(),
+            vec![("vault".to_string(), vault.to_text())]
+        );
     }
 
     #[quickcheck]
@@ -1,17 +1,11 @@
-use super::DexAdapter;
+use super::{Capabilities, DexAdapter, PoolReserves};
 use crate::error::FetchError;
-#[cfg(all(feature = "claim", not(target_arch = "wasm32")))]
+#[cfg(feature = "claim")]
 use crate::utils::now;
-#[cfg(not(target_arch = "wasm32"))]
-use crate::{
-    lp_cache,
-    utils::{format_amount, get_agent},
-};
+use crate::{lp_cache, utils::call_query};
 use async_trait::async_trait;
-use bx_core::Holding;
-use candid::{CandidType, Nat, Principal};
-#[cfg(not(target_arch = "wasm32"))]
-use candid::{Decode, Encode};
+use bx_core::{HoldingKind, TypedHolding};
+use candid::{CandidType, Decode, Encode, Nat, Principal};
 use serde::Deserialize;
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -33,80 +27,87 @@ struct PositionInfo {
     auto_compound: bool,
 }
 
-pub struct SonicAdapter;
+#[derive(CandidType, Deserialize, Clone)]
+struct PairInfo {
+    token0: Token,
+    token1: Token,
+    reserve0: Nat,
+    reserve1: Nat,
+}
+
+pub struct SonicAdapter {
+    router: Principal,
+}
+
+impl SonicAdapter {
+    pub fn new(router: Principal) -> Self {
+        Self { router }
+    }
+}
+
+fn token_holding(token: &Token, amount: Nat, kind: HoldingKind) -> TypedHolding {
+    TypedHolding {
+        ledger: Principal::from_text(&token.address).ok(),
+        ..TypedHolding::new("Sonic", token.address.clone(), amount, token.decimals, kind)
+    }
+}
 
-#[cfg(not(target_arch = "wasm32"))]
 pub fn clear_cache() {}
 
-#[cfg(not(target_arch = "wasm32"))]
-async fn fetch_positions_impl(principal: Principal) -> Result<Vec<Holding>, FetchError> {
-    let router_id = match crate::utils::env_principal("SONIC_ROUTER") {
-        Some(p) => p,
-        None => return Err(FetchError::InvalidConfig("router".into())),
-    };
-    let agent = get_agent().await;
+async fn fetch_positions_impl(
+    router_id: Principal,
+    principal: Principal,
+) -> Result<Vec<TypedHolding>, FetchError> {
     let arg = Encode!(&principal).map_err(|_| FetchError::InvalidResponse)?;
-    let bytes = match agent
-        .query(&router_id, "get_user_positions")
-        .with_arg(arg)
-        .call()
-        .await
-    {
-        Ok(b) => b,
-        Err(e) => return Err(FetchError::from(e)),
-    };
+    let bytes = call_query(router_id, "get_user_positions", arg).await?;
     let positions: Vec<PositionInfo> =
         Decode!(&bytes, Vec<PositionInfo>).map_err(|_| FetchError::InvalidResponse)?;
-    let height = crate::utils::dex_block_height(&agent, router_id)
-        .await
-        .unwrap_or(0);
+    let height = crate::utils::dex_block_height(router_id).await.unwrap_or(0);
     let holdings = lp_cache::get_or_fetch(principal, "sonic", height, || async {
         let mut temp = Vec::with_capacity(positions.len() * 3);
         for pos in positions {
-            let a0 = format_amount(pos.token_a_amount, pos.token_a.decimals);
-            temp.push(Holding {
-                source: "Sonic".into(),
-                token: pos.token_a.address.clone(),
-                amount: a0,
-                status: "lp_escrow".into(),
-            });
-            let a1 = format_amount(pos.token_b_amount, pos.token_b.decimals);
-            temp.push(Holding {
-                source: "Sonic".into(),
-                token: pos.token_b.address.clone(),
-                amount: a1,
-                status: "lp_escrow".into(),
-            });
+            let lp = || HoldingKind::LpPosition {
+                pool: "sonic".into(),
+            };
+            temp.push(token_holding(&pos.token_a, pos.token_a_amount, lp()));
+            temp.push(token_holding(&pos.token_b, pos.token_b_amount, lp()));
             if !pos.auto_compound {
-                let ra = format_amount(pos.reward_amount, pos.reward_token.decimals);
-                temp.push(Holding {
-                    source: "Sonic".into(),
-                    token: pos.reward_token.address.clone(),
-                    amount: ra,
-                    status: "lp_escrow".into(),
-                });
+                let kind = HoldingKind::Claimable {
+                    claim_from: Some(router_id),
+                    pool: Some("sonic".into()),
+                };
+                temp.push(token_holding(&pos.reward_token, pos.reward_amount, kind));
             }
         }
-        temp
+        Ok(temp)
     })
-    .await;
+    .await?;
     Ok(holdings)
 }
 
-#[cfg(target_arch = "wasm32")]
-async fn fetch_positions_impl(_principal: Principal) -> Result<Vec<Holding>, FetchError> {
-    Ok(Vec::new())
+async fn pool_reserves_impl(router_id: Principal) -> Result<Vec<PoolReserves>, FetchError> {
+    let arg = Encode!().map_err(|_| FetchError::InvalidResponse)?;
+    let bytes = call_query(router_id, "get_pairs", arg).await?;
+    let pairs: Vec<PairInfo> =
+        Decode!(&bytes, Vec<PairInfo>).map_err(|_| FetchError::InvalidResponse)?;
+    Ok(pairs
+        .into_iter()
+        .map(|p| PoolReserves {
+            token_a: p.token0.address,
+            decimals_a: p.token0.decimals,
+            reserve_a: p.reserve0,
+            token_b: p.token1.address,
+            decimals_b: p.token1.decimals,
+            reserve_b: p.reserve1,
+        })
+        .collect())
 }
 
 #[cfg(all(feature = "claim", not(target_arch = "wasm32")))]
-async fn claim_impl(principal: Principal) -> Result<u64, String> {
+async fn claim_impl(router_id: Principal, principal: Principal) -> Result<u64, String> {
     use crate::{cache, ledger_fetcher::LEDGERS};
-    let router_id = match crate::utils::env_principal("SONIC_ROUTER") {
-        Some(p) => p,
-        None => return Err("router".into()),
-    };
     let ledger = LEDGERS.first().cloned().ok_or("ledger")?;
-    let agent = get_agent().await;
+    let agent = crate::utils::get_agent().await;
     let arg = Encode!(&principal, &ledger).map_err(|e| e.to_string())?;
     let bytes = agent
         .update(&router_id, "claim")
@@ -115,7 +116,23 @@ async fn claim_impl(principal: Principal) -> Result<u64, String> {
         .await
         .map_err(|e| e.to_string())?;
     let spent: u64 = Decode!(&bytes, u64).map_err(|_| "invalid response")?;
-    let holdings = fetch_positions_impl(principal)
+    let holdings = fetch_positions_impl(router_id, principal)
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+    let summary = crate::summarise(&holdings).map_err(|e| e.to_string())?;
+    cache::get().insert(principal, (holdings, summary, now()));
+    Ok(spent)
+}
+
+#[cfg(all(feature = "claim", target_arch = "wasm32"))]
+async fn claim_impl(router_id: Principal, principal: Principal) -> Result<u64, String> {
+    use crate::{cache, ledger_fetcher::LEDGERS};
+    use ic_cdk::api::call::call;
+    let ledger = LEDGERS.first().cloned().ok_or("ledger")?;
+    let (spent,): (u64,) = call(router_id, "claim", (principal, ledger))
+        .await
+        .map_err(|(_, e)| e)?;
+    let holdings = fetch_positions_impl(router_id, principal)
         .await
         .map_err(|e| format!("{:?}", e))?;
     let summary = crate::summarise(&holdings).map_err(|e| e.to_string())?;
@@ -125,13 +142,43 @@ async fn claim_impl(principal: Principal) -> Result<u64, String> {
 
 #[async_trait]
 impl DexAdapter for SonicAdapter {
-    async fn fetch_positions(&self, principal: Principal) -> Result<Vec<Holding>, FetchError> {
-        fetch_positions_impl(principal).await
+    fn id(&self) -> &str {
+        "SONIC_ROUTER"
+    }
+
+    fn name(&self) -> &str {
+        "Sonic"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            positions: true,
+            claimable: true,
+            claim: true,
+            prices: true,
+            pools: true,
+        }
+    }
+
+    fn config(&self) -> Vec<(String, String)> {
+        vec![("router".to_string(), self.router.to_text())]
+    }
+
+    async fn health(&self) -> Result<(), FetchError> {
+        pool_reserves_impl(self.router).await.map(|_| ())
+    }
+
+    async fn fetch_positions(&self, principal: Principal) -> Result<Vec<TypedHolding>, FetchError> {
+        fetch_positions_impl(self.router, principal).await
+    }
+
+    async fn pool_reserves(&self) -> Result<Vec<PoolReserves>, FetchError> {
+        pool_reserves_impl(self.router).await
     }
 
     #[cfg(feature = "claim")]
     async fn claim_rewards(&self, principal: Principal) -> Result<u64, String> {
-        claim_impl(principal).await
+        claim_impl(self.router, principal).await
     }
 }
 
@@ -141,12 +188,20 @@ mod tests {
     use candid::Principal;
     use quickcheck_macros::quickcheck;
 
-    #[tokio::test]
-    async fn empty_without_env() {
-        std::env::remove_var("SONIC_ROUTER");
-        let adapter = SonicAdapter;
-        let res = adapter.fetch_positions(Principal::anonymous()).await;
-        assert!(matches!(res, Err(FetchError::InvalidConfig(_))));
+    #[test]
+    fn reports_the_configured_router() {
+        let router = Principal::from_slice(&[0x50]);
+        let adapter = SonicAdapter::new(router);
+        assert_eq!(
+            adapter.config(),
+            vec![("router".to_string(), router.to_text())]
+        );
+    }
+
+    #[quickcheck]
+    fn fuzz_decode_pairs(data: Vec<u8>) -> bool {
+        let _ = Decode!(&data, Vec<PairInfo>);
+        true
     }
 
     #[quickcheck]
@@ -0,0 +1,510 @@
+use super::{Capabilities, DexAdapter, RewardInfo};
+use crate::error::FetchError;
+use crate::utils::{call_query, idl_to_nat, idl_to_u8};
+use async_trait::async_trait;
+use bx_core::{HoldingKind, TypedHolding};
+use candid::types::value::{IDLArgs, IDLField, IDLValue};
+use candid::types::Label;
+use candid::{Nat, Principal};
+use serde::Deserialize;
+
+// Adapter for DEXes whose positions can be read with a single query. A
+// `[generic.<NAME>]` table in `ledgers.toml` names the method, its arguments
+// and where each field sits in the reply:
+//
+//   [generic.EXAMPLE_DEX]
+//   canister = "aaaaa-aa"
+//   method = "get_user_positions"
+//   args = ["$principal", { type = "nat64", value = 100 }]
+//   positions = "Ok"
+//   token = "token.address"
+//   amount = "amount"
+//   decimals = "token.decimals"
+//
+// Replies are decoded without their Candid types. A path is a list of record
+// field names, variant tags or vector indices joined by `.`; options are
+// unwrapped on the way. Every element of the `positions` vector (the reply
+// itself when empty) becomes one LP holding, plus a claimable one when the
+// reward fields are set.
+//
+// A bare integer argument is sent as `nat`. Other integer types, and text
+// that has to go out as a principal, are written as `{ type, value }`
+// tables; a record whose only fields are `type` and `value` therefore has to
+// be spelled `{ type = "record", value = { type = ..., value = ... } }`.
+// Holdings whose token is a ledger principal take the ledger's symbol from
+// its metadata, falling back to the principal's text.
+
+/// Decimals read from the reply or fixed in the config
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Decimals {
+    Fixed(u8),
+    Path(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct GenericSpec {
+    pub method: String,
+    /// Candid arguments; `"$principal"` is the queried principal,
+    /// `"$account"` its default ICRC-1 account and `"$null"` is `null`.
+    /// `{ type = "nat64", value = 5 }` gives a value an explicit type.
+    #[serde(default)]
+    pub args: Vec<toml::Value>,
+    #[serde(default)]
+    pub positions: String,
+    pub token: String,
+    pub amount: String,
+    pub decimals: Decimals,
+    pub reward_token: Option<String>,
+    pub reward_amount: Option<String>,
+    pub reward_decimals: Option<Decimals>,
+}
+
+impl GenericSpec {
+    /// Spec from the TOML of a `[generic.<NAME>]` table
+    pub fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| format!("invalid generic adapter spec: {e}"))
+    }
+}
+
+pub struct GenericAdapter {
+    name: String,
+    canister: Principal,
+    spec: GenericSpec,
+}
+
+impl GenericAdapter {
+    pub fn new(name: impl Into<String>, canister: Principal, spec: GenericSpec) -> Self {
+        Self {
+            name: name.into(),
+            canister,
+            spec,
+        }
+    }
+
+    async fn query(&self, principal: Principal) -> Result<IDLValue, FetchError> {
+        let args = encode_args(&self.spec.args, principal)
+            .map_err(|e| FetchError::InvalidConfig(format!("{}: {e}", self.name)))?;
+        let bytes = call_query(self.canister, &self.spec.method, args).await?;
+        IDLArgs::from_bytes(&bytes)
+            .ok()
+            .and_then(|a| a.args.into_iter().next())
+            .ok_or(FetchError::InvalidResponse)
+    }
+
+    /// Holdings described by `reply`
+    fn holdings(&self, reply: &IDLValue) -> Result<Vec<TypedHolding>, FetchError> {
+        let mut out = Vec::new();
+        for pos in positions(reply, &self.spec.positions)? {
+            let lp = HoldingKind::LpPosition {
+                pool: self.name.to_lowercase(),
+            };
+            out.push(self.holding(
+                pos,
+                &self.spec.token,
+                &self.spec.amount,
+                &self.spec.decimals,
+                lp,
+            )?);
+            if let (Some(token), Some(amount)) = (&self.spec.reward_token, &self.spec.reward_amount)
+            {
+                let decimals = self
+                    .spec
+                    .reward_decimals
+                    .as_ref()
+                    .unwrap_or(&self.spec.decimals);
+                let kind = HoldingKind::Claimable {
+                    claim_from: Some(self.canister),
+                    pool: Some(self.name.to_lowercase()),
+                };
+                out.push(self.holding(pos, token, amount, decimals, kind)?);
+            }
+        }
+        Ok(out)
+    }
+
+    fn holding(
+        &self,
+        pos: &IDLValue,
+        token: &str,
+        amount: &str,
+        decimals: &Decimals,
+        kind: HoldingKind,
+    ) -> Result<TypedHolding, FetchError> {
+        let token = lookup(pos, token)
+            .and_then(token_text)
+            .ok_or(FetchError::InvalidResponse)?;
+        let amount = lookup(pos, amount)
+            .and_then(idl_to_nat)
+            .ok_or(FetchError::InvalidResponse)?;
+        let decimals = match decimals {
+            Decimals::Fixed(d) => *d,
+            Decimals::Path(p) => lookup(pos, p)
+                .and_then(idl_to_u8)
+                .ok_or(FetchError::InvalidResponse)?,
+        };
+        Ok(TypedHolding {
+            ledger: Principal::from_text(&token).ok(),
+            ..TypedHolding::new(self.name.clone(), token, amount, decimals, kind)
+        })
+    }
+}
+
+fn positions<'a>(reply: &'a IDLValue, path: &str) -> Result<&'a [IDLValue], FetchError> {
+    match lookup(reply, path) {
+        Some(IDLValue::Vec(items)) => Ok(items),
+        _ => Err(FetchError::InvalidResponse),
+    }
+}
+
+fn unwrap_opt(mut v: &IDLValue) -> Option<&IDLValue> {
+    loop {
+        match v {
+            IDLValue::Opt(inner) => v = inner,
+            IDLValue::None | IDLValue::Null => return None,
+            _ => return Some(v),
+        }
+    }
+}
+
+/// Value at `path` below `value`
+fn lookup<'a>(value: &'a IDLValue, path: &str) -> Option<&'a IDLValue> {
+    let mut v = unwrap_opt(value)?;
+    for seg in path.split('.').filter(|s| !s.is_empty()) {
+        let id = candid::idl_hash(seg);
+        v = match v {
+            IDLValue::Record(fields) => {
+                let field = fields.iter().find(|f| {
+                    f.id.get_id() == id || seg.parse::<u32>().is_ok_and(|n| f.id.get_id() == n)
+                })?;
+                &field.val
+            }
+            IDLValue::Variant(variant) if variant.0.id.get_id() == id => &variant.0.val,
+            IDLValue::Vec(items) => items.get(seg.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+        v = unwrap_opt(v)?;
+    }
+    Some(v)
+}
+
+fn token_text(v: &IDLValue) -> Option<String> {
+    match v {
+        IDLValue::Text(s) => Some(s.clone()),
+        IDLValue::Principal(p) => Some(p.to_text()),
+        _ => None,
+    }
+}
+
+/// Replace the ledger principal standing in as the symbol of `holdings` with
+/// the symbol in the ledger's metadata, where it can be read
+async fn with_symbols(holdings: &mut [TypedHolding]) {
+    let mut symbols: std::collections::HashMap<Principal, Option<String>> = Default::default();
+    for h in holdings.iter_mut() {
+        let Some(ledger) = h.ledger else {
+            continue;
+        };
+        let symbol = match symbols.get(&ledger) {
+            Some(symbol) => symbol.clone(),
+            None => {
+                let symbol = crate::ledger_fetcher::metadata(ledger)
+                    .await
+                    .ok()
+                    .map(|(symbol, _, _)| symbol)
+                    .filter(|s| !s.is_empty());
+                symbols.insert(ledger, symbol.clone());
+                symbol
+            }
+        };
+        if let Some(symbol) = symbol {
+            h.symbol = symbol;
+        }
+    }
+}
+
+/// `value` encoded as the Candid type `ty`
+fn typed_idl(ty: &str, value: &toml::Value, principal: Principal) -> Result<IDLValue, String> {
+    let int = || match value {
+        toml::Value::Integer(n) => Ok(*n),
+        other => Err(format!("{ty} argument {other} is not an integer")),
+    };
+    Ok(match ty {
+        "nat" => IDLValue::Nat(Nat::from(narrow::<u64>(int()?, ty)?)),
+        "nat8" => IDLValue::Nat8(narrow(int()?, ty)?),
+        "nat16" => IDLValue::Nat16(narrow(int()?, ty)?),
+        "nat32" => IDLValue::Nat32(narrow(int()?, ty)?),
+        "nat64" => IDLValue::Nat64(narrow(int()?, ty)?),
+        "int" => IDLValue::Int(candid::Int::from(int()?)),
+        "int8" => IDLValue::Int8(narrow(int()?, ty)?),
+        "int16" => IDLValue::Int16(narrow(int()?, ty)?),
+        "int32" => IDLValue::Int32(narrow(int()?, ty)?),
+        "int64" => IDLValue::Int64(int()?),
+        "principal" => match value {
+            toml::Value::String(s) if s == "$principal" => IDLValue::Principal(principal),
+            toml::Value::String(s) => IDLValue::Principal(
+                Principal::from_text(s).map_err(|e| format!("invalid principal {s}: {e}"))?,
+            ),
+            other => return Err(format!("principal argument {other} is not text")),
+        },
+        "text" => match value {
+            toml::Value::String(s) => IDLValue::Text(s.clone()),
+            other => return Err(format!("text argument {other} is not text")),
+        },
+        "record" => match value {
+            toml::Value::Table(table) => record(table, principal)?,
+            other => return Err(format!("record argument {other} is not a table")),
+        },
+        other => return Err(format!("unsupported argument type {other}")),
+    })
+}
+
+fn narrow<T: TryFrom<i64>>(n: i64, ty: &str) -> Result<T, String> {
+    T::try_from(n).map_err(|_| format!("{n} does not fit in {ty}"))
+}
+
+fn record(table: &toml::value::Table, principal: Principal) -> Result<IDLValue, String> {
+    let mut fields = table
+        .iter()
+        .map(|(k, v)| {
+            Ok(IDLField {
+                id: Label::Named(k.clone()),
+                val: to_idl(v, principal)?,
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    fields.sort_by_key(|f| f.id.get_id());
+    Ok(IDLValue::Record(fields))
+}
+
+fn to_idl(value: &toml::Value, principal: Principal) -> Result<IDLValue, String> {
+    Ok(match value {
+        toml::Value::String(s) if s == "$principal" => IDLValue::Principal(principal),
+        toml::Value::String(s) if s == "$account" => {
+            let mut account = toml::value::Table::new();
+            account.insert("owner".into(), "$principal".into());
+            account.insert("subaccount".into(), "$null".into());
+            to_idl(&toml::Value::Table(account), principal)?
+        }
+        toml::Value::String(s) if s == "$null" => IDLValue::Null,
+        toml::Value::String(s) => IDLValue::Text(s.clone()),
+        toml::Value::Integer(n) => {
+            let n = u64::try_from(*n).map_err(|_| format!("negative argument {n}"))?;
+            IDLValue::Nat(Nat::from(n))
+        }
+        toml::Value::Boolean(b) => IDLValue::Bool(*b),
+        toml::Value::Array(items) => IDLValue::Vec(
+            items
+                .iter()
+                .map(|v| to_idl(v, principal))
+                .collect::<Result<_, _>>()?,
+        ),
+        toml::Value::Table(table) => match (table.get("type"), table.get("value")) {
+            (Some(toml::Value::String(ty)), Some(value)) if table.len() == 2 => {
+                typed_idl(ty, value, principal)?
+            }
+            _ => record(table, principal)?,
+        },
+        other => return Err(format!("unsupported argument {other}")),
+    })
+}
+
+fn encode_args(args: &[toml::Value], principal: Principal) -> Result<Vec<u8>, String> {
+    let values = args
+        .iter()
+        .map(|v| to_idl(v, principal))
+        .collect::<Result<Vec<_>, _>>()?;
+    IDLArgs::new(&values).to_bytes().map_err(|e| e.to_string())
+}
+
+#[async_trait]
+impl DexAdapter for GenericAdapter {
+    fn id(&self) -> &str {
+        &self.name
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            positions: true,
+            claimable: self.spec.reward_amount.is_some(),
+            ..Default::default()
+        }
+    }
+
+    fn config(&self) -> Vec<(String, String)> {
+        vec![
+            ("canister".into(), self.canister.to_text()),
+            ("method".into(), self.spec.method.clone()),
+        ]
+    }
+
+    async fn health(&self) -> Result<(), FetchError> {
+        self.query(Principal::anonymous()).await.map(|_| ())
+    }
+
+    async fn fetch_positions(&self, principal: Principal) -> Result<Vec<TypedHolding>, FetchError> {
+        let reply = self.query(principal).await?;
+        let mut holdings = self.holdings(&reply)?;
+        with_symbols(&mut holdings).await;
+        Ok(holdings)
+    }
+
+    async fn claimable_rewards(&self, principal: Principal) -> Result<Vec<RewardInfo>, FetchError> {
+        if self.spec.reward_amount.is_none() {
+            return Ok(Vec::new());
+        }
+        let rewards = self
+            .fetch_positions(principal)
+            .await?
+            .into_iter()
+            .filter(|h| matches!(h.kind, HoldingKind::Claimable { .. }))
+            .map(|h| RewardInfo {
+                amount: h.formatted_amount(),
+                token: h.symbol,
+            })
+            .collect();
+        Ok(rewards)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::{CandidType, Encode};
+
+    #[derive(CandidType)]
+    struct Token {
+        address: Principal,
+        decimals: u8,
+    }
+
+    #[derive(CandidType)]
+    struct Position {
+        token: Token,
+        amount: Nat,
+        reward: Option<Nat>,
+    }
+
+    const SPEC: &str = r#"
+        method = "positions"
+        args = ["$account", { limit = 10, start = { type = "nat64", value = 3 } }, { type = "int8", value = -2 }, { type = "principal", value = "aaaaa-aa" }]
+        positions = "Ok"
+        token = "token.address"
+        amount = "amount"
+        decimals = "token.decimals"
+        reward_token = "token.address"
+        reward_amount = "reward"
+    "#;
+
+    #[test]
+    fn reads_holdings_from_untyped_reply() {
+        let spec = GenericSpec::parse(SPEC).unwrap();
+        let adapter = GenericAdapter::new("EXAMPLE", Principal::from_slice(&[9]), spec);
+        let ledger = Principal::from_slice(&[1]);
+        let reply: Result<Vec<Position>, String> = Ok(vec![Position {
+            token: Token {
+                address: ledger,
+                decimals: 6,
+            },
+            amount: Nat::from(1_500_000u64),
+            reward: Some(Nat::from(25u64)),
+        }]);
+        let bytes = Encode!(&reply).unwrap();
+        let value = IDLArgs::from_bytes(&bytes).unwrap().args.remove(0);
+
+        let holdings = adapter.holdings(&value).unwrap();
+        assert_eq!(holdings.len(), 2);
+        assert_eq!(holdings[0].source, "EXAMPLE");
+        assert_eq!(holdings[0].ledger, Some(ledger));
+        assert_eq!(holdings[0].decimals, 6);
+        assert_eq!(holdings[0].amount, Nat::from(1_500_000u64));
+        assert!(matches!(holdings[1].kind, HoldingKind::Claimable { .. }));
+        assert_eq!(holdings[1].amount, Nat::from(25u64));
+
+        let err: Result<Vec<Position>, String> = Err("down".into());
+        let value = IDLArgs::from_bytes(&Encode!(&err).unwrap())
+            .unwrap()
+            .args
+            .remove(0);
+        assert_eq!(adapter.holdings(&value), Err(FetchError::InvalidResponse));
+    }
+
+    #[test]
+    fn encodes_placeholder_args() {
+        let spec = GenericSpec::parse(SPEC).unwrap();
+        let p = Principal::from_slice(&[7]);
+        let bytes = encode_args(&spec.args, p).unwrap();
+        let args = IDLArgs::from_bytes(&bytes).unwrap().args;
+        assert_eq!(lookup(&args[0], "owner"), Some(&IDLValue::Principal(p)));
+        assert_eq!(
+            lookup(&args[1], "limit"),
+            Some(&IDLValue::Nat(Nat::from(10u64)))
+        );
+        assert_eq!(lookup(&args[1], "start"), Some(&IDLValue::Nat64(3)));
+        assert_eq!(args[2], IDLValue::Int8(-2));
+        assert_eq!(
+            args[3],
+            IDLValue::Principal(Principal::management_canister())
+        );
+        assert!(GenericSpec::parse("method = 1").is_err());
+
+        let bad = |arg: &str| {
+            encode_args(
+                &[toml::from_str::<toml::Table>(&format!("a = {arg}")).unwrap()["a"].clone()],
+                p,
+            )
+        };
+        assert!(bad(r#"{ type = "nat8", value = 256 }"#).is_err());
+        assert!(bad(r#"{ type = "nat64", value = "x" }"#).is_err());
+        assert!(bad(r#"{ type = "float32", value = 1 }"#).is_err());
+        // a record with fields `type` and `value` is wrapped in a typed one
+        let wrapped = bad(r#"{ type = "record", value = { type = "x", value = 1 } }"#).unwrap();
+        let wrapped = IDLArgs::from_bytes(&wrapped).unwrap().args;
+        assert_eq!(
+            lookup(&wrapped[0], "type"),
+            Some(&IDLValue::Text("x".into()))
+        );
+    }
+
+    #[cfg(not(feature = "live-test"))]
+    #[tokio::test(flavor = "current_thread")]
+    #[serial_test::serial]
+    async fn symbols_come_from_ledger_metadata() {
+        let ledger = Principal::from_slice(&[1]);
+        let kind = HoldingKind::LpPosition {
+            pool: "example".into(),
+        };
+        let mut holdings = vec![
+            TypedHolding {
+                ledger: Some(ledger),
+                ..TypedHolding::new(
+                    "EXAMPLE",
+                    ledger.to_text(),
+                    Nat::from(1u64),
+                    0,
+                    kind.clone(),
+                )
+            },
+            TypedHolding::new("EXAMPLE", "not-a-ledger", Nat::from(1u64), 0, kind),
+        ];
+        crate::ledger_fetcher::set_now(0);
+        crate::ledger_fetcher::set_mock_metadata(Ok(vec![(
+            "icrc1:symbol".into(),
+            IDLValue::Text("EXA".into()),
+        )]));
+        crate::ledger_fetcher::clear_metadata();
+        with_symbols(&mut holdings).await;
+        assert_eq!(holdings[0].symbol, "EXA");
+        assert_eq!(holdings[1].symbol, "not-a-ledger");
+
+        // unreadable metadata leaves the principal as the symbol
+        holdings[0].symbol = ledger.to_text();
+        crate::ledger_fetcher::set_mock_metadata(Err("down".into()));
+        crate::ledger_fetcher::clear_metadata();
+        with_symbols(&mut holdings).await;
+        assert_eq!(holdings[0].symbol, ledger.to_text());
+    }
+}
@@ -1,23 +1,117 @@
 use crate::error::FetchError;
 use async_trait::async_trait;
-use bx_core::Holding;
-use candid::Principal;
+use bx_core::{HoldingKind, TypedHolding};
+use candid::{CandidType, Nat, Principal};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, CandidType, Serialize, Deserialize)]
 pub struct RewardInfo {
     pub token: String,
     pub amount: String,
 }
 
+/// Token reserves of a liquidity pool, used to derive spot prices. Tokens are
+/// identified by ledger address as reported by the DEX.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolReserves {
+    pub token_a: String,
+    pub decimals_a: u8,
+    pub reserve_a: Nat,
+    pub token_b: String,
+    pub decimals_b: u8,
+    pub reserve_b: Nat,
+}
+
+/// What an adapter implements beyond the trait defaults
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Reports positions through `fetch_positions`
+    pub positions: bool,
+    /// Reports rewards through `claimable_rewards` or claimable holdings
+    pub claimable: bool,
+    /// Can claim through `claim_rewards`
+    pub claim: bool,
+    /// Reports pool reserves that price tokens
+    pub prices: bool,
+    /// Lists its pools
+    pub pools: bool,
+}
+
+/// Rewards `claim_rewards` would collect from one adapter
+#[derive(Debug, Clone, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct ClaimPreview {
+    pub adapter: String,
+    pub rewards: Vec<RewardInfo>,
+    /// Canister the claim is sent to, when known
+    pub claim_from: Option<Principal>,
+}
+
+/// Identity, capabilities and settings of an adapter
+#[derive(Debug, Clone, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct AdapterInfo {
+    pub id: String,
+    pub name: String,
+    pub capabilities: Capabilities,
+    pub config: Vec<(String, String)>,
+}
+
+impl AdapterInfo {
+    pub fn of(adapter: &dyn DexAdapter) -> Self {
+        AdapterInfo {
+            id: adapter.id().to_string(),
+            name: adapter.name().to_string(),
+            capabilities: adapter.capabilities(),
+            config: adapter.config(),
+        }
+    }
+}
+
 #[async_trait]
 pub trait DexAdapter: Send + Sync {
-    async fn fetch_positions(&self, principal: Principal) -> Result<Vec<Holding>, FetchError>;
+    /// Stable identifier, the name of its registry entry
+    fn id(&self) -> &str;
+    /// Name shown to users
+    fn name(&self) -> &str;
+    fn capabilities(&self) -> Capabilities;
+    /// Effective settings, such as the canisters it calls
+    fn config(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+    /// Check that the adapter can answer. Breaker probes call this from the
+    /// heartbeat, so it makes a single query rather than a full fetch.
+    async fn health(&self) -> Result<(), FetchError>;
+    async fn fetch_positions(&self, principal: Principal) -> Result<Vec<TypedHolding>, FetchError>;
     async fn claimable_rewards(
         &self,
         _principal: Principal,
     ) -> Result<Vec<RewardInfo>, FetchError> {
         Ok(Vec::new())
     }
+    /// Rewards a claim would collect: the claimable holdings reported by
+    /// `fetch_positions`
+    async fn claim_preview(&self, principal: Principal) -> Result<ClaimPreview, FetchError> {
+        let mut preview = ClaimPreview {
+            adapter: self.id().to_string(),
+            rewards: Vec::new(),
+            claim_from: None,
+        };
+        if !self.capabilities().claim {
+            return Ok(preview);
+        }
+        for h in self.fetch_positions(principal).await? {
+            if let HoldingKind::Claimable { claim_from, .. } = &h.kind {
+                preview.claim_from = preview.claim_from.or(*claim_from);
+                preview.rewards.push(RewardInfo {
+                    amount: h.formatted_amount(),
+                    token: h.symbol,
+                });
+            }
+        }
+        Ok(preview)
+    }
+    async fn pool_reserves(&self) -> Result<Vec<PoolReserves>, FetchError> {
+        Ok(Vec::new())
+    }
     #[cfg(feature = "claim")]
     async fn claim_rewards(&self, _principal: Principal) -> Result<u64, String> {
         Ok(0)
@@ -27,16 +121,89 @@ pub trait DexAdapter: Send + Sync {
 pub mod dex_icpswap;
 pub mod dex_infinity;
 pub mod dex_sonic;
+pub mod generic_adapter;
 pub mod registry;
 pub mod sns_adapter;
 
 /// Clear cached metadata for all adapters
 pub fn clear_all_caches() {
-    #[cfg(not(target_arch = "wasm32"))]
-    {
-        dex_icpswap::clear_cache();
-        dex_sonic::clear_cache();
-        dex_infinity::clear_cache();
-    }
+    dex_icpswap::clear_cache();
+    dex_sonic::clear_cache();
+    dex_infinity::clear_cache();
     sns_adapter::clear_cache();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Stub(Capabilities);
+
+    #[async_trait]
+    impl DexAdapter for Stub {
+        fn id(&self) -> &str {
+            "STUB"
+        }
+
+        fn name(&self) -> &str {
+            "Stub"
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            self.0
+        }
+
+        async fn health(&self) -> Result<(), FetchError> {
+            Ok(())
+        }
+
+        async fn fetch_positions(&self, _: Principal) -> Result<Vec<TypedHolding>, FetchError> {
+            let from = Principal::from_slice(&[3]);
+            Ok(vec![
+                TypedHolding::new("Stub", "AAA", 150u64.into(), 2, HoldingKind::Liquid),
+                TypedHolding::new(
+                    "Stub",
+                    "BBB",
+                    5u64.into(),
+                    0,
+                    HoldingKind::Claimable {
+                        claim_from: Some(from),
+                        pool: None,
+                    },
+                ),
+            ])
+        }
+    }
+
+    #[tokio::test]
+    async fn claim_preview_lists_claimable_holdings() {
+        let caps = Capabilities {
+            positions: true,
+            claim: true,
+            ..Default::default()
+        };
+        let preview = Stub(caps)
+            .claim_preview(Principal::anonymous())
+            .await
+            .unwrap();
+        assert_eq!(preview.adapter, "STUB");
+        assert_eq!(preview.claim_from, Some(Principal::from_slice(&[3])));
+        assert_eq!(
+            preview.rewards,
+            vec![RewardInfo {
+                token: "BBB".into(),
+                amount: "5".into(),
+            }]
+        );
+
+        let stub = Stub(Capabilities::default());
+        assert!(stub
+            .claim_preview(Principal::anonymous())
+            .await
+            .unwrap()
+            .rewards
+            .is_empty());
+        assert!(stub.health().await.is_ok());
+        assert_eq!(AdapterInfo::of(&stub).name, "Stub");
+    }
+}
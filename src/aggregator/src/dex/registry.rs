@@ -1,23 +1,195 @@
-use super::DexAdapter;
-#[cfg(not(target_arch = "wasm32"))]
 use super::{
-    dex_icpswap::IcpswapAdapter, dex_infinity::InfinityAdapter, dex_sonic::SonicAdapter,
+    dex_icpswap::IcpswapAdapter,
+    dex_infinity::InfinityAdapter,
+    dex_sonic::SonicAdapter,
+    generic_adapter::{GenericAdapter, GenericSpec},
     sns_adapter::SnsAdapter,
+    DexAdapter,
 };
+use crate::memory::{self, Candid, Memory};
+use candid::{CandidType, Principal};
+use ic_stable_structures::StableBTreeMap;
 use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::sync::{Arc, RwLock};
 
+// The registry is built from the `[dex]` table of `ledgers.toml` overlaid
+// with entries controllers manage at runtime. Managed entries live in stable
+// memory, so they survive upgrades, and win over a file entry of the same
+// name. File entries can be disabled but not removed: only their enabled
+// flag is stored, so they keep following the file. Removing a managed entry
+// that shadows a file entry brings the file entry back. `[generic.<NAME>]`
+// tables describe `Generic` adapters, see `generic_adapter`.
+
 #[derive(Clone)]
 pub struct AdapterEntry {
-    pub name: String,
     pub adapter: Arc<dyn DexAdapter>,
 }
 
+impl AdapterEntry {
+    /// Id of the adapter, the name of its registry entry
+    pub fn id(&self) -> &str {
+        self.adapter.id()
+    }
+}
+
+/// Adapter implementation an entry runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum AdapterKind {
+    Icpswap,
+    Sonic,
+    Infinity,
+    Sns,
+    /// Described by `AdapterConfig::spec`
+    Generic,
+}
+
+impl AdapterKind {
+    /// Kind of the `[dex]` entry called `name`
+    fn of_name(name: &str) -> Option<Self> {
+        match name {
+            "ICPSWAP_FACTORY" => Some(Self::Icpswap),
+            "SONIC_ROUTER" => Some(Self::Sonic),
+            "INFINITY_VAULT" => Some(Self::Infinity),
+            n if n.starts_with("SNS_") => Some(Self::Sns),
+            _ => None,
+        }
+    }
+}
+
+/// Configuration of one registry entry
+#[derive(Clone, Debug, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct AdapterConfig {
+    pub name: String,
+    pub kind: AdapterKind,
+    /// Canister the adapter calls
+    pub canister: Principal,
+    /// Expected controller of `canister`, checked when the entry is added
+    pub controller: Option<Principal>,
+    pub enabled: bool,
+    /// TOML of a `[generic.<NAME>]` table without its `canister`; required
+    /// for `Generic` adapters
+    pub spec: Option<String>,
+}
+
 static ADAPTERS: Lazy<RwLock<Vec<AdapterEntry>>> = Lazy::new(|| RwLock::new(Vec::new()));
 
+/// Entries read from `ledgers.toml` by the last `load_adapters`
+static FILE: Lazy<RwLock<Vec<AdapterConfig>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+thread_local! {
+    static MANAGED: RefCell<StableBTreeMap<String, Candid<AdapterConfig>, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::ADAPTERS)));
+    /// Enabled flags set on `ledgers.toml` entries
+    static FILE_ENABLED: RefCell<StableBTreeMap<String, bool, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::ADAPTER_FLAGS)));
+}
+
+fn file_configs(
+    dex_table: toml::value::Table,
+    ctrl_table: &toml::value::Table,
+    generic_table: &toml::value::Table,
+) -> Vec<AdapterConfig> {
+    let mut list = Vec::new();
+    for (name, val) in dex_table {
+        let canister = val.as_str().and_then(|s| Principal::from_text(s).ok());
+        if let (Some(canister), Some(kind)) = (canister, AdapterKind::of_name(&name)) {
+            let controller = ctrl_table
+                .get(&name)
+                .and_then(|v| v.as_str())
+                .and_then(|s| Principal::from_text(s).ok());
+            list.push(AdapterConfig {
+                name,
+                kind,
+                canister,
+                controller,
+                enabled: true,
+                spec: None,
+            });
+        }
+    }
+    for (name, val) in generic_table {
+        let Some(mut table) = val.as_table().cloned() else {
+            continue;
+        };
+        let canister = table
+            .remove("canister")
+            .and_then(|v| v.as_str().and_then(|s| Principal::from_text(s).ok()));
+        let Some(canister) = canister else {
+            tracing::warn!("generic adapter {name} has no valid canister");
+            continue;
+        };
+        let config = AdapterConfig {
+            name: name.clone(),
+            kind: AdapterKind::Generic,
+            canister,
+            controller: ctrl_table
+                .get(name)
+                .and_then(|v| v.as_str())
+                .and_then(|s| Principal::from_text(s).ok()),
+            enabled: true,
+            spec: toml::to_string(&table).ok(),
+        };
+        match validate(&config) {
+            Ok(()) => list.push(config),
+            Err(e) => tracing::warn!("generic adapter {name}: {e}"),
+        }
+    }
+    list
+}
+
+fn build(config: &AdapterConfig) -> Option<Arc<dyn DexAdapter>> {
+    Some(match config.kind {
+        AdapterKind::Icpswap => Arc::new(IcpswapAdapter::new(config.canister)),
+        AdapterKind::Sonic => Arc::new(SonicAdapter::new(config.canister)),
+        AdapterKind::Infinity => Arc::new(InfinityAdapter::new(config.canister)),
+        AdapterKind::Sns => Arc::new(SnsAdapter::new(&config.name, config.canister)),
+        AdapterKind::Generic => {
+            let spec = GenericSpec::parse(config.spec.as_deref()?).ok()?;
+            Arc::new(GenericAdapter::new(&config.name, config.canister, spec))
+        }
+    })
+}
+
+fn managed() -> Vec<AdapterConfig> {
+    MANAGED.with(|m| m.borrow().iter().map(|(_, c)| c.0).collect())
+}
+
+/// File entries with their stored enabled flags, overlaid with managed
+/// entries and sorted by name
+fn effective(file: &[AdapterConfig], managed: Vec<AdapterConfig>) -> Vec<AdapterConfig> {
+    let mut out: Vec<AdapterConfig> = file
+        .iter()
+        .filter(|f| !managed.iter().any(|m| m.name == f.name))
+        .map(|f| AdapterConfig {
+            enabled: FILE_ENABLED
+                .with(|e| e.borrow().get(&f.name))
+                .unwrap_or(f.enabled),
+            ..f.clone()
+        })
+        .collect();
+    out.extend(managed);
+    out.sort_by(|a, b| a.name.cmp(&b.name));
+    out
+}
+
+/// Rebuild the adapters from the file and managed entries
+fn rebuild() {
+    let list = effective(&FILE.read().unwrap(), managed())
+        .into_iter()
+        .filter(|c| c.enabled)
+        .filter_map(|c| {
+            Some(AdapterEntry {
+                adapter: build(&c)?,
+            })
+        })
+        .collect();
+    *ADAPTERS.write().unwrap() = list;
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 pub async fn load_adapters() {
-    use candid::Principal;
     use std::fs;
     use std::path::Path;
 
@@ -28,41 +200,236 @@ pub async fn load_adapters() {
     let text = fs::read_to_string(&path).unwrap_or_default();
     let value: toml::Value =
         toml::from_str(&text).unwrap_or(toml::Value::Table(Default::default()));
-    let mut dex_table = value
-        .get("dex")
-        .and_then(|t| t.as_table())
-        .cloned()
-        .unwrap_or_default();
+    let table = |key: &str| {
+        value
+            .get(key)
+            .and_then(|t| t.as_table())
+            .cloned()
+            .unwrap_or_default()
+    };
+    let mut dex_table = table("dex");
     for key in dex_table.clone().keys() {
         if let Ok(v) = std::env::var(key) {
             dex_table.insert(key.clone(), toml::Value::String(v));
         }
     }
-    let mut list = Vec::new();
-    for (name, val) in dex_table {
-        if let Some(id_str) = val.as_str() {
-            if let Ok(principal) = Principal::from_text(id_str) {
-                if let Some(adapter) = match name.as_str() {
-                    "ICPSWAP_FACTORY" => Some(Arc::new(IcpswapAdapter) as Arc<dyn DexAdapter>),
-                    "SONIC_ROUTER" => Some(Arc::new(SonicAdapter) as Arc<dyn DexAdapter>),
-                    "INFINITY_VAULT" => Some(Arc::new(InfinityAdapter) as Arc<dyn DexAdapter>),
-                    n if n.starts_with("SNS_") => {
-                        Some(Arc::new(SnsAdapter::new(principal)) as Arc<dyn DexAdapter>)
-                    }
-                    _ => None,
-                } {
-                    list.push(AdapterEntry { name, adapter });
-                }
-            }
-        }
-    }
-    let mut reg = ADAPTERS.write().unwrap();
-    *reg = list;
+    *FILE.write().unwrap() = file_configs(dex_table, &table("dex_controllers"), &table("generic"));
+    rebuild();
 }
 
+/// Build the registry from the `ledgers.toml` embedded at compile time,
+/// honouring the same `option_env!` overrides as `utils::env_principal`.
 #[cfg(target_arch = "wasm32")]
-pub async fn load_adapters() {}
+pub async fn load_adapters() {
+    let mut dex_table = crate::utils::embedded_table("dex");
+    for key in dex_table.clone().keys() {
+        if let Some(p) = crate::utils::env_principal(key) {
+            dex_table.insert(key.clone(), toml::Value::String(p.to_text()));
+        }
+    }
+    *FILE.write().unwrap() = file_configs(
+        dex_table,
+        &crate::utils::embedded_table("dex_controllers"),
+        &crate::utils::embedded_table("generic"),
+    );
+    rebuild();
+}
 
 pub fn get() -> Vec<AdapterEntry> {
     ADAPTERS.read().unwrap().clone()
 }
+
+/// All entries, including disabled ones
+pub fn list() -> Vec<AdapterConfig> {
+    effective(&FILE.read().unwrap(), managed())
+}
+
+pub fn validate(config: &AdapterConfig) -> Result<(), String> {
+    if config.name.is_empty() || config.name.len() > 64 {
+        return Err("name must be 1 to 64 characters".into());
+    }
+    if AdapterKind::of_name(&config.name).unwrap_or(AdapterKind::Generic) != config.kind {
+        return Err(format!(
+            "{:?} adapters must be named {}",
+            config.kind,
+            match config.kind {
+                AdapterKind::Icpswap => "ICPSWAP_FACTORY",
+                AdapterKind::Sonic => "SONIC_ROUTER",
+                AdapterKind::Infinity => "INFINITY_VAULT",
+                AdapterKind::Sns => "SNS_<name>",
+                AdapterKind::Generic => "anything but a built-in adapter name",
+            }
+        ));
+    }
+    if config.kind == AdapterKind::Generic {
+        GenericSpec::parse(config.spec.as_deref().unwrap_or_default())?;
+    }
+    if config.canister == Principal::anonymous()
+        || config.canister == Principal::management_canister()
+    {
+        return Err("invalid canister".into());
+    }
+    Ok(())
+}
+
+/// Check that `canister` is controlled by `expected`
+#[cfg(target_arch = "wasm32")]
+async fn check_controller(canister: Principal, expected: Principal) -> Result<(), String> {
+    use ic_cdk::api::management_canister::main::{canister_info, CanisterInfoRequest};
+    let (info,) = canister_info(CanisterInfoRequest {
+        canister_id: canister,
+        num_requested_changes: None,
+    })
+    .await
+    .map_err(|(code, msg)| format!("canister_info failed: {code:?}: {msg}"))?;
+    if info.controllers.contains(&expected) {
+        Ok(())
+    } else {
+        Err(format!("{canister} is not controlled by {expected}"))
+    }
+}
+
+// native builds check controllers when loading `ledgers.toml`, see
+// `utils::load_dex_config`
+#[cfg(not(target_arch = "wasm32"))]
+async fn check_controller(_canister: Principal, _expected: Principal) -> Result<(), String> {
+    Ok(())
+}
+
+/// Add a managed entry, replacing any entry of the same name
+pub async fn add(config: AdapterConfig) -> Result<(), String> {
+    validate(&config)?;
+    if let Some(expected) = config.controller {
+        check_controller(config.canister, expected).await?;
+    }
+    tracing::info!("adapter {} added for {}", config.name, config.canister);
+    MANAGED.with(|m| m.borrow_mut().insert(config.name.clone(), Candid(config)));
+    rebuild();
+    super::clear_all_caches();
+    Ok(())
+}
+
+/// Remove the managed entry `name`; a file entry it shadowed applies again
+pub fn remove(name: &str) -> Result<(), String> {
+    remove_managed(&FILE.read().unwrap(), name)?;
+    tracing::info!("adapter {name} removed");
+    rebuild();
+    super::clear_all_caches();
+    Ok(())
+}
+
+fn remove_managed(file: &[AdapterConfig], name: &str) -> Result<(), String> {
+    if MANAGED
+        .with(|m| m.borrow_mut().remove(&name.to_string()))
+        .is_some()
+    {
+        return Ok(());
+    }
+    if file.iter().any(|c| c.name == name) {
+        return Err(format!(
+            "{name} is defined in ledgers.toml; disable it instead"
+        ));
+    }
+    Err(format!("no adapter named {name}"))
+}
+
+pub fn set_enabled(name: &str, enabled: bool) -> Result<(), String> {
+    set_enabled_in(&FILE.read().unwrap(), name, enabled)?;
+    tracing::info!("adapter {name} enabled: {enabled}");
+    rebuild();
+    super::clear_all_caches();
+    Ok(())
+}
+
+/// Flip a managed entry, or store the flag of a file entry
+fn set_enabled_in(file: &[AdapterConfig], name: &str, enabled: bool) -> Result<(), String> {
+    let key = name.to_string();
+    if let Some(Candid(mut config)) = MANAGED.with(|m| m.borrow().get(&key)) {
+        config.enabled = enabled;
+        MANAGED.with(|m| m.borrow_mut().insert(key, Candid(config)));
+        return Ok(());
+    }
+    if file.iter().any(|c| c.name == name) {
+        FILE_ENABLED.with(|e| e.borrow_mut().insert(key, enabled));
+        return Ok(());
+    }
+    Err(format!("no adapter named {name}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(name: &str, kind: AdapterKind, id: u8) -> AdapterConfig {
+        AdapterConfig {
+            name: name.into(),
+            kind,
+            canister: Principal::from_slice(&[id]),
+            controller: None,
+            enabled: true,
+            spec: None,
+        }
+    }
+
+    #[test]
+    fn managed_entries_override_file_entries() {
+        let file = vec![
+            config("SONIC_ROUTER", AdapterKind::Sonic, 1),
+            config("SNS_A", AdapterKind::Sns, 2),
+        ];
+        let mut disabled = config("SONIC_ROUTER", AdapterKind::Sonic, 1);
+        disabled.enabled = false;
+        let out = effective(
+            &file,
+            vec![disabled.clone(), config("SNS_B", AdapterKind::Sns, 3)],
+        );
+        let names: Vec<_> = out.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["SNS_A", "SNS_B", "SONIC_ROUTER"]);
+        assert_eq!(out[2], disabled);
+    }
+
+    #[test]
+    fn file_entries_keep_only_their_flag() {
+        let mut file = vec![config("SNS_F", AdapterKind::Sns, 1)];
+        set_enabled_in(&file, "SNS_F", false).unwrap();
+        assert!(MANAGED.with(|m| m.borrow().is_empty()));
+        // a new canister in the file still applies, with the stored flag
+        file[0].canister = Principal::from_slice(&[2]);
+        let out = effective(&file, managed());
+        assert_eq!(out[0].canister, Principal::from_slice(&[2]));
+        assert!(!out[0].enabled);
+        assert!(remove_managed(&file, "SNS_F").is_err());
+        assert!(set_enabled_in(&file, "SNS_NONE", true).is_err());
+
+        // a managed entry shadowing the file one can be removed again
+        MANAGED.with(|m| {
+            let shadow = config("SNS_F", AdapterKind::Sns, 3);
+            m.borrow_mut().insert("SNS_F".into(), Candid(shadow))
+        });
+        set_enabled_in(&file, "SNS_F", true).unwrap();
+        assert_eq!(
+            effective(&file, managed())[0].canister,
+            Principal::from_slice(&[3])
+        );
+        remove_managed(&file, "SNS_F").unwrap();
+        let out = effective(&file, managed());
+        assert_eq!(out[0].canister, Principal::from_slice(&[2]));
+        assert!(!out[0].enabled);
+    }
+
+    #[test]
+    fn names_must_match_kind() {
+        assert!(validate(&config("SNS_X", AdapterKind::Sns, 1)).is_ok());
+        assert!(validate(&config("ICPSWAP_FACTORY", AdapterKind::Icpswap, 1)).is_ok());
+        assert!(validate(&config("MY_SWAP", AdapterKind::Icpswap, 1)).is_err());
+        assert!(validate(&config("SNS_X", AdapterKind::Sonic, 1)).is_err());
+        let mut generic = config("MY_SWAP", AdapterKind::Generic, 1);
+        assert!(validate(&generic).is_err());
+        generic.spec =
+            Some("method = \"positions\"\ntoken = \"t\"\namount = \"a\"\ndecimals = 8".into());
+        assert!(validate(&generic).is_ok());
+        let mut anon = config("SNS_X", AdapterKind::Sns, 1);
+        anon.canister = Principal::anonymous();
+        assert!(validate(&anon).is_err());
+    }
+}
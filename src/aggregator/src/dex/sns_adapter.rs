@@ -1,12 +1,9 @@
-use super::{DexAdapter, RewardInfo};
+use super::{Capabilities, DexAdapter, RewardInfo};
 use crate::error::FetchError;
-#[cfg(not(target_arch = "wasm32"))]
-use crate::utils::{format_amount, get_agent};
+use crate::utils::call_query;
 use async_trait::async_trait;
-use bx_core::Holding;
-use candid::{CandidType, Nat, Principal};
-#[cfg(not(target_arch = "wasm32"))]
-use candid::{Decode, Encode};
+use bx_core::{HoldingKind, TypedHolding};
+use candid::{CandidType, Decode, Encode, Nat, Principal};
 #[cfg(not(target_arch = "wasm32"))]
 use once_cell::sync::Lazy;
 use serde::Deserialize;
@@ -14,12 +11,16 @@ use serde::Deserialize;
 use std::sync::Mutex;
 
 pub struct SnsAdapter {
+    id: String,
     distributor: Principal,
 }
 
 impl SnsAdapter {
-    pub fn new(distributor: Principal) -> Self {
-        Self { distributor }
+    pub fn new(id: impl Into<String>, distributor: Principal) -> Self {
+        Self {
+            id: id.into(),
+            distributor,
+        }
     }
 }
 
@@ -42,7 +43,34 @@ pub struct Claimable {
 
 #[async_trait]
 impl DexAdapter for SnsAdapter {
-    async fn fetch_positions(&self, principal: Principal) -> Result<Vec<Holding>, FetchError> {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn name(&self) -> &str {
+        "SNS"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            positions: true,
+            claimable: true,
+            claim: true,
+            ..Default::default()
+        }
+    }
+
+    fn config(&self) -> Vec<(String, String)> {
+        vec![("distributor".into(), self.distributor.to_text())]
+    }
+
+    async fn health(&self) -> Result<(), FetchError> {
+        fetch_positions_impl(self.distributor, Principal::anonymous())
+            .await
+            .map(|_| ())
+    }
+
+    async fn fetch_positions(&self, principal: Principal) -> Result<Vec<TypedHolding>, FetchError> {
         fetch_positions_impl(self.distributor, principal).await
     }
 
@@ -51,8 +79,8 @@ impl DexAdapter for SnsAdapter {
         Ok(holdings
             .into_iter()
             .map(|h| RewardInfo {
-                token: h.token,
-                amount: h.amount,
+                amount: h.formatted_amount(),
+                token: h.symbol,
             })
             .collect())
     }
@@ -63,45 +91,33 @@ impl DexAdapter for SnsAdapter {
     }
 }
 
-#[cfg(not(target_arch = "wasm32"))]
+fn to_holdings(distro_id: Principal, claims: Vec<Claimable>) -> Vec<TypedHolding> {
+    claims
+        .into_iter()
+        .map(|c| {
+            let kind = HoldingKind::Claimable {
+                claim_from: Some(distro_id),
+                pool: None,
+            };
+            TypedHolding::new("SNS", c.symbol, c.amount, c.decimals, kind)
+        })
+        .collect()
+}
+
 async fn fetch_positions_impl(
     distro_id: Principal,
     principal: Principal,
-) -> Result<Vec<Holding>, FetchError> {
+) -> Result<Vec<TypedHolding>, FetchError> {
+    #[cfg(not(target_arch = "wasm32"))]
     if let Some(resp) = MOCK_CLAIMABLE.lock().unwrap().clone() {
         let claims = resp.map_err(FetchError::Network)?;
-        return Ok(claims
-            .into_iter()
-            .map(|c| Holding {
-                source: "SNS".into(),
-                token: c.symbol,
-                amount: format_amount(c.amount, c.decimals),
-                status: "claimable".into(),
-            })
-            .collect());
+        return Ok(to_holdings(distro_id, claims));
     }
-    let agent = get_agent().await;
-    let claims = sns_get_claimable(&agent, distro_id, principal)
-        .await
-        .map_err(FetchError::from)?;
-    let mut out = Vec::with_capacity(claims.len());
-    for c in claims {
-        out.push(Holding {
-            source: "SNS".into(),
-            token: c.symbol,
-            amount: format_amount(c.amount, c.decimals),
-            status: "claimable".into(),
-        });
-    }
-    Ok(out)
-}
-
-#[cfg(target_arch = "wasm32")]
-async fn fetch_positions_impl(
-    _distro_id: Principal,
-    _principal: Principal,
-) -> Result<Vec<Holding>, FetchError> {
-    Ok(Vec::new())
+    let arg = Encode!(&principal).map_err(|_| FetchError::InvalidResponse)?;
+    let bytes = call_query(distro_id, "get_claimable_tokens", arg).await?;
+    let claims: Vec<Claimable> =
+        Decode!(&bytes, Vec<Claimable>).map_err(|_| FetchError::InvalidResponse)?;
+    Ok(to_holdings(distro_id, claims))
 }
 
 #[cfg(all(feature = "claim", not(target_arch = "wasm32")))]
@@ -109,7 +125,7 @@ async fn claim_impl(distro_id: Principal, principal: Principal) -> Result<u64, S
     if let Some(resp) = MOCK_CLAIM.lock().unwrap().clone() {
         return resp;
     }
-    let agent = get_agent().await;
+    let agent = crate::utils::get_agent().await;
     let spent = sns_claim(&agent, distro_id, principal)
         .await
         .map_err(|e| e.to_string())?;
@@ -1,8 +1,11 @@
+use crate::breaker;
 use crate::dex::registry::{self, AdapterEntry};
 use crate::error::FetchError;
-use bx_core::Holding;
+use crate::scheduler;
+use crate::sources::{self, Fetched};
+use crate::utils::now;
+use bx_core::TypedHolding;
 use candid::Principal;
-use futures::future::join_all;
 #[cfg(not(target_arch = "wasm32"))]
 use once_cell::sync::Lazy;
 #[cfg(not(target_arch = "wasm32"))]
@@ -24,9 +27,9 @@ async fn pause() {
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-async fn with_timeout<F>(fut: F) -> Result<Vec<Holding>, FetchError>
+async fn with_timeout<F>(fut: F) -> Result<Vec<TypedHolding>, FetchError>
 where
-    F: std::future::Future<Output = Result<Vec<Holding>, FetchError>>,
+    F: std::future::Future<Output = Result<Vec<TypedHolding>, FetchError>>,
 {
     use tokio::time::timeout;
     match timeout(Duration::from_secs(*FETCH_ADAPTER_TIMEOUT_SECS), fut).await {
@@ -36,46 +39,78 @@ where
 }
 
 #[cfg(target_arch = "wasm32")]
-async fn with_timeout<F>(fut: F) -> Result<Vec<Holding>, FetchError>
+async fn with_timeout<F>(fut: F) -> Result<Vec<TypedHolding>, FetchError>
 where
-    F: std::future::Future<Output = Result<Vec<Holding>, FetchError>>,
+    F: std::future::Future<Output = Result<Vec<TypedHolding>, FetchError>>,
 {
     fut.await
 }
 
-pub async fn fetch_filtered(
+/// Report the outcome of a call to adapter `id` to its breaker. Calls the
+/// scheduler turned away never reached the adapter, so they count as neither
+/// a success nor a failure; a half-open probe turned away is retried once its
+/// backoff has passed again.
+fn record<T>(id: &str, res: &Result<T, FetchError>) {
+    match res {
+        Err(FetchError::Busy(_)) => {}
+        Err(e) => breaker::record(id, Some(&e.to_string()), now()),
+        Ok(_) => breaker::record(id, None, now()),
+    }
+}
+
+/// Query every selected adapter, reporting each as its own source
+pub async fn fetch_detailed(
     principal: Principal,
     list: Option<&std::collections::HashSet<String>>,
-) -> Result<Vec<Holding>, FetchError> {
+) -> Vec<Fetched> {
     // allow other tasks to start before launching adapter queries
     pause().await;
     let adapters: Vec<AdapterEntry> = registry::get();
     let tasks = adapters
         .into_iter()
         .filter(|e| match list {
-            Some(s) => s.contains(&e.name),
+            Some(s) => s.contains(e.id()),
             None => true,
         })
         .map(|e| {
             let adapter = e.adapter.clone();
-            async move { with_timeout(adapter.fetch_positions(principal)).await }
+            let id = e.id().to_string();
+            sources::timed("dex", id.clone(), Some(principal), async move {
+                if !breaker::allow(&id, now()) {
+                    return Err(FetchError::Network("circuit open".into()));
+                }
+                let res = with_timeout(adapter.fetch_positions(principal)).await;
+                record(&id, &res);
+                res
+            })
         });
-    let results = join_all(tasks).await;
-    let capacity: usize = results
-        .iter()
-        .filter_map(|r| r.as_ref().ok())
-        .map(|v| v.len())
-        .sum();
-    let mut out = Vec::with_capacity(capacity);
-    for r in results {
-        match r {
-            Ok(v) => out.extend(v),
-            Err(e) => return Err(e),
+    scheduler::join_all(tasks).await
+}
+
+/// Probe adapters whose circuit is open and due for a retry
+pub async fn probe_open_adapters() {
+    let due: Vec<AdapterEntry> = registry::get()
+        .into_iter()
+        .filter(|e| breaker::due(e.id(), now()))
+        .collect();
+    let probes = due.into_iter().map(|e| async move {
+        if !breaker::allow(e.id(), now()) {
+            return;
         }
-    }
-    Ok(out)
+        let res = with_timeout(async { e.adapter.health().await.map(|_| Vec::new()) }).await;
+        record(e.id(), &res);
+    });
+    scheduler::join_all(probes).await;
+}
+
+/// Like [`fetch_detailed`] but fails when any adapter does
+pub async fn fetch_filtered(
+    principal: Principal,
+    list: Option<&std::collections::HashSet<String>>,
+) -> Result<Vec<TypedHolding>, FetchError> {
+    sources::all_or_error(fetch_detailed(principal, list).await)
 }
 
-pub async fn fetch(principal: Principal) -> Result<Vec<Holding>, FetchError> {
+pub async fn fetch(principal: Principal) -> Result<Vec<TypedHolding>, FetchError> {
     fetch_filtered(principal, None).await
 }
@@ -1,6 +1,8 @@
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, candid::CandidType, serde::Serialize, serde::Deserialize)]
 pub enum FetchError {
     Network(String),
+    /// Turned away by the call scheduler before reaching the canister
+    Busy(String),
     InvalidConfig(String),
     InvalidResponse,
 }
@@ -9,6 +11,7 @@ impl core::fmt::Display for FetchError {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Self::Network(e) => write!(f, "network error: {e}"),
+            Self::Busy(e) => write!(f, "busy: {e}"),
             Self::InvalidConfig(name) => write!(f, "invalid config: {name}"),
             Self::InvalidResponse => f.write_str("invalid response"),
         }
@@ -0,0 +1,346 @@
+use crate::dex::registry;
+use crate::{breaker, cache, metrics, pool_registry, sources, user_settings, TokenTotal};
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, Json, Object, Request, Response, Schema,
+    SimpleObject,
+};
+use bx_core::Holding;
+use candid::Principal;
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+
+// One schema behind both the `pools_graphql` query and the HTTP `/graphql`
+// route. Query calls can't wait on other canisters, so there holdings come
+// from the cache only; `/graphql` runs in an update call and fetches
+// whatever is missing. Depth and complexity limits bound the instructions a
+// single request can burn.
+
+static MAX_DEPTH: Lazy<usize> = Lazy::new(|| {
+    option_env!("GRAPHQL_MAX_DEPTH")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(8)
+});
+
+static MAX_COMPLEXITY: Lazy<usize> = Lazy::new(|| {
+    option_env!("GRAPHQL_MAX_COMPLEXITY")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(200)
+});
+
+/// Complexity of a field that may fetch from every ledger and DEX, so the
+/// default limit admits a few of them per request rather than one per field
+const FETCH_COST: usize = 50;
+
+/// Call context a request runs in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Query call: serve cached data only
+    Query,
+    /// Update call: fetch what isn't cached
+    Update,
+}
+
+#[derive(SimpleObject)]
+struct GHolding {
+    source: String,
+    token: String,
+    amount: String,
+    status: String,
+    subaccount: Option<String>,
+    /// Linked wallet the holding belongs to
+    wallet: Option<String>,
+}
+
+impl From<Holding> for GHolding {
+    fn from(h: Holding) -> Self {
+        GHolding {
+            source: h.source,
+            token: h.token,
+            amount: h.amount,
+            status: h.status,
+            subaccount: h.subaccount,
+            wallet: h.wallet,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+struct GTokenTotal {
+    token: String,
+    total: f64,
+    value_icp: Option<f64>,
+    value_usd: Option<f64>,
+}
+
+impl From<TokenTotal> for GTokenTotal {
+    fn from(t: TokenTotal) -> Self {
+        GTokenTotal {
+            token: t.token,
+            total: t.total,
+            value_icp: t.value_icp,
+            value_usd: t.value_usd,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+struct GPool {
+    id: String,
+    token_a: String,
+    token_b: String,
+    decimals_a: u8,
+    decimals_b: u8,
+    image_a: Option<String>,
+    image_b: Option<String>,
+}
+
+impl From<pool_registry::PoolMeta> for GPool {
+    fn from(p: pool_registry::PoolMeta) -> Self {
+        GPool {
+            id: p.id,
+            token_a: p.token_a,
+            token_b: p.token_b,
+            decimals_a: p.decimals_a,
+            decimals_b: p.decimals_b,
+            image_a: p.image_a,
+            image_b: p.image_b,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+struct GSettings {
+    preferred_ledgers: Vec<String>,
+    preferred_dexes: Vec<String>,
+    dark_mode: bool,
+    /// Hex encoded
+    subaccounts: Vec<String>,
+    track_history: bool,
+}
+
+impl From<user_settings::UserSettings> for GSettings {
+    fn from(s: user_settings::UserSettings) -> Self {
+        GSettings {
+            subaccounts: s
+                .subaccounts()
+                .iter()
+                .map(|s| crate::ledger_fetcher::subaccount_hex(s))
+                .collect(),
+            track_history: s.track_history(),
+            preferred_ledgers: s.preferred_ledgers,
+            preferred_dexes: s.preferred_dexes,
+            dark_mode: s.dark_mode,
+        }
+    }
+}
+
+/// Circuit breaker state of a DEX adapter and the outcome of its latest
+/// call; the latter is `None` before the first
+#[derive(SimpleObject)]
+struct GAdapterHealth {
+    name: String,
+    /// `Closed`, `Open` or `HalfOpen`
+    state: String,
+    consecutive_failures: u32,
+    ok: Option<bool>,
+    error: Option<String>,
+    latency_ms: Option<u64>,
+    checked_at: Option<u64>,
+}
+
+fn principal(text: &str) -> async_graphql::Result<Principal> {
+    Principal::from_text(text).map_err(|_| format!("invalid principal {text}").into())
+}
+
+#[derive(Default)]
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Pools of the registry, optionally only those with `id` or trading
+    /// `token`
+    async fn pools(&self, token: Option<String>, id: Option<String>) -> Vec<GPool> {
+        let mut pools: Vec<_> = pool_registry::list()
+            .into_iter()
+            .filter(|p| id.as_ref().is_none_or(|id| &p.id == id))
+            .filter(|p| {
+                token.as_ref().is_none_or(|t| {
+                    p.token_a.eq_ignore_ascii_case(t) || p.token_b.eq_ignore_ascii_case(t)
+                })
+            })
+            .collect();
+        pools.sort_by(|a, b| a.id.cmp(&b.id));
+        pools.into_iter().map(GPool::from).collect()
+    }
+
+    #[graphql(complexity = "FETCH_COST + child_complexity")]
+    async fn holdings(
+        &self,
+        ctx: &Context<'_>,
+        principal: String,
+    ) -> async_graphql::Result<Vec<GHolding>> {
+        let p = self::principal(&principal)?;
+        let holdings: Vec<Holding> = match ctx.data_opt::<Mode>() {
+            Some(Mode::Update) => crate::fetch_typed_holdings(p)
+                .await?
+                .iter()
+                .map(Holding::from)
+                .collect(),
+            _ => cache::get()
+                .get(&p)
+                .map(|(h, _, _)| h.iter().map(Holding::from).collect())
+                .ok_or("no cached holdings; call request_refresh first")?,
+        };
+        Ok(holdings.into_iter().map(GHolding::from).collect())
+    }
+
+    #[graphql(complexity = "FETCH_COST + child_complexity")]
+    async fn summary(
+        &self,
+        ctx: &Context<'_>,
+        principal: String,
+    ) -> async_graphql::Result<Vec<GTokenTotal>> {
+        let p = self::principal(&principal)?;
+        let summary = match ctx.data_opt::<Mode>() {
+            Some(Mode::Update) => crate::fetch_summary(p).await?,
+            _ => crate::cached_summary(p)?,
+        };
+        Ok(summary.into_iter().map(GTokenTotal::from).collect())
+    }
+
+    async fn metrics(&self) -> Json<metrics::Metrics> {
+        Json(metrics::get())
+    }
+
+    async fn settings(&self, principal: String) -> async_graphql::Result<GSettings> {
+        let p = self::principal(&principal)?;
+        Ok(user_settings::get(&p).unwrap_or_default().into())
+    }
+
+    async fn adapter_health(&self) -> Vec<GAdapterHealth> {
+        registry::get()
+            .into_iter()
+            .map(|e| {
+                let status = sources::latest("dex", e.id());
+                let breaker = breaker::health(e.id());
+                GAdapterHealth {
+                    state: format!("{:?}", breaker.state),
+                    consecutive_failures: breaker.consecutive_failures,
+                    ok: status.as_ref().map(|s| s.ok()),
+                    error: status
+                        .as_ref()
+                        .and_then(|s| s.error.as_ref().map(|e| e.to_string())),
+                    latency_ms: status.as_ref().map(|s| s.latency_ms),
+                    checked_at: status.map(|s| s.fetched_at),
+                    name: e.id().to_string(),
+                }
+            })
+            .collect()
+    }
+}
+
+pub type AppSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+static SCHEMA: Lazy<AppSchema> = Lazy::new(|| {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(*MAX_DEPTH)
+        .limit_complexity(*MAX_COMPLEXITY)
+        .finish()
+});
+
+/// A GraphQL request given as JSON or as a bare query
+pub fn request(body: &[u8]) -> Request {
+    serde_json::from_slice(body)
+        .unwrap_or_else(|_| Request::new(std::str::from_utf8(body).unwrap_or("")))
+}
+
+pub async fn execute(request: Request, mode: Mode) -> Response {
+    SCHEMA.execute(request.data(mode)).await
+}
+
+/// Run `body` in a query call and return the JSON response. Cached-only
+/// resolvers never wait, so the request completes in one poll.
+pub fn execute_query(body: &[u8]) -> String {
+    let resp = execute(request(body), Mode::Query)
+        .now_or_never()
+        .unwrap_or_else(|| {
+            Response::from_errors(vec![async_graphql::ServerError::new(
+                "request needs an update call; use /graphql",
+                None,
+            )])
+        });
+    serde_json::to_string(&resp).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(query: &str) -> serde_json::Value {
+        serde_json::from_str(&execute_query(query.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn pools_are_filtered() {
+        pool_registry::load_content(
+            r#"
+            [[pool]]
+            id = "p1"
+            token_a = "ICP"
+            token_b = "CKBTC"
+            decimals_a = 8
+            decimals_b = 8
+            [[pool]]
+            id = "p2"
+            token_a = "ICP"
+            token_b = "CHAT"
+            decimals_a = 8
+            decimals_b = 8
+            "#,
+        );
+        let out = run(r#"{ pools(token: "chat") { id tokenB } }"#);
+        assert_eq!(
+            out["data"]["pools"],
+            serde_json::json!([{"id": "p2", "tokenB": "CHAT"}])
+        );
+        let out = run(r#"{"query": "{ pools(id: \"p1\") { id } }"}"#);
+        assert_eq!(out["data"]["pools"][0]["id"], "p1");
+    }
+
+    #[test]
+    fn limits_and_cache_only_queries() {
+        let out = run(
+            "{ __schema { types { fields { type { ofType { ofType { ofType { ofType { name } } } } } } } } }",
+        );
+        assert!(out["errors"][0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("nested too deep"));
+
+        let p = Principal::from_slice(&[0x6A]);
+        let out = run(&format!(r#"{{ holdings(principal: "{p}") {{ token }} }}"#));
+        assert!(out["errors"][0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("no cached holdings"));
+        let out = run(r#"{ adapterHealth { name } settings(principal: "aaaaa-aa") { darkMode } }"#);
+        assert_eq!(out["data"]["settings"]["darkMode"], false);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn fetching_fields_are_costly() {
+        let p = Principal::from_slice(&[0x6B]);
+        let aliases = |n: usize| {
+            let fields: Vec<String> = (0..n)
+                .map(|i| format!(r#"h{i}: holdings(principal: "{p}") {{ token }}"#))
+                .collect();
+            Request::new(format!("{{ {} }}", fields.join(" ")))
+        };
+        let too_many = *MAX_COMPLEXITY / FETCH_COST + 1;
+        let resp = execute(aliases(too_many), Mode::Update).await;
+        assert_eq!(resp.errors[0].message, "Query is too complex.");
+        // within the limit the fields run, here reading the cache
+        let resp = execute(aliases(too_many - 2), Mode::Query).await;
+        assert!(resp.errors[0].message.contains("no cached holdings"));
+    }
+}
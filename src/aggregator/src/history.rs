@@ -0,0 +1,342 @@
+use crate::memory::{self, Memory};
+use crate::HoldingSummary;
+use candid::{CandidType, Principal};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Periodic per-principal summaries for principals that opted in through
+// `UserSettings::track_history`. Snapshots live in a stable B-tree keyed by
+// (principal, timestamp) so they survive upgrades without being copied
+// through the heap. A second tree keyed by (timestamp, principal) indexes
+// them by age, so pruning only visits the snapshots it drops.
+
+static SNAPSHOT_INTERVAL_SECS: Lazy<u64> = Lazy::new(|| {
+    option_env!("SNAPSHOT_INTERVAL_SECS")
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(3_600)
+        .max(60)
+});
+
+static HISTORY_RETENTION_NS: Lazy<u64> = Lazy::new(|| {
+    option_env!("HISTORY_RETENTION_DAYS")
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(365)
+        * crate::utils::DAY_NS
+});
+
+/// Most points a single `get_history` call may return
+pub const MAX_HISTORY_POINTS: usize = 1_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct SnapshotKey {
+    principal: Principal,
+    ts: u64,
+}
+
+/// `SnapshotKey` with the timestamp first, for the age index
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct AgeKey(SnapshotKey);
+
+const PRINCIPAL_MAX_LEN: usize = 29;
+const KEY_LEN: usize = 1 + PRINCIPAL_MAX_LEN + 8;
+
+impl Storable for SnapshotKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let p = self.principal.as_slice();
+        let mut out = vec![0u8; KEY_LEN];
+        out[0] = p.len() as u8;
+        out[1..1 + p.len()].copy_from_slice(p);
+        out[1 + PRINCIPAL_MAX_LEN..].copy_from_slice(&self.ts.to_be_bytes());
+        Cow::Owned(out)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = bytes[0] as usize;
+        let mut ts = [0u8; 8];
+        ts.copy_from_slice(&bytes[1 + PRINCIPAL_MAX_LEN..KEY_LEN]);
+        SnapshotKey {
+            principal: Principal::from_slice(&bytes[1..1 + len]),
+            ts: u64::from_be_bytes(ts),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: KEY_LEN as u32,
+        is_fixed_size: true,
+    };
+}
+
+impl PartialOrd for AgeKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for AgeKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.0.ts, self.0.principal).cmp(&(other.0.ts, other.0.principal))
+    }
+}
+
+impl Storable for AgeKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let key = self.0.to_bytes();
+        let mut out = Vec::with_capacity(KEY_LEN);
+        out.extend_from_slice(&key[1 + PRINCIPAL_MAX_LEN..]);
+        out.extend_from_slice(&key[..1 + PRINCIPAL_MAX_LEN]);
+        Cow::Owned(out)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut key = Vec::with_capacity(KEY_LEN);
+        key.extend_from_slice(&bytes[8..]);
+        key.extend_from_slice(&bytes[..8]);
+        AgeKey(SnapshotKey::from_bytes(Cow::Owned(key)))
+    }
+
+    const BOUND: Bound = SnapshotKey::BOUND;
+}
+
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub value_icp: f64,
+    pub value_usd: Option<f64>,
+    pub tokens: Vec<HoldingSummary>,
+}
+
+impl Storable for Snapshot {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("encode snapshot"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("decode snapshot")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Clone, CandidType, Serialize, Deserialize)]
+pub struct HistoryPoint {
+    pub ts: u64,
+    pub value_icp: f64,
+    pub value_usd: Option<f64>,
+    pub tokens: Vec<HoldingSummary>,
+}
+
+/// Bucket width used to downsample a series; the last snapshot in each
+/// bucket represents it.
+#[derive(Clone, Copy, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Resolution {
+    Raw,
+    Hourly,
+    Daily,
+    Weekly,
+}
+
+impl Resolution {
+    fn bucket_ns(self) -> Option<u64> {
+        match self {
+            Resolution::Raw => None,
+            Resolution::Hourly => Some(60 * crate::utils::MINUTE_NS),
+            Resolution::Daily => Some(crate::utils::DAY_NS),
+            Resolution::Weekly => Some(crate::utils::WEEK_NS),
+        }
+    }
+}
+
+thread_local! {
+    static SNAPSHOTS: RefCell<StableBTreeMap<SnapshotKey, Snapshot, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::HISTORY)));
+    static BY_AGE: RefCell<StableBTreeMap<AgeKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::HISTORY_BY_TS)));
+}
+
+/// Store the summary of `principal` taken at `ts`
+pub fn record(principal: Principal, ts: u64, tokens: Vec<HoldingSummary>) {
+    let value_icp = tokens.iter().filter_map(|t| t.value_icp).sum();
+    let usd: Vec<f64> = tokens.iter().filter_map(|t| t.value_usd).collect();
+    let value_usd = (!usd.is_empty()).then(|| usd.iter().sum());
+    let snapshot = Snapshot {
+        value_icp,
+        value_usd,
+        tokens,
+    };
+    let key = SnapshotKey { principal, ts };
+    SNAPSHOTS.with(|s| s.borrow_mut().insert(key, snapshot));
+    BY_AGE.with(|a| a.borrow_mut().insert(AgeKey(key), ()));
+}
+
+/// Snapshots of `principal` between `from` and `to` inclusive, downsampled to
+/// `resolution`.
+pub fn get(
+    principal: Principal,
+    from: u64,
+    to: u64,
+    resolution: Resolution,
+) -> Result<Vec<HistoryPoint>, String> {
+    if from > to {
+        return Err("`from` must not be after `to`".into());
+    }
+    let range = SnapshotKey {
+        principal,
+        ts: from,
+    }..=SnapshotKey { principal, ts: to };
+    let mut out: Vec<HistoryPoint> = Vec::new();
+    SNAPSHOTS.with(|s| {
+        for (key, snap) in s.borrow().range(range) {
+            let point = HistoryPoint {
+                ts: key.ts,
+                value_icp: snap.value_icp,
+                value_usd: snap.value_usd,
+                tokens: snap.tokens,
+            };
+            let same_bucket = match (resolution.bucket_ns(), out.last()) {
+                (Some(w), Some(last)) => last.ts / w == key.ts / w,
+                _ => false,
+            };
+            if same_bucket {
+                *out.last_mut().expect("bucket") = point;
+            } else if out.len() == MAX_HISTORY_POINTS {
+                return Err(format!(
+                    "more than {MAX_HISTORY_POINTS} points; use a coarser resolution"
+                ));
+            } else {
+                out.push(point);
+            }
+        }
+        Ok(())
+    })?;
+    Ok(out)
+}
+
+/// Drop snapshots taken before `cutoff`
+pub fn prune(cutoff: u64) -> usize {
+    let first_kept = AgeKey(SnapshotKey {
+        principal: Principal::from_slice(&[]),
+        ts: cutoff,
+    });
+    let stale: Vec<AgeKey> =
+        BY_AGE.with(|a| a.borrow().range(..first_kept).map(|(k, _)| k).collect());
+    BY_AGE.with(|a| {
+        let mut index = a.borrow_mut();
+        SNAPSHOTS.with(|s| {
+            let mut map = s.borrow_mut();
+            for k in &stale {
+                index.remove(k);
+                map.remove(&k.0);
+            }
+        })
+    });
+    stale.len()
+}
+
+pub fn len() -> u64 {
+    SNAPSHOTS.with(|s| s.borrow().len())
+}
+
+/// Snapshot every opted-in principal, reusing cached holdings when fresh,
+/// then drop snapshots past the retention period.
+pub async fn take_snapshots(now: u64) {
+    use futures::stream::{self, StreamExt};
+    let principals = crate::user_settings::history_principals();
+    let results: Vec<_> = stream::iter(principals)
+        .map(|p| async move { (p, crate::holdings_for(p, now).await) })
+        .buffer_unordered(*crate::PORTFOLIO_CONCURRENCY)
+        .collect()
+        .await;
+    for (principal, res) in results {
+        match res {
+            Ok((_, summary)) => record(principal, now, summary),
+            Err(e) => tracing::warn!("snapshot of {principal} failed: {e}"),
+        }
+    }
+    prune(now.saturating_sub(*HISTORY_RETENTION_NS));
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn schedule_snapshots() {
+    use std::time::Duration;
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(*SNAPSHOT_INTERVAL_SECS), || {
+        ic_cdk::spawn(take_snapshots(crate::utils::now()));
+    });
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn schedule_snapshots() {
+    use std::time::Duration;
+    tokio::spawn(async {
+        let mut timer = tokio::time::interval(Duration::from_secs(*SNAPSHOT_INTERVAL_SECS));
+        loop {
+            timer.tick().await;
+            take_snapshots(crate::utils::now()).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * crate::utils::MINUTE_NS;
+
+    fn summary(value: f64) -> Vec<HoldingSummary> {
+        vec![HoldingSummary {
+            token: "ICP".into(),
+            total: value,
+            value_icp: Some(value),
+            value_usd: None,
+        }]
+    }
+
+    #[test]
+    fn key_roundtrip_keeps_order() {
+        let a = SnapshotKey {
+            principal: Principal::from_slice(&[1, 2, 3]),
+            ts: 7,
+        };
+        assert_eq!(SnapshotKey::from_bytes(a.to_bytes()), a);
+        let anon = SnapshotKey {
+            principal: Principal::anonymous(),
+            ts: u64::MAX,
+        };
+        assert_eq!(SnapshotKey::from_bytes(anon.to_bytes()), anon);
+        assert_eq!(AgeKey::from_bytes(AgeKey(a).to_bytes()), AgeKey(a));
+        // the age index orders by time before principal
+        let older = SnapshotKey { ts: 6, ..a };
+        let newer = SnapshotKey { ts: 7, ..anon };
+        assert!(older.to_bytes() > newer.to_bytes());
+        assert!(AgeKey(older).to_bytes() < AgeKey(newer).to_bytes());
+        assert!(AgeKey(older) < AgeKey(newer));
+    }
+
+    #[test]
+    fn history_downsamples_to_last_in_bucket() {
+        let p = Principal::from_slice(&[0xE1]);
+        let other = Principal::from_slice(&[0xE2]);
+        for i in 0..6u64 {
+            record(p, i * HOUR / 2, summary(i as f64));
+        }
+        record(other, 0, summary(100.0));
+
+        let raw = get(p, 0, 10 * HOUR, Resolution::Raw).unwrap();
+        assert_eq!(raw.len(), 6);
+        let hourly = get(p, 0, 10 * HOUR, Resolution::Hourly).unwrap();
+        let values: Vec<f64> = hourly.iter().map(|h| h.value_icp).collect();
+        assert_eq!(values, vec![1.0, 3.0, 5.0]);
+        assert_eq!(hourly[0].value_usd, None);
+        let window = get(p, HOUR, 2 * HOUR, Resolution::Raw).unwrap();
+        assert_eq!(window.len(), 3);
+        assert!(get(p, 2, 1, Resolution::Raw).is_err());
+
+        // the other principal's snapshot is older than the cutoff too
+        assert_eq!(prune(2 * HOUR), 5);
+        assert_eq!(get(p, 0, 10 * HOUR, Resolution::Raw).unwrap().len(), 2);
+        assert_eq!(BY_AGE.with(|a| a.borrow().len()), len());
+        assert_eq!(prune(2 * HOUR), 0);
+    }
+}
@@ -0,0 +1,229 @@
+use crate::{cache, cert, HoldingSummary};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bx_core::Holding;
+use candid::Principal;
+use ic_certified_map::{labeled, labeled_hash, leaf_hash, AsHashTree, Hash, HashTree};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+// HTTP certification v2 for the JSON routes backed by the holdings cache.
+// Each cached principal has one certified response per route, stored in the
+// `http_expr` half of the certified tree under
+// `<route>/<principal>/<$>/<expr hash>/""/<response hash>`. Certification
+// covers the status code, `Content-Type` and the body; the request is not
+// certified.
+
+/// Routes `/<route>/<principal>` served from the certified tree
+pub const ROUTES: [&str; 2] = ["holdings", "summary"];
+
+/// Value of the `IC-CertificateExpression` header of certified responses
+pub const EXPRESSION: &str = "default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{},response_certification:ResponseCertification{certified_response_headers:ResponseHeaderList{headers:[\"content-type\"]}}}})";
+
+const CONTENT_TYPE: &str = "application/json";
+
+static EXPR_HASH: Lazy<Hash> = Lazy::new(|| Sha256::digest(EXPRESSION.as_bytes()).into());
+
+/// `<$>` subtree certifying one exact path
+pub struct Expr {
+    response_hash: Hash,
+}
+
+impl Expr {
+    /// Certify a 200 response with `body`
+    pub fn new(body: &[u8]) -> Self {
+        Expr {
+            response_hash: response_hash(200, &headers(), body),
+        }
+    }
+}
+
+impl AsHashTree for Expr {
+    fn root_hash(&self) -> Hash {
+        let leaf = labeled_hash(&self.response_hash, &leaf_hash(b""));
+        labeled_hash(
+            b"<$>",
+            &labeled_hash(&EXPR_HASH[..], &labeled_hash(b"", &leaf)),
+        )
+    }
+
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        let leaf = labeled(&self.response_hash, HashTree::Leaf(Cow::Borrowed(b"")));
+        labeled(b"<$>", labeled(&EXPR_HASH[..], labeled(b"", leaf)))
+    }
+}
+
+/// Body certified for `route`; the query serves the same bytes
+pub fn body(route: &str, holdings: &[Holding], summary: &[HoldingSummary]) -> Vec<u8> {
+    match route {
+        "holdings" => serde_json::to_vec(holdings),
+        _ => serde_json::to_vec(summary),
+    }
+    .expect("serialize body")
+}
+
+/// Headers covered by the certification
+fn headers() -> Vec<(String, String)> {
+    vec![
+        ("Content-Type".into(), CONTENT_TYPE.into()),
+        ("IC-CertificateExpression".into(), EXPRESSION.into()),
+    ]
+}
+
+enum Value<'a> {
+    Str(&'a str),
+    Num(u64),
+}
+
+/// Representation independent hash of a map
+fn map_hash(entries: &[(&str, Value)]) -> Hash {
+    let mut pairs: Vec<Vec<u8>> = entries
+        .iter()
+        .map(|(k, v)| {
+            let value: Hash = match v {
+                Value::Str(s) => Sha256::digest(s.as_bytes()).into(),
+                Value::Num(n) => Sha256::digest(leb128(*n)).into(),
+            };
+            let mut pair = Sha256::digest(k.as_bytes()).to_vec();
+            pair.extend_from_slice(&value);
+            pair
+        })
+        .collect();
+    pairs.sort();
+    Sha256::digest(pairs.concat()).into()
+}
+
+fn leb128(mut n: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return out;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Hash of the certified parts of a response: the lower-cased headers and
+/// status code, then the body
+pub fn response_hash(status: u16, headers: &[(String, String)], body: &[u8]) -> Hash {
+    let names: Vec<String> = headers.iter().map(|(k, _)| k.to_lowercase()).collect();
+    let mut entries: Vec<(&str, Value)> = names
+        .iter()
+        .zip(headers)
+        .map(|(k, (_, v))| (k.as_str(), Value::Str(v)))
+        .collect();
+    entries.push((":ic-cert-status", Value::Num(status as u64)));
+    let mut h = Sha256::new();
+    h.update(map_hash(&entries));
+    h.update(Sha256::digest(body));
+    h.finalize().into()
+}
+
+fn cbor<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut ser = serde_cbor::Serializer::new(&mut out);
+    let _ = ser.self_describe();
+    value.serialize(&mut ser).expect("serialize cbor");
+    out
+}
+
+/// Path of `/<route>/<principal>` in the certified tree
+pub fn expr_path(route: &str, principal: Principal) -> Vec<String> {
+    vec![
+        "http_expr".into(),
+        route.into(),
+        principal.to_text(),
+        "<$>".into(),
+    ]
+}
+
+/// Headers and body of a certified response
+pub type Certified = (Vec<(String, String)>, Vec<u8>);
+
+/// Certified response of `/<route>/<principal>` when a fresh entry is
+/// cached; anything else has to be served by `http_request_update`
+pub fn response(route: &str, principal: Principal, now: u64) -> Option<Certified> {
+    if !ROUTES.contains(&route) {
+        return None;
+    }
+    let (holdings, summary, fetched_at) = cache::get().get(&principal)?;
+    if !cache::is_fresh(fetched_at, now) {
+        return None;
+    }
+    let legacy: Vec<Holding> = holdings.iter().map(Holding::from).collect();
+    let body = body(route, &legacy, &summary);
+    let mut headers = headers();
+    headers.push((
+        "IC-Certificate".into(),
+        format!(
+            "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
+            STANDARD.encode(cert::data_certificate()),
+            STANDARD.encode(cert::http_witness(route, principal)),
+            STANDARD.encode(cbor(&expr_path(route, principal))),
+        ),
+    ));
+    Some((headers, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bx_core::{HoldingKind, TypedHolding};
+    use ic_agent::hash_tree::{HashTree as Witness, LookupResult};
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> &'a str {
+        &headers.iter().find(|(k, _)| k == name).unwrap().1
+    }
+
+    fn field(header: &str, name: &str) -> Vec<u8> {
+        let start = header.find(&format!("{name}=:")).unwrap() + name.len() + 2;
+        let end = start + header[start..].find(':').unwrap();
+        STANDARD.decode(&header[start..end]).unwrap()
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn cached_response_is_certified() {
+        let p = Principal::from_slice(&[0xB7, 1]);
+        let holding = TypedHolding::new("ledger", "ICP", 5u64.into(), 0, HoldingKind::Liquid);
+        cache::get().insert(p, (vec![holding], Vec::new(), crate::utils::now()));
+
+        let (headers, body) = response("holdings", p, crate::utils::now()).unwrap();
+        assert!(std::str::from_utf8(&body).unwrap().contains("ICP"));
+        let cert_header = header(&headers, "IC-Certificate");
+        let tree: Witness<Vec<u8>> = serde_cbor::from_slice(&field(cert_header, "tree")).unwrap();
+        assert_eq!(tree.digest(), cert::root_hash());
+        let path: Vec<String> = serde_cbor::from_slice(&field(cert_header, "expr_path")).unwrap();
+        assert_eq!(path, expr_path("holdings", p));
+
+        let certified: Vec<(String, String)> = headers
+            .iter()
+            .filter(|(k, _)| k != "IC-Certificate")
+            .cloned()
+            .collect();
+        let mut full: Vec<Vec<u8>> = path.iter().map(|s| s.as_bytes().to_vec()).collect();
+        full.push(Sha256::digest(EXPRESSION.as_bytes()).to_vec());
+        full.push(Vec::new());
+        full.push(response_hash(200, &certified, &body).to_vec());
+        assert!(matches!(tree.lookup_path(&full), LookupResult::Found(b"")));
+
+        // stale or missing entries are left to the update call
+        assert!(response("holdings", p, u64::MAX).is_none());
+        assert!(response("metrics", p, crate::utils::now()).is_none());
+        cache::get().remove(&p);
+        assert!(response("summary", p, crate::utils::now()).is_none());
+    }
+
+    #[test]
+    fn status_is_part_of_the_hash() {
+        let ok = response_hash(200, &headers(), b"[]");
+        assert_ne!(ok, response_hash(404, &headers(), b"[]"));
+        assert_ne!(ok, response_hash(200, &headers()[..1], b"[]"));
+        assert_eq!(leb128(300), vec![0xac, 0x02]);
+    }
+}
@@ -1,45 +1,35 @@
 use crate::error::FetchError;
-#[cfg(not(target_arch = "wasm32"))]
-use crate::utils::format_amount;
-use bx_core::Holding;
-#[cfg(not(target_arch = "wasm32"))]
-use candid::Nat;
-use candid::Principal;
-#[cfg(all(any(not(test), feature = "live-test"), not(target_arch = "wasm32")))]
+use crate::memory::{self, Cached, Candid, Memory};
+use crate::scheduler;
+use crate::sources::{self, Fetched};
+use async_trait::async_trait;
+use bx_core::{HoldingKind, TypedHolding};
+use candid::types::value::IDLValue;
+#[cfg(any(not(test), feature = "live-test"))]
 use candid::{Decode, Encode};
-#[cfg(not(target_arch = "wasm32"))]
-use dashmap::DashMap;
-#[cfg(not(target_arch = "wasm32"))]
-use futures::future::join_all;
+use candid::{Nat, Principal};
+use ic_stable_structures::StableBTreeMap;
 use once_cell::sync::Lazy;
 use serde::Deserialize;
-#[cfg(not(target_arch = "wasm32"))]
 use sha2::{Digest, Sha256};
-#[cfg(not(target_arch = "wasm32"))]
+use std::cell::RefCell;
 use std::future::Future;
-#[cfg(not(target_arch = "wasm32"))]
 use std::num::NonZeroU8;
 
 // Metadata for each ledger is cached with an expiry and a stable hash.
 // When a hash mismatch is detected, the entry is replaced so callers
 // always see the latest token symbol, decimals, and transfer fee.
 
-#[cfg(all(any(not(test), feature = "live-test"), not(target_arch = "wasm32")))]
-use ic_agent::Agent;
-#[cfg(all(test, not(feature = "live-test"), not(target_arch = "wasm32")))]
-#[derive(Clone, Default)]
-struct Agent;
-
-#[cfg(all(test, not(feature = "live-test"), not(target_arch = "wasm32")))]
+#[cfg(all(test, not(feature = "live-test")))]
 use std::sync::Mutex;
 
-#[cfg(all(any(not(test), feature = "live-test"), not(target_arch = "wasm32")))]
+#[cfg(any(not(test), feature = "live-test"))]
 fn now() -> u64 {
     crate::utils::now()
 }
-#[cfg(all(test, not(feature = "live-test"), not(target_arch = "wasm32")))]
+#[cfg(all(test, not(feature = "live-test")))]
 static TEST_NOW: Lazy<Mutex<u64>> = Lazy::new(|| Mutex::new(0));
-#[cfg(all(test, not(feature = "live-test"), not(target_arch = "wasm32")))]
+#[cfg(all(test, not(feature = "live-test")))]
 fn now() -> u64 {
     *TEST_NOW.lock().unwrap()
 }
@@ -100,16 +90,11 @@ pub static LEDGERS: Lazy<Vec<Principal>> = Lazy::new(load_ledgers);
 #[cfg(not(target_arch = "wasm32"))]
 pub static LEDGERS: Lazy<Vec<Principal>> = Lazy::new(load_ledgers);
 
-/// Duration that cached metadata remains valid (default 24h)
-#[cfg(not(target_arch = "wasm32"))]
-static META_TTL_NS: Lazy<u64> = Lazy::new(|| {
-    option_env!("META_TTL_SECS")
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(crate::utils::DAY_SECS)
-        * 1_000_000_000u64
-});
+/// Duration that cached metadata remains valid, see `Config::meta_ttl_secs`
+fn meta_ttl_ns() -> u64 {
+    crate::config::get().meta_ttl_secs * 1_000_000_000
+}
 
-#[cfg(not(target_arch = "wasm32"))]
 static LEDGER_RETRY_LIMIT: Lazy<NonZeroU8> = Lazy::new(|| {
     NonZeroU8::new(
         option_env!("LEDGER_RETRY_LIMIT")
@@ -119,26 +104,28 @@ static LEDGER_RETRY_LIMIT: Lazy<NonZeroU8> = Lazy::new(|| {
     .unwrap()
 });
 
-#[cfg(not(target_arch = "wasm32"))]
-#[derive(Clone)]
+#[derive(Clone, candid::CandidType, serde::Deserialize)]
 struct Meta {
     symbol: String,
     decimals: u8,
     fee: u64,
-    hash: [u8; 32],
+    /// SHA-256 of the encoded `icrc1_metadata` response
+    hash: Vec<u8>,
     expires: u64,
-    last_used: u64,
 }
-#[cfg(not(target_arch = "wasm32"))]
-static META_CACHE: Lazy<DashMap<Principal, Meta>> = Lazy::new(DashMap::new);
 
-#[cfg(not(target_arch = "wasm32"))]
+thread_local! {
+    static META_CACHE: RefCell<StableBTreeMap<Candid<Principal>, Cached<Meta>, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::LEDGER_METADATA)));
+}
+
 static MAX_META_ENTRIES: Lazy<usize> = Lazy::new(|| {
     option_env!("META_CACHE_SIZE")
         .and_then(|v| v.parse::<usize>().ok())
         .unwrap_or(1024)
 });
 
+/// Metadata as serialised by releases that copied state through upgrades
 #[derive(candid::CandidType, serde::Deserialize, serde::Serialize)]
 pub struct StableMeta {
     cid: Principal,
@@ -150,96 +137,123 @@ pub struct StableMeta {
     last_used: u64,
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-pub fn stable_save() -> Vec<StableMeta> {
-    META_CACHE
-        .iter()
-        .map(|e| StableMeta {
-            cid: *e.key(),
-            symbol: e.value().symbol.clone(),
-            decimals: e.value().decimals,
-            fee: e.value().fee,
-            hash: e.value().hash.to_vec(),
-            expires: e.value().expires,
-            last_used: e.value().last_used,
-        })
-        .collect()
-}
-
-#[cfg(not(target_arch = "wasm32"))]
+/// Import metadata saved by a release that serialised state on upgrade
 pub fn stable_restore(data: Vec<StableMeta>) {
-    META_CACHE.clear();
     for m in data {
-        let mut hash = [0u8; 32];
-        if m.hash.len() == 32 {
-            hash.copy_from_slice(&m.hash);
-        }
-        META_CACHE.insert(
+        set_meta(
             m.cid,
             Meta {
                 symbol: m.symbol,
                 decimals: m.decimals,
                 fee: m.fee,
-                hash,
+                hash: m.hash,
                 expires: m.expires,
-                last_used: m.last_used,
             },
         );
     }
     evict_excess();
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-fn evict_expired() {
+fn get_meta(cid: Principal) -> Option<Meta> {
+    META_CACHE.with(|c| c.borrow().get(&Candid(cid)).and_then(|m| m.0))
+}
+
+fn set_meta(cid: Principal, meta: Meta) {
+    META_CACHE.with(|c| c.borrow_mut().insert(Candid(cid), Cached(Some(meta))));
+}
+
+/// Drop expired entries and ones this release can't decode
+pub fn evict_expired() {
     let now = now();
-    META_CACHE.retain(|_, v| v.expires > now);
+    META_CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        let expired: Vec<_> = cache
+            .iter()
+            .filter(|(_, v)| v.0.as_ref().is_none_or(|m| m.expires <= now))
+            .map(|(k, _)| k)
+            .collect();
+        for k in expired {
+            cache.remove(&k);
+        }
+    });
 }
 
-#[cfg(not(target_arch = "wasm32"))]
+/// Once the cache is over `MAX_META_ENTRIES`, drop the entries closest to
+/// expiry in one pass, down to nine tenths of the limit so the next inserts
+/// don't each scan again.
 fn evict_excess() {
-    while META_CACHE.len() > *MAX_META_ENTRIES {
-        if let Some(old_key) = META_CACHE
+    META_CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        let len = cache.len() as usize;
+        if len <= *MAX_META_ENTRIES {
+            return;
+        }
+        let keep = *MAX_META_ENTRIES - *MAX_META_ENTRIES / 10;
+        let mut by_expiry: Vec<_> = cache
             .iter()
-            .min_by_key(|e| e.value().last_used)
-            .map(|e| *e.key())
-        {
-            META_CACHE.remove(&old_key);
-        } else {
-            break;
+            .map(|(k, v)| (v.0.map_or(0, |m| m.expires), k))
+            .collect();
+        by_expiry.sort_unstable_by_key(|(expires, _)| *expires);
+        for (_, k) in by_expiry.into_iter().take(len - keep) {
+            cache.remove(&k);
         }
-    }
+    });
+}
+
+/// Drop expired metadata daily; lookups ignore it until then
+#[cfg(target_arch = "wasm32")]
+pub fn schedule_eviction() {
+    use std::time::Duration;
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(crate::utils::DAY_SECS), evict_expired);
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-pub fn len() -> usize {
-    META_CACHE.len()
+pub fn schedule_eviction() {
+    use std::time::Duration;
+    tokio::spawn(async {
+        let mut timer = tokio::time::interval(Duration::from_secs(crate::utils::DAY_SECS));
+        loop {
+            timer.tick().await;
+            evict_expired();
+        }
+    });
 }
 
-#[cfg(target_arch = "wasm32")]
 pub fn len() -> usize {
-    0
+    META_CACHE.with(|c| c.borrow().len() as usize)
 }
 
-#[cfg(target_arch = "wasm32")]
-pub fn stable_save() -> Vec<StableMeta> {
-    Vec::new()
+pub fn clear_metadata() {
+    META_CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        let keys: Vec<_> = cache.iter().map(|(k, _)| k).collect();
+        for k in keys {
+            cache.remove(&k);
+        }
+    });
 }
 
-#[cfg(target_arch = "wasm32")]
-pub fn stable_restore(_: Vec<StableMeta>) {}
-
+/// Pause between retries. Canisters cannot sleep inside a call, so on wasm32
+/// retries are issued back to back.
 #[cfg(all(any(not(test), feature = "live-test"), not(target_arch = "wasm32")))]
-async fn with_retry<F, Fut, T>(mut f: F) -> Result<T, ic_agent::AgentError>
+async fn backoff(delay_ms: u64) {
+    tokio::time::sleep(std::time::Duration::from_millis(delay_ms)).await;
+}
+
+#[cfg(any(all(test, not(feature = "live-test")), target_arch = "wasm32"))]
+async fn backoff(_delay_ms: u64) {}
+
+async fn with_retry<F, Fut, T>(mut f: F) -> Result<T, FetchError>
 where
     F: FnMut() -> Fut,
-    Fut: Future<Output = Result<T, ic_agent::AgentError>>,
+    Fut: Future<Output = Result<T, FetchError>>,
 {
     let mut delay = 100u64;
     for attempt in 0..LEDGER_RETRY_LIMIT.get() {
         match f().await {
             Ok(v) => return Ok(v),
             Err(_e) if attempt < LEDGER_RETRY_LIMIT.get() - 1 => {
-                tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+                backoff(delay).await;
                 delay *= 2;
             }
             Err(e) => return Err(e),
@@ -248,13 +262,13 @@ where
     unreachable!()
 }
 
-#[cfg(all(any(not(test), feature = "live-test"), not(target_arch = "wasm32")))]
-fn encode_items(items: &[(String, candid::types::value::IDLValue)]) -> Vec<u8> {
+#[cfg(any(not(test), feature = "live-test"))]
+fn encode_items(items: &[(String, IDLValue)]) -> Vec<u8> {
     Encode!(&items).expect("encode items")
 }
 
-#[cfg(all(test, not(feature = "live-test"), not(target_arch = "wasm32")))]
-fn encode_items(items: &[(String, candid::types::value::IDLValue)]) -> Vec<u8> {
+#[cfg(all(test, not(feature = "live-test")))]
+fn encode_items(items: &[(String, IDLValue)]) -> Vec<u8> {
     use std::fmt::Write;
     let mut s = String::new();
     for (k, v) in items {
@@ -263,111 +277,217 @@ fn encode_items(items: &[(String, candid::types::value::IDLValue)]) -> Vec<u8> {
     s.into_bytes()
 }
 
-#[cfg(all(test, not(feature = "live-test"), not(target_arch = "wasm32")))]
-async fn with_retry<F, Fut, T>(mut f: F) -> Result<T, ic_agent::AgentError>
-where
-    F: FnMut() -> Fut,
-    Fut: Future<Output = Result<T, ic_agent::AgentError>>,
-{
-    for attempt in 0..LEDGER_RETRY_LIMIT.get() {
-        match f().await {
-            Ok(v) => return Ok(v),
-            Err(e) if attempt + 1 == LEDGER_RETRY_LIMIT.get() => return Err(e),
-            Err(_) => continue,
+/// Calls needed from an ICRC-1 ledger. The fetch, retry and metadata cache
+/// logic is written against this trait so it runs unchanged natively and
+/// inside the canister.
+#[async_trait]
+trait LedgerTransport: Send + Sync {
+    async fn icrc1_metadata(&self, cid: Principal) -> Result<Vec<(String, IDLValue)>, FetchError>;
+    async fn icrc1_balance_of(
+        &self,
+        cid: Principal,
+        owner: Principal,
+        subaccount: Option<Vec<u8>>,
+    ) -> Result<Nat, FetchError>;
+    /// Legacy ICP ledger balance lookup by account identifier
+    async fn account_balance(&self, cid: Principal, account: Vec<u8>) -> Result<u64, FetchError>;
+}
+
+/// Live transport built on `utils::call_query`: `ic_agent` natively and
+/// `ic_cdk::api::call` on wasm32.
+#[cfg(any(not(test), feature = "live-test"))]
+struct IcTransport;
+
+#[cfg(any(not(test), feature = "live-test"))]
+#[async_trait]
+impl LedgerTransport for IcTransport {
+    async fn icrc1_metadata(&self, cid: Principal) -> Result<Vec<(String, IDLValue)>, FetchError> {
+        let arg = Encode!().map_err(|_| FetchError::InvalidResponse)?;
+        let bytes = crate::utils::call_query(cid, "icrc1_metadata", arg).await?;
+        Decode!(&bytes, Vec<(String, IDLValue)>).map_err(|_| FetchError::InvalidResponse)
+    }
+
+    async fn icrc1_balance_of(
+        &self,
+        cid: Principal,
+        owner: Principal,
+        subaccount: Option<Vec<u8>>,
+    ) -> Result<Nat, FetchError> {
+        #[derive(candid::CandidType)]
+        struct Account {
+            owner: Principal,
+            subaccount: Option<serde_bytes::ByteBuf>,
         }
+        let arg = Encode!(&Account {
+            owner,
+            subaccount: subaccount.map(serde_bytes::ByteBuf::from)
+        })
+        .map_err(|_| FetchError::InvalidResponse)?;
+        let bytes = crate::utils::call_query(cid, "icrc1_balance_of", arg).await?;
+        Decode!(&bytes, Nat).map_err(|_| FetchError::InvalidResponse)
     }
-    unreachable!()
-}
 
-#[cfg(all(any(not(test), feature = "live-test"), not(target_arch = "wasm32")))]
-async fn get_agent() -> Agent {
-    crate::utils::get_agent().await
+    async fn account_balance(&self, cid: Principal, account: Vec<u8>) -> Result<u64, FetchError> {
+        #[derive(candid::CandidType)]
+        struct AccountBalanceArgs {
+            account: serde_bytes::ByteBuf,
+        }
+        #[derive(candid::CandidType, Deserialize)]
+        struct Tokens {
+            e8s: u64,
+        }
+        let arg = Encode!(&AccountBalanceArgs {
+            account: serde_bytes::ByteBuf::from(account)
+        })
+        .map_err(|_| FetchError::InvalidResponse)?;
+        let bytes = crate::utils::call_query(cid, "account_balance", arg).await?;
+        Decode!(&bytes, Tokens)
+            .map(|t| t.e8s)
+            .map_err(|_| FetchError::InvalidResponse)
+    }
 }
 
-#[cfg(all(test, not(feature = "live-test"), not(target_arch = "wasm32")))]
-async fn get_agent() -> Agent {
-    Agent::default()
+#[cfg(any(not(test), feature = "live-test"))]
+fn transport() -> IcTransport {
+    IcTransport
 }
 
-#[cfg(all(any(not(test), feature = "live-test"), not(target_arch = "wasm32")))]
-async fn icrc1_metadata(
-    agent: &Agent,
-    canister_id: Principal,
-) -> Result<Vec<(String, candid::types::value::IDLValue)>, ic_agent::AgentError> {
-    let arg = candid::Encode!().expect("encode args");
-    let bytes = agent
-        .query(&canister_id, "icrc1_metadata")
-        .with_arg(arg)
-        .call()
-        .await?;
-    let res: Vec<(String, candid::types::value::IDLValue)> =
-        candid::Decode!(&bytes, Vec<(String, candid::types::value::IDLValue)>)
-            .expect("decode metadata");
-    Ok(res)
-}
-
-#[cfg(all(test, not(feature = "live-test"), not(target_arch = "wasm32")))]
-static MOCK_METADATA: Lazy<Mutex<Result<Vec<(String, candid::types::value::IDLValue)>, String>>> =
+#[cfg(all(test, not(feature = "live-test")))]
+static MOCK_METADATA: Lazy<Mutex<Result<Vec<(String, IDLValue)>, String>>> =
     Lazy::new(|| Mutex::new(Ok(vec![])));
 
-#[cfg(all(test, not(feature = "live-test"), not(target_arch = "wasm32")))]
-async fn icrc1_metadata(
-    _agent: &Agent,
-    _canister_id: Principal,
-) -> Result<Vec<(String, candid::types::value::IDLValue)>, ic_agent::AgentError> {
-    match MOCK_METADATA.lock().unwrap().clone() {
-        Ok(v) => Ok(v),
-        Err(e) => Err(ic_agent::AgentError::MessageError(e)),
+#[cfg(all(test, not(feature = "live-test")))]
+static MOCK_BALANCE: Lazy<Mutex<Result<Nat, String>>> =
+    Lazy::new(|| Mutex::new(Ok(Nat::from(0u32))));
+
+#[cfg(all(test, not(feature = "live-test")))]
+struct MockTransport;
+
+#[cfg(all(test, not(feature = "live-test")))]
+#[async_trait]
+impl LedgerTransport for MockTransport {
+    async fn icrc1_metadata(&self, _cid: Principal) -> Result<Vec<(String, IDLValue)>, FetchError> {
+        MOCK_METADATA
+            .lock()
+            .unwrap()
+            .clone()
+            .map_err(FetchError::Network)
     }
-}
 
-#[cfg(all(any(not(test), feature = "live-test"), not(target_arch = "wasm32")))]
-async fn icrc1_balance_of(
-    agent: &Agent,
-    canister_id: Principal,
-    owner: Principal,
-) -> Result<Nat, ic_agent::AgentError> {
-    #[derive(candid::CandidType)]
-    struct Account {
-        owner: Principal,
-        subaccount: Option<Vec<u8>>,
+    async fn icrc1_balance_of(
+        &self,
+        _cid: Principal,
+        _owner: Principal,
+        _subaccount: Option<Vec<u8>>,
+    ) -> Result<Nat, FetchError> {
+        MOCK_BALANCE
+            .lock()
+            .unwrap()
+            .clone()
+            .map_err(FetchError::Network)
+    }
+
+    async fn account_balance(&self, _cid: Principal, _account: Vec<u8>) -> Result<u64, FetchError> {
+        use num_traits::ToPrimitive;
+        MOCK_BALANCE
+            .lock()
+            .unwrap()
+            .clone()
+            .map(|n| n.0.to_u64().unwrap_or(u64::MAX))
+            .map_err(FetchError::Network)
     }
-    let arg = candid::Encode!(&Account {
-        owner,
-        subaccount: None
-    })
-    .expect("encode args");
-    let bytes = agent
-        .query(&canister_id, "icrc1_balance_of")
-        .with_arg(arg)
-        .call()
-        .await?;
-    let res: Nat = candid::Decode!(&bytes, Nat).expect("decode balance");
-    Ok(res)
 }
 
-#[cfg(all(test, not(feature = "live-test"), not(target_arch = "wasm32")))]
-static MOCK_BALANCE: Lazy<Mutex<Result<Nat, String>>> =
-    Lazy::new(|| Mutex::new(Ok(Nat::from(0u32))));
+#[cfg(all(test, not(feature = "live-test")))]
+fn transport() -> MockTransport {
+    MockTransport
+}
+
+/// Mainnet ICP ledger used when `ICP_LEDGER` is not set
+pub const DEFAULT_ICP_LEDGER: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+const ICP_DECIMALS: u8 = 8;
 
-#[cfg(all(test, not(feature = "live-test"), not(target_arch = "wasm32")))]
-async fn icrc1_balance_of(
-    _agent: &Agent,
-    _canister_id: Principal,
-    _owner: Principal,
-) -> Result<Nat, ic_agent::AgentError> {
-    match MOCK_BALANCE.lock().unwrap().clone() {
-        Ok(v) => Ok(v),
-        Err(e) => Err(ic_agent::AgentError::MessageError(e)),
+#[cfg(not(target_arch = "wasm32"))]
+pub fn icp_ledger() -> Principal {
+    std::env::var("ICP_LEDGER")
+        .ok()
+        .and_then(|s| Principal::from_text(s).ok())
+        .unwrap_or_else(|| Principal::from_text(DEFAULT_ICP_LEDGER).expect("icp ledger id"))
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn icp_ledger() -> Principal {
+    option_env!("ICP_LEDGER")
+        .and_then(|s| Principal::from_text(s).ok())
+        .unwrap_or_else(|| Principal::from_text(DEFAULT_ICP_LEDGER).expect("icp ledger id"))
+}
+
+/// Hex ICP account identifier: CRC32 of `sha224("\x0Aaccount-id" || owner ||
+/// subaccount)` followed by the hash, with the zero subaccount by default.
+pub fn account_identifier(owner: &Principal, subaccount: Option<&[u8]>) -> String {
+    use sha2::Sha224;
+    let mut sub = [0u8; 32];
+    if let Some(s) = subaccount {
+        let n = s.len().min(32);
+        sub[..n].copy_from_slice(&s[..n]);
+    }
+    let mut hasher = Sha224::new();
+    hasher.update(b"\x0Aaccount-id");
+    hasher.update(owner.as_slice());
+    hasher.update(sub);
+    let hash = hasher.finalize();
+    let crc = crc32fast::hash(&hash).to_be_bytes();
+    let mut out = String::with_capacity(64);
+    out.push_str(&subaccount_hex(&crc));
+    out.push_str(&subaccount_hex(&hash));
+    out
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
     }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-pub async fn fetch_filtered(
+/// ICP balance of a linked account identifier, read with the legacy
+/// `account_balance` method of the ICP ledger.
+pub async fn fetch_account_id(account_id: &str) -> Result<TypedHolding, FetchError> {
+    let account = decode_hex(account_id)
+        .filter(|b| b.len() == 32)
+        .ok_or_else(|| FetchError::InvalidConfig(format!("account id {account_id}")))?;
+    let ledger = icp_ledger();
+    let transport = transport();
+    let e8s = with_retry(|| transport.account_balance(ledger, account.clone())).await?;
+    Ok(TypedHolding {
+        ledger: Some(ledger),
+        ..TypedHolding::new(
+            "ledger",
+            "ICP",
+            e8s.into(),
+            ICP_DECIMALS,
+            HoldingKind::Liquid,
+        )
+    })
+}
+
+/// Hex form of a subaccount as exposed on `TypedHolding::subaccount`
+pub fn subaccount_hex(sub: &[u8]) -> String {
+    sub.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Query the default account of `principal` plus every tracked subaccount on
+/// each selected ledger, reporting each ledger as its own source. All-zero
+/// subaccounts alias the default account and are skipped.
+pub async fn fetch_detailed(
     principal: Principal,
     list: Option<&std::collections::HashSet<Principal>>,
-) -> Result<Vec<Holding>, FetchError> {
-    let agent = get_agent().await;
+    subaccounts: &[Vec<u8>],
+) -> Vec<Fetched> {
+    let transport = transport();
     let mut ids: Vec<Principal> = match list {
         Some(set) => LEDGERS
             .iter()
@@ -377,82 +497,85 @@ pub async fn fetch_filtered(
         None => LEDGERS.iter().cloned().collect(),
     };
     ids.sort();
-    let futures = ids.into_iter().map(|cid| {
-        let agent = agent.clone();
-        async move {
-            let (symbol, decimals, _) = fetch_metadata(&agent, cid).await?;
-            let nat = with_retry(|| icrc1_balance_of(&agent, cid, principal))
-                .await
-                .map_err(FetchError::from)?;
-            Ok::<Holding, FetchError>(Holding {
-                source: "ledger".into(),
-                token: symbol,
-                amount: format_amount(nat, decimals),
-                status: "liquid".into(),
-            })
-        }
-    });
-    let results = join_all(futures).await;
-    let mut holdings = Vec::with_capacity(LEDGERS.len());
-    for r in results {
-        match r {
-            Ok(h) => holdings.push(h),
-            Err(e) => return Err(e),
+    let mut accounts: Vec<Option<Vec<u8>>> = vec![None];
+    for sub in subaccounts {
+        if sub.iter().all(|b| *b == 0) || accounts.contains(&Some(sub.clone())) {
+            continue;
         }
+        accounts.push(Some(sub.clone()));
     }
-    Ok(holdings)
-}
-
-#[cfg(not(target_arch = "wasm32"))]
-pub async fn fetch(principal: Principal) -> Result<Vec<Holding>, FetchError> {
-    fetch_filtered(principal, None).await
+    let futures = ids.into_iter().map(|cid| {
+        let transport = &transport;
+        let accounts = &accounts;
+        sources::timed("ledger", cid.to_text(), Some(principal), async move {
+            let (symbol, decimals, _) = fetch_metadata(transport, cid).await?;
+            let balances = scheduler::join_all_to(
+                cid,
+                accounts.iter().map(|sub| {
+                    with_retry(move || transport.icrc1_balance_of(cid, principal, sub.clone()))
+                }),
+            )
+            .await;
+            let mut out = Vec::with_capacity(accounts.len());
+            for (sub, nat) in accounts.iter().zip(balances) {
+                out.push(TypedHolding {
+                    ledger: Some(cid),
+                    subaccount: sub.as_deref().map(subaccount_hex),
+                    ..TypedHolding::new(
+                        "ledger",
+                        symbol.clone(),
+                        nat?,
+                        decimals,
+                        HoldingKind::Liquid,
+                    )
+                });
+            }
+            Ok::<Vec<TypedHolding>, FetchError>(out)
+        })
+    });
+    scheduler::join_all(futures).await
 }
 
-#[cfg(target_arch = "wasm32")]
+/// Like [`fetch_detailed`] but fails when any ledger does
 pub async fn fetch_filtered(
-    _principal: Principal,
-    _list: Option<&std::collections::HashSet<Principal>>,
-) -> Result<Vec<Holding>, FetchError> {
-    Ok(Vec::new())
+    principal: Principal,
+    list: Option<&std::collections::HashSet<Principal>>,
+    subaccounts: &[Vec<u8>],
+) -> Result<Vec<TypedHolding>, FetchError> {
+    sources::all_or_error(fetch_detailed(principal, list, subaccounts).await)
 }
 
-#[cfg(target_arch = "wasm32")]
-pub async fn fetch(_principal: Principal) -> Result<Vec<Holding>, FetchError> {
-    fetch_filtered(_principal, None).await
+pub async fn fetch(principal: Principal) -> Result<Vec<TypedHolding>, FetchError> {
+    fetch_filtered(principal, None, &[]).await
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-async fn fetch_metadata(agent: &Agent, cid: Principal) -> Result<(String, u8, u64), FetchError> {
-    evict_expired();
-    if let Some(mut meta) = META_CACHE.get_mut(&cid) {
+async fn fetch_metadata<T: LedgerTransport>(
+    transport: &T,
+    cid: Principal,
+) -> Result<(String, u8, u64), FetchError> {
+    if let Some(meta) = get_meta(cid) {
         if meta.expires > now() {
-            meta.last_used = now();
-            return Ok((meta.symbol.clone(), meta.decimals, meta.fee));
+            return Ok((meta.symbol, meta.decimals, meta.fee));
         }
     }
-    let items = with_retry(|| icrc1_metadata(agent, cid))
-        .await
-        .map_err(FetchError::from)?;
+    let items = with_retry(|| transport.icrc1_metadata(cid)).await?;
     let encoded = encode_items(&items);
-    let hash: [u8; 32] = Sha256::digest(&encoded).into();
-    if let Some(meta) = META_CACHE.get(&cid) {
+    let hash = Sha256::digest(&encoded).to_vec();
+    if let Some(mut meta) = get_meta(cid) {
         if meta.hash == hash {
-            let mut updated = meta.clone();
-            updated.hash = hash;
-            updated.expires = now() + *META_TTL_NS;
-            updated.last_used = now();
-            META_CACHE.insert(cid, updated);
-            return Ok((meta.symbol.clone(), meta.decimals, meta.fee));
+            meta.expires = now() + meta_ttl_ns();
+            let out = (meta.symbol.clone(), meta.decimals, meta.fee);
+            set_meta(cid, meta);
+            return Ok(out);
         }
     }
     let mut symbol = String::new();
     let mut decimals: u8 = 0;
     let mut fee: u64 = 0;
     for (k, v) in items {
-        use candid::types::value::IDLValue::Text;
         match k.as_str() {
             "icrc1:symbol" => {
-                if let Text(s) = v {
+                if let IDLValue::Text(s) = v {
                     symbol = s;
                 }
             }
@@ -469,43 +592,41 @@ async fn fetch_metadata(agent: &Agent, cid: Principal) -> Result<(String, u8, u6
             _ => {}
         }
     }
-    META_CACHE.insert(
+    set_meta(
         cid,
         Meta {
             symbol: symbol.clone(),
             decimals,
             fee,
             hash,
-            expires: now() + *META_TTL_NS,
-            last_used: now(),
+            expires: now() + meta_ttl_ns(),
         },
     );
     evict_excess();
     Ok((symbol, decimals, fee))
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-pub async fn warm_metadata(cid: Principal) {
-    let agent = get_agent().await;
-    let _ = fetch_metadata(&agent, cid).await;
+/// Symbol, decimals and transfer fee of the ledger `cid`, cached like the
+/// metadata of configured ledgers
+pub async fn metadata(cid: Principal) -> Result<(String, u8, u64), FetchError> {
+    fetch_metadata(&transport(), cid).await
 }
 
-#[cfg(target_arch = "wasm32")]
-pub async fn warm_metadata(_cid: Principal) {}
+pub async fn warm_metadata(cid: Principal) {
+    let _ = fetch_metadata(&transport(), cid).await;
+}
 
-#[cfg(all(test, not(feature = "live-test"), not(target_arch = "wasm32")))]
-pub(super) fn set_mock_metadata(
-    resp: Result<Vec<(String, candid::types::value::IDLValue)>, String>,
-) {
+#[cfg(all(test, not(feature = "live-test")))]
+pub(super) fn set_mock_metadata(resp: Result<Vec<(String, IDLValue)>, String>) {
     *MOCK_METADATA.lock().unwrap() = resp;
 }
 
-#[cfg(all(test, not(feature = "live-test"), not(target_arch = "wasm32")))]
+#[cfg(all(test, not(feature = "live-test")))]
 pub(super) fn set_mock_balance(resp: Result<Nat, String>) {
     *MOCK_BALANCE.lock().unwrap() = resp;
 }
 
-#[cfg(all(test, not(feature = "live-test"), not(target_arch = "wasm32")))]
+#[cfg(all(test, not(feature = "live-test")))]
 pub(super) fn set_now(value: u64) {
     *TEST_NOW.lock().unwrap() = value;
 }
@@ -513,7 +634,7 @@ pub(super) fn set_now(value: u64) {
 #[cfg(all(test, not(feature = "live-test")))]
 mod tests {
     use super::*;
-    use candid::types::value::IDLValue;
+    use crate::utils::format_amount;
 
     #[test]
     fn format_amount_basic() {
@@ -526,7 +647,7 @@ mod tests {
     #[serial_test::serial]
     async fn metadata_caching_and_expiry() {
         let cid = Principal::from_text("aaaaa-aa").unwrap();
-        let agent = get_agent().await;
+        let transport = transport();
 
         set_now(1);
         set_mock_metadata(Ok(vec![
@@ -534,8 +655,8 @@ mod tests {
             ("icrc1:decimals".into(), IDLValue::Nat8(2)),
             ("icrc1:fee".into(), IDLValue::Nat(Nat::from(10u64))),
         ]));
-        META_CACHE.clear();
-        let v1 = fetch_metadata(&agent, cid).await.unwrap();
+        clear_metadata();
+        let v1 = fetch_metadata(&transport, cid).await.unwrap();
         assert_eq!(v1, ("AAA".into(), 2, 10));
 
         set_now(2);
@@ -544,25 +665,29 @@ mod tests {
             ("icrc1:decimals".into(), IDLValue::Nat8(3)),
             ("icrc1:fee".into(), IDLValue::Nat(Nat::from(20u64))),
         ]));
-        let v2 = fetch_metadata(&agent, cid).await.unwrap();
+        let v2 = fetch_metadata(&transport, cid).await.unwrap();
         assert_eq!(v2, ("AAA".into(), 2, 10));
-        assert_eq!(META_CACHE.get(&cid).unwrap().symbol, "AAA");
+        assert_eq!(get_meta(cid).unwrap().symbol, "AAA");
 
-        set_now(*META_TTL_NS + 3);
-        let v3 = fetch_metadata(&agent, cid).await.unwrap();
+        set_now(meta_ttl_ns() + 3);
+        let v3 = fetch_metadata(&transport, cid).await.unwrap();
         assert_eq!(v3, ("BBB".into(), 3, 20));
-        assert_eq!(META_CACHE.get(&cid).unwrap().symbol, "BBB");
+        assert_eq!(get_meta(cid).unwrap().symbol, "BBB");
+
+        set_now(2 * meta_ttl_ns() + 4);
+        evict_expired();
+        assert!(get_meta(cid).is_none());
     }
 
     #[tokio::test(flavor = "current_thread")]
     #[serial_test::serial]
     async fn metadata_error() {
         let cid = Principal::from_text("aaaaa-aa").unwrap();
-        let agent = get_agent().await;
+        let transport = transport();
         set_now(0);
         set_mock_metadata(Err("fail".into()));
-        META_CACHE.clear();
-        let err = fetch_metadata(&agent, cid).await.unwrap_err();
+        clear_metadata();
+        let err = fetch_metadata(&transport, cid).await.unwrap_err();
         assert!(matches!(err, FetchError::Network(_)));
     }
 
@@ -574,7 +699,7 @@ mod tests {
             attempts += 1;
             async move {
                 if attempts < 3 {
-                    Err(ic_agent::AgentError::MessageError("no".into()))
+                    Err(FetchError::Network("no".into()))
                 } else {
                     Ok(5)
                 }
@@ -596,13 +721,14 @@ mod tests {
             ("icrc1:decimals".into(), IDLValue::Nat8(2)),
         ]));
         set_mock_balance(Ok(Nat::from(1234u64)));
-        META_CACHE.clear();
+        clear_metadata();
         let principal = Principal::from_text("aaaaa-aa").unwrap();
         let res = fetch(principal).await.unwrap();
         assert_eq!(res.len(), 1);
-        assert_eq!(res[0].token, "AAA");
-        assert_eq!(res[0].amount, "12.34");
-        assert_eq!(res[0].status, "liquid");
+        assert_eq!(res[0].symbol, "AAA");
+        assert_eq!(res[0].formatted_amount(), "12.34");
+        assert_eq!(res[0].kind, HoldingKind::Liquid);
+        assert_eq!(res[0].ledger, LEDGERS.first().cloned());
     }
 
     #[tokio::test(flavor = "current_thread")]
@@ -615,7 +741,7 @@ mod tests {
             ("icrc1:decimals".into(), IDLValue::Nat8(2)),
         ]));
         set_mock_balance(Err("oops".into()));
-        META_CACHE.clear();
+        clear_metadata();
         let principal = Principal::from_text("aaaaa-aa").unwrap();
         let err = fetch(principal).await.unwrap_err();
         assert!(matches!(err, FetchError::Network(_)));
@@ -628,9 +754,59 @@ mod tests {
         once_cell::sync::Lazy::force(&LEDGERS);
         set_mock_metadata(Err("bad".into()));
         set_mock_balance(Ok(Nat::from(10u64)));
-        META_CACHE.clear();
+        clear_metadata();
         let principal = Principal::from_text("aaaaa-aa").unwrap();
         let err = fetch(principal).await.unwrap_err();
         assert!(matches!(err, FetchError::Network(_)));
+        let detailed = fetch_detailed(principal, None, &[]).await;
+        assert_eq!(detailed.len(), 1);
+        assert_eq!(detailed[0].status.kind, "ledger");
+        assert!(!detailed[0].status.ok());
+        assert!(detailed[0].holdings.is_empty());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[serial_test::serial]
+    async fn fetch_tags_subaccounts() {
+        std::env::set_var("LEDGERS_CONFIG", "tests/ledgers_single.toml");
+        once_cell::sync::Lazy::force(&LEDGERS);
+        set_mock_metadata(Ok(vec![
+            ("icrc1:symbol".into(), IDLValue::Text("AAA".into())),
+            ("icrc1:decimals".into(), IDLValue::Nat8(2)),
+        ]));
+        set_mock_balance(Ok(Nat::from(500u64)));
+        clear_metadata();
+        let principal = Principal::from_text("aaaaa-aa").unwrap();
+        let mut sub = vec![0u8; 32];
+        sub[31] = 1;
+        let subs = vec![vec![0u8; 32], sub.clone(), sub];
+        let res = fetch_filtered(principal, None, &subs).await.unwrap();
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].subaccount, None);
+        assert_eq!(
+            res[1].subaccount.as_deref(),
+            Some(format!("{}01", "00".repeat(31)).as_str())
+        );
+        assert!(res.iter().all(|h| h.formatted_amount() == "5.00"));
+    }
+
+    #[test]
+    fn account_identifier_matches_known_vector() {
+        // anonymous principal, default subaccount
+        assert_eq!(
+            account_identifier(&Principal::anonymous(), None),
+            "1c7a48ba6a562aa9eaa2481a9049cdf0433b9738c992d698c31d8abf89cadc79"
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[serial_test::serial]
+    async fn fetch_account_id_balance() {
+        set_mock_balance(Ok(Nat::from(250_000_000u64)));
+        let id = account_identifier(&Principal::anonymous(), None);
+        let h = fetch_account_id(&id).await.unwrap();
+        assert_eq!(h.symbol, "ICP");
+        assert_eq!(h.formatted_amount(), "2.50000000");
+        assert!(fetch_account_id("zz").await.is_err());
     }
 }
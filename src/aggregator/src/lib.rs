@@ -1,44 +1,51 @@
+pub mod breaker;
 pub mod cache;
 pub mod cert;
+pub mod config;
 pub mod cycles;
 pub mod dex;
 pub mod dex_fetchers;
 pub mod error;
+pub mod graphql;
+pub mod history;
+pub mod http_cert;
 pub mod ledger_fetcher;
 pub mod logging;
 pub mod lp_cache;
+pub mod memory;
 pub mod metrics;
+pub mod migrations;
 pub mod neuron_fetcher;
 pub mod pool_registry;
+pub mod price;
+pub mod scheduler;
+pub mod single_flight;
+pub mod sources;
 pub mod user_settings;
 pub mod utils;
 pub mod warm;
 
-use crate::utils::{now, MINUTE_NS};
-use bx_core::Holding;
+use crate::single_flight::SingleFlight;
+use crate::utils::now;
+use bx_core::{Holding, TypedHolding};
 use candid::Principal;
-use lazy_static::lazy_static;
 use once_cell::sync::Lazy;
 #[cfg(feature = "claim")]
 use std::collections::{HashMap, HashSet};
 #[cfg(feature = "claim")]
 use std::sync::Mutex;
 
-static MAX_HOLDINGS: Lazy<usize> = Lazy::new(|| {
-    option_env!("MAX_HOLDINGS")
+static MAX_PORTFOLIO_PRINCIPALS: Lazy<usize> = Lazy::new(|| {
+    option_env!("MAX_PORTFOLIO_PRINCIPALS")
         .and_then(|v| v.parse::<usize>().ok())
-        .unwrap_or(500)
+        .unwrap_or(10)
+});
+static PORTFOLIO_CONCURRENCY: Lazy<usize> = Lazy::new(|| {
+    option_env!("PORTFOLIO_CONCURRENCY")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(4)
+        .max(1)
 });
-lazy_static! {
-    pub static ref CALL_PRICE: u128 = std::env::var("CALL_PRICE_CYCLES")
-        .ok()
-        .and_then(|v| v.parse::<u128>().ok())
-        .unwrap_or(0);
-    pub static ref CLAIM_PRICE: u128 = std::env::var("CLAIM_PRICE_CYCLES")
-        .ok()
-        .and_then(|v| v.parse::<u128>().ok())
-        .unwrap_or(0);
-}
 #[cfg(feature = "claim")]
 static CLAIM_WALLETS: Lazy<HashSet<Principal>> = Lazy::new(|| {
     option_env!("CLAIM_WALLETS")
@@ -73,13 +80,6 @@ static CLAIM_LIMIT_WINDOW_NS: Lazy<u64> = Lazy::new(|| {
         * 1_000_000_000u64
 });
 
-#[cfg(feature = "claim")]
-static CLAIM_DAILY_LIMIT: Lazy<u32> = Lazy::new(|| {
-    option_env!("CLAIM_DAILY_LIMIT")
-        .and_then(|v| v.parse::<u32>().ok())
-        .unwrap_or(5)
-});
-
 #[cfg(feature = "claim")]
 static CLAIM_MAX_TOTAL: Lazy<u64> = Lazy::new(|| {
     option_env!("CLAIM_MAX_TOTAL")
@@ -98,14 +98,6 @@ static MAX_CLAIM_PER_CALL: Lazy<usize> = Lazy::new(|| {
 static CLAIM_COUNTS: Lazy<Mutex<HashMap<Principal, (u32, u64)>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
-#[cfg(feature = "claim")]
-static CLAIM_COOLDOWN_NS: Lazy<u64> = Lazy::new(|| {
-    option_env!("CLAIM_COOLDOWN_SECS")
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(60)
-        * 1_000_000_000u64
-});
-
 #[cfg(feature = "claim")]
 static CLAIM_COOLDOWN: Lazy<Mutex<HashMap<Principal, u64>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
@@ -120,9 +112,38 @@ static CLAIM_ADAPTER_TIMEOUT_SECS: Lazy<std::sync::atomic::AtomicU64> = Lazy::ne
     )
 });
 
+/// How long a holdings fetch counts as in flight. A fetch that traps never
+/// clears its marker, so after this the principal may be fetched again.
+const FETCH_LEASE_NS: u64 = 5 * utils::MINUTE_NS;
+
+/// Running holdings fetches keyed by principal and the filters applied; the
+/// settings-driven fetch uses an empty filter key.
+static HOLDINGS_FLIGHT: Lazy<SingleFlight<(Principal, String), Result<cache::Entry, String>>> =
+    Lazy::new(|| SingleFlight::new(FETCH_LEASE_NS));
+type Filtered = (Vec<TypedHolding>, Vec<sources::SourceStatus>);
+static FILTERED_FLIGHT: Lazy<SingleFlight<(Principal, String), Filtered>> =
+    Lazy::new(|| SingleFlight::new(FETCH_LEASE_NS));
+
+/// Recalculate and cache the holdings of `principal`. While another request
+/// is already fetching them this waits for that fetch and returns its result.
+async fn fetch_holdings(principal: Principal, now: u64) -> Result<CachedHoldings, String> {
+    let fetch = async move {
+        let (holdings, summary) = calculate_holdings(principal)
+            .await
+            .map_err(|e| e.to_string())?;
+        let entry = (holdings, summary, now);
+        cache::get().insert(principal, entry.clone());
+        Ok(entry)
+    };
+    let entry = HOLDINGS_FLIGHT
+        .run((principal, String::new()), utils::now, fetch)
+        .await?;
+    Ok(CachedHoldings::new(entry, false))
+}
+
 async fn calculate_holdings(
     principal: Principal,
-) -> Result<(Vec<Holding>, Vec<HoldingSummary>), rust_decimal::Error> {
+) -> Result<(Vec<TypedHolding>, Vec<HoldingSummary>), rust_decimal::Error> {
     let settings = user_settings::get(&principal).unwrap_or_default();
     use std::collections::HashSet;
     let ledger_set: HashSet<Principal> = settings
@@ -141,25 +162,67 @@ async fn calculate_holdings(
     } else {
         Some(&dex_set)
     };
-    let (ledger, neuron, dex) = futures::join!(
-        ledger_fetcher::fetch_filtered(principal, ledger_filter),
-        neuron_fetcher::fetch(principal),
-        dex_fetchers::fetch_filtered(principal, dex_filter)
-    );
-
-    let capacity =
-        ledger.as_ref().map_or(0, |v| v.len()) + neuron.len() + dex.as_ref().map_or(0, |v| v.len());
-    let mut holdings = Vec::with_capacity(capacity);
-    holdings.extend(ledger.unwrap_or_default());
-    holdings.extend(neuron);
-    holdings.extend(dex.unwrap_or_default());
-    if holdings.len() > *MAX_HOLDINGS {
-        holdings.truncate(*MAX_HOLDINGS);
+    let own = fetch_principal(principal, ledger_filter, dex_filter, settings.subaccounts());
+    // Linked wallets are fetched one level deep with the owner's filters;
+    // their own links are not followed.
+    let linked = futures::future::join_all(settings.linked_wallets().iter().map(|w| async move {
+        let (mut holdings, statuses) = match w {
+            user_settings::LinkedWallet::Principal(p) => {
+                fetch_principal(*p, ledger_filter, dex_filter, &[]).await
+            }
+            user_settings::LinkedWallet::AccountId(a) => {
+                let fetched = sources::timed("account", a.clone(), None, async {
+                    ledger_fetcher::fetch_account_id(a).await.map(|h| vec![h])
+                })
+                .await;
+                sources::merge(vec![fetched])
+            }
+        };
+        for h in holdings.iter_mut() {
+            h.wallet = Some(w.id());
+        }
+        (holdings, statuses)
+    }));
+    let ((mut holdings, mut statuses), linked) = futures::join!(own, linked);
+    for (h, s) in linked {
+        holdings.extend(h);
+        statuses.extend(s);
+    }
+    let max_holdings = config::get().max_holdings as usize;
+    if holdings.len() > max_holdings {
+        holdings.truncate(max_holdings);
     }
+    price::annotate(&mut holdings);
     let summary = summarise(&holdings)?;
+    // only results that will be cached keep their statuses, so they are
+    // dropped together with the cache entry
+    sources::record(principal, statuses);
     Ok((holdings, summary))
 }
 
+/// Ledger, neuron and DEX holdings of a single principal with the status of
+/// every source queried; failed sources contribute no holdings.
+async fn fetch_principal(
+    principal: Principal,
+    ledger_filter: Option<&std::collections::HashSet<Principal>>,
+    dex_filter: Option<&std::collections::HashSet<String>>,
+    subaccounts: &[Vec<u8>],
+) -> (Vec<TypedHolding>, Vec<sources::SourceStatus>) {
+    let (mut fetched, neuron, dex) = futures::join!(
+        ledger_fetcher::fetch_detailed(principal, ledger_filter, subaccounts),
+        sources::timed(
+            "neurons",
+            neuron_fetcher::governance_id().to_text(),
+            Some(principal),
+            neuron_fetcher::fetch(principal)
+        ),
+        dex_fetchers::fetch_detailed(principal, dex_filter)
+    );
+    fetched.push(neuron);
+    fetched.extend(dex);
+    sources::merge(fetched)
+}
+
 #[cfg(target_arch = "wasm32")]
 fn instructions() -> u64 {
     ic_cdk::api::instruction_counter()
@@ -198,48 +261,259 @@ fn accept_cycles(price: u128) -> u128 {
 
 #[ic_cdk_macros::update]
 pub async fn get_holdings(principal: Principal) -> Result<Vec<Holding>, String> {
+    let holdings = cached_holdings(principal, "get_holdings").await?.holdings;
+    Ok(holdings.iter().map(Holding::from).collect())
+}
+
+/// Typed, versioned variant of `get_holdings` exposing raw amounts, decimals
+/// and the holding kind.
+#[ic_cdk_macros::update]
+pub async fn get_holdings_v2(principal: Principal) -> Result<Vec<TypedHolding>, String> {
+    cached_holdings(principal, "get_holdings_v2")
+        .await
+        .map(|c| c.holdings)
+}
+
+#[derive(Clone, candid::CandidType, serde::Serialize, serde::Deserialize)]
+pub struct DetailedHoldings {
+    pub holdings: Vec<TypedHolding>,
+    pub summary: Vec<HoldingSummary>,
+    /// Every ledger, DEX and governance call made by the fetch the holdings
+    /// came from, including the ones that failed
+    pub sources: Vec<sources::SourceStatus>,
+    /// False when any source failed and the totals are partial, or when no
+    /// source status is known for the cached holdings
+    pub complete: bool,
+    /// Served from a cache entry past its fresh window; a refresh is running
+    pub stale: bool,
+    /// When the holdings were fetched, in nanoseconds
+    pub fetched_at: u64,
+}
+
+/// `get_holdings_v2` plus the outcome of each source, so callers can tell
+/// which ledgers or DEXes are missing from a partial result.
+#[ic_cdk_macros::update]
+pub async fn get_holdings_detailed(principal: Principal) -> Result<DetailedHoldings, String> {
+    let cached = cached_holdings(principal, "get_holdings_detailed").await?;
+    let sources = sources::last(&principal);
+    Ok(DetailedHoldings {
+        holdings: cached.holdings,
+        summary: cached.summary,
+        complete: !sources.is_empty() && sources.iter().all(|s| s.ok()),
+        sources,
+        stale: cached.stale,
+        fetched_at: cached.fetched_at,
+    })
+}
+
+async fn cached_holdings(principal: Principal, label: &str) -> Result<CachedHoldings, String> {
     metrics::inc_query();
-    let accepted = accept_cycles(*CALL_PRICE);
-    if accepted < *CALL_PRICE {
+    let price = config::get().call_price;
+    let accepted = accept_cycles(price);
+    if accepted < price {
         return Err(format!(
             "Insufficient cycles: sent {}, required {}",
-            accepted, *CALL_PRICE
+            accepted, price
         ));
     }
     cycles::ensure_margin();
     let start_cycles = cycles::available();
     let start = instructions();
-    let now = now();
-    {
-        let cache = cache::get();
-        if let Some(v) = cache.get(&principal) {
-            let (cached, _, ts) = v.value().clone();
-            if now - ts < MINUTE_NS {
-                let used = instructions().saturating_sub(start);
-                tracing::info!(
-                    "get_holdings took {used} instructions ({:.2} B)",
-                    used as f64 / 1_000_000_000f64
-                );
-                return Ok(cached);
-            }
+    let cached = cached_or_fetch(principal, now()).await?;
+    let used = instructions().saturating_sub(start);
+    tracing::info!(
+        "{label} took {used} instructions ({:.2} B)",
+        used as f64 / 1_000_000_000f64
+    );
+    let used_cycles = start_cycles.saturating_sub(cycles::available());
+    metrics::record_query_cycles(used_cycles as u64);
+    Ok(cached)
+}
+
+pub(crate) struct CachedHoldings {
+    pub holdings: Vec<TypedHolding>,
+    pub summary: Vec<HoldingSummary>,
+    pub fetched_at: u64,
+    pub stale: bool,
+}
+
+impl CachedHoldings {
+    fn new((holdings, summary, fetched_at): cache::Entry, stale: bool) -> Self {
+        CachedHoldings {
+            holdings,
+            summary,
+            fetched_at,
+            stale,
+        }
+    }
+}
+
+/// Holdings for `principal` from the cache when fresh or stale, otherwise
+/// recalculated and cached. Stale entries are refreshed in the background.
+pub(crate) async fn cached_or_fetch(
+    principal: Principal,
+    now: u64,
+) -> Result<CachedHoldings, String> {
+    match cache::get().lookup(&principal, now) {
+        cache::Lookup::Fresh(e) => return Ok(CachedHoldings::new(e, false)),
+        cache::Lookup::Stale(e) => {
+            revalidate(principal);
+            return Ok(CachedHoldings::new(e, true));
         }
+        cache::Lookup::Miss => {}
     }
+    fetch_holdings(principal, now).await
+}
 
-    let (holdings, summary) = calculate_holdings(principal)
+pub(crate) async fn holdings_for(
+    principal: Principal,
+    now: u64,
+) -> Result<(Vec<TypedHolding>, Vec<HoldingSummary>), String> {
+    cached_or_fetch(principal, now)
         .await
-        .map_err(|e| e.to_string())?;
+        .map(|c| (c.holdings, c.summary))
+}
 
-    {
-        cache::get().insert(principal, (holdings.clone(), summary, now));
+/// When each background refresh in flight started. Like fetches, a refresh
+/// counts as in flight for at most `FETCH_LEASE_NS`.
+static REVALIDATING: Lazy<std::sync::Mutex<std::collections::HashMap<Principal, u64>>> =
+    Lazy::new(Default::default);
+
+fn is_revalidating(principal: &Principal, now: u64) -> bool {
+    REVALIDATING
+        .lock()
+        .unwrap()
+        .get(principal)
+        .is_some_and(|started| now.saturating_sub(*started) < FETCH_LEASE_NS)
+}
+
+/// Record a background refresh of `principal` starting at `now`, unless one
+/// is already in flight. Expired entries are dropped on the way.
+fn start_revalidation(principal: Principal, now: u64) -> bool {
+    let mut running = REVALIDATING.lock().unwrap();
+    running.retain(|_, started| now.saturating_sub(*started) < FETCH_LEASE_NS);
+    if running.contains_key(&principal) {
+        return false;
     }
-    let used = instructions().saturating_sub(start);
-    tracing::info!(
-        "get_holdings took {used} instructions ({:.2} B)",
-        used as f64 / 1_000_000_000f64
-    );
+    running.insert(principal, now);
+    true
+}
+
+async fn refresh_cached(principal: Principal) {
+    if let Err(e) = fetch_holdings(principal, now()).await {
+        tracing::warn!("background refresh of {principal} failed: {e}");
+    }
+    REVALIDATING.lock().unwrap().remove(&principal);
+}
+
+/// Recalculate the cached holdings of `principal` outside the current call
+fn revalidate(principal: Principal) {
+    if !start_revalidation(principal, now()) {
+        return;
+    }
+    #[cfg(target_arch = "wasm32")]
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, move || {
+        ic_cdk::spawn(refresh_cached(principal))
+    });
+    #[cfg(not(target_arch = "wasm32"))]
+    match tokio::runtime::Handle::try_current() {
+        Ok(rt) => {
+            rt.spawn(refresh_cached(principal));
+        }
+        Err(_) => {
+            REVALIDATING.lock().unwrap().remove(&principal);
+        }
+    }
+}
+
+#[derive(Clone, candid::CandidType, serde::Serialize, serde::Deserialize)]
+pub struct PrincipalHoldings {
+    pub principal: Principal,
+    pub holdings: Vec<TypedHolding>,
+    pub summary: Vec<HoldingSummary>,
+    /// Set when this principal could not be fetched; it is left out of the
+    /// merged summary
+    pub error: Option<String>,
+}
+
+#[derive(Clone, candid::CandidType, serde::Serialize, serde::Deserialize)]
+pub struct Portfolio {
+    pub principals: Vec<PrincipalHoldings>,
+    pub summary: Vec<HoldingSummary>,
+}
+
+/// Aggregate holdings across several principals owned by the same user.
+/// Each principal is served from the cache when fresh; at most
+/// `PORTFOLIO_CONCURRENCY` are recalculated at once. One cycles fee is
+/// charged per distinct principal. Holdings of a linked wallet that is also
+/// requested are summarised once, as that wallet's own.
+#[ic_cdk_macros::update]
+pub async fn get_portfolio(principals: Vec<Principal>) -> Result<Portfolio, String> {
+    use futures::stream::{self, StreamExt};
+    metrics::inc_query();
+    let mut unique: Vec<Principal> = Vec::with_capacity(principals.len());
+    for p in principals {
+        if !unique.contains(&p) {
+            unique.push(p);
+        }
+    }
+    if unique.len() > *MAX_PORTFOLIO_PRINCIPALS {
+        return Err(format!(
+            "at most {} principals per portfolio",
+            *MAX_PORTFOLIO_PRINCIPALS
+        ));
+    }
+    let price = config::get()
+        .call_price
+        .saturating_mul(unique.len() as u128);
+    let accepted = accept_cycles(price);
+    if accepted < price {
+        return Err(format!(
+            "Insufficient cycles: sent {}, required {}",
+            accepted, price
+        ));
+    }
+    cycles::ensure_margin();
+    let start_cycles = cycles::available();
+    let now = now();
+    let requested: std::collections::HashSet<String> =
+        unique.iter().map(Principal::to_text).collect();
+    let results: Vec<_> = stream::iter(unique)
+        .map(|p| async move { (p, holdings_for(p, now).await) })
+        .buffered(*PORTFOLIO_CONCURRENCY)
+        .collect()
+        .await;
+    let mut merged = Vec::new();
+    let mut breakdown = Vec::with_capacity(results.len());
+    for (principal, res) in results {
+        match res {
+            Ok((holdings, summary)) => {
+                let own = holdings
+                    .iter()
+                    .filter(|h| h.wallet.as_ref().is_none_or(|w| !requested.contains(w)));
+                merged.extend(own.cloned());
+                breakdown.push(PrincipalHoldings {
+                    principal,
+                    holdings,
+                    summary,
+                    error: None,
+                });
+            }
+            Err(e) => breakdown.push(PrincipalHoldings {
+                principal,
+                holdings: Vec::new(),
+                summary: Vec::new(),
+                error: Some(e),
+            }),
+        }
+    }
+    let summary = summarise(&merged).map_err(|e| e.to_string())?;
     let used_cycles = start_cycles.saturating_sub(cycles::available());
     metrics::record_query_cycles(used_cycles as u64);
-    Ok(holdings)
+    Ok(Portfolio {
+        principals: breakdown,
+        summary,
+    })
 }
 
 #[ic_cdk_macros::update]
@@ -249,45 +523,51 @@ pub async fn get_holdings_filtered(
     dexes: Vec<String>,
 ) -> Result<Vec<Holding>, String> {
     metrics::inc_query();
-    let accepted = accept_cycles(*CALL_PRICE);
-    if accepted < *CALL_PRICE {
+    let price = config::get().call_price;
+    let accepted = accept_cycles(price);
+    if accepted < price {
         return Err(format!(
             "Insufficient cycles: sent {}, required {}",
-            accepted, *CALL_PRICE
+            accepted, price
         ));
     }
     cycles::ensure_margin();
     let start_cycles = cycles::available();
     let start = instructions();
-    use std::collections::HashSet;
-    let ledger_set: HashSet<Principal> = ledgers
+    use std::collections::BTreeSet;
+    let ledger_set: BTreeSet<Principal> = ledgers
         .iter()
         .filter_map(|s| Principal::from_text(s).ok())
         .collect();
-    let dex_set: HashSet<String> = dexes.into_iter().collect();
-    let ledger_filter = if ledger_set.is_empty() {
-        None
-    } else {
-        Some(&ledger_set)
-    };
-    let dex_filter = if dex_set.is_empty() {
-        None
-    } else {
-        Some(&dex_set)
-    };
-    let (ledger, neuron, dex) = futures::join!(
-        ledger_fetcher::fetch_filtered(principal, ledger_filter),
-        neuron_fetcher::fetch(principal),
-        dex_fetchers::fetch_filtered(principal, dex_filter)
+    let dex_set: BTreeSet<String> = dexes.into_iter().collect();
+    let subaccounts = user_settings::get(&principal)
+        .and_then(|s| s.subaccounts)
+        .unwrap_or_default();
+    let key = format!(
+        "{}|{}|{}",
+        ledger_set
+            .iter()
+            .map(Principal::to_text)
+            .collect::<Vec<_>>()
+            .join(","),
+        dex_set.iter().cloned().collect::<Vec<_>>().join(","),
+        subaccounts
+            .iter()
+            .map(|s| ledger_fetcher::subaccount_hex(s))
+            .collect::<Vec<_>>()
+            .join(",")
     );
-    let capacity =
-        ledger.as_ref().map_or(0, |v| v.len()) + neuron.len() + dex.as_ref().map_or(0, |v| v.len());
-    let mut holdings = Vec::with_capacity(capacity);
-    holdings.extend(ledger.unwrap_or_default());
-    holdings.extend(neuron);
-    holdings.extend(dex.unwrap_or_default());
-    if holdings.len() > *MAX_HOLDINGS {
-        holdings.truncate(*MAX_HOLDINGS);
+    let ledger_set: std::collections::HashSet<Principal> = ledger_set.into_iter().collect();
+    let dex_set: std::collections::HashSet<String> = dex_set.into_iter().collect();
+    let ledger_filter = (!ledger_set.is_empty()).then_some(&ledger_set);
+    let dex_filter = (!dex_set.is_empty()).then_some(&dex_set);
+    let fetch = fetch_principal(principal, ledger_filter, dex_filter, &subaccounts);
+    let (mut holdings, _) = FILTERED_FLIGHT
+        .run((principal, key), utils::now, fetch)
+        .await;
+    let max_holdings = config::get().max_holdings as usize;
+    if holdings.len() > max_holdings {
+        holdings.truncate(max_holdings);
     }
     let used = instructions().saturating_sub(start);
     tracing::info!(
@@ -296,18 +576,19 @@ pub async fn get_holdings_filtered(
     );
     let used_cycles = start_cycles.saturating_sub(cycles::available());
     metrics::record_query_cycles(used_cycles as u64);
-    Ok(holdings)
+    Ok(holdings.iter().map(Holding::from).collect())
 }
 
 #[cfg(feature = "claim")]
 #[ic_cdk_macros::update]
 pub async fn claim_all_rewards(principal: Principal) -> Vec<u64> {
     metrics::inc_query();
-    let accepted = accept_cycles(*CLAIM_PRICE);
-    if accepted < *CLAIM_PRICE {
+    let price = config::get().claim_price;
+    let accepted = accept_cycles(price);
+    if accepted < price {
         ic_cdk::api::trap(&format!(
             "Insufficient cycles: sent {}, required {}",
-            accepted, *CLAIM_PRICE
+            accepted, price
         ));
     }
     cycles::ensure_margin();
@@ -332,7 +613,10 @@ pub async fn claim_all_rewards(principal: Principal) -> Vec<u64> {
                 ic_cdk::api::trap("cooldown");
             }
         }
-        map.insert(principal, now + *CLAIM_COOLDOWN_NS);
+        map.insert(
+            principal,
+            now + config::get().claim_cooldown_secs * 1_000_000_000,
+        );
     }
     {
         let mut counts = CLAIM_COUNTS.lock().unwrap();
@@ -343,7 +627,7 @@ pub async fn claim_all_rewards(principal: Principal) -> Vec<u64> {
         if now > entry.1 {
             *entry = (0, now + *CLAIM_LIMIT_WINDOW_NS);
         }
-        if entry.0 >= *CLAIM_DAILY_LIMIT {
+        if entry.0 >= config::get().claim_daily_limit {
             ic_cdk::api::trap("claim limit reached");
         }
         entry.0 += 1;
@@ -365,7 +649,10 @@ pub async fn claim_all_rewards(principal: Principal) -> Vec<u64> {
     }
     let _guard = Guard(principal);
     use dex::registry;
-    let mut adapters: Vec<registry::AdapterEntry> = registry::get();
+    let mut adapters: Vec<registry::AdapterEntry> = registry::get()
+        .into_iter()
+        .filter(|e| e.adapter.capabilities().claim)
+        .collect();
     if *MAX_CLAIM_PER_CALL < adapters.len() {
         adapters.truncate(*MAX_CLAIM_PER_CALL);
     }
@@ -426,18 +713,22 @@ where
 #[ic_cdk_macros::query]
 pub fn pools_graphql(query: String) -> String {
     metrics::inc_query();
-    pay_cycles(*CALL_PRICE);
+    pay_cycles(config::get().call_price);
     cycles::ensure_margin();
     let start_cycles = cycles::available();
-    let res = pool_registry::graphql(query);
+    let res = graphql::execute_query(query.as_bytes());
     let used_cycles = start_cycles.saturating_sub(cycles::available());
     metrics::record_query_cycles(used_cycles as u64);
     res
 }
 
-#[derive(candid::CandidType, serde::Serialize)]
+#[derive(candid::CandidType, serde::Serialize, serde::Deserialize)]
 pub struct CertifiedHoldings {
     pub holdings: Vec<Holding>,
+    /// Summary certified along with the holdings
+    pub summary: Vec<HoldingSummary>,
+    /// When the holdings were fetched; `None` when nothing is cached
+    pub fetched_at: Option<u64>,
     #[serde(with = "serde_bytes")]
     pub certificate: Vec<u8>,
     #[serde(with = "serde_bytes")]
@@ -447,15 +738,10 @@ pub struct CertifiedHoldings {
 #[ic_cdk_macros::update]
 pub async fn refresh_holdings(principal: Principal) -> Result<(), String> {
     metrics::inc_query();
-    pay_cycles(*CALL_PRICE);
+    pay_cycles(config::get().call_price);
     cycles::ensure_margin();
     let start_cycles = cycles::available();
-    let now = now();
-    let (holdings, summary) = calculate_holdings(principal)
-        .await
-        .map_err(|e| e.to_string())?;
-    cache::get().insert(principal, (holdings.clone(), summary, now));
-    cert::update(principal, &holdings);
+    fetch_holdings(principal, now()).await?;
     let used_cycles = start_cycles.saturating_sub(cycles::available());
     metrics::record_query_cycles(used_cycles as u64);
     Ok(())
@@ -464,17 +750,19 @@ pub async fn refresh_holdings(principal: Principal) -> Result<(), String> {
 #[ic_cdk_macros::query]
 pub fn get_holdings_cert(principal: Principal) -> CertifiedHoldings {
     metrics::inc_query();
-    pay_cycles(*CALL_PRICE);
+    pay_cycles(config::get().call_price);
     cycles::ensure_margin();
     let start_cycles = cycles::available();
-    let holdings = cache::get()
-        .get(&principal)
-        .map(|v| v.value().0.clone())
-        .unwrap_or_default();
+    let (holdings, summary, fetched_at) = match cache::get().get(&principal) {
+        Some((h, s, ts)) => (h.iter().map(Holding::from).collect(), s, Some(ts)),
+        None => (Vec::new(), Vec::new(), None),
+    };
     let certificate = ic_cdk::api::data_certificate().unwrap_or_default();
     let witness = cert::witness(principal);
     let out = CertifiedHoldings {
         holdings,
+        summary,
+        fetched_at,
         certificate,
         witness,
     };
@@ -483,60 +771,212 @@ pub fn get_holdings_cert(principal: Principal) -> CertifiedHoldings {
     out
 }
 
+/// Age of a cached entry and whether a newer one is on its way
+#[derive(Clone, Debug, PartialEq, candid::CandidType, serde::Serialize, serde::Deserialize)]
+pub struct Freshness {
+    /// When the cached holdings were fetched; `None` when nothing is cached
+    pub fetched_at: Option<u64>,
+    pub age_secs: Option<u64>,
+    /// Past the fresh window, or nothing cached
+    pub stale: bool,
+    /// A fetch for this principal is in flight
+    pub refreshing: bool,
+}
+
+fn freshness(principal: Principal, fetched_at: Option<u64>, now: u64) -> Freshness {
+    Freshness {
+        fetched_at,
+        age_secs: fetched_at.map(|ts| now.saturating_sub(ts) / 1_000_000_000),
+        stale: !fetched_at.is_some_and(|ts| cache::is_fresh(ts, now)),
+        refreshing: is_revalidating(&principal, now)
+            || HOLDINGS_FLIGHT.contains(&(principal, String::new()), now),
+    }
+}
+
+#[derive(Clone, candid::CandidType, serde::Serialize, serde::Deserialize)]
+pub struct CachedView {
+    pub holdings: Vec<TypedHolding>,
+    pub summary: Vec<HoldingSummary>,
+    /// Sources of the fetch the cached holdings came from
+    pub sources: Vec<sources::SourceStatus>,
+    pub freshness: Freshness,
+}
+
+#[derive(Clone, candid::CandidType, serde::Serialize, serde::Deserialize)]
+pub struct CachedSummary {
+    pub summary: Vec<HoldingSummary>,
+    pub freshness: Freshness,
+}
+
+/// Cached holdings of `principal` without fetching anything, so clients can
+/// render at query latency. Empty with no `fetched_at` on a miss; call
+/// `request_refresh` to fill or update the cache.
+#[ic_cdk_macros::query]
+pub fn get_cached_holdings(principal: Principal) -> CachedView {
+    metrics::inc_query();
+    pay_cycles(config::get().call_price);
+    let (holdings, summary, fetched_at) = match cache::get().get(&principal) {
+        Some((h, s, ts)) => (h, s, Some(ts)),
+        None => (Vec::new(), Vec::new(), None),
+    };
+    CachedView {
+        holdings,
+        summary,
+        sources: sources::last(&principal),
+        freshness: freshness(principal, fetched_at, now()),
+    }
+}
+
+/// Cached token totals of `principal`; see `get_cached_holdings`
+#[ic_cdk_macros::query]
+pub fn get_cached_summary(principal: Principal) -> CachedSummary {
+    metrics::inc_query();
+    pay_cycles(config::get().call_price);
+    let cached = cache::get().get(&principal);
+    CachedSummary {
+        freshness: freshness(principal, cached.as_ref().map(|e| e.2), now()),
+        summary: cached.map(|(_, s, _)| s).unwrap_or_default(),
+    }
+}
+
+/// Start a background refresh of the cached holdings of `principal` unless
+/// they are fresh, returning at once. Poll `get_cached_holdings` for the
+/// result.
+#[ic_cdk_macros::update]
+pub fn request_refresh(principal: Principal) -> Result<Freshness, String> {
+    metrics::inc_query();
+    let price = config::get().call_price;
+    let accepted = accept_cycles(price);
+    if accepted < price {
+        return Err(format!(
+            "Insufficient cycles: sent {}, required {}",
+            accepted, price
+        ));
+    }
+    cycles::ensure_margin();
+    let now = now();
+    let fetched_at = cache::get().get(&principal).map(|e| e.2);
+    if !fetched_at.is_some_and(|ts| cache::is_fresh(ts, now)) {
+        revalidate(principal);
+    }
+    Ok(freshness(principal, fetched_at, now))
+}
+
 #[derive(Clone, candid::CandidType, serde::Serialize, serde::Deserialize)]
 pub struct HoldingSummary {
     pub token: String,
     pub total: f64,
+    /// Value of `total` in ICP; `None` unless every holding of the token is
+    /// priced
+    pub value_icp: Option<f64>,
+    pub value_usd: Option<f64>,
 }
 
 #[ic_cdk_macros::update]
 pub async fn get_holdings_summary(principal: Principal) -> Result<Vec<HoldingSummary>, String> {
     metrics::inc_query();
-    let accepted = accept_cycles(*CALL_PRICE);
-    if accepted < *CALL_PRICE {
+    let price = config::get().call_price;
+    let accepted = accept_cycles(price);
+    if accepted < price {
         return Err(format!(
             "Insufficient cycles: sent {}, required {}",
-            accepted, *CALL_PRICE
+            accepted, price
         ));
     }
     cycles::ensure_margin();
     let start_cycles = cycles::available();
-    let now = now();
-    {
-        if let Some(v) = cache::get().get(&principal) {
-            let (_, summary, ts) = v.value().clone();
-            if now - ts < MINUTE_NS {
-                return Ok(summary);
-            }
-        }
-    }
-    let (holdings, summary) = calculate_holdings(principal)
-        .await
-        .map_err(|e| e.to_string())?;
-    cache::get().insert(principal, (holdings, summary.clone(), now));
+    let summary = cached_or_fetch(principal, now()).await?.summary;
     let used_cycles = start_cycles.saturating_sub(cycles::available());
     metrics::record_query_cycles(used_cycles as u64);
     Ok(summary)
 }
 
-fn summarise(holdings: &[Holding]) -> Result<Vec<HoldingSummary>, rust_decimal::Error> {
+fn summarise(holdings: &[TypedHolding]) -> Result<Vec<HoldingSummary>, rust_decimal::Error> {
     use rust_decimal::prelude::{FromStr, ToPrimitive};
     use std::collections::BTreeMap;
-    let mut map: BTreeMap<String, rust_decimal::Decimal> = BTreeMap::new();
+    struct Acc {
+        total: rust_decimal::Decimal,
+        icp: Option<f64>,
+        usd: Option<f64>,
+    }
+    let mut map: BTreeMap<String, Acc> = BTreeMap::new();
     for h in holdings {
-        let v = rust_decimal::Decimal::from_str(&h.amount)?;
-        *map.entry(h.token.clone())
-            .or_insert(rust_decimal::Decimal::ZERO) += v;
+        let v = rust_decimal::Decimal::from_str(&h.formatted_amount())?;
+        let value = price::value(h);
+        let acc = map.entry(h.symbol.clone()).or_insert(Acc {
+            total: rust_decimal::Decimal::ZERO,
+            icp: Some(0.0),
+            usd: Some(0.0),
+        });
+        acc.total += v;
+        acc.icp = acc.icp.zip(value.map(|p| p.icp)).map(|(a, b)| a + b);
+        acc.usd = acc.usd.zip(value.and_then(|p| p.usd)).map(|(a, b)| a + b);
     }
     Ok(map
         .into_iter()
-        .map(|(token, total)| HoldingSummary {
+        .map(|(token, acc)| HoldingSummary {
             token,
-            total: total.to_f64().unwrap_or(0.0),
+            total: acc.total.to_f64().unwrap_or(0.0),
+            value_icp: acc.icp,
+            value_usd: acc.usd,
         })
         .collect())
 }
 
+#[derive(Clone, candid::CandidType, serde::Serialize, serde::Deserialize)]
+pub struct PortfolioValue {
+    pub value_icp: f64,
+    /// `None` when no ICP/USD rate is available
+    pub value_usd: Option<f64>,
+    pub icp_usd: Option<f64>,
+    pub tokens: Vec<HoldingSummary>,
+    /// Tokens held without a known price; they are left out of the totals
+    pub unpriced: Vec<String>,
+}
+
+/// Total ICP and USD value of the holdings of `principals`, priced from DEX
+/// pool reserves and the optional price oracle.
+#[ic_cdk_macros::update]
+pub async fn get_portfolio_value(principals: Vec<Principal>) -> Result<PortfolioValue, String> {
+    let portfolio = get_portfolio(principals).await?;
+    let icp_usd = price::icp_usd();
+    let mut value_icp = 0.0;
+    let mut unpriced = Vec::new();
+    for t in &portfolio.summary {
+        match t.value_icp {
+            Some(v) => value_icp += v,
+            None => unpriced.push(t.token.clone()),
+        }
+    }
+    Ok(PortfolioValue {
+        value_icp,
+        value_usd: icp_usd.map(|r| r * value_icp),
+        icp_usd,
+        tokens: portfolio.summary,
+        unpriced,
+    })
+}
+
+/// Portfolio snapshots of `principal` taken between `from` and `to`
+/// (nanoseconds, inclusive). Principals opt in with
+/// `UserSettings::track_history`.
+#[ic_cdk_macros::query]
+pub fn get_history(
+    principal: Principal,
+    from: u64,
+    to: u64,
+    resolution: history::Resolution,
+) -> Result<Vec<history::HistoryPoint>, String> {
+    metrics::inc_query();
+    pay_cycles(config::get().call_price);
+    cycles::ensure_margin();
+    let start_cycles = cycles::available();
+    let out = history::get(principal, from, to, resolution);
+    let used_cycles = start_cycles.saturating_sub(cycles::available());
+    metrics::record_query_cycles(used_cycles as u64);
+    out
+}
+
 #[derive(candid::CandidType, serde::Serialize)]
 pub struct Version {
     pub git_sha: &'static str,
@@ -546,7 +986,7 @@ pub struct Version {
 #[ic_cdk_macros::query]
 pub fn get_version() -> Version {
     metrics::inc_query();
-    pay_cycles(*CALL_PRICE);
+    pay_cycles(config::get().call_price);
     cycles::ensure_margin();
     let start_cycles = cycles::available();
     let out = Version {
@@ -561,7 +1001,7 @@ pub fn get_version() -> Version {
 #[ic_cdk_macros::query]
 pub fn get_cycles_log() -> Vec<String> {
     metrics::inc_query();
-    pay_cycles(*CALL_PRICE);
+    pay_cycles(config::get().call_price);
     cycles::ensure_margin();
     let start_cycles = cycles::available();
     let log = cycles::log();
@@ -570,10 +1010,120 @@ pub fn get_cycles_log() -> Vec<String> {
     log
 }
 
+/// Caller when it controls the canister
+#[cfg(target_arch = "wasm32")]
+fn controller() -> Result<Principal, String> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller) {
+        Ok(caller)
+    } else {
+        Err("unauthorized: controllers only".into())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn controller() -> Result<Principal, String> {
+    Ok(Principal::anonymous())
+}
+
+#[ic_cdk_macros::update]
+pub fn get_config() -> Result<config::Config, String> {
+    controller()?;
+    Ok(config::get())
+}
+
+/// Change the fields set in `update`; the rest keep their value
+#[ic_cdk_macros::update]
+pub fn set_config(update: config::ConfigUpdate) -> Result<config::Config, String> {
+    let caller = controller()?;
+    config::set(caller, update, now())
+}
+
+/// Changes made by `set_config`, newest first
+#[ic_cdk_macros::update]
+pub fn get_config_history(offset: u64, limit: u64) -> Result<Vec<config::ConfigChange>, String> {
+    controller()?;
+    Ok(config::history(offset as usize, limit.min(1000) as usize))
+}
+
+/// Add a DEX adapter, replacing a managed one of the same name
+#[ic_cdk_macros::update]
+pub async fn add_adapter(config: dex::registry::AdapterConfig) -> Result<(), String> {
+    controller()?;
+    dex::registry::add(config).await
+}
+
+#[ic_cdk_macros::update]
+pub fn remove_adapter(name: String) -> Result<(), String> {
+    controller()?;
+    dex::registry::remove(&name)
+}
+
+#[ic_cdk_macros::update]
+pub fn set_adapter_enabled(name: String, enabled: bool) -> Result<(), String> {
+    controller()?;
+    dex::registry::set_enabled(&name, enabled)
+}
+
+#[ic_cdk_macros::update]
+pub fn list_adapters() -> Result<Vec<dex::registry::AdapterConfig>, String> {
+    controller()?;
+    Ok(dex::registry::list())
+}
+
+/// Circuit breaker state of every enabled adapter
+#[ic_cdk_macros::query]
+pub fn get_adapter_health() -> Vec<breaker::AdapterHealth> {
+    metrics::inc_query();
+    pay_cycles(config::get().call_price);
+    breaker::all()
+}
+
+/// Identity, capabilities and settings of every enabled adapter
+#[ic_cdk_macros::query]
+pub fn get_adapters() -> Vec<dex::AdapterInfo> {
+    metrics::inc_query();
+    pay_cycles(config::get().call_price);
+    dex::registry::get()
+        .iter()
+        .map(|e| dex::AdapterInfo::of(e.adapter.as_ref()))
+        .collect()
+}
+
+/// Rewards `claim_all_rewards` would collect, per adapter that can claim
+#[ic_cdk_macros::update]
+pub async fn preview_claims(principal: Principal) -> Result<Vec<dex::ClaimPreview>, String> {
+    metrics::inc_query();
+    let price = config::get().call_price;
+    let accepted = accept_cycles(price);
+    if accepted < price {
+        return Err(format!(
+            "Insufficient cycles: sent {}, required {}",
+            accepted, price
+        ));
+    }
+    cycles::ensure_margin();
+    let adapters: Vec<_> = dex::registry::get()
+        .into_iter()
+        .filter(|e| e.adapter.capabilities().claim)
+        .collect();
+    let previews =
+        futures::future::join_all(adapters.iter().map(|e| e.adapter.claim_preview(principal)))
+            .await;
+    let mut out = Vec::with_capacity(previews.len());
+    for (entry, preview) in adapters.iter().zip(previews) {
+        match preview {
+            Ok(p) => out.push(p),
+            Err(e) => tracing::warn!("no claim preview from {}: {e}", entry.id()),
+        }
+    }
+    Ok(out)
+}
+
 #[ic_cdk_macros::query]
 pub fn get_user_settings(principal: Principal) -> user_settings::UserSettings {
     metrics::inc_query();
-    pay_cycles(*CALL_PRICE);
+    pay_cycles(config::get().call_price);
     cycles::ensure_margin();
     let start_cycles = cycles::available();
     let out = user_settings::get(&principal).unwrap_or_default();
@@ -585,19 +1135,64 @@ pub fn get_user_settings(principal: Principal) -> user_settings::UserSettings {
 #[ic_cdk_macros::update]
 pub fn update_user_settings(principal: Principal, settings: user_settings::UserSettings) {
     metrics::inc_query();
-    pay_cycles(*CALL_PRICE);
+    pay_cycles(config::get().call_price);
     cycles::ensure_margin();
     let start_cycles = cycles::available();
     let caller = ic_cdk::caller();
     if caller != principal {
         ic_cdk::api::trap("unauthorized");
     }
+    if let Err(e) = user_settings::validate(&settings) {
+        ic_cdk::api::trap(&e);
+    }
+    let mut settings = settings;
+    settings.linked_wallets = user_settings::get(&principal).and_then(|s| s.linked_wallets);
     user_settings::update(principal, settings);
     cache::get().remove(&principal);
     let used_cycles = start_cycles.saturating_sub(cycles::available());
     metrics::record_query_cycles(used_cycles as u64);
 }
 
+/// Ask to aggregate `wallet` into the caller's holdings. The wallet must
+/// confirm with `accept_link` within a day.
+#[ic_cdk_macros::update]
+pub fn propose_link(wallet: user_settings::LinkedWallet) -> Result<(), String> {
+    metrics::inc_query();
+    pay_cycles(config::get().call_price);
+    cycles::ensure_margin();
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("anonymous caller".into());
+    }
+    user_settings::propose_link(caller, wallet, now())
+}
+
+/// Confirm a link proposed by `owner`. For account identifiers the caller
+/// passes the subaccount that, with the caller's principal, derives it.
+#[ic_cdk_macros::update]
+pub fn accept_link(owner: Principal, subaccount: Option<Vec<u8>>) -> Result<(), String> {
+    metrics::inc_query();
+    pay_cycles(config::get().call_price);
+    cycles::ensure_margin();
+    let caller = ic_cdk::caller();
+    user_settings::accept_link(caller, owner, subaccount, now())?;
+    cache::get().remove(&owner);
+    Ok(())
+}
+
+#[ic_cdk_macros::update]
+pub fn unlink_wallet(wallet: user_settings::LinkedWallet) -> bool {
+    metrics::inc_query();
+    pay_cycles(config::get().call_price);
+    cycles::ensure_margin();
+    let caller = ic_cdk::caller();
+    let removed = user_settings::unlink(caller, &wallet);
+    if removed {
+        cache::get().remove(&caller);
+    }
+    removed
+}
+
 #[cfg(feature = "claim")]
 #[derive(candid::CandidType, serde::Serialize)]
 pub struct ClaimStatus {
@@ -610,7 +1205,7 @@ pub struct ClaimStatus {
 #[ic_cdk_macros::query]
 pub fn get_claim_status(principal: Principal) -> ClaimStatus {
     metrics::inc_query();
-    pay_cycles(*CALL_PRICE);
+    pay_cycles(config::get().call_price);
     cycles::ensure_margin();
     let start_cycles = cycles::available();
     let now = now();
@@ -638,7 +1233,7 @@ pub fn get_claim_status(principal: Principal) -> ClaimStatus {
 #[ic_cdk_macros::query]
 pub fn health_check() -> &'static str {
     metrics::inc_query();
-    pay_cycles(*CALL_PRICE);
+    pay_cycles(config::get().call_price);
     cycles::ensure_margin();
     let start_cycles = cycles::available();
     let out = "ok";
@@ -647,43 +1242,59 @@ pub fn health_check() -> &'static str {
     out
 }
 
-#[derive(candid::CandidType, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, candid::CandidType, serde::Serialize, serde::Deserialize)]
 pub struct TokenTotal {
     pub token: String,
     pub total: f64,
+    pub value_icp: Option<f64>,
+    pub value_usd: Option<f64>,
 }
 
-fn summarize(holdings: &[Holding]) -> Result<Vec<TokenTotal>, rust_decimal::Error> {
-    use rust_decimal::prelude::{FromStr, ToPrimitive};
-    use std::collections::HashMap;
-    let mut map: HashMap<String, rust_decimal::Decimal> = HashMap::new();
-    for h in holdings {
-        let v = rust_decimal::Decimal::from_str(&h.amount)?;
-        *map.entry(h.token.clone())
-            .or_insert(rust_decimal::Decimal::ZERO) += v;
+impl From<HoldingSummary> for TokenTotal {
+    fn from(s: HoldingSummary) -> Self {
+        TokenTotal {
+            token: s.token,
+            total: s.total,
+            value_icp: s.value_icp,
+            value_usd: s.value_usd,
+        }
     }
-    let mut out: Vec<TokenTotal> = map
-        .into_iter()
-        .map(|(token, total)| TokenTotal {
-            token,
-            total: total.to_f64().unwrap_or(0.0),
-        })
-        .collect();
-    out.sort_by(|a, b| a.token.cmp(&b.token));
-    Ok(out)
 }
 
+/// Token totals of `principal` from the cache without charging or fetching;
+/// errors on a miss, saying whether a refresh is already pending
+pub fn cached_summary(principal: Principal) -> Result<Vec<TokenTotal>, String> {
+    match cache::get().get(&principal) {
+        Some((_, summary, _)) => Ok(summary.into_iter().map(TokenTotal::from).collect()),
+        None if freshness(principal, None, now()).refreshing => Err(format!(
+            "holdings of {principal} are being refreshed; retry shortly"
+        )),
+        None => Err(format!(
+            "no cached holdings for {principal}; call request_refresh first"
+        )),
+    }
+}
+
+/// Holdings of `principal`, fetched on a miss, without charging
+pub async fn fetch_typed_holdings(principal: Principal) -> Result<Vec<TypedHolding>, String> {
+    holdings_for(principal, now())
+        .await
+        .map(|(holdings, _)| holdings)
+}
+
+/// Token totals of `principal`, fetched on a miss, without charging
+pub async fn fetch_summary(principal: Principal) -> Result<Vec<TokenTotal>, String> {
+    let (_, summary) = holdings_for(principal, now()).await?;
+    Ok(summary.into_iter().map(TokenTotal::from).collect())
+}
+
+/// Token totals from the cache. It never fetches, so it can stay a query;
+/// see `cached_summary` for the errors on a miss.
 #[ic_cdk_macros::query]
-pub async fn get_summary(principal: Principal) -> Result<Vec<TokenTotal>, String> {
+pub fn get_summary(principal: Principal) -> Result<Vec<TokenTotal>, String> {
     metrics::inc_query();
-    pay_cycles(*CALL_PRICE);
-    cycles::ensure_margin();
-    let start_cycles = cycles::available();
-    let holdings = get_holdings(principal).await?;
-    let res = summarize(&holdings).map_err(|e| e.to_string());
-    let used_cycles = start_cycles.saturating_sub(cycles::available());
-    metrics::record_query_cycles(used_cycles as u64);
-    res
+    pay_cycles(config::get().call_price);
+    cached_summary(principal)
 }
 
 #[cfg(test)]
@@ -709,4 +1320,170 @@ mod tests {
         let after = metrics::get().cycles.collected;
         assert_eq!(before, after);
     }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[serial_test::serial]
+    async fn stale_holdings_are_served_and_revalidated() {
+        let p = Principal::from_slice(&[0x5A]);
+        let fetched_at = now() - 2 * utils::MINUTE_NS;
+        cache::get().insert(p, (Vec::new(), Vec::new(), fetched_at));
+        let detailed = get_holdings_detailed(p).await.unwrap();
+        assert!(detailed.stale);
+        assert_eq!(detailed.fetched_at, fetched_at);
+        // no fetch recorded sources for the seeded entry
+        assert!(detailed.sources.is_empty() && !detailed.complete);
+        assert!(is_revalidating(&p, now()));
+        cache::get().remove(&p);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[serial_test::serial]
+    async fn concurrent_fetch_waits_for_the_running_one() {
+        let p = Principal::from_slice(&[0x5D]);
+        let now = now();
+        let key = (p, String::new());
+        let (release, gate) = futures::channel::oneshot::channel::<()>();
+        let fetched_at = now - 2 * utils::MINUTE_NS;
+        let mut running = Box::pin(HOLDINGS_FLIGHT.run(key, utils::now, async move {
+            let _ = gate.await;
+            Ok((Vec::new(), Vec::new(), fetched_at))
+        }));
+        assert!(futures::poll!(&mut running).is_pending());
+
+        let mut waiting = Box::pin(fetch_holdings(p, now));
+        assert!(futures::poll!(&mut waiting).is_pending());
+        assert!(get_cached_summary(p).freshness.refreshing);
+
+        release.send(()).unwrap();
+        assert!(running.await.is_ok());
+        let served = waiting.await.unwrap();
+        assert_eq!(served.fetched_at, fetched_at);
+        assert!(!HOLDINGS_FLIGHT.contains(&(p, String::new()), now));
+        cache::get().remove(&p);
+    }
+
+    #[test]
+    fn revalidation_lease_expires() {
+        let p = Principal::from_slice(&[0x5C]);
+        assert!(start_revalidation(p, 1));
+        assert!(!start_revalidation(p, 2));
+        assert!(is_revalidating(&p, 2));
+        // the refresh never finished, as if it had trapped
+        let later = 1 + FETCH_LEASE_NS;
+        assert!(!is_revalidating(&p, later));
+        assert!(start_revalidation(p, later));
+        REVALIDATING.lock().unwrap().remove(&p);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn cached_reads_report_freshness() {
+        let p = Principal::from_slice(&[0x5B]);
+        let view = get_cached_holdings(p);
+        assert!(view.holdings.is_empty());
+        assert_eq!(view.freshness.fetched_at, None);
+        assert!(view.freshness.stale);
+        assert!(get_summary(p).unwrap_err().contains("request_refresh"));
+
+        let fetched_at = now() - 2 * utils::MINUTE_NS;
+        cache::get().insert(p, (Vec::new(), Vec::new(), fetched_at));
+        assert!(get_summary(p).unwrap().is_empty());
+        let before = get_cached_summary(p).freshness;
+        assert!(before.stale && !before.refreshing);
+        assert!(before.age_secs.unwrap() >= 120);
+        let after = request_refresh(p).unwrap();
+        assert!(after.refreshing);
+        assert_eq!(after.fetched_at, Some(fetched_at));
+        cache::get().remove(&p);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[serial_test::serial]
+    async fn portfolio_merges_cached_principals() {
+        use bx_core::HoldingKind;
+        let a = Principal::from_slice(&[0xA1]);
+        let b = Principal::from_slice(&[0xB2]);
+        let holding = |sym: &str, amt: u64| {
+            TypedHolding::new("test", sym, amt.into(), 0, HoldingKind::Liquid)
+        };
+        for (p, holdings) in [
+            (a, vec![holding("AAA", 1), holding("BBB", 2)]),
+            (b, vec![holding("AAA", 3)]),
+        ] {
+            let summary = summarise(&holdings).unwrap();
+            cache::get().insert(p, (holdings, summary, now()));
+        }
+        let portfolio = get_portfolio(vec![a, b, a]).await.unwrap();
+        assert_eq!(portfolio.principals.len(), 2);
+        assert_eq!(portfolio.principals[0].principal, a);
+        assert_eq!(portfolio.principals[0].holdings.len(), 2);
+        assert_eq!(portfolio.principals[1].summary[0].total, 3.0);
+        let totals: Vec<(String, f64)> = portfolio
+            .summary
+            .into_iter()
+            .map(|s| (s.token, s.total))
+            .collect();
+        assert_eq!(totals, vec![("AAA".into(), 4.0), ("BBB".into(), 2.0)]);
+        cache::get().remove(&a);
+        cache::get().remove(&b);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[serial_test::serial]
+    async fn portfolio_counts_requested_linked_wallets_once() {
+        use bx_core::HoldingKind;
+        let owner = Principal::from_slice(&[0xA3]);
+        let wallet = Principal::from_slice(&[0xB4]);
+        let holding =
+            |amt: u64| TypedHolding::new("test", "AAA", amt.into(), 0, HoldingKind::Liquid);
+        let linked = TypedHolding {
+            wallet: Some(wallet.to_text()),
+            ..holding(3)
+        };
+        for (p, holdings) in [
+            (owner, vec![holding(1), linked]),
+            (wallet, vec![holding(3)]),
+        ] {
+            let summary = summarise(&holdings).unwrap();
+            cache::get().insert(p, (holdings, summary, now()));
+        }
+        let portfolio = get_portfolio(vec![owner, wallet]).await.unwrap();
+        // the owner's breakdown still shows the linked wallet
+        assert_eq!(portfolio.principals[0].summary[0].total, 4.0);
+        assert_eq!(portfolio.summary[0].total, 4.0);
+        // on its own the owner's portfolio includes the linked wallet
+        let portfolio = get_portfolio(vec![owner]).await.unwrap();
+        assert_eq!(portfolio.summary[0].total, 4.0);
+        cache::get().remove(&owner);
+        cache::get().remove(&wallet);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn portfolio_rejects_too_many_principals() {
+        let many: Vec<Principal> = (0..=*MAX_PORTFOLIO_PRINCIPALS as u8)
+            .map(|i| Principal::from_slice(&[0xC0, i]))
+            .collect();
+        assert!(get_portfolio(many).await.is_err());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[serial_test::serial]
+    async fn portfolio_value_sums_priced_tokens() {
+        use bx_core::HoldingKind;
+        let p = Principal::from_slice(&[0xD4]);
+        let mut prices = std::collections::HashMap::new();
+        prices.insert("AAA".to_string(), 2.0);
+        price::set(prices, Some(5.0));
+        let holdings = vec![
+            TypedHolding::new("test", "AAA", 3u64.into(), 0, HoldingKind::Liquid),
+            TypedHolding::new("test", "ZZZ", 1u64.into(), 0, HoldingKind::Liquid),
+        ];
+        let summary = summarise(&holdings).unwrap();
+        assert_eq!(summary[0].value_usd, Some(30.0));
+        cache::get().insert(p, (holdings, summary, now()));
+        let value = get_portfolio_value(vec![p]).await.unwrap();
+        assert_eq!(value.value_icp, 6.0);
+        assert_eq!(value.value_usd, Some(30.0));
+        assert_eq!(value.unpriced, vec!["ZZZ".to_string()]);
+        price::clear();
+    }
 }
@@ -1,56 +1,71 @@
+use crate::error::FetchError;
+use crate::memory::{self, Cached, Candid, Memory};
 use crate::utils::{now, WEEK_NS};
-use bx_core::Holding;
+use bx_core::{Holding, HoldingKind, TypedHolding};
 use candid::Principal;
-use dashmap::DashMap;
-use once_cell::sync::Lazy;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
 use std::future::Future;
 
+#[derive(Clone, candid::CandidType, serde::Deserialize)]
 struct Entry {
-    data: Vec<Holding>,
+    data: Vec<TypedHolding>,
     height: u64,
     ts: u64,
 }
 
-static CACHE: Lazy<DashMap<(Principal, String), Entry>> = Lazy::new(DashMap::new);
+type Key = Candid<(Principal, String)>;
 
-static MAX_ENTRIES: Lazy<usize> = Lazy::new(|| {
-    option_env!("LP_CACHE_SIZE")
-        .and_then(|v| v.parse::<usize>().ok())
-        .unwrap_or(1024)
-});
+thread_local! {
+    static CACHE: RefCell<StableBTreeMap<Key, Cached<Entry>, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::LP_CACHE)));
+}
 
+/// Entry as serialised by releases that copied state through upgrades.
+/// Entries saved before typed holdings have the legacy `data` field instead.
 #[derive(candid::CandidType, serde::Serialize, serde::Deserialize)]
 pub struct StableEntry {
     principal: Principal,
     pool: String,
-    data: Vec<Holding>,
+    holdings: Option<Vec<TypedHolding>>,
+    data: Option<Vec<Holding>>,
     height: u64,
     ts: u64,
 }
 
-pub fn stable_save() -> Vec<StableEntry> {
-    CACHE
-        .iter()
-        .map(|e| {
-            let (p, pool) = e.key();
-            StableEntry {
-                principal: *p,
-                pool: pool.clone(),
-                data: e.value().data.clone(),
-                height: e.value().height,
-                ts: e.value().ts,
-            }
+/// Typed holdings of a legacy entry for `pool`; `None` if any can't be read
+fn migrate(data: &[Holding], pool: &str) -> Option<Vec<TypedHolding>> {
+    data.iter()
+        .map(|h| {
+            let kind = match h.status.as_str() {
+                "claimable" => HoldingKind::Claimable {
+                    claim_from: None,
+                    pool: None,
+                },
+                _ => HoldingKind::LpPosition { pool: pool.into() },
+            };
+            TypedHolding::from_legacy(h, kind)
         })
         .collect()
 }
 
+/// Import entries saved by a release that serialised state on upgrade,
+/// converting legacy entries to typed holdings
 pub fn stable_restore(entries: Vec<StableEntry>) {
-    CACHE.clear();
     for e in entries {
-        CACHE.insert(
-            (e.principal, e.pool.clone()),
+        let data = match (e.holdings, e.data) {
+            (Some(holdings), _) => holdings,
+            (None, Some(legacy)) => match migrate(&legacy, &e.pool) {
+                Some(holdings) => holdings,
+                None => continue,
+            },
+            (None, None) => continue,
+        };
+        insert(
+            e.principal,
+            e.pool,
             Entry {
-                data: e.data,
+                data,
                 height: e.height,
                 ts: e.ts,
             },
@@ -59,42 +74,68 @@ pub fn stable_restore(entries: Vec<StableEntry>) {
     evict_excess();
 }
 
+fn get(principal: Principal, pool: &str) -> Option<Entry> {
+    CACHE.with(|c| {
+        c.borrow()
+            .get(&Candid((principal, pool.to_string())))
+            .and_then(|e| e.0)
+    })
+}
+
+fn insert(principal: Principal, pool: String, entry: Entry) {
+    CACHE.with(|c| {
+        c.borrow_mut()
+            .insert(Candid((principal, pool)), Cached(Some(entry)))
+    });
+}
+
+/// Once the cache is over `lp_cache_size`, drop the oldest entries in one
+/// pass, down to nine tenths of the limit so the next inserts don't each
+/// scan again.
 fn evict_excess() {
-    while CACHE.len() > *MAX_ENTRIES {
-        if let Some(old_key) = CACHE
+    let limit = crate::config::get().lp_cache_size as usize;
+    CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        let len = cache.len() as usize;
+        if len <= limit {
+            return;
+        }
+        let keep = limit - limit / 10;
+        let mut by_age: Vec<_> = cache
             .iter()
-            .min_by_key(|e| e.value().ts)
-            .map(|e| e.key().clone())
-        {
-            CACHE.remove(&old_key);
-        } else {
-            break;
+            .map(|(k, v)| (v.0.map_or(0, |e| e.ts), k))
+            .collect();
+        by_age.sort_unstable_by_key(|(ts, _)| *ts);
+        for (_, k) in by_age.into_iter().take(len - keep) {
+            cache.remove(&k);
         }
-    }
+    });
 }
 
 const STALE_NS: u64 = WEEK_NS; // one week
 
+/// Holdings of `principal` in `pool` at `height`, fetched with `fetch` unless
+/// cached. Failed fetches are returned as they are and not cached.
 pub async fn get_or_fetch<F, Fut>(
     principal: Principal,
     pool: &str,
     height: u64,
     fetch: F,
-) -> Vec<Holding>
+) -> Result<Vec<TypedHolding>, FetchError>
 where
     F: FnOnce() -> Fut,
-    Fut: Future<Output = Vec<Holding>>,
+    Fut: Future<Output = Result<Vec<TypedHolding>, FetchError>>,
 {
-    if let Some(mut e) = CACHE.get_mut(&(principal, pool.to_string())) {
+    if let Some(e) = get(principal, pool) {
         if e.height == height && now() - e.ts < STALE_NS {
-            e.ts = now();
-            return e.data.clone();
+            return Ok(e.data);
         }
     }
-    let data = fetch().await;
+    let data = fetch().await?;
     let ts = now();
-    CACHE.insert(
-        (principal, pool.to_string()),
+    insert(
+        principal,
+        pool.to_string(),
         Entry {
             data: data.clone(),
             height,
@@ -102,16 +143,26 @@ where
         },
     );
     evict_excess();
-    data
+    Ok(data)
 }
 
 pub fn evict_stale() {
     let n = now();
-    CACHE.retain(|_, v| n - v.ts < STALE_NS);
+    CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        let stale: Vec<Key> = cache
+            .iter()
+            .filter(|(_, v)| v.0.as_ref().is_none_or(|e| n - e.ts >= STALE_NS))
+            .map(|(k, _)| k)
+            .collect();
+        for k in stale {
+            cache.remove(&k);
+        }
+    });
 }
 
 pub fn len() -> usize {
-    CACHE.len()
+    CACHE.with(|c| c.borrow().len() as usize)
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -137,10 +188,60 @@ pub fn schedule_eviction() {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bx_core::Holding;
+    use bx_core::HoldingKind;
     use std::sync::atomic::{AtomicUsize, Ordering};
 
+    fn lp_holding(amount: u64) -> TypedHolding {
+        let kind = HoldingKind::LpPosition { pool: "p1".into() };
+        TypedHolding::new("x", "t", amount.into(), 0, kind)
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn restore_migrates_legacy_entries() {
+        #[derive(candid::CandidType)]
+        struct Legacy {
+            principal: Principal,
+            pool: String,
+            data: Vec<Holding>,
+            height: u64,
+            ts: u64,
+        }
+        let holding = |amount: &str| Holding {
+            source: "Sonic".into(),
+            token: "AAA".into(),
+            amount: amount.into(),
+            status: "lp_escrow".into(),
+            subaccount: None,
+            wallet: None,
+        };
+        let legacy = vec![
+            Legacy {
+                principal: Principal::anonymous(),
+                pool: "legacy".into(),
+                data: vec![holding("1.50")],
+                height: 1,
+                ts: 1,
+            },
+            Legacy {
+                principal: Principal::anonymous(),
+                pool: "broken".into(),
+                data: vec![holding("n/a")],
+                height: 1,
+                ts: 1,
+            },
+        ];
+        let bytes = candid::encode_one(legacy).unwrap();
+        let entries: Vec<StableEntry> = candid::decode_one(&bytes).unwrap();
+        assert!(entries[0].holdings.is_none());
+        stable_restore(entries);
+        let restored = get(Principal::anonymous(), "legacy").unwrap().data;
+        assert_eq!(restored[0].to_legacy(), holding("1.50"));
+        assert!(get(Principal::anonymous(), "broken").is_none());
+    }
+
     #[tokio::test(flavor = "current_thread")]
+    #[serial_test::serial]
     async fn cache_respects_height() {
         static CALLS: AtomicUsize = AtomicUsize::new(0);
         let principal = Principal::from_text("aaaaa-aa").unwrap();
@@ -148,33 +249,56 @@ mod tests {
         let h1 = 1u64;
         let v1 = get_or_fetch(principal, pool, h1, || async {
             CALLS.fetch_add(1, Ordering::SeqCst);
-            vec![Holding {
-                source: "x".into(),
-                token: "t".into(),
-                amount: "1".into(),
-                status: "lp_escrow".into(),
-            }]
+            Ok(vec![lp_holding(1)])
         })
-        .await;
+        .await
+        .unwrap();
         assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        let fetched_at = get(principal, pool).unwrap().ts;
         let v2 = get_or_fetch(principal, pool, h1, || async {
             CALLS.fetch_add(1, Ordering::SeqCst);
-            vec![]
+            Ok(vec![])
         })
-        .await;
+        .await
+        .unwrap();
         assert_eq!(CALLS.load(Ordering::SeqCst), 1);
         assert_eq!(v2, v1);
-        let v3 = get_or_fetch(principal, pool, h1 + 1, || async {
+        // hits don't rewrite the entry
+        assert_eq!(get(principal, pool).unwrap().ts, fetched_at);
+        // failures are passed on and leave the cached entry alone
+        let failed = get_or_fetch(principal, pool, h1 + 1, || async {
             CALLS.fetch_add(1, Ordering::SeqCst);
-            vec![Holding {
-                source: "x".into(),
-                token: "t".into(),
-                amount: "2".into(),
-                status: "lp_escrow".into(),
-            }]
+            Err(FetchError::Network("rejected".into()))
         })
         .await;
-        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
-        assert_eq!(v3[0].amount, "2");
+        assert_eq!(failed, Err(FetchError::Network("rejected".into())));
+        assert_eq!(get(principal, pool).unwrap().height, h1);
+        let v3 = get_or_fetch(principal, pool, h1 + 1, || async {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![lp_holding(2)])
+        })
+        .await
+        .unwrap();
+        assert_eq!(CALLS.load(Ordering::SeqCst), 3);
+        assert_eq!(v3[0].formatted_amount(), "2");
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn excess_is_evicted_oldest_first_in_one_batch() {
+        let limit = crate::config::get().lp_cache_size as usize;
+        let principal = Principal::from_slice(&[0x1C]);
+        for ts in 0..=limit as u64 {
+            let entry = Entry {
+                data: Vec::new(),
+                height: 1,
+                ts: ts + 1,
+            };
+            insert(principal, format!("pool{ts}"), entry);
+        }
+        evict_excess();
+        assert_eq!(len(), limit - limit / 10);
+        assert!(get(principal, "pool0").is_none());
+        assert!(get(principal, &format!("pool{limit}")).is_some());
     }
 }
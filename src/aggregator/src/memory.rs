@@ -0,0 +1,120 @@
+use candid::CandidType;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, Storable};
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Stable memory is split into virtual memories by `MemoryManager`. Ids are
+// part of the on-chain layout: never reuse or renumber them.
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Id 0 is unused: state serialised by `pre_upgrade` only ever lived at
+// offset 0 of raw stable memory, see `legacy_layout`.
+
+/// Portfolio history, see `history`
+pub const HISTORY: MemoryId = MemoryId::new(1);
+pub const USER_SETTINGS: MemoryId = MemoryId::new(2);
+pub const LEDGER_METADATA: MemoryId = MemoryId::new(3);
+pub const LP_CACHE: MemoryId = MemoryId::new(4);
+pub const METRICS: MemoryId = MemoryId::new(5);
+pub const CYCLES_LOG: MemoryId = MemoryId::new(6);
+/// Layout version, see `migrations`
+pub const STATE_VERSION: MemoryId = MemoryId::new(7);
+/// Runtime config overrides and their audit trail, see `config`
+pub const CONFIG: MemoryId = MemoryId::new(8);
+pub const CONFIG_AUDIT: MemoryId = MemoryId::new(9);
+/// Adapters managed by controllers, see `dex::registry`
+pub const ADAPTERS: MemoryId = MemoryId::new(10);
+/// History keys ordered by time, so pruning is a range scan
+pub const HISTORY_BY_TS: MemoryId = MemoryId::new(11);
+/// Entries of the holdings cache, see `cache`
+pub const HOLDINGS_CACHE: MemoryId = MemoryId::new(12);
+/// Enabled flags of `ledgers.toml` adapters, see `dex::registry`
+pub const ADAPTER_FLAGS: MemoryId = MemoryId::new(13);
+
+thread_local! {
+    static MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn get(id: MemoryId) -> Memory {
+    MANAGER.with(|m| m.borrow().get(id))
+}
+
+/// True when stable memory still holds a snapshot written directly by
+/// `ic_cdk::storage::stable_save` before the memory manager was introduced.
+/// Must be checked before anything touches [`get`], which claims the layout.
+#[cfg(target_arch = "wasm32")]
+pub fn legacy_layout() -> bool {
+    if ic_cdk::api::stable::stable64_size() == 0 {
+        return false;
+    }
+    let mut magic = [0u8; 4];
+    ic_cdk::api::stable::stable64_read(0, &mut magic);
+    &magic == b"DIDL"
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn legacy_layout() -> bool {
+    false
+}
+
+/// Stores any candid type in a stable structure. Only for state that every
+/// release can decode: `migrations` rewrites it when its layout changes.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Candid<T>(pub T);
+
+impl<T: CandidType + DeserializeOwned> Storable for Candid<T> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(&self.0).expect("encode stable value"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Candid(candid::decode_one(&bytes).expect("decode stable value"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Stores a cached candid value. A value this release can't decode reads
+/// back as `None`, which caches treat as a miss.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cached<T>(pub Option<T>);
+
+impl<T: CandidType + DeserializeOwned> Storable for Cached<T> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(&self.0).expect("encode stable value"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Cached(candid::decode_one::<Option<T>>(&bytes).ok().flatten())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candid_roundtrip() {
+        let v = Candid((candid::Principal::anonymous(), "pool".to_string()));
+        assert_eq!(Candid::from_bytes(v.to_bytes()), v);
+    }
+
+    #[test]
+    fn undecodable_cached_value_is_none() {
+        let v = Cached(Some(7u64));
+        assert_eq!(Cached::from_bytes(v.to_bytes()), v);
+        let other = Cached(Some("not a number".to_string()));
+        assert_eq!(Cached::<u64>::from_bytes(other.to_bytes()), Cached(None));
+        assert_eq!(
+            Cached::<u64>::from_bytes(Cow::Borrowed(b"junk")),
+            Cached(None)
+        );
+    }
+}
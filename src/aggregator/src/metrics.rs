@@ -1,22 +1,53 @@
+use crate::memory::{self, Memory};
 use candid::CandidType;
-use core::sync::atomic::{AtomicU64, Ordering};
+use ic_stable_structures::StableVec;
 use serde::Serialize;
+use std::cell::RefCell;
 
-static QUERY_COUNT: AtomicU64 = AtomicU64::new(0);
-static HEARTBEAT_COUNT: AtomicU64 = AtomicU64::new(0);
-static LAST_HEARTBEAT: AtomicU64 = AtomicU64::new(0);
-static CLAIM_ATTEMPTS: AtomicU64 = AtomicU64::new(0);
-static CLAIM_SUCCESSES: AtomicU64 = AtomicU64::new(0);
-static CYCLE_REFILL_ATTEMPTS: AtomicU64 = AtomicU64::new(0);
-static CYCLE_REFILL_SUCCESSES: AtomicU64 = AtomicU64::new(0);
-static CYCLES_COLLECTED: AtomicU64 = AtomicU64::new(0);
-static LAST_QUERY_CYCLES: AtomicU64 = AtomicU64::new(0);
+// Counters live in a stable vector, one slot each, so they survive upgrades.
+// Slots are part of the stable layout: append new ones, never reorder.
+const QUERY_COUNT: u64 = 0;
+const HEARTBEAT_COUNT: u64 = 1;
+const LAST_HEARTBEAT: u64 = 2;
+const CLAIM_ATTEMPTS: u64 = 3;
+const CLAIM_SUCCESSES: u64 = 4;
+const CYCLE_REFILL_ATTEMPTS: u64 = 5;
+const CYCLE_REFILL_SUCCESSES: u64 = 6;
+const CYCLES_COLLECTED: u64 = 7;
+const LAST_QUERY_CYCLES: u64 = 8;
+const SLOTS: u64 = 9;
+
+thread_local! {
+    static COUNTERS: RefCell<StableVec<u64, Memory>> = RefCell::new({
+        let v = StableVec::init(memory::get(memory::METRICS)).expect("metrics memory");
+        while v.len() < SLOTS {
+            v.push(&0).expect("grow metrics memory");
+        }
+        v
+    });
+}
+
+fn load(slot: u64) -> u64 {
+    COUNTERS.with(|c| c.borrow().get(slot).unwrap_or(0))
+}
+
+fn store(slot: u64, value: u64) {
+    COUNTERS.with(|c| c.borrow().set(slot, &value));
+}
+
+fn add(slot: u64, amount: u64) {
+    store(slot, load(slot).saturating_add(amount));
+}
 
 #[derive(CandidType, Serialize)]
 pub struct Metrics {
     pub counters: Counters,
     pub cycles: CycleUsage,
     pub caches: Caches,
+    /// Circuit breaker of every registered adapter
+    pub adapters: Vec<crate::breaker::AdapterHealth>,
+    /// Outgoing calls and the time they queued for a slot
+    pub calls: crate::scheduler::SchedulerStats,
 }
 
 #[derive(CandidType, Serialize)]
@@ -40,41 +71,49 @@ pub struct CycleUsage {
 #[derive(CandidType, Serialize)]
 pub struct Caches {
     pub holdings: usize,
+    pub holdings_capacity: usize,
+    /// Lookups served from a fresh entry
+    pub holdings_hits: u64,
+    /// Lookups served from a stale entry while it was refreshed
+    pub holdings_stale_hits: u64,
+    pub holdings_misses: u64,
+    /// Share of lookups served from the cache, fresh or stale
+    pub holdings_hit_rate: f64,
     pub lp: usize,
     pub metadata: usize,
 }
 
 pub fn inc_query() {
-    QUERY_COUNT.fetch_add(1, Ordering::Relaxed);
+    add(QUERY_COUNT, 1);
 }
 
 pub fn inc_claim_attempt() {
-    CLAIM_ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+    add(CLAIM_ATTEMPTS, 1);
 }
 
 pub fn inc_claim_success() {
-    CLAIM_SUCCESSES.fetch_add(1, Ordering::Relaxed);
+    add(CLAIM_SUCCESSES, 1);
 }
 
 pub fn inc_cycle_refill_attempt() {
-    CYCLE_REFILL_ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+    add(CYCLE_REFILL_ATTEMPTS, 1);
 }
 
 pub fn inc_cycle_refill_success() {
-    CYCLE_REFILL_SUCCESSES.fetch_add(1, Ordering::Relaxed);
+    add(CYCLE_REFILL_SUCCESSES, 1);
 }
 
 pub fn add_cycles_collected(amount: u128) {
-    CYCLES_COLLECTED.fetch_add(amount as u64, Ordering::Relaxed);
+    add(CYCLES_COLLECTED, amount as u64);
 }
 
 pub fn record_query_cycles(amount: u64) {
-    LAST_QUERY_CYCLES.store(amount, Ordering::Relaxed);
+    store(LAST_QUERY_CYCLES, amount);
 }
 
 pub fn inc_heartbeat(now: u64) {
-    HEARTBEAT_COUNT.fetch_add(1, Ordering::Relaxed);
-    LAST_HEARTBEAT.store(now, Ordering::Relaxed);
+    add(HEARTBEAT_COUNT, 1);
+    store(LAST_HEARTBEAT, now);
 }
 
 pub fn get() -> Metrics {
@@ -83,59 +122,65 @@ pub fn get() -> Metrics {
     } else {
         0
     };
+    let holdings = crate::cache::get().stats();
+    let lookups = holdings.hits + holdings.stale_hits + holdings.misses;
     Metrics {
         cycles: CycleUsage {
             current: cycles,
-            collected: CYCLES_COLLECTED.load(Ordering::Relaxed),
-            last_query: LAST_QUERY_CYCLES.load(Ordering::Relaxed),
+            collected: load(CYCLES_COLLECTED),
+            last_query: load(LAST_QUERY_CYCLES),
         },
         counters: Counters {
-            query_count: QUERY_COUNT.load(Ordering::Relaxed),
-            heartbeat_count: HEARTBEAT_COUNT.load(Ordering::Relaxed),
-            last_heartbeat: LAST_HEARTBEAT.load(Ordering::Relaxed),
-            claim_attempts: CLAIM_ATTEMPTS.load(Ordering::Relaxed),
-            claim_successes: CLAIM_SUCCESSES.load(Ordering::Relaxed),
-            cycle_refill_attempts: CYCLE_REFILL_ATTEMPTS.load(Ordering::Relaxed),
-            cycle_refill_successes: CYCLE_REFILL_SUCCESSES.load(Ordering::Relaxed),
+            query_count: load(QUERY_COUNT),
+            heartbeat_count: load(HEARTBEAT_COUNT),
+            last_heartbeat: load(LAST_HEARTBEAT),
+            claim_attempts: load(CLAIM_ATTEMPTS),
+            claim_successes: load(CLAIM_SUCCESSES),
+            cycle_refill_attempts: load(CYCLE_REFILL_ATTEMPTS),
+            cycle_refill_successes: load(CYCLE_REFILL_SUCCESSES),
         },
         caches: Caches {
-            holdings: crate::cache::get().len(),
+            holdings: holdings.len,
+            holdings_capacity: holdings.capacity,
+            holdings_hits: holdings.hits,
+            holdings_stale_hits: holdings.stale_hits,
+            holdings_misses: holdings.misses,
+            holdings_hit_rate: if lookups == 0 {
+                0.0
+            } else {
+                (holdings.hits + holdings.stale_hits) as f64 / lookups as f64
+            },
             lp: crate::lp_cache::len(),
             metadata: crate::ledger_fetcher::len(),
         },
+        adapters: crate::breaker::all(),
+        calls: crate::scheduler::stats(),
     }
 }
 
-#[cfg(target_arch = "wasm32")]
-pub fn stable_save() -> (u64, u64, u64, u64, u64, u64, u64, u64) {
-    (
-        QUERY_COUNT.load(Ordering::Relaxed),
-        HEARTBEAT_COUNT.load(Ordering::Relaxed),
-        LAST_HEARTBEAT.load(Ordering::Relaxed),
-        CLAIM_ATTEMPTS.load(Ordering::Relaxed),
-        CLAIM_SUCCESSES.load(Ordering::Relaxed),
-        CYCLE_REFILL_ATTEMPTS.load(Ordering::Relaxed),
-        CYCLE_REFILL_SUCCESSES.load(Ordering::Relaxed),
-        CYCLES_COLLECTED.load(Ordering::Relaxed),
-    )
-}
-
-#[cfg(target_arch = "wasm32")]
+/// Import counters saved by a release that serialised state on upgrade
 pub fn stable_restore(data: (u64, u64, u64, u64, u64, u64, u64, u64)) {
-    QUERY_COUNT.store(data.0, Ordering::Relaxed);
-    HEARTBEAT_COUNT.store(data.1, Ordering::Relaxed);
-    LAST_HEARTBEAT.store(data.2, Ordering::Relaxed);
-    CLAIM_ATTEMPTS.store(data.3, Ordering::Relaxed);
-    CLAIM_SUCCESSES.store(data.4, Ordering::Relaxed);
-    CYCLE_REFILL_ATTEMPTS.store(data.5, Ordering::Relaxed);
-    CYCLE_REFILL_SUCCESSES.store(data.6, Ordering::Relaxed);
-    CYCLES_COLLECTED.store(data.7, Ordering::Relaxed);
-}
-
-#[cfg(not(target_arch = "wasm32"))]
-pub fn stable_save() -> (u64, u64, u64, u64, u64, u64, u64, u64) {
-    (0, 0, 0, 0, 0, 0, 0, 0)
+    store(QUERY_COUNT, data.0);
+    store(HEARTBEAT_COUNT, data.1);
+    store(LAST_HEARTBEAT, data.2);
+    store(CLAIM_ATTEMPTS, data.3);
+    store(CLAIM_SUCCESSES, data.4);
+    store(CYCLE_REFILL_ATTEMPTS, data.5);
+    store(CYCLE_REFILL_SUCCESSES, data.6);
+    store(CYCLES_COLLECTED, data.7);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restore_imports_counters() {
+        stable_restore((1, 2, 3, 4, 5, 6, 7, 8));
+        inc_query();
+        let m = get();
+        assert_eq!(m.counters.query_count, 2);
+        assert_eq!(m.counters.cycle_refill_successes, 7);
+        assert_eq!(m.cycles.collected, 8);
+    }
 }
-
-#[cfg(not(target_arch = "wasm32"))]
-pub fn stable_restore(_: (u64, u64, u64, u64, u64, u64, u64, u64)) {}
@@ -0,0 +1,265 @@
+use crate::memory::{self, Memory};
+use crate::{cycles, ledger_fetcher, lp_cache, metrics, user_settings};
+use candid::de::IDLDeserialize;
+use ic_stable_structures::StableCell;
+use std::cell::RefCell;
+
+// Every layout stable state has had gets a version. Upgrading walks from the
+// version found on chain to `CURRENT_VERSION` one step at a time, so a
+// release only has to know how to decode each old version and how to turn
+// it into the next one.
+//
+// To change the layout: add a `State` variant holding the decoded old data,
+// teach `load` to decode it, add the step to `step` and bump
+// `CURRENT_VERSION`. Fields added to stored candid records must be `Option`
+// so values written before them still decode.
+
+/// Layout written by this release
+pub const CURRENT_VERSION: u32 = 2;
+
+/// Metrics counters in the order version 1 serialised them
+pub type MetricsV1 = (u64, u64, u64, u64, u64, u64, u64, u64);
+
+/// Everything `pre_upgrade` serialised in version 1, after the leading
+/// version number
+pub struct StateV1 {
+    pub cycle_log: Vec<String>,
+    pub metadata: Vec<ledger_fetcher::StableMeta>,
+    pub lp_cache: Vec<lp_cache::StableEntry>,
+    pub user_settings: Vec<user_settings::StableEntry>,
+    pub metrics: MetricsV1,
+}
+
+/// Stable state at some version on its way to `CURRENT_VERSION`
+pub enum State {
+    /// Candid snapshot written by `pre_upgrade`
+    V1(StateV1),
+    /// Settings, caches, metrics and history in stable structures
+    V2,
+}
+
+impl State {
+    pub fn version(&self) -> u32 {
+        match self {
+            State::V1(_) => 1,
+            State::V2 => 2,
+        }
+    }
+}
+
+thread_local! {
+    /// Version of the layout in stable memory. Zero means it was never
+    /// recorded: a fresh canister or one installed by the first release
+    /// using stable structures, which is version 2.
+    static VERSION: RefCell<StableCell<u32, Memory>> = RefCell::new(
+        StableCell::init(memory::get(memory::STATE_VERSION), 0).expect("init state version"),
+    );
+}
+
+fn stored_version() -> u32 {
+    VERSION.with(|v| *v.borrow().get())
+}
+
+fn set_version(version: u32) {
+    VERSION.with(|v| {
+        v.borrow_mut().set(version).expect("write state version");
+    });
+}
+
+/// Decode a snapshot written by `pre_upgrade`. Its first value is the
+/// version the rest was written with.
+pub fn decode_snapshot(bytes: &[u8]) -> Result<State, String> {
+    let mut de = IDLDeserialize::new(bytes).map_err(|e| format!("invalid snapshot: {e}"))?;
+    let version: u32 = de
+        .get_value()
+        .map_err(|e| format!("invalid snapshot version: {e}"))?;
+    let decode_err = |e: candid::Error| format!("invalid version {version} snapshot: {e}");
+    match version {
+        1 => Ok(State::V1(StateV1 {
+            cycle_log: de.get_value().map_err(decode_err)?,
+            metadata: de.get_value().map_err(decode_err)?,
+            lp_cache: de.get_value().map_err(decode_err)?,
+            user_settings: de.get_value().map_err(decode_err)?,
+            metrics: de.get_value().map_err(decode_err)?,
+        })),
+        v => Err(format!("unknown snapshot version {v}")),
+    }
+}
+
+/// The state left by the previous release. Releases that serialised state
+/// in `pre_upgrade` wrote their snapshot at offset 0, so that is checked
+/// before the memory manager claims stable memory.
+#[cfg(target_arch = "wasm32")]
+fn load() -> Result<State, String> {
+    if memory::legacy_layout() {
+        return decode_snapshot(&ic_cdk::api::stable::stable_bytes());
+    }
+    load_managed()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn load() -> Result<State, String> {
+    load_managed()
+}
+
+fn load_managed() -> Result<State, String> {
+    match stored_version() {
+        0 | 2 => Ok(State::V2),
+        v if v > CURRENT_VERSION => Err(format!(
+            "state version {v} is newer than this release ({CURRENT_VERSION})"
+        )),
+        v => Err(format!("unknown state version {v}")),
+    }
+}
+
+/// Move `state` one version forward
+fn step(state: State) -> Result<State, String> {
+    match state {
+        State::V1(s) => {
+            cycles::set_log(s.cycle_log);
+            ledger_fetcher::stable_restore(s.metadata);
+            lp_cache::stable_restore(s.lp_cache);
+            user_settings::stable_restore(s.user_settings);
+            metrics::stable_restore(s.metrics);
+            Ok(State::V2)
+        }
+        State::V2 => Err("no migration past version 2".into()),
+    }
+}
+
+/// Walk `state` up to `CURRENT_VERSION` and record it
+pub fn migrate(mut state: State) -> Result<(), String> {
+    while state.version() < CURRENT_VERSION {
+        let from = state.version();
+        state = step(state).map_err(|e| format!("migration from version {from}: {e}"))?;
+        tracing::info!("migrated stable state from version {from}");
+    }
+    set_version(CURRENT_VERSION);
+    Ok(())
+}
+
+/// Bring whatever the previous release left in stable memory up to date.
+/// Called from `post_upgrade`; an error should trap so the upgrade is rolled
+/// back instead of losing state.
+pub fn run() -> Result<(), String> {
+    migrate(load()?)
+}
+
+/// Record a fresh install as current
+pub fn init() {
+    set_version(CURRENT_VERSION);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::{CandidType, Principal};
+
+    #[derive(CandidType)]
+    struct MetaV1 {
+        cid: Principal,
+        symbol: String,
+        decimals: u8,
+        fee: u64,
+        hash: Vec<u8>,
+        expires: u64,
+        last_used: u64,
+    }
+
+    #[derive(CandidType)]
+    struct SettingsV1 {
+        preferred_ledgers: Vec<String>,
+        preferred_dexes: Vec<String>,
+        dark_mode: bool,
+    }
+
+    #[derive(CandidType)]
+    struct SettingsEntryV1 {
+        principal: Principal,
+        settings: SettingsV1,
+    }
+
+    #[derive(CandidType)]
+    struct LpEntryV1 {
+        principal: Principal,
+        pool: String,
+        data: Vec<bx_core::Holding>,
+        height: u64,
+        ts: u64,
+    }
+
+    /// Snapshot exactly as the first release's `pre_upgrade` wrote it
+    fn snapshot_v1(user: Principal, ledger: Principal) -> Vec<u8> {
+        candid::encode_args((
+            1u32,
+            vec!["refilled".to_string()],
+            vec![MetaV1 {
+                cid: ledger,
+                symbol: "TKN".into(),
+                decimals: 8,
+                fee: 10,
+                hash: vec![0; 32],
+                expires: u64::MAX,
+                last_used: 1,
+            }],
+            vec![LpEntryV1 {
+                principal: user,
+                pool: "pool".into(),
+                data: Vec::new(),
+                height: 1,
+                ts: 1,
+            }],
+            vec![SettingsEntryV1 {
+                principal: user,
+                settings: SettingsV1 {
+                    preferred_ledgers: vec![ledger.to_text()],
+                    preferred_dexes: vec!["icpswap".into()],
+                    dark_mode: true,
+                },
+            }],
+            (7u64, 6u64, 5u64, 4u64, 3u64, 2u64, 1u64, 9u64),
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn v1_snapshot_migrates_to_current() {
+        let user = Principal::from_slice(&[0xA1]);
+        let ledger = Principal::from_slice(&[0xA2]);
+        let state = decode_snapshot(&snapshot_v1(user, ledger)).unwrap();
+        assert_eq!(state.version(), 1);
+        migrate(state).unwrap();
+        assert_eq!(stored_version(), CURRENT_VERSION);
+
+        let settings = user_settings::get(&user).unwrap();
+        assert_eq!(settings.preferred_dexes, vec!["icpswap".to_string()]);
+        assert!(settings.dark_mode);
+        assert!(settings.subaccounts.is_none());
+        assert!(settings.track_history.is_none());
+        assert_eq!(ledger_fetcher::len(), 1);
+        let m = metrics::get();
+        assert_eq!(m.counters.query_count, 7);
+        assert_eq!(m.cycles.collected, 9);
+    }
+
+    #[test]
+    fn current_state_needs_no_steps() {
+        assert!(matches!(load_managed(), Ok(State::V2)));
+        migrate(State::V2).unwrap();
+        assert_eq!(stored_version(), CURRENT_VERSION);
+        set_version(CURRENT_VERSION + 1);
+        assert!(load_managed().err().unwrap().contains("newer"));
+    }
+
+    #[test]
+    fn bad_snapshots_are_errors() {
+        assert!(decode_snapshot(b"not candid").is_err());
+        let unknown = candid::encode_args((99u32, 1u8)).unwrap();
+        assert_eq!(
+            decode_snapshot(&unknown).err().unwrap(),
+            "unknown snapshot version 99"
+        );
+        let truncated = candid::encode_args((1u32, vec!["log".to_string()])).unwrap();
+        assert!(decode_snapshot(&truncated).is_err());
+    }
+}
@@ -1,20 +1,337 @@
-use bx_core::Holding;
-use candid::Principal;
+use crate::error::FetchError;
+use crate::scheduler;
+use crate::utils::call_query;
+use crate::utils::{now, DAY_SECS};
+use bx_core::{HoldingKind, TypedHolding};
+use candid::types::value::IDLValue;
+use candid::{CandidType, Decode, Encode, Principal};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
 
-#[cfg(target_arch = "wasm32")]
-async fn sleep_ms(_: u64) {}
+// Neurons are discovered through the NNS governance canister. `list_neurons`
+// only returns neurons readable by the caller, so users add the aggregator as
+// a hotkey; the controller field is then used to attribute each neuron.
+//
+// The aggregator can't ask for one principal's neurons, so every fetch lists
+// all neurons that made it a hotkey: its cost grows with the number of such
+// neurons across all users, not with this user's. The reply's `neuron_infos`
+// carry no controller, so an id without a full neuron is only followed up
+// with `get_full_neuron` when an earlier listing showed it belongs to the
+// principal, and at most `MAX_FOLLOW_UPS` of them per fetch.
+
+/// Mainnet NNS governance canister used when `NNS_GOVERNANCE` is not set
+pub const DEFAULT_GOVERNANCE: &str = "rrkah-fqaaa-aaaaa-aaaaq-cai";
+
+/// ICP uses eight decimals for e8s amounts
+const ICP_DECIMALS: u8 = 8;
+/// Seconds in an average year (365.25 days)
+const YEAR_SECS: u64 = 31_557_600;
+/// Most `get_full_neuron` calls one fetch makes
+const MAX_FOLLOW_UPS: usize = 16;
+
+/// Controller of each listed neuron, learned from full neurons
+static CONTROLLERS: Lazy<DashMap<u64, Principal>> = Lazy::new(DashMap::new);
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NeuronId {
+    pub id: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum DissolveState {
+    DissolveDelaySeconds(u64),
+    WhenDissolvedTimestampSeconds(u64),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Neuron {
+    pub id: Option<NeuronId>,
+    pub controller: Option<Principal>,
+    pub hot_keys: Vec<Principal>,
+    pub cached_neuron_stake_e8s: u64,
+    pub neuron_fees_e8s: u64,
+    pub maturity_e8s_equivalent: u64,
+    pub staked_maturity_e8s_equivalent: Option<u64>,
+    pub dissolve_state: Option<DissolveState>,
+}
+
+impl Neuron {
+    /// Stake net of fees plus maturity and staked maturity, in e8s
+    pub fn total_e8s(&self) -> u64 {
+        self.cached_neuron_stake_e8s
+            .saturating_sub(self.neuron_fees_e8s)
+            .saturating_add(self.maturity_e8s_equivalent)
+            .saturating_add(self.staked_maturity_e8s_equivalent.unwrap_or(0))
+    }
+}
+
+#[derive(CandidType)]
+struct ListNeurons {
+    neuron_ids: Vec<u64>,
+    include_neurons_readable_by_caller: bool,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ListNeuronsResponse {
+    neuron_infos: Vec<(u64, IDLValue)>,
+    full_neurons: Vec<Neuron>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GovernanceError {
+    error_type: i32,
+    error_message: String,
+}
+
+impl From<GovernanceError> for FetchError {
+    fn from(e: GovernanceError) -> Self {
+        FetchError::Network(format!(
+            "governance error {}: {}",
+            e.error_type, e.error_message
+        ))
+    }
+}
 
 #[cfg(not(target_arch = "wasm32"))]
-async fn sleep_ms(ms: u64) {
-    tokio::time::sleep(std::time::Duration::from_millis(ms)).await;
-}
-
-pub async fn fetch(_principal: Principal) -> Vec<Holding> {
-    sleep_ms(7).await;
-    vec![Holding {
-        source: "neuron".to_string(),
-        token: "ICP".to_string(),
-        amount: "1200".to_string(),
-        status: "locked_8y".to_string(),
-    }]
+pub fn governance_id() -> Principal {
+    std::env::var("NNS_GOVERNANCE")
+        .ok()
+        .and_then(|s| Principal::from_text(s).ok())
+        .unwrap_or_else(|| Principal::from_text(DEFAULT_GOVERNANCE).expect("governance id"))
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn governance_id() -> Principal {
+    option_env!("NNS_GOVERNANCE")
+        .and_then(|s| Principal::from_text(s).ok())
+        .unwrap_or_else(|| Principal::from_text(DEFAULT_GOVERNANCE).expect("governance id"))
+}
+
+fn list_neurons_arg() -> ListNeurons {
+    ListNeurons {
+        neuron_ids: Vec::new(),
+        include_neurons_readable_by_caller: true,
+    }
+}
+
+async fn list_neurons(gov: Principal) -> Result<ListNeuronsResponse, FetchError> {
+    let arg = Encode!(&list_neurons_arg()).map_err(|_| FetchError::InvalidResponse)?;
+    let bytes = call_query(gov, "list_neurons", arg).await?;
+    Decode!(&bytes, ListNeuronsResponse).map_err(|_| FetchError::InvalidResponse)
+}
+
+async fn get_full_neuron(gov: Principal, id: u64) -> Result<Neuron, FetchError> {
+    let arg = Encode!(&id).map_err(|_| FetchError::InvalidResponse)?;
+    let bytes = call_query(gov, "get_full_neuron", arg).await?;
+    let res = Decode!(&bytes, Result<Neuron, GovernanceError>)
+        .map_err(|_| FetchError::InvalidResponse)?;
+    res.map_err(FetchError::from)
+}
+
+/// Ids listed without a full neuron whose controller isn't known to be
+/// someone other than `principal`, known ones first, at most
+/// `MAX_FOLLOW_UPS` of them
+fn follow_ups(resp: &ListNeuronsResponse, principal: Principal) -> Vec<u64> {
+    let full: std::collections::HashSet<u64> = resp
+        .full_neurons
+        .iter()
+        .filter_map(|n| n.id.as_ref().map(|i| i.id))
+        .collect();
+    let mut ids: Vec<(bool, u64)> = resp
+        .neuron_infos
+        .iter()
+        .map(|(id, _)| *id)
+        .filter(|id| !full.contains(id))
+        .filter_map(|id| match CONTROLLERS.get(&id).map(|c| *c) {
+            Some(c) if c != principal => None,
+            known => Some((known.is_none(), id)),
+        })
+        .collect();
+    ids.sort_unstable();
+    ids.into_iter()
+        .take(MAX_FOLLOW_UPS)
+        .map(|(_, id)| id)
+        .collect()
+}
+
+/// Forget neurons that are gone from the listing in `resp`
+fn forget_unlisted(resp: &ListNeuronsResponse) {
+    let listed: std::collections::HashSet<u64> =
+        resp.neuron_infos.iter().map(|(id, _)| *id).collect();
+    CONTROLLERS.retain(|id, _| listed.contains(id));
+}
+
+/// Remember the controllers of `neurons`
+fn learn_controllers<'a>(neurons: impl IntoIterator<Item = &'a Neuron>) {
+    for n in neurons {
+        if let (Some(id), Some(controller)) = (&n.id, n.controller) {
+            CONTROLLERS.insert(id.id, controller);
+        }
+    }
+}
+
+/// Human readable lock status such as `locked_8y`, `dissolving` or `dissolved`
+pub fn dissolve_status(state: Option<&DissolveState>, now_secs: u64) -> String {
+    match state {
+        Some(DissolveState::DissolveDelaySeconds(d)) if *d >= YEAR_SECS => {
+            format!("locked_{}y", d / YEAR_SECS)
+        }
+        Some(DissolveState::DissolveDelaySeconds(d)) if *d > 0 => {
+            format!("locked_{}d", d / DAY_SECS)
+        }
+        Some(DissolveState::WhenDissolvedTimestampSeconds(ts)) if *ts > now_secs => {
+            "dissolving".to_string()
+        }
+        _ => "dissolved".to_string(),
+    }
+}
+
+fn to_holding(neuron: &Neuron, now_secs: u64) -> TypedHolding {
+    let (dissolve_delay_secs, dissolved_at_secs) = match neuron.dissolve_state {
+        Some(DissolveState::DissolveDelaySeconds(d)) => (Some(d), None),
+        Some(DissolveState::WhenDissolvedTimestampSeconds(ts)) => (None, Some(ts)),
+        None => (None, None),
+    };
+    let kind = HoldingKind::Neuron {
+        neuron_id: neuron.id.as_ref().map_or(0, |i| i.id),
+        dissolve_delay_secs,
+        dissolved_at_secs,
+        lock: dissolve_status(neuron.dissolve_state.as_ref(), now_secs),
+    };
+    TypedHolding {
+        ledger: Some(crate::ledger_fetcher::icp_ledger()),
+        ..TypedHolding::new(
+            "neuron",
+            "ICP",
+            neuron.total_e8s().into(),
+            ICP_DECIMALS,
+            kind,
+        )
+    }
+}
+
+/// Fetch every neuron controlled by `principal` that the governance canister
+/// exposes to the aggregator.
+pub async fn list(principal: Principal) -> Result<Vec<Neuron>, FetchError> {
+    let gov = governance_id();
+    let resp = list_neurons(gov).await?;
+    forget_unlisted(&resp);
+    learn_controllers(&resp.full_neurons);
+    let ids = follow_ups(&resp, principal);
+    let mut neurons = resp.full_neurons;
+    let fetched = scheduler::join_all_to(gov, ids.iter().map(|id| get_full_neuron(gov, *id)));
+    for (id, res) in ids.iter().zip(fetched.await) {
+        match res {
+            Ok(n) => {
+                learn_controllers([&n]);
+                neurons.push(n);
+            }
+            Err(e) => tracing::warn!("neuron {id} unavailable: {e}"),
+        }
+    }
+    neurons.retain(|n| n.controller == Some(principal));
+    neurons.sort_by_key(|n| n.id.as_ref().map(|i| i.id));
+    Ok(neurons)
+}
+
+pub async fn fetch(principal: Principal) -> Result<Vec<TypedHolding>, FetchError> {
+    let neurons = list(principal).await?;
+    let now_secs = now() / 1_000_000_000;
+    Ok(neurons.iter().map(|n| to_holding(n, now_secs)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck_macros::quickcheck;
+
+    fn neuron(state: Option<DissolveState>) -> Neuron {
+        Neuron {
+            id: Some(NeuronId { id: 1 }),
+            controller: Some(Principal::anonymous()),
+            hot_keys: Vec::new(),
+            cached_neuron_stake_e8s: 120_000_010_000,
+            neuron_fees_e8s: 10_000,
+            maturity_e8s_equivalent: 50_000_000,
+            staked_maturity_e8s_equivalent: Some(25_000_000),
+            dissolve_state: state,
+        }
+    }
+
+    #[test]
+    fn status_labels() {
+        let now = 1_000;
+        let eight_years = DissolveState::DissolveDelaySeconds(8 * YEAR_SECS);
+        assert_eq!(dissolve_status(Some(&eight_years), now), "locked_8y");
+        let half_year = DissolveState::DissolveDelaySeconds(182 * DAY_SECS);
+        assert_eq!(dissolve_status(Some(&half_year), now), "locked_182d");
+        let dissolving = DissolveState::WhenDissolvedTimestampSeconds(now + 1);
+        assert_eq!(dissolve_status(Some(&dissolving), now), "dissolving");
+        let dissolved = DissolveState::WhenDissolvedTimestampSeconds(now);
+        assert_eq!(dissolve_status(Some(&dissolved), now), "dissolved");
+        assert_eq!(dissolve_status(None, now), "dissolved");
+    }
+
+    #[test]
+    fn holding_includes_maturity() {
+        let n = neuron(Some(DissolveState::DissolveDelaySeconds(8 * YEAR_SECS)));
+        let h = to_holding(&n, 0).to_legacy();
+        assert_eq!(h.source, "neuron");
+        assert_eq!(h.token, "ICP");
+        assert_eq!(h.amount, "1200.75000000");
+        assert_eq!(h.status, "locked_8y");
+        let typed = to_holding(&n, 0);
+        assert!(matches!(
+            typed.kind,
+            HoldingKind::Neuron { neuron_id: 1, dissolve_delay_secs: Some(d), .. } if d == 8 * YEAR_SECS
+        ));
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn partial_neurons_not_known_to_be_others_are_followed_up() {
+        let (owner, other) = (
+            Principal::from_slice(&[0x4E, 1]),
+            Principal::from_slice(&[0x4E, 2]),
+        );
+        let full = |id, controller| Neuron {
+            id: Some(NeuronId { id }),
+            controller: Some(controller),
+            ..neuron(None)
+        };
+        let listing = |ids: &[u64], full_neurons| ListNeuronsResponse {
+            neuron_infos: ids.iter().map(|id| (*id, IDLValue::Null)).collect(),
+            full_neurons,
+        };
+        let base = 0x4E00;
+        CONTROLLERS.clear();
+
+        // a neuron never seen in full is followed up, full ones are not
+        let first = listing(&[base + 1, base + 2, base + 3], vec![full(base + 1, owner)]);
+        assert_eq!(follow_ups(&first, owner), vec![base + 2, base + 3]);
+
+        // a follow-up showed base + 2 is someone else's, base + 3 the owner's
+        learn_controllers(&[full(base + 2, other), full(base + 3, owner)]);
+        let resp = listing(&[base + 2, base + 3, base + 4], Vec::new());
+        assert_eq!(follow_ups(&resp, owner), vec![base + 3, base + 4]);
+        assert_eq!(follow_ups(&resp, other), vec![base + 2, base + 4]);
+
+        let many: Vec<u64> = (base + 10..base + 10 + 2 * MAX_FOLLOW_UPS as u64).collect();
+        assert_eq!(
+            follow_ups(&listing(&many, Vec::new()), owner).len(),
+            MAX_FOLLOW_UPS
+        );
+
+        // neurons missing from a listing are forgotten
+        forget_unlisted(&listing(&[base + 4], Vec::new()));
+        assert_eq!(follow_ups(&resp, owner), vec![base + 2, base + 3, base + 4]);
+        CONTROLLERS.clear();
+    }
+
+    #[quickcheck]
+    fn fuzz_decode_list_response(data: Vec<u8>) -> bool {
+        let _ = Decode!(&data, ListNeuronsResponse);
+        true
+    }
 }
@@ -87,7 +87,7 @@ pub async fn refresh() {
     )));
 }
 
-fn load_content(content: &str) {
+pub(crate) fn load_content(content: &str) {
     if let Ok(pf) = toml::from_str::<PoolsFile>(content) {
         let count = pf.pool.len();
         let mut map = HashMap::with_capacity(count);
@@ -118,8 +118,3 @@ pub fn schedule_refresh() {
         }
     });
 }
-
-pub fn graphql(_query: String) -> String {
-    let data = list();
-    serde_json::json!({"data": {"pools": data}}).to_string()
-}
@@ -0,0 +1,368 @@
+use crate::dex::{registry, PoolReserves};
+use crate::error::FetchError;
+use crate::utils::{call_query, now};
+use bx_core::TypedHolding;
+use candid::{CandidType, Decode, Encode, Nat, Principal};
+use num_traits::ToPrimitive;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+// Token prices are quoted in ICP and derived from DEX pool reserves: tokens
+// paired with ICP are priced directly and tokens paired with an already
+// priced token are priced through it. ICP itself is converted to USD with
+// the optional oracle canister or, failing that, a pool against a configured
+// USD stablecoin ledger. Prices are refreshed from a timer only; requests
+// read whatever is cached.
+
+/// Pairs followed away from ICP when pricing a token
+const MAX_HOPS: usize = 2;
+
+static PRICE_TTL_NS: Lazy<u64> = Lazy::new(|| {
+    option_env!("PRICE_TTL_SECS")
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(60)
+        * 1_000_000_000u64
+});
+
+static ICP_USD_TTL_NS: Lazy<u64> = Lazy::new(|| {
+    option_env!("ICP_USD_TTL_SECS")
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(300)
+        * 1_000_000_000u64
+});
+
+#[derive(Clone, Copy, Debug, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct Price {
+    pub icp: f64,
+    pub usd: Option<f64>,
+}
+
+#[derive(Default)]
+struct Prices {
+    /// ICP per whole token, keyed by ledger id
+    icp: HashMap<String, f64>,
+    icp_ts: u64,
+    icp_usd: Option<f64>,
+    icp_usd_ts: u64,
+}
+
+static PRICES: Lazy<RwLock<Prices>> = Lazy::new(|| RwLock::new(Prices::default()));
+
+/// How long a refresh may hold the lease before another may start
+const REFRESH_LEASE_NS: u64 = 5 * crate::utils::MINUTE_NS;
+
+/// Expiry of the lease held by the refresh in flight; 0 when none is. A
+/// refresh that traps never releases it, so it expires instead.
+static REFRESH_LEASE: AtomicU64 = AtomicU64::new(0);
+
+/// Releases the refresh lease when dropped
+struct Lease;
+
+impl Lease {
+    fn acquire(now: u64) -> Option<Lease> {
+        let held = REFRESH_LEASE.load(Ordering::Acquire);
+        if held > now {
+            return None;
+        }
+        REFRESH_LEASE
+            .compare_exchange(
+                held,
+                now + REFRESH_LEASE_NS,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .ok()
+            .map(|_| Lease)
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        REFRESH_LEASE.store(0, Ordering::Release);
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn oracle_id() -> Option<Principal> {
+    std::env::var("PRICE_ORACLE")
+        .ok()
+        .and_then(|s| Principal::from_text(s).ok())
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn oracle_id() -> Option<Principal> {
+    option_env!("PRICE_ORACLE").and_then(|s| Principal::from_text(s).ok())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn usd_ledger() -> Option<String> {
+    std::env::var("USD_LEDGER").ok()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn usd_ledger() -> Option<String> {
+    option_env!("USD_LEDGER").map(str::to_string)
+}
+
+fn icp_key() -> String {
+    crate::ledger_fetcher::icp_ledger().to_text()
+}
+
+/// Key prices are stored under: the ledger id when known, else the symbol.
+/// Unattributed `ICP` holdings map to the ICP ledger.
+pub fn token_key(h: &TypedHolding) -> String {
+    match h.ledger {
+        Some(l) => l.to_text(),
+        None if h.symbol == "ICP" => icp_key(),
+        None => h.symbol.clone(),
+    }
+}
+
+fn units(amount: &Nat, decimals: u8) -> Option<f64> {
+    let v = amount.0.to_f64()? / 10f64.powi(decimals as i32);
+    (v.is_finite() && v > 0.0).then_some(v)
+}
+
+/// ICP price of every token reachable from `icp` within `MAX_HOPS` pools.
+/// When several pools could price a token the one holding the most ICP
+/// value on the already priced side wins.
+pub fn derive_icp_prices(pools: &[PoolReserves], icp: &str) -> HashMap<String, f64> {
+    let mut prices = HashMap::new();
+    prices.insert(icp.to_string(), 1.0);
+    for _ in 0..MAX_HOPS {
+        let mut found: HashMap<String, (f64, f64)> = HashMap::new();
+        for pool in pools {
+            let (Some(a), Some(b)) = (
+                units(&pool.reserve_a, pool.decimals_a),
+                units(&pool.reserve_b, pool.decimals_b),
+            ) else {
+                continue;
+            };
+            let sides = [
+                (&pool.token_a, a, &pool.token_b, b),
+                (&pool.token_b, b, &pool.token_a, a),
+            ];
+            for (known, known_units, other, other_units) in sides {
+                let (Some(p), false) = (prices.get(known), prices.contains_key(other)) else {
+                    continue;
+                };
+                let depth = known_units * p;
+                let price = depth / other_units;
+                let better = found.get(other).is_none_or(|(_, d)| depth > *d);
+                if better {
+                    found.insert(other.clone(), (price, depth));
+                }
+            }
+        }
+        if found.is_empty() {
+            break;
+        }
+        prices.extend(found.into_iter().map(|(k, (p, _))| (k, p)));
+    }
+    prices
+}
+
+async fn oracle_icp_usd(oracle: Principal) -> Result<Option<f64>, FetchError> {
+    let arg = Encode!(&"ICP").map_err(|_| FetchError::InvalidResponse)?;
+    let bytes = call_query(oracle, "get_price", arg).await?;
+    Decode!(&bytes, Option<f64>).map_err(|_| FetchError::InvalidResponse)
+}
+
+async fn fetch_pools() -> Vec<PoolReserves> {
+    let adapters: Vec<_> = registry::get()
+        .into_iter()
+        .filter(|e| e.adapter.capabilities().prices)
+        .collect();
+    let results =
+        futures::future::join_all(adapters.iter().map(|a| a.adapter.pool_reserves())).await;
+    let mut pools = Vec::new();
+    for (entry, res) in adapters.iter().zip(results) {
+        match res {
+            Ok(p) => pools.extend(p),
+            Err(e) => tracing::debug!("no reserves from {}: {e}", entry.id()),
+        }
+    }
+    pools
+}
+
+/// Refresh pool prices and the ICP/USD rate whose TTL has passed. Readers
+/// keep using the previous values while one refresh is in flight.
+pub async fn refresh_if_stale() {
+    let now = now();
+    let (pools_stale, usd_stale) = {
+        let p = PRICES.read().unwrap();
+        (
+            p.icp_ts == 0 || now.saturating_sub(p.icp_ts) >= *PRICE_TTL_NS,
+            p.icp_usd_ts == 0 || now.saturating_sub(p.icp_usd_ts) >= *ICP_USD_TTL_NS,
+        )
+    };
+    if !(pools_stale || usd_stale) {
+        return;
+    }
+    let Some(_lease) = Lease::acquire(now) else {
+        return;
+    };
+    if pools_stale {
+        let prices = derive_icp_prices(&fetch_pools().await, &icp_key());
+        let mut p = PRICES.write().unwrap();
+        p.icp = prices;
+        p.icp_ts = now;
+    }
+    if usd_stale {
+        let from_oracle = match oracle_id() {
+            Some(oracle) => oracle_icp_usd(oracle).await.unwrap_or_else(|e| {
+                tracing::warn!("price oracle unavailable: {e}");
+                None
+            }),
+            None => None,
+        };
+        let mut p = PRICES.write().unwrap();
+        let from_pool = usd_ledger()
+            .and_then(|usd| p.icp.get(&usd).copied())
+            .map(|icp_per_usd| 1.0 / icp_per_usd);
+        p.icp_usd = from_oracle.or(from_pool);
+        p.icp_usd_ts = now;
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn schedule_refresh() {
+    use std::time::Duration;
+    let every = Duration::from_nanos(*PRICE_TTL_NS);
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(refresh_if_stale()));
+    ic_cdk_timers::set_timer_interval(every, || ic_cdk::spawn(refresh_if_stale()));
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn schedule_refresh() {
+    use std::time::Duration;
+    tokio::spawn(async {
+        let mut timer = tokio::time::interval(Duration::from_nanos(*PRICE_TTL_NS));
+        loop {
+            timer.tick().await;
+            refresh_if_stale().await;
+        }
+    });
+}
+
+/// Cached price of the token stored under `key`
+pub fn quote(key: &str) -> Option<Price> {
+    let p = PRICES.read().unwrap();
+    let icp = *p.icp.get(key)?;
+    Some(Price {
+        icp,
+        usd: p.icp_usd.map(|r| r * icp),
+    })
+}
+
+pub fn icp_usd() -> Option<f64> {
+    PRICES.read().unwrap().icp_usd
+}
+
+/// Value of a holding in ICP and USD, when its token is priced
+pub fn value(h: &TypedHolding) -> Option<Price> {
+    let price = quote(&token_key(h))?;
+    let amount = units(&h.amount, h.decimals).unwrap_or(0.0);
+    Some(Price {
+        icp: amount * price.icp,
+        usd: price.usd.map(|u| amount * u),
+    })
+}
+
+/// Fill `usd_value` on every holding whose token has a USD price
+pub fn annotate(holdings: &mut [TypedHolding]) {
+    for h in holdings {
+        h.usd_value = value(h).and_then(|v| v.usd);
+    }
+}
+
+/// Replace the cached prices, marking them fresh
+pub fn set(prices: HashMap<String, f64>, icp_usd: Option<f64>) {
+    let now = now();
+    let mut p = PRICES.write().unwrap();
+    p.icp = prices;
+    p.icp_ts = now;
+    p.icp_usd = icp_usd;
+    p.icp_usd_ts = now;
+}
+
+pub fn clear() {
+    *PRICES.write().unwrap() = Prices::default();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bx_core::HoldingKind;
+
+    fn pool(a: &str, ra: u64, b: &str, rb: u64) -> PoolReserves {
+        PoolReserves {
+            token_a: a.into(),
+            decimals_a: 8,
+            reserve_a: ra.into(),
+            token_b: b.into(),
+            decimals_b: 8,
+            reserve_b: rb.into(),
+        }
+    }
+
+    #[test]
+    fn prices_follow_pools_from_icp() {
+        let pools = vec![
+            pool("ICP", 100, "AAA", 400),
+            pool("BBB", 50, "AAA", 100),
+            // shallower AAA pool is ignored
+            pool("AAA", 10, "ICP", 10),
+            pool("CCC", 1, "DDD", 1),
+            pool("EEE", 0, "ICP", 10),
+        ];
+        let prices = derive_icp_prices(&pools, "ICP");
+        assert_eq!(prices["ICP"], 1.0);
+        assert_eq!(prices["AAA"], 0.25);
+        assert_eq!(prices["BBB"], 0.5);
+        assert!(!prices.contains_key("CCC"));
+        assert!(!prices.contains_key("EEE"));
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn values_holdings_from_cache() {
+        let mut prices = HashMap::new();
+        prices.insert(icp_key(), 1.0);
+        prices.insert("AAA".to_string(), 0.25);
+        set(prices, Some(8.0));
+        let mut holdings = vec![
+            TypedHolding::new(
+                "ledger",
+                "ICP",
+                200_000_000u64.into(),
+                8,
+                HoldingKind::Liquid,
+            ),
+            TypedHolding::new("dex", "AAA", 4u64.into(), 0, HoldingKind::Liquid),
+            TypedHolding::new("dex", "ZZZ", 4u64.into(), 0, HoldingKind::Liquid),
+        ];
+        annotate(&mut holdings);
+        assert_eq!(holdings[0].usd_value, Some(16.0));
+        assert_eq!(holdings[1].usd_value, Some(8.0));
+        assert_eq!(holdings[2].usd_value, None);
+        assert_eq!(value(&holdings[1]).unwrap().icp, 1.0);
+        clear();
+        assert!(quote("AAA").is_none());
+    }
+
+    #[test]
+    fn refresh_lease_is_released_or_expires() {
+        let lease = Lease::acquire(1).unwrap();
+        assert!(Lease::acquire(2).is_none());
+        drop(lease);
+        let stuck = Lease::acquire(3).unwrap();
+        // a refresh that trapped never drops its lease
+        std::mem::forget(stuck);
+        assert!(Lease::acquire(4).is_none());
+        assert!(Lease::acquire(3 + REFRESH_LEASE_NS).is_some());
+    }
+}
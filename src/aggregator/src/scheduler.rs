@@ -0,0 +1,681 @@
+use crate::error::FetchError;
+use candid::{CandidType, Principal};
+#[cfg(not(target_arch = "wasm32"))]
+use futures::stream::{self, StreamExt};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Every outgoing query goes through `call`, which keeps at most
+// `CALL_MAX_CONCURRENT` calls in flight overall and `CALL_MAX_PER_CANISTER`
+// against any one canister, and records how long calls queued for a slot.
+// Natively a call waits on a semaphore per target and a global one.
+//
+// Inside the canister ic-cdk's executor can't resume a call parked by another
+// message, and ignores wake-ups sent while the message's own future is being
+// polled, so a call can't be woken when a slot frees up. Slots are counted
+// across messages instead, and a call that finds them full pauses with
+// `utils::pause`, which resumes it in its own message, and tries again. Only
+// a call still without a slot after `CALL_MAX_WAIT_NS`, or one that can't
+// pause, is rejected, with `FetchError::Busy` so adapter breakers don't count
+// it as the adapter failing. Fan-outs run their futures in chunks no larger
+// than the slots left free, starting the next chunk once the previous one is
+// done, so their calls rarely have to wait at all.
+
+static MAX_CONCURRENT: Lazy<usize> = Lazy::new(|| {
+    option_env!("CALL_MAX_CONCURRENT")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(64)
+        .max(1)
+});
+
+static MAX_PER_CANISTER: Lazy<usize> = Lazy::new(|| {
+    option_env!("CALL_MAX_PER_CANISTER")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(8)
+        .max(1)
+});
+
+/// How long a slot counts as taken when its message trapped and never released
+/// it
+#[cfg(any(target_arch = "wasm32", test))]
+const CALL_LEASE_NS: u64 = 5 * crate::utils::MINUTE_NS;
+
+/// How long a call inside the canister waits for a slot before it is rejected
+#[cfg(any(target_arch = "wasm32", test))]
+const CALL_MAX_WAIT_NS: u64 = crate::utils::MINUTE_NS;
+
+static CALLS: AtomicU64 = AtomicU64::new(0);
+static QUEUED: AtomicU64 = AtomicU64::new(0);
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+static IN_FLIGHT: AtomicU64 = AtomicU64::new(0);
+static WAIT_US_TOTAL: AtomicU64 = AtomicU64::new(0);
+static WAIT_US_MAX: AtomicU64 = AtomicU64::new(0);
+
+/// Outgoing calls since the canister started
+#[derive(Clone, Debug, PartialEq, CandidType, Serialize)]
+pub struct SchedulerStats {
+    pub max_concurrent: u64,
+    pub max_per_canister: u64,
+    pub calls: u64,
+    /// Calls that had to wait for a slot
+    pub queued: u64,
+    /// Calls turned away inside the canister after waiting too long for a
+    /// slot
+    pub rejected: u64,
+    pub in_flight: u64,
+    pub avg_wait_us: u64,
+    pub max_wait_us: u64,
+}
+
+pub fn stats() -> SchedulerStats {
+    let calls = CALLS.load(Ordering::Relaxed);
+    SchedulerStats {
+        max_concurrent: *MAX_CONCURRENT as u64,
+        max_per_canister: *MAX_PER_CANISTER as u64,
+        calls,
+        queued: QUEUED.load(Ordering::Relaxed),
+        rejected: REJECTED.load(Ordering::Relaxed),
+        in_flight: IN_FLIGHT.load(Ordering::Relaxed),
+        avg_wait_us: WAIT_US_TOTAL
+            .load(Ordering::Relaxed)
+            .checked_div(calls)
+            .unwrap_or(0),
+        max_wait_us: WAIT_US_MAX.load(Ordering::Relaxed),
+    }
+}
+
+fn record_call() {
+    CALLS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn record_wait(wait_us: u64) {
+    if wait_us > 0 {
+        QUEUED.fetch_add(1, Ordering::Relaxed);
+        WAIT_US_TOTAL.fetch_add(wait_us, Ordering::Relaxed);
+        WAIT_US_MAX.fetch_max(wait_us, Ordering::Relaxed);
+    }
+}
+
+/// Counts a call as in flight until dropped, so timed out calls are released
+struct InFlight;
+
+impl InFlight {
+    fn start() -> Self {
+        IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod slots {
+    use candid::Principal;
+    use dashmap::DashMap;
+    use once_cell::sync::Lazy;
+    use std::sync::Arc;
+    use tokio::sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+
+    static GLOBAL: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(*super::MAX_CONCURRENT));
+    static PER_CANISTER: Lazy<DashMap<Principal, Arc<Semaphore>>> = Lazy::new(DashMap::new);
+
+    fn target(cid: Principal) -> Arc<Semaphore> {
+        PER_CANISTER
+            .entry(cid)
+            .or_insert_with(|| Arc::new(Semaphore::new(*super::MAX_PER_CANISTER)))
+            .clone()
+    }
+
+    pub type Slot = (OwnedSemaphorePermit, SemaphorePermit<'static>);
+
+    /// Wait for a slot at `cid`, then for a global one, returning the time
+    /// spent waiting in microseconds. Taking the canister's slot first keeps
+    /// a busy canister from holding global slots other canisters could use.
+    pub async fn acquire(cid: Principal) -> (Slot, u64) {
+        let start = std::time::Instant::now();
+        let mut waited = false;
+        let sem = target(cid);
+        let own = match sem.clone().try_acquire_owned() {
+            Ok(p) => p,
+            Err(_) => {
+                waited = true;
+                sem.acquire_owned().await.expect("semaphore closed")
+            }
+        };
+        let global = match GLOBAL.try_acquire() {
+            Ok(p) => p,
+            Err(_) => {
+                waited = true;
+                GLOBAL.acquire().await.expect("semaphore closed")
+            }
+        };
+        let wait_us = if waited {
+            (start.elapsed().as_micros() as u64).max(1)
+        } else {
+            0
+        };
+        ((own, global), wait_us)
+    }
+
+    /// Calls to `cid` currently holding a slot
+    #[cfg(test)]
+    pub fn running(cid: Principal) -> usize {
+        *super::MAX_PER_CANISTER - target(cid).available_permits()
+    }
+}
+
+/// Slots of the calls made inside the canister, counted across messages
+#[cfg(any(target_arch = "wasm32", test))]
+mod local {
+    use super::{record_call, record_wait, InFlight, CALL_MAX_WAIT_NS, REJECTED};
+    use crate::error::FetchError;
+    use candid::Principal;
+    use futures::future::{join_all, JoinAll};
+    use futures::ready;
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+    use std::task::{Context, Poll};
+
+    pub struct Limits {
+        global: usize,
+        per_canister: usize,
+        lease_ns: u64,
+        running: Mutex<Running>,
+    }
+
+    #[derive(Default)]
+    struct Running {
+        next: u64,
+        /// Target and start of each call holding a slot
+        calls: HashMap<u64, (Principal, u64)>,
+    }
+
+    impl Running {
+        fn expire(&mut self, now: u64, lease_ns: u64) {
+            self.calls
+                .retain(|_, (_, started)| now.saturating_sub(*started) < lease_ns);
+        }
+
+        fn to(&self, target: Principal) -> usize {
+            self.calls.values().filter(|(t, _)| *t == target).count()
+        }
+    }
+
+    /// Releases its slot when the call finishes or is cancelled
+    pub struct Slot<'a> {
+        limits: &'a Limits,
+        id: u64,
+    }
+
+    impl Drop for Slot<'_> {
+        fn drop(&mut self) {
+            self.limits.running.lock().unwrap().calls.remove(&self.id);
+        }
+    }
+
+    impl Limits {
+        pub fn new(global: usize, per_canister: usize, lease_ns: u64) -> Self {
+            Limits {
+                global: global.max(1),
+                per_canister: per_canister.max(1),
+                lease_ns,
+                running: Mutex::new(Running::default()),
+            }
+        }
+
+        /// Slots a new call could take at `now`, to `target` when given
+        pub fn free(&self, target: Option<Principal>, now: u64) -> usize {
+            let mut running = self.running.lock().unwrap();
+            running.expire(now, self.lease_ns);
+            let global = self.global.saturating_sub(running.calls.len());
+            match target {
+                Some(t) => global.min(self.per_canister.saturating_sub(running.to(t))),
+                None => global,
+            }
+        }
+
+        /// A slot for a call to `target` at `now`, or `None` when the global
+        /// or the canister's limit is reached
+        pub fn try_acquire(&self, target: Principal, now: u64) -> Option<Slot<'_>> {
+            let mut running = self.running.lock().unwrap();
+            running.expire(now, self.lease_ns);
+            if running.calls.len() >= self.global || running.to(target) >= self.per_canister {
+                return None;
+            }
+            let id = running.next;
+            running.next += 1;
+            running.calls.insert(id, (target, now));
+            Some(Slot { limits: self, id })
+        }
+    }
+
+    /// Run `fut` to `target` in a slot of `limits`. While every slot is
+    /// taken the call awaits `pause` and tries again; it is rejected when
+    /// `pause` fails or no slot frees up within `CALL_MAX_WAIT_NS`.
+    pub async fn call<T, F, P>(
+        limits: &Limits,
+        target: Principal,
+        clock: fn() -> u64,
+        pause: fn() -> P,
+        fut: F,
+    ) -> Result<T, FetchError>
+    where
+        F: Future<Output = Result<T, FetchError>>,
+        P: Future<Output = Result<(), FetchError>>,
+    {
+        let start = clock();
+        let _slot = loop {
+            let now = clock();
+            if let Some(slot) = limits.try_acquire(target, now) {
+                if now > start {
+                    record_wait(((now - start) / 1_000).max(1));
+                }
+                break slot;
+            }
+            let waited = now.saturating_sub(start);
+            let reason = if waited >= CALL_MAX_WAIT_NS {
+                format!(
+                    "no free slot for a call to {target} in {}s",
+                    waited / 1_000_000_000
+                )
+            } else {
+                match pause().await {
+                    Ok(()) => continue,
+                    Err(e) => format!("can't wait for a slot for a call to {target}: {e}"),
+                }
+            };
+            REJECTED.fetch_add(1, Ordering::Relaxed);
+            return Err(FetchError::Busy(reason));
+        };
+        record_call();
+        let _in_flight = InFlight::start();
+        fut.await
+    }
+
+    /// Await `futs` in their original order, at most `limit` at once, in
+    /// chunks no larger than the slots of `limits` free at the start of each,
+    /// counting only those to `target` when given. The futures of later
+    /// chunks are reported as having queued since the first one started.
+    pub fn chunked<F: Future>(
+        futs: Vec<F>,
+        limit: usize,
+        limits: &Limits,
+        target: Option<Principal>,
+        clock: fn() -> u64,
+    ) -> Chunked<'_, F> {
+        Chunked {
+            started: clock(),
+            out: Vec::with_capacity(futs.len()),
+            pending: futs.into_iter(),
+            running: None,
+            limit: limit.max(1),
+            limits,
+            target,
+            clock,
+        }
+    }
+
+    // A hand-written future rather than an `async fn`, which would hold the
+    // caller's futures across awaits and keep it from being provably `Send`.
+    pub struct Chunked<'a, F: Future> {
+        pending: std::vec::IntoIter<F>,
+        running: Option<JoinAll<F>>,
+        out: Vec<F::Output>,
+        limit: usize,
+        limits: &'a Limits,
+        target: Option<Principal>,
+        clock: fn() -> u64,
+        started: u64,
+    }
+
+    // the futures are never pinned here; they are moved into `JoinAll`, which
+    // pins them on the heap
+    impl<F: Future> Unpin for Chunked<'_, F> {}
+
+    impl<F: Future> Future for Chunked<'_, F> {
+        type Output = Vec<F::Output>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let this = &mut *self;
+            loop {
+                if let Some(running) = &mut this.running {
+                    let done = ready!(Pin::new(running).poll(cx));
+                    this.out.extend(done);
+                    this.running = None;
+                }
+                if this.pending.len() == 0 {
+                    return Poll::Ready(std::mem::take(&mut this.out));
+                }
+                let now = (this.clock)();
+                // a chunk of one when nothing is free; its call waits for a
+                // slot
+                let n = this.limits.free(this.target, now).clamp(1, this.limit);
+                let chunk: Vec<F> = this.pending.by_ref().take(n).collect();
+                if now > this.started {
+                    let wait_us = ((now - this.started) / 1_000).max(1);
+                    for _ in &chunk {
+                        record_wait(wait_us);
+                    }
+                }
+                this.running = Some(join_all(chunk));
+            }
+        }
+    }
+}
+
+/// Run the call `fut` to `target` once a slot is free
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn call<T, F>(target: Principal, fut: F) -> Result<T, FetchError>
+where
+    F: Future<Output = Result<T, FetchError>>,
+{
+    let (_slot, wait_us) = slots::acquire(target).await;
+    record_call();
+    record_wait(wait_us);
+    let _in_flight = InFlight::start();
+    fut.await
+}
+
+#[cfg(target_arch = "wasm32")]
+static LIMITS: Lazy<local::Limits> =
+    Lazy::new(|| local::Limits::new(*MAX_CONCURRENT, *MAX_PER_CANISTER, CALL_LEASE_NS));
+
+/// Run the call `fut` to `target` once a slot is free, or reject it with
+/// `FetchError::Busy` when none frees up in time
+#[cfg(target_arch = "wasm32")]
+pub async fn call<T, F>(target: Principal, fut: F) -> Result<T, FetchError>
+where
+    F: Future<Output = Result<T, FetchError>>,
+{
+    local::call(&LIMITS, target, ic_cdk::api::time, crate::utils::pause, fut).await
+}
+
+// The fan-out helpers return plain combinators rather than being `async fn`s,
+// which would capture the caller's iterator and its closures and keep the
+// future from being provably `Send`.
+#[cfg(not(target_arch = "wasm32"))]
+type Bounded<F> = stream::Collect<
+    stream::Buffered<stream::Iter<std::vec::IntoIter<F>>>,
+    Vec<<F as Future>::Output>,
+>;
+
+#[cfg(target_arch = "wasm32")]
+type Bounded<F> = local::Chunked<'static, F>;
+
+/// Await `futs` with at most `limit` running at once, in their original order
+#[cfg(not(target_arch = "wasm32"))]
+pub fn bounded<I>(futs: I, limit: usize) -> Bounded<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    let futs: Vec<_> = futs.into_iter().collect();
+    stream::iter(futs).buffered(limit.max(1)).collect()
+}
+
+/// Like `futures::future::join_all` within the global call limit
+#[cfg(not(target_arch = "wasm32"))]
+pub fn join_all<I>(futs: I) -> Bounded<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    bounded(futs, *MAX_CONCURRENT)
+}
+
+/// Like `join_all` for calls that all go to `target`
+#[cfg(not(target_arch = "wasm32"))]
+pub fn join_all_to<I>(_target: Principal, futs: I) -> Bounded<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    bounded(futs, *MAX_PER_CANISTER)
+}
+
+/// Await `futs` in chunks that fit in the free slots, in their original order
+#[cfg(target_arch = "wasm32")]
+pub fn bounded<I>(futs: I, limit: usize) -> Bounded<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    let futs = futs.into_iter().collect();
+    local::chunked(futs, limit, &LIMITS, None, ic_cdk::api::time)
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn join_all<I>(futs: I) -> Bounded<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    bounded(futs, *MAX_CONCURRENT)
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn join_all_to<I>(target: Principal, futs: I) -> Bounded<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Future,
+{
+    let futs = futs.into_iter().collect();
+    local::chunked(
+        futs,
+        *MAX_PER_CANISTER,
+        &LIMITS,
+        Some(target),
+        ic_cdk::api::time,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn calls_to_one_canister_queue_past_the_limit() {
+        let cid = Principal::from_slice(&[0x5C, 1]);
+        let (release, gate) = futures::channel::oneshot::channel::<()>();
+        let gate = gate.shared();
+        let extra = 2;
+        let queued = QUEUED.load(Ordering::Relaxed);
+        let mut calls: Vec<_> = (0..*MAX_PER_CANISTER + extra)
+            .map(|i| {
+                let gate = gate.clone();
+                Box::pin(call(cid, async move {
+                    let _ = gate.await;
+                    Ok(i)
+                }))
+            })
+            .collect();
+        for c in calls.iter_mut() {
+            assert!(futures::poll!(c).is_pending());
+        }
+        assert_eq!(slots::running(cid), *MAX_PER_CANISTER);
+
+        // other canisters aren't held up by the busy one
+        let other = Principal::from_slice(&[0x5C, 2]);
+        assert_eq!(call(other, async { Ok(7) }).await, Ok(7));
+
+        release.send(()).unwrap();
+        let out = futures::future::join_all(calls).await;
+        assert_eq!(
+            out,
+            (0..*MAX_PER_CANISTER + extra).map(Ok).collect::<Vec<_>>()
+        );
+        assert_eq!(slots::running(cid), 0);
+        assert!(QUEUED.load(Ordering::Relaxed) >= queued + extra as u64);
+        assert!(stats().max_wait_us > 0);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn bounded_limits_running_futures_and_keeps_order() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let futs = (0..10).map(|i| {
+            let running = running.clone();
+            let peak = peak.clone();
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                i * 2
+            }
+        });
+        let out = bounded(futs, 3).await;
+        assert_eq!(out, (0..10).map(|i| i * 2).collect::<Vec<_>>());
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn local_slots_are_limited_globally_and_per_canister() {
+        let limits = local::Limits::new(3, 2, 10);
+        let (a, b) = (
+            Principal::from_slice(&[0x5C, 3]),
+            Principal::from_slice(&[0x5C, 4]),
+        );
+        let first = limits.try_acquire(a, 0).unwrap();
+        let _second = limits.try_acquire(a, 0).unwrap();
+        assert!(limits.try_acquire(a, 0).is_none());
+        assert_eq!(limits.free(Some(a), 0), 0);
+        assert_eq!(limits.free(Some(b), 0), 1);
+        let third = limits.try_acquire(b, 0).unwrap();
+        assert!(limits.try_acquire(b, 0).is_none());
+        assert_eq!(limits.free(None, 0), 0);
+
+        drop(first);
+        assert_eq!(limits.free(Some(a), 0), 1);
+        // a message that trapped never drops its slots; they lapse instead
+        std::mem::forget(third);
+        assert_eq!(limits.free(None, 9), 1);
+        assert_eq!(limits.free(None, 10), 3);
+    }
+
+    async fn refuse() -> Result<(), FetchError> {
+        Err(FetchError::Network("no calls from a query".into()))
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[serial_test::serial]
+    async fn local_calls_wait_for_a_free_slot() {
+        let limits = local::Limits::new(1, 1, CALL_LEASE_NS);
+        let (a, b) = (
+            Principal::from_slice(&[0x5C, 5]),
+            Principal::from_slice(&[0x5C, 6]),
+        );
+        let (queued, rejected) = (
+            QUEUED.load(Ordering::Relaxed),
+            REJECTED.load(Ordering::Relaxed),
+        );
+        let (release, gate) = futures::channel::oneshot::channel::<()>();
+        let mut held = Box::pin(local::call(&limits, a, clock, crate::utils::pause, async {
+            let _ = gate.await;
+            Ok(1)
+        }));
+        assert!(futures::poll!(&mut held).is_pending());
+
+        let mut waiting = Box::pin(local::call(&limits, b, clock, crate::utils::pause, async {
+            Ok(2)
+        }));
+        assert!(futures::poll!(&mut waiting).is_pending());
+        CLOCK.fetch_add(2_000, Ordering::SeqCst);
+        release.send(()).unwrap();
+        assert_eq!(held.await, Ok(1));
+        assert_eq!(waiting.await, Ok(2));
+        assert!(QUEUED.load(Ordering::Relaxed) > queued);
+        assert_eq!(REJECTED.load(Ordering::Relaxed), rejected);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[serial_test::serial]
+    async fn local_calls_that_cant_wait_are_rejected_as_busy() {
+        let limits = local::Limits::new(1, 1, CALL_LEASE_NS);
+        let a = Principal::from_slice(&[0x5C, 10]);
+        let rejected = REJECTED.load(Ordering::Relaxed);
+        let _held = limits.try_acquire(a, clock()).unwrap();
+
+        let busy = local::call(&limits, a, clock, refuse, async { Ok(1) }).await;
+        assert!(matches!(busy, Err(FetchError::Busy(_))));
+
+        // a call that waited past the limit gives up without pausing again
+        let slow = || async {
+            CLOCK.fetch_add(CALL_MAX_WAIT_NS, Ordering::SeqCst);
+            Ok(())
+        };
+        let late = local::call(&limits, a, clock, slow, async { Ok(1) }).await;
+        assert!(matches!(late, Err(FetchError::Busy(_))));
+        assert_eq!(REJECTED.load(Ordering::Relaxed), rejected + 2);
+    }
+
+    static CLOCK: AtomicU64 = AtomicU64::new(1_000);
+
+    fn clock() -> u64 {
+        CLOCK.load(Ordering::SeqCst)
+    }
+
+    /// Futures that each make one call of `limits` to `target(i)`, tracking
+    /// the most running at once in `peak`
+    fn local_calls<'a>(
+        limits: &'a local::Limits,
+        n: u8,
+        target: impl Fn(u8) -> Principal,
+        peak: &'a AtomicUsize,
+        running: &'a AtomicUsize,
+    ) -> Vec<impl Future<Output = Result<u8, FetchError>> + 'a> {
+        (0..n)
+            .map(|i| {
+                local::call(limits, target(i), clock, refuse, async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    tokio::task::yield_now().await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    CLOCK.fetch_add(2_000, Ordering::SeqCst);
+                    Ok(i)
+                })
+            })
+            .collect()
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[serial_test::serial]
+    async fn chunked_fan_outs_defer_past_the_free_slots() {
+        let limits = local::Limits::new(3, 2, CALL_LEASE_NS);
+        let (running, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        // another message holds one of the slots throughout
+        let other = limits.try_acquire(Principal::from_slice(&[0x5C, 7]), clock());
+        let queued = QUEUED.load(Ordering::Relaxed);
+
+        let target = |i| Principal::from_slice(&[0x5C, 8, i]);
+        let futs = local_calls(&limits, 5, target, &peak, &running);
+        let out = local::chunked(futs, 64, &limits, None, clock).await;
+        assert_eq!(out, (0..5).map(Ok).collect::<Vec<_>>());
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        // chunks of 2, 2 and 1: the last three futures queued
+        assert!(QUEUED.load(Ordering::Relaxed) >= queued + 3);
+        assert!(stats().max_wait_us >= 4);
+        assert_eq!(limits.free(None, clock()), 2);
+        drop(other);
+
+        // a fan-out to one canister stays within its limit
+        peak.store(0, Ordering::SeqCst);
+        let one = Principal::from_slice(&[0x5C, 9]);
+        let futs = local_calls(&limits, 5, |_| one, &peak, &running);
+        let out = local::chunked(futs, 64, &limits, Some(one), clock).await;
+        assert_eq!(out, (0..5).map(Ok).collect::<Vec<_>>());
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+// Keeps concurrent requests for the same key from each starting their own
+// fan-out to ledgers and DEXes. The first request runs its fetch; the others
+// wait for it and get a copy of its result. Futures are never shared between
+// requests: ic-cdk resumes a future in the call context of whichever message
+// wakes it, so a reply awaited by a second message would be sent from the
+// first one. Waiters instead pause with `utils::pause`, which resumes them in
+// their own message, and check for the result each time.
+//
+// A running key is released when its fetch finishes or is dropped. A trap
+// skips both, so keys also expire after the lease given to `new`; a waiter
+// then runs the fetch itself.
+
+pub struct SingleFlight<K, V> {
+    state: Mutex<State<K, V>>,
+    lease_ns: u64,
+}
+
+struct State<K, V> {
+    /// The running call of each key
+    inflight: HashMap<K, Flight>,
+    /// Results of finished calls that waiters have yet to take
+    done: HashMap<K, Done<V>>,
+}
+
+struct Flight {
+    started: u64,
+    waiters: usize,
+}
+
+struct Done<V> {
+    started: u64,
+    waiters: usize,
+    value: V,
+}
+
+/// Releases `key` when its call finishes or is cancelled
+struct Running<'a, K: Hash + Eq, V> {
+    flight: &'a SingleFlight<K, V>,
+    key: Option<K>,
+    started: u64,
+}
+
+impl<K: Hash + Eq, V> Running<'_, K, V> {
+    /// Release the key and leave `value` to the calls waiting for it
+    fn finish(mut self, value: V) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        let mut state = self.flight.state.lock().unwrap();
+        // the key may have expired and been taken by a newer call
+        if state.inflight.get(&key).map(|f| f.started) != Some(self.started) {
+            return;
+        }
+        let waiters = state.inflight.remove(&key).map_or(0, |f| f.waiters);
+        if waiters > 0 {
+            let started = self.started;
+            state.done.insert(
+                key,
+                Done {
+                    started,
+                    waiters,
+                    value,
+                },
+            );
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> Drop for Running<'_, K, V> {
+    fn drop(&mut self) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        let mut state = self.flight.state.lock().unwrap();
+        if state.inflight.get(&key).map(|f| f.started) == Some(self.started) {
+            state.inflight.remove(&key);
+        }
+    }
+}
+
+/// What a call for a key does next
+enum Turn<'a, K: Hash + Eq, V> {
+    Lead(Running<'a, K, V>),
+    Wait,
+    Take(V),
+}
+
+impl<K, V> SingleFlight<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn new(lease_ns: u64) -> Self {
+        SingleFlight {
+            state: Mutex::new(State {
+                inflight: HashMap::new(),
+                done: HashMap::new(),
+            }),
+            lease_ns,
+        }
+    }
+
+    /// Run `fut` for `key`, or wait for the call for `key` already in flight
+    /// and return a copy of its result. `clock` gives the current time; when
+    /// the running call's key expires, or a pause fails, `fut` is run after
+    /// all.
+    pub async fn run<F>(&self, key: K, clock: fn() -> u64, fut: F) -> V
+    where
+        F: Future<Output = V>,
+    {
+        let mut waiting = None;
+        loop {
+            match self.turn(&key, clock(), &mut waiting) {
+                Turn::Take(value) => return value,
+                Turn::Lead(running) => {
+                    let value = fut.await;
+                    running.finish(value.clone());
+                    return value;
+                }
+                Turn::Wait => {
+                    if let Err(e) = crate::utils::pause().await {
+                        tracing::warn!("can't wait for a call in flight: {e}");
+                        self.leave(&key, &mut waiting);
+                        return fut.await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Take the result of the call `waiting` is registered with, or start
+    /// a call for `key`, or register with the one running
+    fn turn(&self, key: &K, now: u64, waiting: &mut Option<u64>) -> Turn<'_, K, V> {
+        let mut state = self.state.lock().unwrap();
+        let lease = self.lease_ns;
+        state
+            .inflight
+            .retain(|_, f| now.saturating_sub(f.started) < lease);
+        state
+            .done
+            .retain(|_, d| now.saturating_sub(d.started) < lease);
+        if let Some(started) = *waiting {
+            if let Some(done) = state.done.get_mut(key).filter(|d| d.started == started) {
+                let value = done.value.clone();
+                done.waiters -= 1;
+                if done.waiters == 0 {
+                    state.done.remove(key);
+                }
+                return Turn::Take(value);
+            }
+        }
+        match state.inflight.get_mut(key) {
+            Some(f) => {
+                if *waiting != Some(f.started) {
+                    f.waiters += 1;
+                    *waiting = Some(f.started);
+                }
+                Turn::Wait
+            }
+            None => {
+                // the call waited for expired or was cancelled
+                *waiting = None;
+                state.inflight.insert(
+                    key.clone(),
+                    Flight {
+                        started: now,
+                        waiters: 0,
+                    },
+                );
+                Turn::Lead(Running {
+                    flight: self,
+                    key: Some(key.clone()),
+                    started: now,
+                })
+            }
+        }
+    }
+
+    /// Stop waiting for the call `waiting` is registered with
+    fn leave(&self, key: &K, waiting: &mut Option<u64>) {
+        let Some(started) = waiting.take() else {
+            return;
+        };
+        let mut state = self.state.lock().unwrap();
+        if let Some(f) = state.inflight.get_mut(key).filter(|f| f.started == started) {
+            f.waiters = f.waiters.saturating_sub(1);
+        }
+    }
+
+    /// Whether a call for `key` is in flight at `now`
+    pub fn contains(&self, key: &K, now: u64) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .inflight
+            .get(key)
+            .is_some_and(|f| now.saturating_sub(f.started) < self.lease_ns)
+    }
+
+    /// Keys with a call in flight, including expired ones not yet dropped
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().inflight.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static CLOCK: AtomicU64 = AtomicU64::new(0);
+
+    fn clock() -> u64 {
+        CLOCK.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[serial_test::serial]
+    async fn concurrent_calls_wait_for_the_first_one() {
+        CLOCK.store(0, Ordering::SeqCst);
+        let flight: SingleFlight<&str, u32> = SingleFlight::new(10);
+        let (release, gate) = futures::channel::oneshot::channel::<()>();
+        let mut first = Box::pin(flight.run("p", clock, async move {
+            let _ = gate.await;
+            1
+        }));
+        assert!(futures::poll!(&mut first).is_pending());
+        assert!(flight.contains(&"p", 0));
+
+        // a second call for the key waits instead of running its own
+        let mut second = Box::pin(flight.run("p", clock, async { 2 }));
+        assert!(futures::poll!(&mut second).is_pending());
+        assert_eq!(flight.run("q", clock, async { 3 }).await, 3);
+
+        release.send(()).unwrap();
+        assert_eq!(first.await, 1);
+        assert_eq!(second.await, 1);
+        assert!(flight.is_empty());
+        assert!(flight.state.lock().unwrap().done.is_empty());
+        assert_eq!(flight.run("p", clock, async { 4 }).await, 4);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[serial_test::serial]
+    async fn keys_of_calls_that_never_finish_expire() {
+        CLOCK.store(0, Ordering::SeqCst);
+        let flight: SingleFlight<&str, u32> = SingleFlight::new(10);
+        let mut stuck = Box::pin(flight.run("p", clock, futures::future::pending()));
+        assert!(futures::poll!(&mut stuck).is_pending());
+        // a trap unwinds nothing, as if the future were leaked
+        std::mem::forget(stuck);
+        CLOCK.store(9, Ordering::SeqCst);
+        let mut waiting = Box::pin(flight.run("p", clock, async { 1 }));
+        assert!(futures::poll!(&mut waiting).is_pending());
+        assert!(!flight.contains(&"p", 10));
+
+        // once the lease lapses the waiter runs the call itself
+        CLOCK.store(10, Ordering::SeqCst);
+        assert_eq!(waiting.await, 1);
+        assert!(flight.is_empty());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    #[serial_test::serial]
+    async fn cancelled_calls_hand_their_key_to_a_waiter() {
+        CLOCK.store(0, Ordering::SeqCst);
+        let flight: SingleFlight<&str, u32> = SingleFlight::new(10);
+        let mut call = Box::pin(flight.run("p", clock, futures::future::pending()));
+        assert!(futures::poll!(&mut call).is_pending());
+        let mut waiting = Box::pin(flight.run("p", clock, async { 2 }));
+        assert!(futures::poll!(&mut waiting).is_pending());
+        drop(call);
+        assert!(!flight.contains(&"p", 0));
+        assert_eq!(waiting.await, 2);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::error::FetchError;
+use crate::utils::now;
+use bx_core::TypedHolding;
+use candid::{CandidType, Principal};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+
+// Every ledger, DEX adapter and the governance canister is queried as a
+// separate source. A failing source only loses its own holdings; its error is
+// reported next to the others so callers can tell a partial total from a
+// complete one.
+
+#[derive(Clone, Debug, PartialEq, CandidType, Serialize, Deserialize)]
+pub struct SourceStatus {
+    /// `ledger`, `neurons`, `dex` or `account`
+    pub kind: String,
+    /// Ledger canister id, adapter name or account identifier
+    pub id: String,
+    /// Principal the source was queried for; `None` for account identifiers
+    pub principal: Option<Principal>,
+    pub holdings: u32,
+    pub error: Option<FetchError>,
+    pub latency_ms: u64,
+    /// When the call returned, in nanoseconds
+    pub fetched_at: u64,
+}
+
+impl SourceStatus {
+    pub fn ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Holdings returned by one source and how the call went
+pub struct Fetched {
+    pub holdings: Vec<TypedHolding>,
+    pub status: SourceStatus,
+}
+
+/// Await `fut` and record its outcome as source `kind`/`id`
+pub async fn timed<F>(kind: &str, id: String, principal: Option<Principal>, fut: F) -> Fetched
+where
+    F: Future<Output = Result<Vec<TypedHolding>, FetchError>>,
+{
+    let start = now();
+    let res = fut.await;
+    let fetched_at = now();
+    let (holdings, error) = match res {
+        Ok(h) => (h, None),
+        Err(e) => {
+            tracing::warn!("{kind} {id} failed: {e}");
+            (Vec::new(), Some(e))
+        }
+    };
+    Fetched {
+        status: SourceStatus {
+            kind: kind.to_string(),
+            id,
+            principal,
+            holdings: holdings.len() as u32,
+            error,
+            latency_ms: fetched_at.saturating_sub(start) / 1_000_000,
+            fetched_at,
+        },
+        holdings,
+    }
+}
+
+/// Holdings of every source that answered plus the status of all of them
+pub fn merge(fetched: Vec<Fetched>) -> (Vec<TypedHolding>, Vec<SourceStatus>) {
+    let mut holdings = Vec::with_capacity(fetched.iter().map(|f| f.holdings.len()).sum());
+    let mut statuses = Vec::with_capacity(fetched.len());
+    for f in fetched {
+        holdings.extend(f.holdings);
+        statuses.push(f.status);
+    }
+    (holdings, statuses)
+}
+
+/// All holdings, or the first error when any source failed
+pub fn all_or_error(fetched: Vec<Fetched>) -> Result<Vec<TypedHolding>, FetchError> {
+    let (holdings, statuses) = merge(fetched);
+    match statuses.into_iter().find_map(|s| s.error) {
+        Some(e) => Err(e),
+        None => Ok(holdings),
+    }
+}
+
+/// Source statuses of the last full fetch of each principal, served next to
+/// its cached holdings and dropped when the holdings cache evicts them
+static LAST: Lazy<DashMap<Principal, Vec<SourceStatus>>> = Lazy::new(DashMap::new);
+
+/// Most recent status of each source by `(kind, id)`, whoever it was
+/// queried for
+static LATEST: Lazy<DashMap<(String, String), SourceStatus>> = Lazy::new(DashMap::new);
+
+pub fn record(principal: Principal, statuses: Vec<SourceStatus>) {
+    for s in &statuses {
+        let key = (s.kind.clone(), s.id.clone());
+        let newer = LATEST
+            .get(&key)
+            .is_none_or(|prev| prev.fetched_at <= s.fetched_at);
+        if newer {
+            LATEST.insert(key, s.clone());
+        }
+    }
+    LAST.insert(principal, statuses);
+}
+
+pub fn last(principal: &Principal) -> Vec<SourceStatus> {
+    LAST.get(principal)
+        .map(|s| s.value().clone())
+        .unwrap_or_default()
+}
+
+pub fn forget(principal: &Principal) {
+    LAST.remove(principal);
+}
+
+pub fn clear() {
+    LAST.clear();
+}
+
+/// Latest status of source `kind`/`id`
+pub fn latest(kind: &str, id: &str) -> Option<SourceStatus> {
+    LATEST
+        .get(&(kind.to_string(), id.to_string()))
+        .map(|s| s.value().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bx_core::HoldingKind;
+
+    #[tokio::test]
+    async fn failed_source_keeps_the_others() {
+        let ok = timed("dex", "icpswap".into(), None, async {
+            Ok(vec![TypedHolding::new(
+                "icpswap",
+                "AAA",
+                1u64.into(),
+                0,
+                HoldingKind::Liquid,
+            )])
+        });
+        let failed = timed("dex", "sonic".into(), None, async {
+            Err(FetchError::Network("timeout".into()))
+        });
+        let (a, b) = futures::join!(ok, failed);
+        let (holdings, statuses) = merge(vec![a, b]);
+        assert_eq!(holdings.len(), 1);
+        assert_eq!(statuses.len(), 2);
+        assert!(statuses[0].ok());
+        assert_eq!(statuses[0].holdings, 1);
+        assert_eq!(
+            statuses[1].error,
+            Some(FetchError::Network("timeout".into()))
+        );
+        assert!(statuses[1].fetched_at > 0);
+    }
+}
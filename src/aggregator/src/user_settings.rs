@@ -1,50 +1,239 @@
+use crate::memory::{self, Candid, Memory};
+use crate::utils::DAY_NS;
 use candid::Principal;
 use dashmap::DashMap;
+use ic_stable_structures::StableBTreeMap;
 use once_cell::sync::Lazy;
+use std::cell::RefCell;
 
 use serde::{Deserialize, Serialize};
 
+/// Another wallet whose holdings are aggregated into its owner's view
+#[derive(Clone, candid::CandidType, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum LinkedWallet {
+    Principal(Principal),
+    /// Hex-encoded ICP ledger account identifier, kept in lowercase
+    AccountId(String),
+}
+
+impl LinkedWallet {
+    /// Account identifiers in lowercase so differently cased ones compare equal
+    pub fn normalized(self) -> Self {
+        match self {
+            LinkedWallet::AccountId(a) => LinkedWallet::AccountId(a.to_ascii_lowercase()),
+            w => w,
+        }
+    }
+
+    /// Principal text or account identifier, as used for `Holding::wallet`
+    pub fn id(&self) -> String {
+        match self {
+            LinkedWallet::Principal(p) => p.to_text(),
+            LinkedWallet::AccountId(a) => a.clone(),
+        }
+    }
+}
+
 #[derive(Default, Clone, candid::CandidType, Serialize, Deserialize, PartialEq, Debug)]
 pub struct UserSettings {
     pub preferred_ledgers: Vec<String>,
     pub preferred_dexes: Vec<String>,
     pub dark_mode: bool,
+    /// ICRC-1 subaccounts queried on every ledger in addition to the default.
+    /// Optional so settings stored or sent before the field existed decode.
+    pub subaccounts: Option<Vec<Vec<u8>>>,
+    /// Wallets confirmed through `propose_link` / `accept_link`. Ignored by
+    /// `update_user_settings` so links always carry an ownership proof.
+    pub linked_wallets: Option<Vec<LinkedWallet>>,
+    /// Record periodic portfolio snapshots for `get_history`
+    pub track_history: Option<bool>,
+}
+
+impl UserSettings {
+    pub fn subaccounts(&self) -> &[Vec<u8>] {
+        self.subaccounts.as_deref().unwrap_or_default()
+    }
+
+    pub fn linked_wallets(&self) -> &[LinkedWallet] {
+        self.linked_wallets.as_deref().unwrap_or_default()
+    }
+
+    pub fn track_history(&self) -> bool {
+        self.track_history.unwrap_or(false)
+    }
 }
 
-static SETTINGS: Lazy<DashMap<Principal, UserSettings>> = Lazy::new(DashMap::new);
+/// Maximum number of linked wallets per principal
+pub const MAX_LINKED_WALLETS: usize = 8;
+/// How long a link proposal waits for confirmation
+const LINK_TTL_NS: u64 = DAY_NS;
 
+/// Maximum number of tracked subaccounts per principal
+pub const MAX_SUBACCOUNTS: usize = 16;
+/// ICRC-1 subaccounts are always 32 bytes
+pub const SUBACCOUNT_LEN: usize = 32;
+
+thread_local! {
+    static SETTINGS: RefCell<StableBTreeMap<Candid<Principal>, Candid<UserSettings>, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::USER_SETTINGS)));
+}
+
+/// Pending link proposals keyed by owner, with their expiry. Proposals are
+/// short lived and not persisted across upgrades; accepted links are.
+static PENDING_LINKS: Lazy<DashMap<Principal, Vec<(LinkedWallet, u64)>>> = Lazy::new(DashMap::new);
+
+/// Settings as serialised by releases that copied state through upgrades
 #[derive(candid::CandidType, serde::Serialize, serde::Deserialize)]
 pub struct StableEntry {
     pub principal: Principal,
     pub settings: UserSettings,
 }
 
+/// Reject settings the fetchers cannot use.
+pub fn validate(settings: &UserSettings) -> Result<(), String> {
+    let subaccounts = settings.subaccounts();
+    if subaccounts.len() > MAX_SUBACCOUNTS {
+        return Err(format!(
+            "at most {MAX_SUBACCOUNTS} subaccounts may be tracked"
+        ));
+    }
+    if subaccounts.iter().any(|s| s.len() != SUBACCOUNT_LEN) {
+        return Err(format!("subaccounts must be {SUBACCOUNT_LEN} bytes"));
+    }
+    Ok(())
+}
+
 pub fn get(principal: &Principal) -> Option<UserSettings> {
-    SETTINGS.get(principal).map(|e| e.value().clone())
+    SETTINGS.with(|s| s.borrow().get(&Candid(*principal)).map(|c| c.0))
 }
 
 pub fn update(principal: Principal, settings: UserSettings) {
-    SETTINGS.insert(principal, settings);
+    SETTINGS.with(|s| s.borrow_mut().insert(Candid(principal), Candid(settings)));
 }
 
 pub fn remove(principal: Principal) {
-    SETTINGS.remove(&principal);
+    SETTINGS.with(|s| s.borrow_mut().remove(&Candid(principal)));
 }
 
-pub fn stable_save() -> Vec<StableEntry> {
-    SETTINGS
-        .iter()
-        .map(|e| StableEntry {
-            principal: *e.key(),
-            settings: e.value().clone(),
-        })
-        .collect()
+/// Record that `owner` wants to link `wallet`. The link only takes effect once
+/// the wallet confirms with [`accept_link`].
+pub fn propose_link(owner: Principal, wallet: LinkedWallet, now: u64) -> Result<(), String> {
+    let wallet = wallet.normalized();
+    match &wallet {
+        LinkedWallet::Principal(p) if *p == owner || *p == Principal::anonymous() => {
+            return Err("cannot link this principal".into());
+        }
+        LinkedWallet::AccountId(a) if !is_account_id(a) => {
+            return Err("account identifier must be 64 hex characters".into());
+        }
+        _ => {}
+    }
+    let linked = get(&owner).unwrap_or_default();
+    if linked.linked_wallets().contains(&wallet) {
+        return Err("wallet already linked".into());
+    }
+    if linked.linked_wallets().len() >= MAX_LINKED_WALLETS {
+        return Err(format!(
+            "at most {MAX_LINKED_WALLETS} wallets may be linked"
+        ));
+    }
+    let mut pending = PENDING_LINKS.entry(owner).or_default();
+    pending.retain(|(w, exp)| *exp > now && *w != wallet);
+    // proposals count toward the limit too, so they can't pile up unbounded
+    if linked.linked_wallets().len() + pending.len() >= MAX_LINKED_WALLETS {
+        drop(pending);
+        PENDING_LINKS.remove_if(&owner, |_, v| v.is_empty());
+        return Err(format!(
+            "at most {MAX_LINKED_WALLETS} wallets may be linked or pending"
+        ));
+    }
+    pending.push((wallet, now + LINK_TTL_NS));
+    Ok(())
 }
 
+/// Confirm a proposal made by `owner`. `caller` proves ownership either by
+/// being the proposed principal or, for account identifiers, by supplying the
+/// subaccount that together with `caller` derives the proposed identifier.
+pub fn accept_link(
+    caller: Principal,
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+    now: u64,
+) -> Result<LinkedWallet, String> {
+    let account = crate::ledger_fetcher::account_identifier(&caller, subaccount.as_deref());
+    let mut settings = get(&owner).unwrap_or_default();
+    let mut wallets = settings.linked_wallets.take().unwrap_or_default();
+    let wallet = {
+        let mut pending = PENDING_LINKS
+            .get_mut(&owner)
+            .ok_or_else(|| "no pending link".to_string())?;
+        pending.retain(|(_, exp)| *exp > now);
+        let idx = pending
+            .iter()
+            .position(|(w, _)| match w {
+                LinkedWallet::Principal(p) => *p == caller,
+                LinkedWallet::AccountId(a) => a.eq_ignore_ascii_case(&account),
+            })
+            .ok_or_else(|| "no pending link".to_string())?;
+        // a rejected accept leaves the proposal in place
+        if wallets.len() >= MAX_LINKED_WALLETS && !wallets.contains(&pending[idx].0) {
+            return Err(format!(
+                "at most {MAX_LINKED_WALLETS} wallets may be linked"
+            ));
+        }
+        pending.remove(idx).0
+    };
+    PENDING_LINKS.remove_if(&owner, |_, v| v.is_empty());
+    if !wallets.contains(&wallet) {
+        wallets.push(wallet.clone());
+    }
+    settings.linked_wallets = Some(wallets);
+    update(owner, settings);
+    Ok(wallet)
+}
+
+/// Remove a confirmed link or a pending proposal. Returns whether anything
+/// was removed.
+pub fn unlink(owner: Principal, wallet: &LinkedWallet) -> bool {
+    let wallet = &wallet.clone().normalized();
+    let mut removed = false;
+    if let Some(mut pending) = PENDING_LINKS.get_mut(&owner) {
+        let before = pending.len();
+        pending.retain(|(w, _)| w != wallet);
+        removed = pending.len() != before;
+    }
+    if let Some(mut settings) = get(&owner) {
+        if let Some(wallets) = settings.linked_wallets.as_mut() {
+            let before = wallets.len();
+            wallets.retain(|w| w != wallet);
+            if wallets.len() != before {
+                removed = true;
+                update(owner, settings);
+            }
+        }
+    }
+    removed
+}
+
+fn is_account_id(s: &str) -> bool {
+    s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Principals that opted into portfolio snapshots
+pub fn history_principals() -> Vec<Principal> {
+    SETTINGS.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, v)| v.0.track_history())
+            .map(|(k, _)| k.0)
+            .collect()
+    })
+}
+
+/// Import settings saved by a release that serialised state on upgrade
 pub fn stable_restore(entries: Vec<StableEntry>) {
-    SETTINGS.clear();
     for e in entries {
-        SETTINGS.insert(e.principal, e.settings);
+        update(e.principal, e.settings);
     }
 }
 
@@ -61,6 +250,9 @@ mod tests {
             preferred_ledgers: vec![p.to_text()],
             preferred_dexes: Vec::new(),
             dark_mode: false,
+            subaccounts: None,
+            linked_wallets: None,
+            track_history: None,
         };
         update(p, s1.clone());
         assert_eq!(get(&p), Some(s1.clone()));
@@ -68,10 +260,137 @@ mod tests {
             preferred_ledgers: Vec::new(),
             preferred_dexes: vec!["ICPSWAP_FACTORY".to_string()],
             dark_mode: true,
+            subaccounts: Some(vec![vec![1; SUBACCOUNT_LEN]]),
+            linked_wallets: None,
+            track_history: None,
         };
         update(p, s2.clone());
         assert_eq!(get(&p), Some(s2.clone()));
         remove(p);
         assert!(get(&p).is_none());
     }
+
+    #[test]
+    fn validate_subaccounts() {
+        let mut s = UserSettings {
+            subaccounts: Some(vec![vec![0; SUBACCOUNT_LEN]]),
+            ..Default::default()
+        };
+        assert!(validate(&s).is_ok());
+        s.subaccounts.as_mut().unwrap().push(vec![1; 8]);
+        assert!(validate(&s).is_err());
+        s.subaccounts = Some(vec![vec![2; SUBACCOUNT_LEN]; MAX_SUBACCOUNTS + 1]);
+        assert!(validate(&s).is_err());
+    }
+
+    #[test]
+    fn decode_settings_without_subaccounts() {
+        #[derive(candid::CandidType)]
+        struct Legacy {
+            preferred_ledgers: Vec<String>,
+            preferred_dexes: Vec<String>,
+            dark_mode: bool,
+        }
+        let bytes = candid::encode_one(Legacy {
+            preferred_ledgers: Vec::new(),
+            preferred_dexes: Vec::new(),
+            dark_mode: true,
+        })
+        .unwrap();
+        let s: UserSettings = candid::decode_one(&bytes).unwrap();
+        assert!(s.dark_mode);
+        assert!(s.subaccounts().is_empty());
+    }
+
+    #[test]
+    fn link_requires_confirmation() {
+        let owner = Principal::from_slice(&[1, 7]);
+        let wallet = Principal::from_slice(&[2, 7]);
+        let other = Principal::from_slice(&[3, 7]);
+        let w = LinkedWallet::Principal(wallet);
+        assert!(propose_link(owner, LinkedWallet::Principal(owner), 0).is_err());
+        propose_link(owner, w.clone(), 0).unwrap();
+        assert!(get(&owner).is_none());
+        assert!(accept_link(other, owner, None, 1).is_err());
+        assert!(accept_link(wallet, owner, None, LINK_TTL_NS + 1).is_err());
+        propose_link(owner, w.clone(), 10).unwrap();
+        assert_eq!(accept_link(wallet, owner, None, 11).unwrap(), w);
+        assert_eq!(
+            get(&owner).unwrap().linked_wallets(),
+            std::slice::from_ref(&w)
+        );
+        assert!(propose_link(owner, w.clone(), 12).is_err());
+        assert!(unlink(owner, &w));
+        assert!(get(&owner).unwrap().linked_wallets().is_empty());
+        remove(owner);
+    }
+
+    #[test]
+    fn link_account_id_with_subaccount_proof() {
+        let owner = Principal::from_slice(&[1, 8]);
+        let wallet = Principal::from_slice(&[2, 8]);
+        let sub = vec![9u8; SUBACCOUNT_LEN];
+        let account = crate::ledger_fetcher::account_identifier(&wallet, Some(&sub));
+        let w = LinkedWallet::AccountId(account.to_uppercase());
+        propose_link(owner, w.clone(), 0).unwrap();
+        assert!(accept_link(wallet, owner, None, 1).is_err());
+        let linked = LinkedWallet::AccountId(account.clone());
+        assert_eq!(accept_link(wallet, owner, Some(sub), 1).unwrap(), linked);
+        // the same account in another case is already linked
+        assert!(propose_link(owner, w.clone(), 2).is_err());
+        assert!(unlink(owner, &w));
+        remove(owner);
+    }
+
+    #[test]
+    fn proposals_count_toward_the_limit() {
+        let owner = Principal::from_slice(&[1, 10]);
+        let wallet = |i: u8| LinkedWallet::Principal(Principal::from_slice(&[5, i]));
+        update(
+            owner,
+            UserSettings {
+                linked_wallets: Some(vec![wallet(0)]),
+                ..Default::default()
+            },
+        );
+        for i in 1..MAX_LINKED_WALLETS as u8 {
+            propose_link(owner, wallet(i), 0).unwrap();
+        }
+        let extra = wallet(MAX_LINKED_WALLETS as u8);
+        assert!(propose_link(owner, extra.clone(), 0).is_err());
+        // proposing a pending wallet again only renews it
+        propose_link(owner, wallet(1), 1).unwrap();
+        assert_eq!(
+            PENDING_LINKS.get(&owner).unwrap().len(),
+            MAX_LINKED_WALLETS - 1
+        );
+        // expired proposals free their place
+        assert!(propose_link(owner, extra, LINK_TTL_NS + 1).is_ok());
+        assert!(unlink(owner, &wallet(0)));
+        PENDING_LINKS.remove(&owner);
+        remove(owner);
+    }
+
+    #[test]
+    fn accept_over_the_limit_keeps_the_proposal() {
+        let owner = Principal::from_slice(&[1, 9]);
+        let full: Vec<LinkedWallet> = (0..MAX_LINKED_WALLETS as u8)
+            .map(|i| LinkedWallet::Principal(Principal::from_slice(&[4, i])))
+            .collect();
+        let wallet = Principal::from_slice(&[2, 9]);
+        propose_link(owner, LinkedWallet::Principal(wallet), 0).unwrap();
+        update(
+            owner,
+            UserSettings {
+                linked_wallets: Some(full),
+                ..Default::default()
+            },
+        );
+        assert!(accept_link(wallet, owner, None, 1).is_err());
+        let mut settings = get(&owner).unwrap();
+        settings.linked_wallets.as_mut().unwrap().pop();
+        update(owner, settings);
+        assert!(accept_link(wallet, owner, None, 2).is_ok());
+        remove(owner);
+    }
 }
@@ -1,5 +1,4 @@
-use candid::Nat;
-#[cfg(not(target_arch = "wasm32"))]
+use crate::error::FetchError;
 use num_traits::cast::ToPrimitive;
 #[cfg(not(target_arch = "wasm32"))]
 use once_cell::sync::{Lazy, OnceCell};
@@ -34,29 +33,30 @@ pub fn now() -> u64 {
     ic_cdk::api::time()
 }
 
+/// How long a native pause lasts
 #[cfg(not(target_arch = "wasm32"))]
-pub fn format_amount(n: Nat, decimals: u8) -> String {
-    use num_bigint::BigUint;
-    use num_integer::Integer;
-    let div = BigUint::from(10u32).pow(decimals as u32);
-    let (q, r) = n.0.div_rem(&div);
-    let mut frac = r.to_str_radix(10);
-    while frac.len() < decimals as usize {
-        frac.insert(0, '0');
-    }
-    if decimals == 0 {
-        q.to_str_radix(10)
-    } else {
-        format!("{}.{frac}", q.to_str_radix(10))
-    }
-}
+const PAUSE: std::time::Duration = std::time::Duration::from_millis(10);
 
+/// Let the current message wait a moment before checking again on work
+/// another message is doing. Inside the canister the message awaits a call to
+/// the management canister and resumes, in its own call context, once that
+/// call returns in a later round. Queries can't make calls, so there it fails.
 #[cfg(target_arch = "wasm32")]
-pub fn format_amount(n: Nat, _decimals: u8) -> String {
-    n.0.to_string()
+pub async fn pause() -> Result<(), FetchError> {
+    ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map(|_| ())
+        .map_err(|(code, msg)| FetchError::Network(format!("{code:?}: {msg}")))
 }
 
 #[cfg(not(target_arch = "wasm32"))]
+pub async fn pause() -> Result<(), FetchError> {
+    tokio::time::sleep(PAUSE).await;
+    Ok(())
+}
+
+pub use bx_core::format_amount;
+
 pub fn idl_to_u64(val: &candid::types::value::IDLValue) -> Option<u64> {
     use candid::types::value::IDLValue;
     match val {
@@ -69,12 +69,18 @@ pub fn idl_to_u64(val: &candid::types::value::IDLValue) -> Option<u64> {
     }
 }
 
-#[cfg(not(target_arch = "wasm32"))]
+pub fn idl_to_nat(val: &candid::types::value::IDLValue) -> Option<candid::Nat> {
+    use candid::types::value::IDLValue;
+    match val {
+        IDLValue::Nat(n) => Some(n.clone()),
+        _ => idl_to_u64(val).map(candid::Nat::from),
+    }
+}
+
 pub fn idl_to_u8(val: &candid::types::value::IDLValue) -> Option<u8> {
     idl_to_u64(val).map(|v| v as u8)
 }
 
-#[cfg(not(target_arch = "wasm32"))]
 pub fn idl_to_string(val: &candid::types::value::IDLValue) -> Option<String> {
     use candid::types::value::IDLValue;
     match val {
@@ -107,6 +113,43 @@ pub async fn get_agent() -> ic_agent::Agent {
     agent
 }
 
+/// Issue a query against `cid` and return the raw Candid reply.
+///
+/// Natively the shared `ic_agent::Agent` is used; inside the canister the
+/// call is made with `ic_cdk` so adapters can share their decoding logic.
+/// Calls go through [`crate::scheduler::call`] to stay within its limits.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn call_query(
+    cid: candid::Principal,
+    method: &str,
+    arg: Vec<u8>,
+) -> Result<Vec<u8>, FetchError> {
+    let agent = get_agent().await;
+    crate::scheduler::call(cid, async {
+        agent
+            .query(&cid, method)
+            .with_arg(arg)
+            .call()
+            .await
+            .map_err(FetchError::from)
+    })
+    .await
+}
+
+#[cfg(target_arch = "wasm32")]
+pub async fn call_query(
+    cid: candid::Principal,
+    method: &str,
+    arg: Vec<u8>,
+) -> Result<Vec<u8>, FetchError> {
+    crate::scheduler::call(cid, async move {
+        ic_cdk::api::call::call_raw(cid, method, arg, 0)
+            .await
+            .map_err(|(code, msg)| FetchError::Network(format!("{code:?}: {msg}")))
+    })
+    .await
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 pub struct DexEntry {
     pub id: candid::Principal,
@@ -248,8 +291,9 @@ async fn sanity_check_dex() {
         }
         let mut disable = false;
         if icrc1_metadata(&agent, id).await.is_none() {
-            error!("{name} metadata failed; disabling adapter");
-            disable = true;
+            // may be transient: the circuit breaker probes it again later
+            error!("{name} metadata failed; opening its circuit");
+            crate::breaker::trip(&name, "metadata check failed", now());
         } else if let Some(c) = controller {
             if !controller_matches(&agent, id, c).await {
                 error!("{name} controller mismatch; disabling adapter");
@@ -394,36 +438,42 @@ pub fn dex_ids() -> Vec<candid::Principal> {
         .collect()
 }
 
+/// Table `key` of the `ledgers.toml` embedded at build time
+#[cfg(target_arch = "wasm32")]
+pub fn embedded_table(key: &str) -> toml::value::Table {
+    let value: toml::Value = toml::from_str(include_str!("../../../config/ledgers.toml"))
+        .unwrap_or(toml::Value::Table(Default::default()));
+    value
+        .get(key)
+        .and_then(|d| d.as_table())
+        .cloned()
+        .unwrap_or_default()
+}
+
 #[cfg(target_arch = "wasm32")]
 pub fn env_principal(name: &str) -> Option<candid::Principal> {
-    match name {
-        "ICPSWAP_FACTORY" => {
-            option_env!("ICPSWAP_FACTORY").and_then(|s| candid::Principal::from_text(s).ok())
-        }
-        "SONIC_ROUTER" => {
-            option_env!("SONIC_ROUTER").and_then(|s| candid::Principal::from_text(s).ok())
-        }
-        "INFINITY_VAULT" => {
-            option_env!("INFINITY_VAULT").and_then(|s| candid::Principal::from_text(s).ok())
-        }
-        "SNS_DISTRIBUTOR" => {
-            option_env!("SNS_DISTRIBUTOR").and_then(|s| candid::Principal::from_text(s).ok())
-        }
+    let overridden = match name {
+        "ICPSWAP_FACTORY" => option_env!("ICPSWAP_FACTORY"),
+        "SONIC_ROUTER" => option_env!("SONIC_ROUTER"),
+        "INFINITY_VAULT" => option_env!("INFINITY_VAULT"),
+        "SNS_DISTRIBUTOR" => option_env!("SNS_DISTRIBUTOR"),
         _ => None,
+    };
+    match overridden {
+        Some(s) => candid::Principal::from_text(s).ok(),
+        None => embedded_table("dex")
+            .get(name)
+            .and_then(|v| v.as_str())
+            .and_then(|s| candid::Principal::from_text(s).ok()),
     }
 }
 
 #[cfg(target_arch = "wasm32")]
 pub fn dex_ids() -> Vec<candid::Principal> {
-    [
-        "ICPSWAP_FACTORY",
-        "SONIC_ROUTER",
-        "INFINITY_VAULT",
-        "SNS_DISTRIBUTOR",
-    ]
-    .into_iter()
-    .filter_map(env_principal)
-    .collect()
+    embedded_table("dex")
+        .keys()
+        .filter_map(|k| env_principal(k))
+        .collect()
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -438,15 +488,9 @@ pub async fn warm_icrc_metadata(cid: candid::Principal) {
     let _ = icrc1_metadata(&agent, cid).await;
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-pub async fn dex_block_height(agent: &ic_agent::Agent, cid: candid::Principal) -> Option<u64> {
+pub async fn dex_block_height(cid: candid::Principal) -> Result<u64, FetchError> {
     use candid::{Decode, Encode};
     let arg = Encode!().expect("encode args");
-    let bytes = agent
-        .query(&cid, "block_height")
-        .with_arg(arg)
-        .call()
-        .await
-        .ok()?;
-    Decode!(&bytes, u64).ok()
+    let bytes = call_query(cid, "block_height", arg).await?;
+    Decode!(&bytes, u64).map_err(|_| FetchError::InvalidResponse)
 }
@@ -114,7 +114,7 @@ mod tests {
 
     fn gen_principal(i: u8) -> Principal {
         let bytes = [i; 32];
-        Principal::self_authenticating(&bytes)
+        Principal::self_authenticating(bytes)
     }
 
     #[tokio::test(flavor = "current_thread")]
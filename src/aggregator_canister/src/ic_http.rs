@@ -7,6 +7,8 @@ pub struct Request {
     pub url: String,
     pub headers: Vec<(String, String)>,
     pub body: ByteBuf,
+    /// Highest response verification version the gateway supports
+    pub certificate_version: Option<u16>,
 }
 
 #[derive(Clone, CandidType, Deserialize)]
@@ -14,4 +16,6 @@ pub struct Response {
     pub status_code: u16,
     pub headers: Vec<(String, String)>,
     pub body: ByteBuf,
+    /// Ask the gateway to repeat the request as `http_request_update`
+    pub upgrade: Option<bool>,
 }
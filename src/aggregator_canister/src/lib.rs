@@ -1,20 +1,13 @@
 pub use aggregator::*;
 pub mod ic_http;
-use async_graphql::{EmptyMutation, EmptySubscription, Object, Request as GqlRequest, Schema};
-use once_cell::sync::Lazy;
-
-const STABLE_VERSION: u32 = 1;
-static MAX_STATE_BYTES: Lazy<u64> = Lazy::new(|| {
-    option_env!("MAX_STATE_BYTES")
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(1_000_000)
-});
+pub mod rest;
 
 #[ic_cdk_macros::init]
 fn init() {
     aggregator::logging::init();
-    #[cfg(not(target_arch = "wasm32"))]
+    aggregator::migrations::init();
     ic_cdk::spawn(async {
+        #[cfg(not(target_arch = "wasm32"))]
         aggregator::utils::load_dex_config().await;
         aggregator::dex::registry::load_adapters().await;
     });
@@ -24,51 +17,26 @@ fn init() {
     aggregator::pool_registry::watch_pools_file();
     ic_cdk::spawn(async { aggregator::pool_registry::refresh().await });
     aggregator::pool_registry::schedule_refresh();
+    aggregator::price::schedule_refresh();
     aggregator::lp_cache::schedule_eviction();
+    aggregator::ledger_fetcher::schedule_eviction();
+    aggregator::history::schedule_snapshots();
     aggregator::warm::init();
 }
 
-#[ic_cdk_macros::pre_upgrade]
-fn pre_upgrade() {
-    let log = aggregator::cycles::take_log();
-    let meta = aggregator::ledger_fetcher::stable_save();
-    let lp = aggregator::lp_cache::stable_save();
-    let settings = aggregator::user_settings::stable_save();
-    let metrics = aggregator::metrics::stable_save();
-    let snapshot = (STABLE_VERSION, &log, &meta, &lp, &settings, &metrics);
-    let bytes = candid::encode_one(snapshot).expect("encode state");
-    if bytes.len() as u64 > *MAX_STATE_BYTES {
-        ic_cdk::trap(&format!(
-            "stable state {} bytes exceeds limit {}",
-            bytes.len(),
-            *MAX_STATE_BYTES
-        ));
-    }
-    ic_cdk::storage::stable_save((STABLE_VERSION, log, meta, lp, settings, metrics)).unwrap();
-}
-
 #[ic_cdk_macros::post_upgrade]
 fn post_upgrade() {
-    if let Ok((ver, log, meta, lp, settings, metrics)) = ic_cdk::storage::stable_restore::<(
-        u32,
-        Vec<String>,
-        Vec<aggregator::ledger_fetcher::StableMeta>,
-        Vec<aggregator::lp_cache::StableEntry>,
-        Vec<aggregator::user_settings::StableEntry>,
-        (u64, u64, u64, u64, u64, u64, u64, u64),
-    )>() {
-        if ver != STABLE_VERSION {
-            ic_cdk::trap(&format!(
-                "incompatible state version {}, expected {}",
-                ver, STABLE_VERSION
-            ));
-        }
-        aggregator::cycles::set_log(log);
-        aggregator::ledger_fetcher::stable_restore(meta);
-        aggregator::lp_cache::stable_restore(lp);
-        aggregator::user_settings::stable_restore(settings);
-        aggregator::metrics::stable_restore(metrics);
+    // Trapping rolls the upgrade back, keeping the previous release and its
+    // state instead of starting empty.
+    if let Err(e) = aggregator::migrations::run() {
+        ic_cdk::trap(&format!("stable state migration failed: {e}"));
     }
+    aggregator::cache::restore(aggregator::utils::now());
+    aggregator::history::schedule_snapshots();
+    aggregator::price::schedule_refresh();
+    aggregator::lp_cache::schedule_eviction();
+    aggregator::ledger_fetcher::schedule_eviction();
+    ic_cdk::spawn(async { aggregator::dex::registry::load_adapters().await });
 }
 
 #[ic_cdk_macros::heartbeat]
@@ -76,162 +44,82 @@ async fn heartbeat() {
     aggregator::metrics::inc_heartbeat(aggregator::utils::now());
     aggregator::cycles::tick().await;
     aggregator::warm::tick().await;
+    aggregator::dex_fetchers::probe_open_adapters().await;
 }
 
 #[ic_cdk_macros::query]
 fn get_metrics() -> String {
-    pay_cycles(*CALL_PRICE);
+    pay_cycles(config::get().call_price);
     serde_json::to_string(&aggregator::metrics::get()).unwrap()
 }
 
 use crate::ic_http::{Request as HttpRequest, Response as HttpResponse};
-
-#[derive(async_graphql::SimpleObject)]
-struct GHolding {
-    source: String,
-    token: String,
-    amount: String,
-    status: String,
-}
-
-impl From<bx_core::Holding> for GHolding {
-    fn from(h: bx_core::Holding) -> Self {
-        GHolding {
-            source: h.source,
-            token: h.token,
-            amount: h.amount,
-            status: h.status,
-        }
-    }
-}
-
-#[derive(async_graphql::SimpleObject)]
-struct GTokenTotal {
-    token: String,
-    total: f64,
-}
-
-impl From<aggregator::TokenTotal> for GTokenTotal {
-    fn from(t: aggregator::TokenTotal) -> Self {
-        GTokenTotal {
-            token: t.token,
-            total: t.total,
-        }
-    }
-}
 use serde_bytes::ByteBuf;
 
-#[derive(Default)]
-struct QueryRoot;
-
-#[Object]
-impl QueryRoot {
-    async fn holdings(&self, principal: String) -> async_graphql::Result<Vec<GHolding>> {
-        let p = candid::Principal::from_text(&principal)?;
-        let holdings = aggregator::get_holdings(p)
-            .await
-            .map_err(async_graphql::Error::new)?;
-        Ok(holdings.into_iter().map(GHolding::from).collect())
-    }
-
-    async fn summary(&self, principal: String) -> async_graphql::Result<Vec<GTokenTotal>> {
-        let p = candid::Principal::from_text(&principal)?;
-        let summary = aggregator::get_summary(p)
-            .await
-            .map_err(async_graphql::Error::new)?;
-        Ok(summary.into_iter().map(GTokenTotal::from).collect())
-    }
+/// Run a GraphQL request given as JSON or as a bare query
+pub(crate) async fn execute_graphql(body: &[u8]) -> Vec<u8> {
+    use aggregator::graphql::{self, Mode};
+    let resp = graphql::execute(graphql::request(body), Mode::Update).await;
+    serde_json::to_vec(&resp).unwrap()
 }
 
-static SCHEMA: Lazy<Schema<QueryRoot, EmptyMutation, EmptySubscription>> =
-    Lazy::new(|| Schema::build(QueryRoot::default(), EmptyMutation, EmptySubscription).finish());
+/// Serves fresh cached `/holdings` and `/summary` responses with HTTP
+/// certification v2 headers. Only their plain JSON form is certified, so
+/// query strings, CSV and everything else, including gateways that can't
+/// verify v2 certificates, are upgraded to `http_request_update`, whose
+/// response goes through consensus. Preflights and bad requests are answered
+/// here. A request is charged once: here when answered, otherwise by the
+/// update it is upgraded to.
 #[ic_cdk_macros::query]
-pub async fn http_request(req: HttpRequest) -> HttpResponse {
-    use candid::Principal;
-
-    pay_cycles(*CALL_PRICE);
-
-    let path = req.url.split('?').next().unwrap_or("");
-    let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
-    let not_found = || HttpResponse {
-        status_code: 404,
-        headers: vec![("Content-Type".into(), "application/json".into())],
-        body: ByteBuf::from(r#"{"error":"not found"}"#),
-    };
-
-    match parts.as_slice() {
-        ["holdings", pid] => {
-            let principal = match Principal::from_text(pid) {
-                Ok(p) => p,
-                Err(_) => return not_found(),
-            };
-            let holdings = match aggregator::get_holdings(principal).await {
-                Ok(v) => v,
-                Err(e) => {
-                    return HttpResponse {
-                        status_code: 500,
-                        headers: vec![("Content-Type".into(), "application/json".into())],
-                        body: ByteBuf::from(format!("{{\"error\":\"{}\"}}", e)),
-                    };
-                }
-            };
-            let body = serde_json::to_vec(&holdings).unwrap();
-            HttpResponse {
-                status_code: 200,
-                headers: vec![("Content-Type".into(), "application/json".into())],
-                body: ByteBuf::from(body),
-            }
+pub fn http_request(req: HttpRequest) -> HttpResponse {
+    let route = match rest::route(&req) {
+        Ok(route) => route,
+        Err(resp) => {
+            pay_cycles(config::get().call_price);
+            return resp;
         }
-        ["metrics"] => {
-            let metrics = aggregator::metrics::get();
-            let body = serde_json::to_vec(&metrics).unwrap();
-            HttpResponse {
-                status_code: 200,
-                headers: vec![("Content-Type".into(), "application/json".into())],
-                body: ByteBuf::from(body),
-            }
+    };
+    let upgrade = HttpResponse {
+        status_code: 200,
+        headers: Vec::new(),
+        body: ByteBuf::default(),
+        upgrade: Some(true),
+    };
+    if req.certificate_version.unwrap_or(1) < 2 || req.url.contains('?') || rest::wants_csv(&req) {
+        return upgrade;
+    }
+    let certified = match route {
+        rest::Route::Holdings(p, _) => {
+            aggregator::http_cert::response("holdings", p, aggregator::utils::now())
         }
-        ["summary", pid] => {
-            let principal = match Principal::from_text(pid) {
-                Ok(p) => p,
-                Err(_) => return not_found(),
-            };
-            let summary = match aggregator::get_summary(principal).await {
-                Ok(v) => v,
-                Err(e) => {
-                    return HttpResponse {
-                        status_code: 500,
-                        headers: vec![("Content-Type".into(), "application/json".into())],
-                        body: ByteBuf::from(format!("{{\"error\":\"{}\"}}", e)),
-                    };
-                }
-            };
-            let body = serde_json::to_vec(&summary).unwrap();
-            HttpResponse {
-                status_code: 200,
-                headers: vec![("Content-Type".into(), "application/json".into())],
-                body: ByteBuf::from(body),
-            }
+        rest::Route::Summary(p, _) => {
+            aggregator::http_cert::response("summary", p, aggregator::utils::now())
         }
-        ["graphql"] => {
-            let gql_req: GqlRequest = match serde_json::from_slice(req.body.as_ref()) {
-                Ok(r) => r,
-                Err(_) => {
-                    let query = std::str::from_utf8(req.body.as_ref()).unwrap_or("");
-                    GqlRequest::new(query)
-                }
-            };
-            let resp = SCHEMA.execute(gql_req).await;
-            let body = serde_json::to_vec(&resp).unwrap();
+        _ => None,
+    };
+    match certified {
+        Some((mut headers, body)) => {
+            pay_cycles(config::get().call_price);
             HttpResponse {
                 status_code: 200,
-                headers: vec![("Content-Type".into(), "application/json".into())],
+                headers: {
+                    headers.extend(rest::cors_headers());
+                    headers
+                },
                 body: ByteBuf::from(body),
+                upgrade: None,
             }
         }
-        _ => not_found(),
+        None => upgrade,
     }
 }
+
+#[ic_cdk_macros::update]
+pub async fn http_request_update(req: HttpRequest) -> HttpResponse {
+    pay_cycles(config::get().call_price);
+    rest::handle(&req).await
+}
+
 #[cfg(feature = "export_candid")]
 ic_cdk::export_candid!();
 
@@ -239,35 +127,25 @@ ic_cdk::export_candid!();
 mod tests {
     use super::*;
     use aggregator::cache;
-    use bx_core::Holding;
-    use serde_json;
+    use bx_core::{HoldingKind, TypedHolding};
+
+    fn holding(token: &str, amount: u64) -> TypedHolding {
+        TypedHolding::new("test", token, amount.into(), 0, HoldingKind::Liquid)
+    }
 
     #[tokio::test]
     #[serial_test::serial]
     async fn http_paths() {
         let p = candid::Principal::from_text("aaaaa-aa").unwrap();
         cache::get().clear();
-        let holdings = vec![
-            Holding {
-                source: "test".into(),
-                token: "AAA".into(),
-                amount: "1".into(),
-                status: "ok".into(),
-            },
-            Holding {
-                source: "test".into(),
-                token: "AAA".into(),
-                amount: "2".into(),
-                status: "ok".into(),
-            },
-        ];
+        let holdings = vec![holding("AAA", 1), holding("AAA", 2)];
         let summary = {
             use rust_decimal::prelude::{FromStr, ToPrimitive};
             use std::collections::BTreeMap;
             let mut map: BTreeMap<String, rust_decimal::Decimal> = BTreeMap::new();
             for h in &holdings {
-                if let Ok(v) = rust_decimal::Decimal::from_str(&h.amount) {
-                    *map.entry(h.token.clone())
+                if let Ok(v) = rust_decimal::Decimal::from_str(&h.formatted_amount()) {
+                    *map.entry(h.symbol.clone())
                         .or_insert(rust_decimal::Decimal::ZERO) += v;
                 }
             }
@@ -275,6 +153,8 @@ mod tests {
                 .map(|(token, total)| aggregator::HoldingSummary {
                     token,
                     total: total.to_f64().unwrap_or(0.0),
+                    value_icp: None,
+                    value_usd: None,
                 })
                 .collect::<Vec<_>>()
         };
@@ -285,22 +165,69 @@ mod tests {
             url: format!("/holdings/{p}"),
             headers: vec![],
             body: ByteBuf::default(),
+            certificate_version: Some(2),
         };
-        let resp = http_request(req).await;
+        let resp = http_request(req);
         assert_eq!(resp.status_code, 200u16);
+        assert!(resp.upgrade.is_none());
+        assert!(resp.headers.iter().any(|(k, _)| k == "IC-Certificate"));
 
         let req = HttpRequest {
             method: "GET".into(),
             url: format!("/summary/{p}"),
             headers: vec![],
             body: ByteBuf::default(),
+            certificate_version: Some(2),
         };
-        let resp = http_request(req).await;
+        let resp = http_request(req.clone());
         assert_eq!(resp.status_code, 200u16);
         let body = std::str::from_utf8(resp.body.as_ref()).unwrap();
         println!("body http: {}", body);
         assert!(body.contains("AAA"));
         assert!(body.contains("3"));
+
+        let resp = http_request_update(req).await;
+        assert_eq!(resp.status_code, 200u16);
+        assert!(std::str::from_utf8(resp.body.as_ref())
+            .unwrap()
+            .contains("AAA"));
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn uncertified_requests_upgrade() {
+        cache::get().clear();
+        let get = |url: &str, version| HttpRequest {
+            method: "GET".into(),
+            url: url.into(),
+            headers: vec![],
+            body: ByteBuf::default(),
+            certificate_version: version,
+        };
+        let p = candid::Principal::from_text("aaaaa-aa").unwrap();
+        let uncached = format!("/holdings/{p}");
+        for req in [
+            get(&uncached, Some(2)),
+            get("/metrics", Some(2)),
+            get(&uncached, None),
+        ] {
+            assert_eq!(http_request(req).upgrade, Some(true));
+        }
+        // preflights and bad requests are answered without upgrading
+        let resp = http_request(get("/nope", Some(2)));
+        assert_eq!((resp.status_code, resp.upgrade), (404, None));
+        let resp = http_request(get("/holdings/nope", None));
+        assert_eq!((resp.status_code, resp.upgrade), (400, None));
+        let preflight = HttpRequest {
+            method: "OPTIONS".into(),
+            ..get("/graphql", Some(2))
+        };
+        let resp = http_request(preflight);
+        assert_eq!((resp.status_code, resp.upgrade), (204, None));
+        cache::get().insert(p, (Vec::new(), Vec::new(), aggregator::utils::now()));
+        assert_eq!(http_request(get(&uncached, None)).upgrade, Some(true));
+        assert_eq!(http_request(get(&uncached, Some(2))).upgrade, None);
+        cache::get().clear();
     }
 
     #[tokio::test]
@@ -311,15 +238,12 @@ mod tests {
         cache::get().insert(
             p,
             (
-                vec![Holding {
-                    source: "test".into(),
-                    token: "BBB".into(),
-                    amount: "5".into(),
-                    status: "ok".into(),
-                }],
+                vec![holding("BBB", 5)],
                 vec![aggregator::HoldingSummary {
                     token: "BBB".into(),
                     total: 5.0,
+                    value_icp: None,
+                    value_usd: None,
                 }],
                 aggregator::utils::now(),
             ),
@@ -330,8 +254,10 @@ mod tests {
             url: "/graphql".into(),
             headers: vec![],
             body: ByteBuf::from(serde_json::to_vec(&serde_json::json!({"query": query})).unwrap()),
+            certificate_version: Some(2),
         };
-        let resp = http_request(req).await;
+        assert_eq!(http_request(req.clone()).upgrade, Some(true));
+        let resp = http_request_update(req).await;
         assert_eq!(resp.status_code, 200u16);
         let body = std::str::from_utf8(resp.body.as_ref()).unwrap();
         assert!(body.contains("BBB"));
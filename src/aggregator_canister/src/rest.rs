@@ -0,0 +1,665 @@
+use crate::ic_http::{Request, Response};
+use aggregator::TokenTotal;
+use bx_core::{Holding, TypedHolding};
+use candid::Principal;
+use serde_bytes::ByteBuf;
+use std::cmp::Ordering;
+
+// Plain HTTP interface for dashboards. Every route answers JSON by default
+// and CSV when asked with `Accept: text/csv`; lists take filters, `sort`,
+// `limit` and `offset` from the query string and report the unpaged length
+// in `X-Total-Count`. Bad input is a 400, a known path with the wrong
+// method a 405.
+
+const MAX_LIMIT: usize = 1000;
+const HOLDING_FIELDS: [&str; 4] = ["token", "amount", "source", "status"];
+const SUMMARY_FIELDS: [&str; 4] = ["token", "total", "value_icp", "value_usd"];
+
+pub fn path_parts(url: &str) -> Vec<&str> {
+    let path = url.split('?').next().unwrap_or("");
+    path.trim_start_matches('/').split('/').collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' => match s
+                .get(i + 1..i + 3)
+                .filter(|h| h.bytes().all(|c| c.is_ascii_hexdigit()))
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+            {
+                Some(b) => {
+                    out.push(b);
+                    i += 2;
+                }
+                None => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Decoded `key=value` pairs of the query string
+pub fn query_pairs(url: &str) -> Vec<(String, String)> {
+    let Some((_, query)) = url.split_once('?') else {
+        return Vec::new();
+    };
+    query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (k, v) = p.split_once('=').unwrap_or((p, ""));
+            (percent_decode(k), percent_decode(v))
+        })
+        .collect()
+}
+
+/// Filters, sort order and page of a list request
+#[derive(Debug, Default, PartialEq)]
+pub struct Params {
+    pub ledgers: Vec<String>,
+    pub dexes: Vec<String>,
+    pub status: Vec<String>,
+    /// Field and whether to sort descending
+    pub sort: Option<(String, bool)>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+fn list(v: &str) -> Vec<String> {
+    v.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn number(key: &str, v: &str) -> Result<usize, String> {
+    v.parse()
+        .map_err(|_| format!("{key} must be a non-negative integer"))
+}
+
+impl Params {
+    /// Parse the query string of `url`, accepting `sort` on `fields`.
+    /// Unknown parameters are ignored.
+    pub fn parse(url: &str, fields: &[&str]) -> Result<Params, String> {
+        let mut params = Params::default();
+        for (key, value) in query_pairs(url) {
+            match key.as_str() {
+                "ledgers" => {
+                    for id in list(&value) {
+                        Principal::from_text(&id).map_err(|_| format!("invalid ledger {id}"))?;
+                        params.ledgers.push(id);
+                    }
+                }
+                "dexes" => params.dexes = list(&value),
+                "status" => params.status = list(&value),
+                "sort" => {
+                    let (field, desc) = match value.strip_prefix('-') {
+                        Some(f) => (f, true),
+                        None => (value.as_str(), false),
+                    };
+                    if !fields.contains(&field) {
+                        return Err(format!(
+                            "sort must be one of {}, optionally prefixed with -",
+                            fields.join(", ")
+                        ));
+                    }
+                    params.sort = Some((field.to_string(), desc));
+                }
+                "limit" => {
+                    let limit = number("limit", &value)?;
+                    if limit == 0 || limit > MAX_LIMIT {
+                        return Err(format!("limit must be between 1 and {MAX_LIMIT}"));
+                    }
+                    params.limit = Some(limit);
+                }
+                "offset" => params.offset = number("offset", &value)?,
+                _ => {}
+            }
+        }
+        Ok(params)
+    }
+
+    /// Items of the requested page and the total before paging
+    fn page<T>(&self, items: Vec<T>) -> (Vec<T>, usize) {
+        let total = items.len();
+        let page = items
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(MAX_LIMIT))
+            .collect();
+        (page, total)
+    }
+}
+
+/// Holdings matching the ledger, DEX and status filters. Ledgers and DEXes
+/// select sources, so a holding is kept when it matches either of them.
+pub fn filter_holdings(holdings: Vec<TypedHolding>, params: &Params) -> Vec<Holding> {
+    let by_source = !params.ledgers.is_empty() || !params.dexes.is_empty();
+    holdings
+        .into_iter()
+        .filter(|h| {
+            !by_source
+                || h.ledger
+                    .is_some_and(|l| params.ledgers.contains(&l.to_text()))
+                || params
+                    .dexes
+                    .iter()
+                    .any(|d| d.eq_ignore_ascii_case(&h.source))
+        })
+        .map(|h| h.to_legacy())
+        .filter(|h| params.status.is_empty() || params.status.contains(&h.status))
+        .collect()
+}
+
+fn cmp_f64(a: Option<f64>, b: Option<f64>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        // unknown values come after known ones in ascending order
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+fn sort_by<T>(items: &mut [T], desc: bool, cmp: impl Fn(&T, &T) -> Ordering) {
+    items.sort_by(|a, b| if desc { cmp(b, a) } else { cmp(a, b) });
+}
+
+pub fn sort_holdings(holdings: &mut [Holding], params: &Params) {
+    let Some((field, desc)) = &params.sort else {
+        return;
+    };
+    match field.as_str() {
+        "amount" => sort_by(holdings, *desc, |a, b| {
+            cmp_f64(a.amount.parse().ok(), b.amount.parse().ok())
+        }),
+        "source" => sort_by(holdings, *desc, |a, b| a.source.cmp(&b.source)),
+        "status" => sort_by(holdings, *desc, |a, b| a.status.cmp(&b.status)),
+        _ => sort_by(holdings, *desc, |a, b| a.token.cmp(&b.token)),
+    }
+}
+
+pub fn sort_summary(summary: &mut [TokenTotal], params: &Params) {
+    let Some((field, desc)) = &params.sort else {
+        return;
+    };
+    match field.as_str() {
+        "total" => sort_by(summary, *desc, |a, b| cmp_f64(Some(a.total), Some(b.total))),
+        "value_icp" => sort_by(summary, *desc, |a, b| cmp_f64(a.value_icp, b.value_icp)),
+        "value_usd" => sort_by(summary, *desc, |a, b| cmp_f64(a.value_usd, b.value_usd)),
+        _ => sort_by(summary, *desc, |a, b| a.token.cmp(&b.token)),
+    }
+}
+
+fn csv_field(v: &str) -> String {
+    if v.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", v.replace('"', "\"\""))
+    } else {
+        v.to_string()
+    }
+}
+
+fn csv(header: &str, rows: impl Iterator<Item = Vec<String>>) -> Vec<u8> {
+    let mut out = format!("{header}\r\n");
+    for row in rows {
+        let fields: Vec<String> = row.iter().map(|f| csv_field(f)).collect();
+        out.push_str(&fields.join(","));
+        out.push_str("\r\n");
+    }
+    out.into_bytes()
+}
+
+fn opt(v: Option<f64>) -> String {
+    v.map(|v| v.to_string()).unwrap_or_default()
+}
+
+pub fn holdings_csv(holdings: &[Holding]) -> Vec<u8> {
+    csv(
+        "source,token,amount,status,subaccount",
+        holdings.iter().map(|h| {
+            vec![
+                h.source.clone(),
+                h.token.clone(),
+                h.amount.clone(),
+                h.status.clone(),
+                h.subaccount.clone().unwrap_or_default(),
+            ]
+        }),
+    )
+}
+
+pub fn summary_csv(summary: &[TokenTotal]) -> Vec<u8> {
+    csv(
+        "token,total,value_icp,value_usd",
+        summary.iter().map(|s| {
+            vec![
+                s.token.clone(),
+                s.total.to_string(),
+                opt(s.value_icp),
+                opt(s.value_usd),
+            ]
+        }),
+    )
+}
+
+/// Whether the client asked for CSV
+pub fn wants_csv(req: &Request) -> bool {
+    req.headers
+        .iter()
+        .any(|(k, v)| k.eq_ignore_ascii_case("accept") && v.contains("text/csv"))
+}
+
+pub fn cors_headers() -> Vec<(String, String)> {
+    vec![
+        ("Access-Control-Allow-Origin".into(), "*".into()),
+        (
+            "Access-Control-Allow-Methods".into(),
+            "GET, POST, OPTIONS".into(),
+        ),
+        (
+            "Access-Control-Allow-Headers".into(),
+            "Accept, Content-Type".into(),
+        ),
+        (
+            "Access-Control-Expose-Headers".into(),
+            "X-Total-Count".into(),
+        ),
+    ]
+}
+
+fn respond(status_code: u16, content_type: &str, body: Vec<u8>) -> Response {
+    let mut headers = vec![("Content-Type".to_string(), content_type.to_string())];
+    headers.extend(cors_headers());
+    Response {
+        status_code,
+        headers,
+        body: ByteBuf::from(body),
+        upgrade: None,
+    }
+}
+
+fn json(value: &impl serde::Serialize) -> Response {
+    respond(200, "application/json", serde_json::to_vec(value).unwrap())
+}
+
+pub fn error(status_code: u16, message: &str) -> Response {
+    let body = serde_json::to_vec(&serde_json::json!({ "error": message })).unwrap();
+    respond(status_code, "application/json", body)
+}
+
+/// One page of a list, as CSV or JSON
+fn list_response<T: serde::Serialize>(
+    req: &Request,
+    params: &Params,
+    items: Vec<T>,
+    to_csv: fn(&[T]) -> Vec<u8>,
+) -> Response {
+    let (page, total) = params.page(items);
+    let mut resp = if wants_csv(req) {
+        respond(200, "text/csv; charset=utf-8", to_csv(&page))
+    } else {
+        json(&page)
+    };
+    resp.headers
+        .push(("X-Total-Count".into(), total.to_string()));
+    resp
+}
+
+/// Methods each known path accepts; `None` for unknown paths
+fn allowed_methods(parts: &[&str]) -> Option<&'static [&'static str]> {
+    match parts {
+        ["holdings", _] | ["summary", _] | ["metrics"] | ["openapi.json"] => Some(&["GET"]),
+        ["graphql"] => Some(&["POST"]),
+        _ => None,
+    }
+}
+
+fn principal(pid: &str) -> Result<Principal, Response> {
+    Principal::from_text(pid).map_err(|_| error(400, &format!("invalid principal {pid}")))
+}
+
+/// A valid request for one of the routes
+pub enum Route {
+    Holdings(Principal, Params),
+    Summary(Principal, Params),
+    Metrics,
+    OpenApi,
+    Graphql,
+}
+
+/// The route `req` asks for, or the response that answers it outright:
+/// a CORS preflight, or an error for an unknown path, a method the path
+/// doesn't accept or bad parameters. Nothing is fetched, so queries can
+/// answer these without upgrading.
+pub fn route(req: &Request) -> Result<Route, Response> {
+    let parts = path_parts(&req.url);
+    let Some(allowed) = allowed_methods(&parts) else {
+        return Err(error(404, "not found"));
+    };
+    if req.method == "OPTIONS" {
+        let mut resp = respond(204, "text/plain", Vec::new());
+        resp.headers
+            .push(("Allow".into(), format!("{}, OPTIONS", allowed.join(", "))));
+        return Err(resp);
+    }
+    if !allowed.contains(&req.method.as_str()) {
+        let mut resp = error(405, &format!("method {} not allowed", req.method));
+        resp.headers.push(("Allow".into(), allowed.join(", ")));
+        return Err(resp);
+    }
+    let params = |fields: &[&str]| Params::parse(&req.url, fields).map_err(|e| error(400, &e));
+    match parts.as_slice() {
+        ["holdings", pid] => Ok(Route::Holdings(principal(pid)?, params(&HOLDING_FIELDS)?)),
+        ["summary", pid] => Ok(Route::Summary(principal(pid)?, params(&SUMMARY_FIELDS)?)),
+        ["metrics"] => Ok(Route::Metrics),
+        ["openapi.json"] => Ok(Route::OpenApi),
+        ["graphql"] => Ok(Route::Graphql),
+        _ => Err(error(404, "not found")),
+    }
+}
+
+/// Route `req`, fetching whatever is not cached. Nothing here charges; the
+/// HTTP endpoints do that once per request.
+pub async fn handle(req: &Request) -> Response {
+    let route = match route(req) {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+    match route {
+        Route::Holdings(principal, params) => {
+            let holdings = match aggregator::fetch_typed_holdings(principal).await {
+                Ok(v) => v,
+                Err(e) => return error(500, &e),
+            };
+            let mut holdings = filter_holdings(holdings, &params);
+            sort_holdings(&mut holdings, &params);
+            list_response(req, &params, holdings, holdings_csv)
+        }
+        Route::Summary(principal, params) => {
+            let mut summary = match aggregator::fetch_summary(principal).await {
+                Ok(v) => v,
+                Err(e) => return error(500, &e),
+            };
+            sort_summary(&mut summary, &params);
+            list_response(req, &params, summary, summary_csv)
+        }
+        Route::Metrics => json(&aggregator::metrics::get()),
+        Route::OpenApi => json(&openapi()),
+        Route::Graphql => respond(
+            200,
+            "application/json",
+            crate::execute_graphql(req.body.as_ref()).await,
+        ),
+    }
+}
+
+/// OpenAPI description of the routes above
+pub fn openapi() -> serde_json::Value {
+    use serde_json::json;
+    let principal = json!({
+        "name": "principal", "in": "path", "required": true,
+        "schema": { "type": "string" }
+    });
+    let list = |name: &str, description: &str| {
+        json!({
+            "name": name, "in": "query", "required": false,
+            "description": description,
+            "schema": { "type": "string" }
+        })
+    };
+    let sort = |fields: &[&str]| {
+        let values: Vec<String> = fields
+            .iter()
+            .flat_map(|f| [f.to_string(), format!("-{f}")])
+            .collect();
+        json!({
+            "name": "sort", "in": "query", "required": false,
+            "description": "Field to sort by; prefix with - for descending order",
+            "schema": { "type": "string", "enum": values }
+        })
+    };
+    let paging = [
+        json!({
+            "name": "limit", "in": "query", "required": false,
+            "schema": { "type": "integer", "minimum": 1, "maximum": MAX_LIMIT }
+        }),
+        json!({
+            "name": "offset", "in": "query", "required": false,
+            "schema": { "type": "integer", "minimum": 0, "default": 0 }
+        }),
+    ];
+    let list_responses = |schema: &str| {
+        json!({
+            "200": {
+                "description": "One page of results",
+                "headers": {
+                    "X-Total-Count": {
+                        "description": "Number of results before paging",
+                        "schema": { "type": "integer" }
+                    }
+                },
+                "content": {
+                    "application/json": {
+                        "schema": { "type": "array", "items": { "$ref": schema } }
+                    },
+                    "text/csv": { "schema": { "type": "string" } }
+                }
+            },
+            "400": { "$ref": "#/components/responses/Error" },
+            "405": { "$ref": "#/components/responses/Error" },
+            "500": { "$ref": "#/components/responses/Error" }
+        })
+    };
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "BlockXpand aggregator",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": {
+            "/holdings/{principal}": {
+                "get": {
+                    "summary": "Holdings of a principal across ledgers, neurons and DEXes",
+                    "parameters": [
+                        principal,
+                        list("ledgers", "Comma separated ledger canister ids"),
+                        list("dexes", "Comma separated DEX names"),
+                        list("status", "Comma separated holding statuses"),
+                        sort(&HOLDING_FIELDS),
+                        paging[0],
+                        paging[1]
+                    ],
+                    "responses": list_responses("#/components/schemas/Holding")
+                }
+            },
+            "/summary/{principal}": {
+                "get": {
+                    "summary": "Totals per token",
+                    "parameters": [principal, sort(&SUMMARY_FIELDS), paging[0], paging[1]],
+                    "responses": list_responses("#/components/schemas/TokenTotal")
+                }
+            },
+            "/metrics": {
+                "get": {
+                    "summary": "Service metrics",
+                    "responses": {
+                        "200": {
+                            "description": "Metrics",
+                            "content": { "application/json": { "schema": { "type": "object" } } }
+                        }
+                    }
+                }
+            },
+            "/graphql": {
+                "post": {
+                    "summary": "GraphQL endpoint",
+                    "requestBody": {
+                        "content": {
+                            "application/json": { "schema": { "type": "object" } }
+                        }
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "GraphQL response",
+                            "content": { "application/json": { "schema": { "type": "object" } } }
+                        }
+                    }
+                }
+            },
+            "/openapi.json": {
+                "get": {
+                    "summary": "This document",
+                    "responses": { "200": { "description": "OpenAPI document" } }
+                }
+            }
+        },
+        "components": {
+            "schemas": {
+                "Holding": {
+                    "type": "object",
+                    "required": ["source", "token", "amount", "status"],
+                    "properties": {
+                        "source": { "type": "string" },
+                        "token": { "type": "string" },
+                        "amount": { "type": "string" },
+                        "status": { "type": "string" },
+                        "subaccount": { "type": "string" }
+                    }
+                },
+                "TokenTotal": {
+                    "type": "object",
+                    "required": ["token", "total"],
+                    "properties": {
+                        "token": { "type": "string" },
+                        "total": { "type": "number" },
+                        "value_icp": { "type": "number", "nullable": true },
+                        "value_usd": { "type": "number", "nullable": true }
+                    }
+                }
+            },
+            "responses": {
+                "Error": {
+                    "description": "Error",
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "properties": { "error": { "type": "string" } }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bx_core::HoldingKind;
+
+    fn holding(source: &str, token: &str, amount: u64, kind: HoldingKind) -> TypedHolding {
+        TypedHolding::new(source, token, amount.into(), 0, kind)
+    }
+
+    #[test]
+    fn parses_query_strings() {
+        let ledger = Principal::from_slice(&[0x10]).to_text();
+        let url = format!("/holdings/x?ledgers={ledger}&dexes=Sonic%2CICPSwap&status=liquid&sort=-amount&limit=5&offset=2&_=1");
+        let params = Params::parse(&url, &HOLDING_FIELDS).unwrap();
+        assert_eq!(params.ledgers, vec![ledger]);
+        assert_eq!(params.dexes, vec!["Sonic", "ICPSwap"]);
+        assert_eq!(params.sort, Some(("amount".into(), true)));
+        assert_eq!((params.limit, params.offset), (Some(5), 2));
+
+        assert!(Params::parse("/x?sort=color", &HOLDING_FIELDS).is_err());
+        assert!(Params::parse("/x?limit=0", &HOLDING_FIELDS).is_err());
+        assert!(Params::parse("/x?offset=-1", &HOLDING_FIELDS).is_err());
+        assert!(Params::parse("/x?ledgers=nope", &HOLDING_FIELDS).is_err());
+        assert_eq!(percent_decode("a%20b+c%zz"), "a b c%zz");
+    }
+
+    #[test]
+    fn filters_sorts_and_pages() {
+        let ledger = Principal::from_slice(&[0x10]);
+        let holdings = vec![
+            TypedHolding {
+                ledger: Some(ledger),
+                ..holding("ledger", "ICP", 3, HoldingKind::Liquid)
+            },
+            holding("Sonic", "AAA", 10, HoldingKind::Liquid),
+            holding(
+                "ICPSwap",
+                "BBB",
+                7,
+                HoldingKind::LpPosition { pool: "p".into() },
+            ),
+        ];
+        let url = format!("/h?ledgers={ledger}&dexes=sonic&sort=-amount");
+        let params = Params::parse(&url, &HOLDING_FIELDS).unwrap();
+        let mut filtered = filter_holdings(holdings.clone(), &params);
+        sort_holdings(&mut filtered, &params);
+        let tokens: Vec<&str> = filtered.iter().map(|h| h.token.as_str()).collect();
+        assert_eq!(tokens, vec!["AAA", "ICP"]);
+
+        let params = Params::parse("/h?status=lp_escrow", &HOLDING_FIELDS).unwrap();
+        assert_eq!(filter_holdings(holdings.clone(), &params).len(), 1);
+
+        let params = Params::parse("/h?sort=token&limit=1&offset=1", &HOLDING_FIELDS).unwrap();
+        let mut all = filter_holdings(holdings, &params);
+        sort_holdings(&mut all, &params);
+        let (page, total) = params.page(all);
+        assert_eq!((page[0].token.as_str(), total), ("BBB", 3));
+    }
+
+    #[test]
+    fn csv_quotes_fields() {
+        let holdings = vec![Holding {
+            source: "a,b".into(),
+            token: "say \"hi\"".into(),
+            amount: "1".into(),
+            status: "liquid".into(),
+            subaccount: None,
+            wallet: None,
+        }];
+        let body = String::from_utf8(holdings_csv(&holdings)).unwrap();
+        assert_eq!(
+            body,
+            "source,token,amount,status,subaccount\r\n\"a,b\",\"say \"\"hi\"\"\",1,liquid,\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_requests() {
+        let req = |method: &str, url: &str| Request {
+            method: method.into(),
+            url: url.into(),
+            headers: vec![],
+            body: ByteBuf::default(),
+            certificate_version: None,
+        };
+        assert_eq!(handle(&req("GET", "/nope")).await.status_code, 404);
+        assert_eq!(handle(&req("GET", "/holdings/nope")).await.status_code, 400);
+        let resp = handle(&req("DELETE", "/metrics")).await;
+        assert_eq!(resp.status_code, 405);
+        assert!(resp.headers.contains(&("Allow".into(), "GET".into())));
+        let resp = handle(&req("OPTIONS", "/graphql")).await;
+        assert_eq!(resp.status_code, 204);
+        assert!(resp
+            .headers
+            .iter()
+            .any(|(k, v)| k == "Access-Control-Allow-Origin" && v == "*"));
+        let resp = handle(&req("GET", "/openapi.json")).await;
+        let doc: serde_json::Value = serde_json::from_slice(&resp.body).unwrap();
+        assert!(doc["paths"]["/holdings/{principal}"]["get"].is_object());
+    }
+}
@@ -1,7 +1,173 @@
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, candid::CandidType, Eq, PartialEq)]
+use candid::{CandidType, Nat, Principal};
+use serde::{Deserialize, Serialize};
+
+/// Legacy holding record kept for existing Candid and JSON clients.
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, Eq, PartialEq)]
 pub struct Holding {
     pub source: String,
     pub token: String,
     pub amount: String,
     pub status: String,
+    /// Hex-encoded ICRC-1 subaccount the balance was read from; `None` for the
+    /// default account and for non-ledger sources
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subaccount: Option<String>,
+    /// Linked wallet the holding belongs to, as a principal or ICP account
+    /// identifier; `None` for the queried principal's own holdings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wallet: Option<String>,
+}
+
+/// Schema version stamped on every [`TypedHolding`]
+pub const HOLDING_VERSION: u16 = 1;
+
+/// What a holding represents, with details specific to each kind.
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, Eq, PartialEq)]
+pub enum HoldingKind {
+    /// Spendable ledger balance
+    Liquid,
+    /// Tokens escrowed in a DEX liquidity position
+    LpPosition { pool: String },
+    /// Tokens locked in a staking contract until `unlock_at` (ns), if known
+    Staked { unlock_at: Option<u64> },
+    /// Rewards that can be claimed from `claim_from`, accrued in the
+    /// liquidity position `pool` if any
+    Claimable {
+        claim_from: Option<Principal>,
+        pool: Option<String>,
+    },
+    /// NNS neuron stake including maturity
+    Neuron {
+        neuron_id: u64,
+        dissolve_delay_secs: Option<u64>,
+        dissolved_at_secs: Option<u64>,
+        lock: String,
+    },
+}
+
+impl HoldingKind {
+    /// Status label used by the legacy [`Holding`] shape
+    pub fn legacy_status(&self) -> String {
+        match self {
+            HoldingKind::Liquid => "liquid".into(),
+            HoldingKind::LpPosition { .. } => "lp_escrow".into(),
+            HoldingKind::Staked { .. } => "staked".into(),
+            // LP rewards were reported as escrowed before holdings were typed
+            HoldingKind::Claimable { pool: Some(_), .. } => "lp_escrow".into(),
+            HoldingKind::Claimable { pool: None, .. } => "claimable".into(),
+            HoldingKind::Neuron { lock, .. } => lock.clone(),
+        }
+    }
+}
+
+/// Typed, versioned holding record. Amounts are kept as raw token units so
+/// consumers never have to re-parse decimal strings.
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq)]
+pub struct TypedHolding {
+    pub version: u16,
+    pub source: String,
+    pub ledger: Option<Principal>,
+    pub symbol: String,
+    pub amount: Nat,
+    pub decimals: u8,
+    pub kind: HoldingKind,
+    pub usd_value: Option<f64>,
+    pub subaccount: Option<String>,
+    /// Linked wallet the holding belongs to, see [`Holding::wallet`]
+    pub wallet: Option<String>,
+}
+
+impl TypedHolding {
+    pub fn new(
+        source: impl Into<String>,
+        symbol: impl Into<String>,
+        amount: Nat,
+        decimals: u8,
+        kind: HoldingKind,
+    ) -> Self {
+        Self {
+            version: HOLDING_VERSION,
+            source: source.into(),
+            ledger: None,
+            symbol: symbol.into(),
+            amount,
+            decimals,
+            kind,
+            usd_value: None,
+            subaccount: None,
+            wallet: None,
+        }
+    }
+
+    /// Amount as a decimal string, e.g. `12.34`
+    pub fn formatted_amount(&self) -> String {
+        format_amount(self.amount.clone(), self.decimals)
+    }
+
+    pub fn to_legacy(&self) -> Holding {
+        Holding {
+            source: self.source.clone(),
+            token: self.symbol.clone(),
+            amount: self.formatted_amount(),
+            status: self.kind.legacy_status(),
+            subaccount: self.subaccount.clone(),
+            wallet: self.wallet.clone(),
+        }
+    }
+}
+
+impl TypedHolding {
+    /// Typed holding of `kind` from a legacy record, reading the decimals
+    /// from the fractional digits of its amount. `None` if the amount isn't
+    /// a decimal number.
+    pub fn from_legacy(h: &Holding, kind: HoldingKind) -> Option<Self> {
+        let (int, frac) = h.amount.split_once('.').unwrap_or((&h.amount, ""));
+        let digits = format!("{int}{frac}");
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let amount = digits.parse::<num_bigint::BigUint>().ok()?;
+        let decimals = u8::try_from(frac.len()).ok()?;
+        Some(TypedHolding {
+            ledger: Principal::from_text(&h.token).ok(),
+            subaccount: h.subaccount.clone(),
+            wallet: h.wallet.clone(),
+            ..TypedHolding::new(
+                h.source.clone(),
+                h.token.clone(),
+                Nat(amount),
+                decimals,
+                kind,
+            )
+        })
+    }
+}
+
+impl From<&TypedHolding> for Holding {
+    fn from(h: &TypedHolding) -> Self {
+        h.to_legacy()
+    }
+}
+
+impl From<TypedHolding> for Holding {
+    fn from(h: TypedHolding) -> Self {
+        h.to_legacy()
+    }
+}
+
+/// Render `n` raw units with `decimals` fractional digits.
+pub fn format_amount(n: Nat, decimals: u8) -> String {
+    use num_bigint::BigUint;
+    use num_integer::Integer;
+    let div = BigUint::from(10u32).pow(decimals as u32);
+    let (q, r) = n.0.div_rem(&div);
+    let mut frac = r.to_str_radix(10);
+    while frac.len() < decimals as usize {
+        frac.insert(0, '0');
+    }
+    if decimals == 0 {
+        q.to_str_radix(10)
+    } else {
+        format!("{}.{frac}", q.to_str_radix(10))
+    }
 }
@@ -7,8 +7,100 @@ fn json_round_trip() {
         token: "ICP".into(),
         amount: "1.23".into(),
         status: "liquid".into(),
+        subaccount: None,
+        wallet: None,
     };
     let json = serde_json::to_string(&holding).unwrap();
     let decoded: Holding = serde_json::from_str(&json).unwrap();
     assert_eq!(holding, decoded);
 }
+
+#[test]
+fn typed_to_legacy() {
+    use bx_core::{HoldingKind, TypedHolding, HOLDING_VERSION};
+    let mut typed = TypedHolding::new(
+        "ledger",
+        "ICP",
+        candid::Nat::from(123_000_000u64),
+        8,
+        HoldingKind::Liquid,
+    );
+    typed.subaccount = Some("01".into());
+    assert_eq!(typed.version, HOLDING_VERSION);
+    let legacy = Holding::from(&typed);
+    assert_eq!(legacy.token, "ICP");
+    assert_eq!(legacy.amount, "1.23000000");
+    assert_eq!(legacy.status, "liquid");
+    assert_eq!(legacy.subaccount.as_deref(), Some("01"));
+
+    let neuron = TypedHolding::new(
+        "neuron",
+        "ICP",
+        candid::Nat::from(1u64),
+        8,
+        HoldingKind::Neuron {
+            neuron_id: 7,
+            dissolve_delay_secs: Some(86_400),
+            dissolved_at_secs: None,
+            lock: "locked_1d".into(),
+        },
+    );
+    assert_eq!(neuron.to_legacy().status, "locked_1d");
+}
+
+#[test]
+fn lp_rewards_keep_legacy_status() {
+    use bx_core::{HoldingKind, TypedHolding};
+    let kind = |pool: Option<&str>| HoldingKind::Claimable {
+        claim_from: None,
+        pool: pool.map(Into::into),
+    };
+    let reward = TypedHolding::new("Sonic", "AAA", 1u64.into(), 0, kind(Some("sonic")));
+    assert_eq!(reward.to_legacy().status, "lp_escrow");
+    let sns = TypedHolding::new("SNS", "BBB", 1u64.into(), 0, kind(None));
+    assert_eq!(sns.to_legacy().status, "claimable");
+}
+
+#[test]
+fn legacy_to_typed() {
+    use bx_core::{HoldingKind, TypedHolding};
+    let legacy = Holding {
+        source: "Sonic".into(),
+        token: "ryjl3-tyaaa-aaaaa-aaaba-cai".into(),
+        amount: "12.0500".into(),
+        status: "lp_escrow".into(),
+        subaccount: None,
+        wallet: None,
+    };
+    let kind = HoldingKind::LpPosition {
+        pool: "sonic".into(),
+    };
+    let typed = TypedHolding::from_legacy(&legacy, kind).unwrap();
+    assert_eq!(typed.amount, candid::Nat::from(120_500u64));
+    assert_eq!(typed.decimals, 4);
+    assert!(typed.ledger.is_some());
+    assert_eq!(typed.to_legacy(), legacy);
+
+    let bad = Holding {
+        amount: "1e5".into(),
+        ..legacy
+    };
+    assert!(TypedHolding::from_legacy(&bad, HoldingKind::Liquid).is_none());
+}
+
+#[test]
+fn typed_candid_round_trip() {
+    use bx_core::{HoldingKind, TypedHolding};
+    let typed = TypedHolding::new(
+        "ICPSwap",
+        "AAA",
+        candid::Nat::from(5u64),
+        0,
+        HoldingKind::LpPosition {
+            pool: "pool1".into(),
+        },
+    );
+    let bytes = candid::encode_one(&typed).unwrap();
+    let decoded: TypedHolding = candid::decode_one(&bytes).unwrap();
+    assert_eq!(typed, decoded);
+}
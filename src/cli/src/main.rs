@@ -1,9 +1,10 @@
-use aggregator::TokenTotal;
-use anyhow::Result;
+use aggregator::{cert, CertifiedHoldings, TokenTotal};
+use anyhow::{anyhow, bail, Result};
 use bx_core::Holding;
 use candid::{Decode, Encode, Principal};
 use clap::{Parser, Subcommand};
-use ic_agent::Agent;
+use ic_agent::hash_tree::LookupResult;
+use ic_agent::{Agent, Certificate};
 
 #[derive(Parser)]
 struct Cli {
@@ -23,6 +24,8 @@ enum Commands {
     Holdings { principal: String },
     /// Fetch summary for a principal
     Summary { principal: String },
+    /// Fetch certified holdings and verify them against the IC root key
+    Certified { principal: String },
 }
 
 async fn get_agent(url: &str) -> Agent {
@@ -33,6 +36,75 @@ async fn get_agent(url: &str) -> Agent {
     agent
 }
 
+/// How far a certificate's time may be from the local clock
+const MAX_CERT_SKEW: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// Unsigned LEB128 number, as the certificate `time` is encoded
+fn read_leb128(bytes: &[u8]) -> Option<u64> {
+    let mut n: u64 = 0;
+    for (i, b) in bytes.iter().enumerate() {
+        let shift = 7 * i as u32;
+        if shift >= 64 {
+            return None;
+        }
+        n |= u64::from(b & 0x7f) << shift;
+        if b & 0x80 == 0 {
+            return Some(n);
+        }
+    }
+    None
+}
+
+/// Fail unless the certificate was issued within `MAX_CERT_SKEW` of now.
+/// `Agent::verify` checks only the signature, so an old certificate
+/// replayed with its witness would otherwise pass.
+fn check_cert_time(certificate: &Certificate) -> Result<()> {
+    let time = match certificate.tree.lookup_path([b"time".as_slice()]) {
+        LookupResult::Found(time) => {
+            read_leb128(time).ok_or_else(|| anyhow!("certificate time is malformed"))?
+        }
+        _ => bail!("certificate has no time"),
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_nanos();
+    let skew = now.abs_diff(u128::from(time));
+    if skew > MAX_CERT_SKEW.as_nanos() {
+        bail!(
+            "certificate is {}s away from the local clock",
+            skew / 1_000_000_000
+        );
+    }
+    Ok(())
+}
+
+/// Check the certificate is recent and signed by the IC for `cid` and that
+/// its certified data proves `res` as the entry of `principal`
+fn verify_certified(
+    agent: &Agent,
+    cid: Principal,
+    principal: Principal,
+    res: &CertifiedHoldings,
+) -> Result<()> {
+    if res.certificate.is_empty() {
+        bail!("response carries no certificate");
+    }
+    let certificate: Certificate = serde_cbor::from_slice(&res.certificate)?;
+    agent.verify(&certificate, cid)?;
+    check_cert_time(&certificate)?;
+    let path: [&[u8]; 3] = [b"canister", cid.as_slice(), b"certified_data"];
+    let certified_data = match certificate.tree.lookup_path(path) {
+        LookupResult::Found(data) => data,
+        _ => bail!("certificate has no certified data for {cid}"),
+    };
+    let fetched_at = res
+        .fetched_at
+        .ok_or_else(|| anyhow!("no cached holdings for {principal}"))?;
+    let expected = cert::entry_hash(&res.holdings, &res.summary, fetched_at);
+    cert::verify_witness(&res.witness, certified_data, principal, expected)
+        .map_err(anyhow::Error::msg)
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
@@ -58,9 +130,22 @@ async fn main() -> Result<()> {
                 .with_arg(arg)
                 .call()
                 .await?;
-            let summary: Vec<TokenTotal> = Decode!(&bytes, Vec<TokenTotal>)?;
+            let summary =
+                Decode!(&bytes, Result<Vec<TokenTotal>, String>)?.map_err(anyhow::Error::msg)?;
             println!("{}", serde_json::to_string_pretty(&summary)?);
         }
+        Commands::Certified { principal } => {
+            let p = Principal::from_text(principal)?;
+            let arg = Encode!(&p)?;
+            let bytes = agent
+                .query(&cid, "get_holdings_cert")
+                .with_arg(arg)
+                .call()
+                .await?;
+            let res = Decode!(&bytes, CertifiedHoldings)?;
+            verify_certified(&agent, cid, p, &res)?;
+            println!("{}", serde_json::to_string_pretty(&res.holdings)?);
+        }
     }
     Ok(())
 }
@@ -1,7 +1,12 @@
 export const idlFactory = ({ IDL }) => {
-  const TokenTotal = IDL.Record({ token: IDL.Text, total: IDL.Float64 });
+  const HoldingSummary = IDL.Record({
+    token: IDL.Text,
+    total: IDL.Float64,
+    value_icp: IDL.Opt(IDL.Float64),
+    value_usd: IDL.Opt(IDL.Float64),
+  });
   return IDL.Service({
-    get_holdings_summary: IDL.Func([IDL.Principal], [IDL.Variant({ Ok: IDL.Vec(TokenTotal), Err: IDL.Text })], []),
+    get_holdings_summary: IDL.Func([IDL.Principal], [IDL.Variant({ Ok: IDL.Vec(HoldingSummary), Err: IDL.Text })], []),
   });
 };
 
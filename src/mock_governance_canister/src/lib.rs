@@ -0,0 +1,151 @@
+use candid::{CandidType, Principal};
+use ic_cdk_macros::{query, update};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::sync::Mutex;
+
+#[derive(CandidType, Deserialize, Clone)]
+struct NeuronId {
+    id: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+enum DissolveState {
+    DissolveDelaySeconds(u64),
+    WhenDissolvedTimestampSeconds(u64),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Neuron {
+    id: Option<NeuronId>,
+    controller: Option<Principal>,
+    hot_keys: Vec<Principal>,
+    cached_neuron_stake_e8s: u64,
+    neuron_fees_e8s: u64,
+    maturity_e8s_equivalent: u64,
+    staked_maturity_e8s_equivalent: Option<u64>,
+    dissolve_state: Option<DissolveState>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct NeuronInfo {
+    stake_e8s: u64,
+    dissolve_delay_seconds: u64,
+    state: i32,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ListNeurons {
+    neuron_ids: Vec<u64>,
+    include_neurons_readable_by_caller: bool,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ListNeuronsResponse {
+    neuron_infos: Vec<(u64, NeuronInfo)>,
+    full_neurons: Vec<Neuron>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GovernanceError {
+    error_type: i32,
+    error_message: String,
+}
+
+const EIGHT_YEARS_SECS: u64 = 252_460_800;
+
+static NEURONS: Lazy<Mutex<Vec<Neuron>>> = Lazy::new(|| {
+    Mutex::new(vec![Neuron {
+        id: Some(NeuronId { id: 1 }),
+        controller: Some(Principal::anonymous()),
+        hot_keys: Vec::new(),
+        cached_neuron_stake_e8s: 120_000_000_000,
+        neuron_fees_e8s: 0,
+        maturity_e8s_equivalent: 0,
+        staked_maturity_e8s_equivalent: None,
+        dissolve_state: Some(DissolveState::DissolveDelaySeconds(EIGHT_YEARS_SECS)),
+    }])
+});
+
+fn readable_by(n: &Neuron, caller: Principal) -> bool {
+    n.controller == Some(caller) || n.hot_keys.contains(&caller)
+}
+
+fn info(n: &Neuron) -> NeuronInfo {
+    let (dissolve_delay_seconds, state) = match n.dissolve_state {
+        Some(DissolveState::DissolveDelaySeconds(d)) if d > 0 => (d, 1),
+        Some(DissolveState::WhenDissolvedTimestampSeconds(_)) => (0, 2),
+        _ => (0, 3),
+    };
+    NeuronInfo {
+        stake_e8s: n.cached_neuron_stake_e8s,
+        dissolve_delay_seconds,
+        state,
+    }
+}
+
+#[candid::candid_method(query)]
+#[query]
+fn list_neurons(req: ListNeurons) -> ListNeuronsResponse {
+    let caller = ic_cdk::caller();
+    let neurons = NEURONS.lock().unwrap();
+    let selected: Vec<Neuron> = neurons
+        .iter()
+        .filter(|n| {
+            let id = n.id.as_ref().map(|i| i.id).unwrap_or_default();
+            (req.include_neurons_readable_by_caller && readable_by(n, caller))
+                || req.neuron_ids.contains(&id)
+        })
+        .cloned()
+        .collect();
+    ListNeuronsResponse {
+        neuron_infos: selected
+            .iter()
+            .filter_map(|n| n.id.as_ref().map(|i| (i.id, info(n))))
+            .collect(),
+        full_neurons: selected
+            .into_iter()
+            .filter(|n| readable_by(n, caller))
+            .collect(),
+    }
+}
+
+#[candid::candid_method(query)]
+#[query]
+fn get_full_neuron(id: u64) -> Result<Neuron, GovernanceError> {
+    NEURONS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|n| n.id.as_ref().map(|i| i.id) == Some(id))
+        .cloned()
+        .ok_or(GovernanceError {
+            error_type: 3,
+            error_message: format!("neuron {id} not found"),
+        })
+}
+
+#[candid::candid_method(update)]
+#[update]
+fn add_neuron(
+    controller: Principal,
+    stake_e8s: u64,
+    maturity_e8s: u64,
+    dissolve_delay: u64,
+) -> u64 {
+    let mut neurons = NEURONS.lock().unwrap();
+    let id = neurons.len() as u64 + 1;
+    neurons.push(Neuron {
+        id: Some(NeuronId { id }),
+        controller: Some(controller),
+        hot_keys: vec![ic_cdk::caller()],
+        cached_neuron_stake_e8s: stake_e8s,
+        neuron_fees_e8s: 0,
+        maturity_e8s_equivalent: maturity_e8s,
+        staked_maturity_e8s_equivalent: None,
+        dissolve_state: Some(DissolveState::DissolveDelaySeconds(dissolve_delay)),
+    });
+    id
+}
+
+ic_cdk::export_candid!();
@@ -58,6 +58,12 @@ fn metadata() -> PoolMetadata {
     }
 }
 
+#[candid::candid_method(query)]
+#[query]
+fn get_reserves() -> (Nat, Nat) {
+    (Nat::from(50_000_000_000u64), Nat::from(10_000_000_000u64))
+}
+
 #[candid::candid_method(query)]
 #[query]
 fn get_pools() -> Vec<PoolData> {
@@ -45,12 +45,6 @@ fn icrc1_balance_of(account: Account) -> Nat {
     Nat::from(bal)
 }
 
-#[derive(CandidType, Deserialize)]
-struct CreditArgs {
-    owner: Principal,
-    amount: Nat,
-}
-
 #[candid::candid_method(update)]
 #[update]
 async fn credit(owner: Principal, amount: Nat) {
@@ -22,6 +22,14 @@ struct PositionInfo {
     auto_compound: bool,
 }
 
+#[derive(CandidType, Deserialize, Clone)]
+struct PairInfo {
+    token0: Token,
+    token1: Token,
+    reserve0: u64,
+    reserve1: u64,
+}
+
 static HEIGHT: Lazy<Mutex<u64>> = Lazy::new(|| Mutex::new(0));
 static TOTAL_SUPPLY: Lazy<Mutex<u64>> = Lazy::new(|| Mutex::new(10_000_000_000));
 static TOTAL_REWARDS: Lazy<Mutex<u64>> = Lazy::new(|| Mutex::new(50_000_000));
@@ -69,6 +77,23 @@ fn get_user_positions(_p: Principal) -> Vec<PositionInfo> {
     ]
 }
 
+#[candid::candid_method(query)]
+#[query]
+fn get_pairs() -> Vec<PairInfo> {
+    vec![PairInfo {
+        token0: Token {
+            address: "sonic0".to_string(),
+            decimals: 8,
+        },
+        token1: Token {
+            address: "sonic1".to_string(),
+            decimals: 8,
+        },
+        reserve0: 100_000_000_000,
+        reserve1: 200_000_000_000,
+    }]
+}
+
 #[candid::candid_method(query)]
 #[query]
 fn block_height() -> u64 {
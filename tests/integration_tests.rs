@@ -359,6 +359,55 @@ mod tests {
         assert!(holdings.iter().any(|h| h.source == "InfinitySwap"));
     }
 
+    #[tokio::test]
+    async fn integration_neuron_holdings() {
+        if !ensure_dfx() {
+            eprintln!("dfx not found; skipping integration test");
+            return;
+        }
+
+        let replica = match Replica::start() {
+            Some(r) => r,
+            None => {
+                eprintln!("failed to start dfx; skipping test");
+                return;
+            }
+        };
+
+        let ledger_id = match deploy(replica.dir.path(), "mock_ledger") {
+            Some(id) => id,
+            None => {
+                eprintln!("failed to deploy mock ledger; skipping test");
+                return;
+            }
+        };
+        let gov_id = match deploy(replica.dir.path(), "mock_governance") {
+            Some(id) => id,
+            None => {
+                eprintln!("failed to deploy mock governance; skipping test");
+                return;
+            }
+        };
+
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "[ledgers]\nMOCK = \"{ledger_id}\"").unwrap();
+
+        std::env::set_var("LEDGER_URL", "http://127.0.0.1:4943");
+        std::env::set_var("LEDGERS_CONFIG", file.path());
+        std::env::set_var("NNS_GOVERNANCE", &gov_id);
+
+        aggregator::utils::load_dex_config().await;
+
+        let neurons = aggregator::neuron_fetcher::fetch(Principal::anonymous())
+            .await
+            .unwrap();
+        assert_eq!(neurons.len(), 1);
+        let neuron = neurons[0].to_legacy();
+        assert_eq!(neuron.token, "ICP");
+        assert_eq!(neuron.amount, "1200.00000000");
+        assert_eq!(neuron.status, "locked_8y");
+    }
+
     #[cfg(feature = "claim")]
     #[tokio::test]
     async fn integration_reward_claim() {
@@ -535,6 +584,9 @@ mod tests {
                 preferred_ledgers: vec![cid.clone()],
                 preferred_dexes: Vec::new(),
                 dark_mode: false,
+                subaccounts: None,
+                linked_wallets: None,
+                track_history: None,
             },
         );
 
@@ -704,7 +756,7 @@ mod tests {
 
         let req = Request {
             method: "GET".into(),
-            url: format!("/summary/{principal}").into(),
+            url: format!("/summary/{principal}"),
             headers: vec![],
             body: ByteBuf::default(),
         };